@@ -1,57 +1,298 @@
-use std::{
-    collections::BTreeMap,
-    io,
-    ops::{AddAssign, MulAssign},
-};
+use std::{collections::BTreeMap, fmt, io};
 
 use thiserror::Error;
 
+mod de;
+mod ser;
+
+pub use de::{from_bytes, from_bytes_with_options, from_bytes_with_span, Deserializer};
+pub use ser::{to_bytes, to_value};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ParseErrorKind {
+    UnexpectedEof,
+    UnexpectedByte(u8),
+    EmptyNumber,
+    IntegerOverflow,
+    NonCanonicalInteger,
+    StringTooLong(usize),
+    DepthLimitExceeded,
+    UnsortedKey,
+    DuplicateKey,
+    TrailingData,
+    Custom(String),
+}
+
+impl fmt::Display for ParseErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::UnexpectedEof => write!(f, "unexpected end of input"),
+            Self::UnexpectedByte(val) => write!(f, "unexpected byte {:?}", *val as char),
+            Self::EmptyNumber => write!(f, "number cannot be empty"),
+            Self::IntegerOverflow => write!(f, "integer overflow"),
+            Self::NonCanonicalInteger => write!(f, "non canonical integer"),
+            Self::StringTooLong(len) => write!(f, "string is too long ({len} bytes)"),
+            Self::DepthLimitExceeded => write!(f, "nesting depth limit exceeded"),
+            Self::UnsortedKey => write!(f, "dict keys are not sorted"),
+            Self::DuplicateKey => write!(f, "duplicate dict key"),
+            Self::TrailingData => write!(f, "trailing data after bencode value"),
+            Self::Custom(msg) => write!(f, "{msg}"),
+        }
+    }
+}
+
 #[derive(Debug, Error, PartialEq, Eq)]
-#[error("Bencode parse error: {0}")]
-pub struct ParseError(String);
+#[error("Bencode parse error at byte {offset}: {kind}")]
+pub struct ParseError {
+    pub kind: ParseErrorKind,
+    pub offset: usize,
+}
 
 impl ParseError {
     pub fn new(msg: &str) -> Self {
-        Self(msg.to_string())
+        Self::custom(msg, 0)
+    }
+
+    pub fn custom(msg: &str, offset: usize) -> Self {
+        Self {
+            kind: ParseErrorKind::Custom(msg.to_string()),
+            offset,
+        }
     }
 }
 
-impl From<(&str, &[u8])> for ParseError {
-    fn from((msg, input): (&str, &[u8])) -> Self {
-        Self(format!("{msg}: {input:?}"))
+impl serde::de::Error for ParseError {
+    fn custom<T: fmt::Display>(msg: T) -> Self {
+        Self::new(&msg.to_string())
+    }
+}
+
+impl serde::ser::Error for ParseError {
+    fn custom<T: fmt::Display>(msg: T) -> Self {
+        Self::new(&msg.to_string())
     }
 }
 
 pub type IResult<'a, T> = Result<(&'a [u8], T), ParseError>;
 
-fn parse_num<O>(input: &[u8], end_char: u8) -> IResult<O>
-where
-    O: Default + MulAssign<O> + AddAssign<O> + From<u8>,
-{
-    let mut idx = 0;
-    let mut num = O::default();
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ParseOptions {
+    pub max_depth: usize,
+    pub max_string_length: usize,
+    // Reject non canonical integers and unsorted / duplicate dict keys
+    pub strict: bool,
+}
 
-    loop {
-        if idx >= input.len() {
-            return Err(("String num does not contains end tag", input).into());
+impl Default for ParseOptions {
+    fn default() -> Self {
+        Self {
+            max_depth: 64,
+            max_string_length: usize::MAX,
+            strict: false,
         }
+    }
+}
+
+impl ParseOptions {
+    pub fn strict() -> Self {
+        Self {
+            strict: true,
+            ..Default::default()
+        }
+    }
+}
 
-        match input[idx] {
-            val if val == end_char && idx == 0 => {
-                return Err(("Number cannot be empty", input).into())
+// Cursor over the input: keeps track of the absolute offset to report it in errors.
+#[derive(Debug)]
+struct Parser<'a> {
+    input: &'a [u8],
+    pos: usize,
+    depth: usize,
+    options: ParseOptions,
+}
+
+impl<'a> Parser<'a> {
+    fn new(input: &'a [u8], options: ParseOptions) -> Self {
+        Self {
+            input,
+            pos: 0,
+            depth: 0,
+            options,
+        }
+    }
+
+    fn remaining(&self) -> &'a [u8] {
+        &self.input[self.pos..]
+    }
+
+    fn error_at(&self, kind: ParseErrorKind, offset: usize) -> ParseError {
+        ParseError { kind, offset }
+    }
+
+    fn error(&self, kind: ParseErrorKind) -> ParseError {
+        self.error_at(kind, self.pos)
+    }
+
+    fn peek(&self) -> Result<u8, ParseError> {
+        match self.input.get(self.pos) {
+            Some(val) => Ok(*val),
+            None => Err(self.error(ParseErrorKind::UnexpectedEof)),
+        }
+    }
+
+    fn expect(&mut self, tag: u8) -> Result<(), ParseError> {
+        match self.peek()? {
+            val if val == tag => {
+                self.pos += 1;
+                Ok(())
             }
-            val if val == end_char => return Ok((&input[idx + 1..], num)),
-            val @ b'0'..=b'9' => {
-                num *= O::from(10);
-                num += O::from(val - b'0');
+            val => Err(self.error(ParseErrorKind::UnexpectedByte(val))),
+        }
+    }
+
+    fn parse_num(&mut self, end_char: u8) -> Result<u64, ParseError> {
+        let start = self.pos;
+        let mut num: u64 = 0;
+
+        loop {
+            match self.peek()? {
+                val if val == end_char && self.pos == start => {
+                    return Err(self.error(ParseErrorKind::EmptyNumber))
+                }
+                val if val == end_char => break,
+                val @ b'0'..=b'9' => {
+                    num = num
+                        .checked_mul(10)
+                        .and_then(|x| x.checked_add((val - b'0') as u64))
+                        .ok_or_else(|| self.error_at(ParseErrorKind::IntegerOverflow, start))?;
+                }
+                val => return Err(self.error(ParseErrorKind::UnexpectedByte(val))),
             }
-            val => {
-                return Err(ParseError::new(&format!(
-                    "String num is not a number={val}: {input:?}"
-                )))
+            self.pos += 1;
+        }
+
+        if self.options.strict && self.input[start] == b'0' && self.pos - start > 1 {
+            return Err(self.error_at(ParseErrorKind::NonCanonicalInteger, start));
+        }
+
+        self.pos += 1;
+        Ok(num)
+    }
+
+    fn parse_text(&mut self) -> Result<&'a [u8], ParseError> {
+        let start = self.pos;
+        let str_len = usize::try_from(self.parse_num(b':')?)
+            .map_err(|_| self.error_at(ParseErrorKind::IntegerOverflow, start))?;
+
+        if str_len > self.options.max_string_length {
+            return Err(self.error_at(ParseErrorKind::StringTooLong(str_len), start));
+        }
+        if str_len > self.input.len() - self.pos {
+            return Err(self.error(ParseErrorKind::UnexpectedEof));
+        }
+
+        let text = &self.input[self.pos..self.pos + str_len];
+        self.pos += str_len;
+        Ok(text)
+    }
+
+    fn parse_integer(&mut self) -> Result<i64, ParseError> {
+        self.expect(b'i')?;
+        let start = self.pos;
+
+        let negative = self.peek()? == b'-';
+        if negative {
+            self.pos += 1;
+        }
+        let num = self.parse_num(b'e')?;
+
+        if negative && num == 0 && self.options.strict {
+            return Err(self.error_at(ParseErrorKind::NonCanonicalInteger, start));
+        }
+
+        let num = if negative {
+            0i64.checked_sub_unsigned(num)
+        } else {
+            i64::try_from(num).ok()
+        };
+        num.ok_or_else(|| self.error_at(ParseErrorKind::IntegerOverflow, start))
+    }
+
+    fn enter_container(&mut self) -> Result<(), ParseError> {
+        if self.depth >= self.options.max_depth {
+            return Err(self.error(ParseErrorKind::DepthLimitExceeded));
+        }
+        self.depth += 1;
+        self.pos += 1;
+        Ok(())
+    }
+
+    fn leave_container(&mut self) -> Result<(), ParseError> {
+        self.expect(b'e')?;
+        self.depth -= 1;
+        Ok(())
+    }
+
+    fn check_key_order(
+        &self,
+        previous: Option<&[u8]>,
+        key: &[u8],
+        key_offset: usize,
+    ) -> Result<(), ParseError> {
+        if !self.options.strict {
+            return Ok(());
+        }
+
+        match previous {
+            Some(previous) if previous == key => {
+                Err(self.error_at(ParseErrorKind::DuplicateKey, key_offset))
+            }
+            Some(previous) if previous > key => {
+                Err(self.error_at(ParseErrorKind::UnsortedKey, key_offset))
             }
+            _ => Ok(()),
+        }
+    }
+
+    fn parse_value(&mut self) -> Result<BencodeRef<'a>, ParseError> {
+        match self.peek()? {
+            b'0'..=b'9' => Ok(BencodeRef::Data(self.parse_text()?)),
+            b'i' => Ok(BencodeRef::Integer(self.parse_integer()?)),
+            b'l' => Ok(BencodeRef::List(self.parse_list()?)),
+            b'd' => Ok(BencodeRef::Dict(self.parse_dict()?)),
+            val => Err(self.error(ParseErrorKind::UnexpectedByte(val))),
+        }
+    }
+
+    fn parse_list(&mut self) -> Result<Vec<BencodeRef<'a>>, ParseError> {
+        let mut output = Vec::new();
+
+        self.enter_container()?;
+        while self.peek()? != b'e' {
+            output.push(self.parse_value()?);
+        }
+        self.leave_container()?;
+
+        Ok(output)
+    }
+
+    fn parse_dict(&mut self) -> Result<BTreeMap<&'a [u8], BencodeRef<'a>>, ParseError> {
+        let mut output = BTreeMap::new();
+        let mut previous_key = None;
+
+        self.enter_container()?;
+        while self.peek()? != b'e' {
+            let key_offset = self.pos;
+            let key = self.parse_text()?;
+            self.check_key_order(previous_key, key, key_offset)?;
+
+            let item = self.parse_value()?;
+            output.insert(key, item);
+            previous_key = Some(key);
         }
-        idx += 1;
+        self.leave_container()?;
+
+        Ok(output)
     }
 }
 
@@ -63,13 +304,10 @@ impl BencodeText {
         Self(data.to_vec())
     }
 
-    pub fn parse(input: &[u8]) -> IResult<Self> {
-        let (input, str_len) = parse_num(input, b':')?;
-        if str_len > input.len() {
-            return Err(("String payload is too short", input).into());
-        }
-        let (text, input) = input.split_at(str_len);
-        Ok((input, Self::new(text)))
+    pub fn parse(input: &[u8]) -> IResult<'_, Self> {
+        let mut parser = Parser::new(input, ParseOptions::default());
+        let text = parser.parse_text()?;
+        Ok((parser.remaining(), Self::new(text)))
     }
 
     pub fn encode<W: io::Write>(&self, out: &mut W) -> io::Result<()> {
@@ -110,35 +348,13 @@ pub enum BencodeValue {
 }
 
 impl BencodeValue {
-    pub fn parse(input: &[u8]) -> IResult<Self> {
-        if input.is_empty() {
-            return Err(("Input is empty", input).into());
-        }
+    pub fn parse(input: &[u8]) -> IResult<'_, Self> {
+        Self::parse_with_options(input, ParseOptions::default())
+    }
 
-        match input[0] {
-            b'0'..=b'9' => {
-                let (input, text) = BencodeText::parse(input)?;
-                Ok((input, Self::Data(text)))
-            }
-            b'i' => {
-                if input.len() >= 2 && input[1] == b'-' {
-                    let (input, num) = parse_num::<i64>(&input[2..], b'e')?;
-                    Ok((input, Self::Integer(-num)))
-                } else {
-                    let (input, num) = parse_num(&input[1..], b'e')?;
-                    Ok((input, Self::Integer(num)))
-                }
-            }
-            b'l' => {
-                let (input, items) = parse_list(&input[1..])?;
-                Ok((input, Self::List(items)))
-            }
-            b'd' => {
-                let (input, dict) = parse_dict(&input[1..])?;
-                Ok((input, Self::Dict(dict)))
-            }
-            _ => Err(("Invalid Bencode content", input).into()),
-        }
+    pub fn parse_with_options(input: &[u8], options: ParseOptions) -> IResult<'_, Self> {
+        let (input, value) = BencodeRef::parse_with_options(input, options)?;
+        Ok((input, value.into_owned()))
     }
 
     pub fn encode<W: io::Write>(&self, out: &mut W) -> io::Result<()> {
@@ -183,37 +399,99 @@ impl From<BencodeValue> for serde_json::Value {
     }
 }
 
-fn parse_list(mut input: &[u8]) -> IResult<Vec<BencodeValue>> {
-    let mut output = Vec::new();
+/// Borrowed variant of `BencodeValue`: byte strings are slices of the parsed input.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BencodeRef<'a> {
+    Data(&'a [u8]),
+    Integer(i64),
+    List(Vec<BencodeRef<'a>>),
+    Dict(BTreeMap<&'a [u8], BencodeRef<'a>>),
+}
+
+impl<'a> BencodeRef<'a> {
+    pub fn parse(input: &'a [u8]) -> IResult<'a, Self> {
+        Self::parse_with_options(input, ParseOptions::default())
+    }
+
+    pub fn parse_with_options(input: &'a [u8], options: ParseOptions) -> IResult<'a, Self> {
+        let mut parser = Parser::new(input, options);
+        let value = parser.parse_value()?;
+        Ok((parser.remaining(), value))
+    }
+
+    pub fn into_owned(self) -> BencodeValue {
+        match self {
+            BencodeRef::Data(data) => BencodeValue::Data(BencodeText::new(data)),
+            BencodeRef::Integer(num) => BencodeValue::Integer(num),
+            BencodeRef::List(values) => {
+                BencodeValue::List(values.into_iter().map(|x| x.into_owned()).collect())
+            }
+            BencodeRef::Dict(dict) => BencodeValue::Dict(
+                dict.into_iter()
+                    .map(|(k, v)| (BencodeText::new(k), v.into_owned()))
+                    .collect(),
+            ),
+        }
+    }
 
-    loop {
-        if input.is_empty() {
-            return Err(("List miss end tag", input).into());
+    pub fn encode<W: io::Write>(&self, out: &mut W) -> io::Result<()> {
+        match self {
+            BencodeRef::Data(data) => {
+                write!(out, "{}:", data.len())?;
+                out.write_all(data)
+            }
+            BencodeRef::Integer(val) => write!(out, "i{val}e"),
+            BencodeRef::List(values) => {
+                write!(out, "l")?;
+                for value in values {
+                    value.encode(out)?;
+                }
+                write!(out, "e")
+            }
+            BencodeRef::Dict(dict) => {
+                write!(out, "d")?;
+                for (key, value) in dict {
+                    BencodeRef::Data(key).encode(out)?;
+                    value.encode(out)?;
+                }
+                write!(out, "e")
+            }
         }
+    }
 
-        if input[0] == b'e' {
-            return Ok((&input[1..], output));
+    pub fn as_bytes(&self) -> Option<&'a [u8]> {
+        match self {
+            BencodeRef::Data(data) => Some(data),
+            _ => None,
         }
-        let (next_input, item) = BencodeValue::parse(input)?;
-        input = next_input;
-        output.push(item);
     }
-}
 
-fn parse_dict(mut input: &[u8]) -> IResult<BTreeMap<BencodeText, BencodeValue>> {
-    let mut output = BTreeMap::new();
+    pub fn as_str(&self) -> Option<&'a str> {
+        self.as_bytes().and_then(|x| std::str::from_utf8(x).ok())
+    }
 
-    loop {
-        if input.is_empty() {
-            return Err(("Dict miss end tag", input).into());
+    pub fn as_integer(&self) -> Option<i64> {
+        match self {
+            BencodeRef::Integer(num) => Some(*num),
+            _ => None,
         }
+    }
+
+    pub fn as_list(&self) -> Option<&[BencodeRef<'a>]> {
+        match self {
+            BencodeRef::List(values) => Some(values),
+            _ => None,
+        }
+    }
 
-        if input[0] == b'e' {
-            return Ok((&input[1..], output));
+    pub fn as_dict(&self) -> Option<&BTreeMap<&'a [u8], BencodeRef<'a>>> {
+        match self {
+            BencodeRef::Dict(dict) => Some(dict),
+            _ => None,
         }
-        let (next_input, key) = BencodeText::parse(input)?;
-        let (next_input, item) = BencodeValue::parse(next_input)?;
-        input = next_input;
-        output.insert(key, item);
+    }
+
+    pub fn get(&self, key: &[u8]) -> Option<&BencodeRef<'a>> {
+        self.as_dict().and_then(|dict| dict.get(key))
     }
 }
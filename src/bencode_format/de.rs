@@ -0,0 +1,304 @@
+use std::ops::Range;
+
+use serde::{
+    de::{
+        self,
+        value::{BorrowedBytesDeserializer, BorrowedStrDeserializer},
+        DeserializeSeed, IntoDeserializer, Visitor,
+    },
+    forward_to_deserialize_any, Deserialize,
+};
+
+use super::{ParseError, ParseErrorKind, ParseOptions, Parser};
+
+pub fn from_bytes<'de, T: Deserialize<'de>>(input: &'de [u8]) -> Result<T, ParseError> {
+    from_bytes_with_options(input, ParseOptions::default())
+}
+
+pub fn from_bytes_with_options<'de, T: Deserialize<'de>>(
+    input: &'de [u8],
+    options: ParseOptions,
+) -> Result<T, ParseError> {
+    Deserializer::with_options(input, options).deserialize_all()
+}
+
+// Also gives where the value of `key` in the top level dict was, to keep its
+// exact encoding (ex: the info dict of a torrent, for its hash)
+pub fn from_bytes_with_span<'de, T: Deserialize<'de>>(
+    input: &'de [u8],
+    key: &[u8],
+) -> Result<(T, Option<Range<usize>>), ParseError> {
+    let mut deserializer = Deserializer::from_bytes(input);
+    deserializer.span_key = Some(key.to_vec());
+    let value = deserializer.deserialize_all()?;
+    Ok((value, deserializer.span))
+}
+
+/// Streaming deserializer reading bencode values straight from the input buffer.
+///
+/// Byte strings are borrowed from the input, so `&[u8]` / `&str` fields are zero-copy.
+#[derive(Debug)]
+pub struct Deserializer<'de> {
+    parser: Parser<'de>,
+    span_key: Option<Vec<u8>>,
+    span: Option<Range<usize>>,
+}
+
+impl<'de> Deserializer<'de> {
+    pub fn from_bytes(input: &'de [u8]) -> Self {
+        Self::with_options(input, ParseOptions::default())
+    }
+
+    pub fn with_options(input: &'de [u8], options: ParseOptions) -> Self {
+        Self {
+            parser: Parser::new(input, options),
+            span_key: None,
+            span: None,
+        }
+    }
+
+    fn deserialize_all<T: Deserialize<'de>>(&mut self) -> Result<T, ParseError> {
+        let value = T::deserialize(&mut *self).map_err(|err| self.locate(err))?;
+
+        if !self.parser.remaining().is_empty() {
+            return Err(self.parser.error(ParseErrorKind::TrailingData));
+        }
+        Ok(value)
+    }
+
+    fn locate(&self, mut err: ParseError) -> ParseError {
+        // Errors raised by serde visitors do not know where they happened.
+        if matches!(err.kind, ParseErrorKind::Custom(_)) {
+            err.offset = self.parser.pos;
+        }
+        err
+    }
+
+    fn skip_value(&mut self) -> Result<(), ParseError> {
+        self.parser.parse_value()?;
+        Ok(())
+    }
+}
+
+impl<'de> de::Deserializer<'de> for &mut Deserializer<'de> {
+    type Error = ParseError;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        match self.parser.peek()? {
+            b'0'..=b'9' => {
+                let text = self.parser.parse_text()?;
+                match std::str::from_utf8(text) {
+                    Ok(text) => visitor.visit_borrowed_str(text),
+                    Err(_) => visitor.visit_borrowed_bytes(text),
+                }
+            }
+            b'i' => visitor.visit_i64(self.parser.parse_integer()?),
+            b'l' => {
+                self.parser.enter_container()?;
+                let value = visitor.visit_seq(ListAccess { de: self })?;
+                self.parser.leave_container()?;
+                Ok(value)
+            }
+            b'd' => {
+                self.parser.enter_container()?;
+                let value = visitor.visit_map(DictAccess {
+                    de: self,
+                    previous_key: None,
+                })?;
+                self.parser.leave_container()?;
+                Ok(value)
+            }
+            val => Err(self.parser.error(ParseErrorKind::UnexpectedByte(val))),
+        }
+    }
+
+    fn deserialize_bool<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        // Bencode has no boolean type: flags (ex: `private`) are stored as integers.
+        match self.parser.peek()? {
+            b'i' => visitor.visit_bool(self.parser.parse_integer()? != 0),
+            _ => self.deserialize_any(visitor),
+        }
+    }
+
+    fn deserialize_bytes<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        match self.parser.peek()? {
+            b'0'..=b'9' => visitor.visit_borrowed_bytes(self.parser.parse_text()?),
+            _ => self.deserialize_any(visitor),
+        }
+    }
+
+    fn deserialize_byte_buf<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        self.deserialize_bytes(visitor)
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        // Bencode has no null: missing keys are handled by serde itself.
+        visitor.visit_some(self)
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        match self.parser.peek()? {
+            // Unit variant stored as its name
+            b'0'..=b'9' => {
+                let offset = self.parser.pos;
+                let text = self.parser.parse_text()?;
+                let text = std::str::from_utf8(text)
+                    .map_err(|_| ParseError::custom("Enum variant is not UTF-8", offset))?;
+                visitor.visit_enum(text.into_deserializer())
+            }
+            // Other variants stored as a single key dict
+            b'd' => {
+                self.parser.enter_container()?;
+                let value = visitor.visit_enum(EnumAccess { de: self })?;
+                self.parser.leave_container()?;
+                Ok(value)
+            }
+            val => Err(self.parser.error(ParseErrorKind::UnexpectedByte(val))),
+        }
+    }
+
+    fn deserialize_ignored_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        self.skip_value()?;
+        visitor.visit_unit()
+    }
+
+    fn is_human_readable(&self) -> bool {
+        false
+    }
+
+    forward_to_deserialize_any! {
+        i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+        unit unit_struct seq tuple tuple_struct map struct identifier
+    }
+}
+
+struct ListAccess<'a, 'de> {
+    de: &'a mut Deserializer<'de>,
+}
+
+impl<'de, 'a> de::SeqAccess<'de> for ListAccess<'a, 'de> {
+    type Error = ParseError;
+
+    fn next_element_seed<T: DeserializeSeed<'de>>(
+        &mut self,
+        seed: T,
+    ) -> Result<Option<T::Value>, Self::Error> {
+        match self.de.parser.peek()? {
+            b'e' => Ok(None),
+            _ => seed.deserialize(&mut *self.de).map(Some),
+        }
+    }
+}
+
+struct DictAccess<'a, 'de> {
+    de: &'a mut Deserializer<'de>,
+    previous_key: Option<&'de [u8]>,
+}
+
+impl<'de, 'a> de::MapAccess<'de> for DictAccess<'a, 'de> {
+    type Error = ParseError;
+
+    fn next_key_seed<K: DeserializeSeed<'de>>(
+        &mut self,
+        seed: K,
+    ) -> Result<Option<K::Value>, Self::Error> {
+        if self.de.parser.peek()? == b'e' {
+            return Ok(None);
+        }
+
+        let key_offset = self.de.parser.pos;
+        let key = self.de.parser.parse_text()?;
+        self.de
+            .parser
+            .check_key_order(self.previous_key, key, key_offset)?;
+        self.previous_key = Some(key);
+
+        deserialize_key(key, seed).map(Some)
+    }
+
+    fn next_value_seed<V: DeserializeSeed<'de>>(
+        &mut self,
+        seed: V,
+    ) -> Result<V::Value, Self::Error> {
+        let start = self.de.parser.pos;
+        let value = seed.deserialize(&mut *self.de)?;
+
+        // Entries of the top level dict are at depth 1
+        if self.de.parser.depth == 1 && self.de.span_key.as_deref() == self.previous_key {
+            self.de.span = Some(start..self.de.parser.pos);
+        }
+        Ok(value)
+    }
+}
+
+struct EnumAccess<'a, 'de> {
+    de: &'a mut Deserializer<'de>,
+}
+
+impl<'de, 'a> de::EnumAccess<'de> for EnumAccess<'a, 'de> {
+    type Error = ParseError;
+    type Variant = Self;
+
+    fn variant_seed<V: DeserializeSeed<'de>>(
+        self,
+        seed: V,
+    ) -> Result<(V::Value, Self::Variant), Self::Error> {
+        let variant = deserialize_key(self.de.parser.parse_text()?, seed)?;
+        Ok((variant, self))
+    }
+}
+
+impl<'de, 'a> de::VariantAccess<'de> for EnumAccess<'a, 'de> {
+    type Error = ParseError;
+
+    fn unit_variant(self) -> Result<(), Self::Error> {
+        self.de.skip_value()
+    }
+
+    fn newtype_variant_seed<T: DeserializeSeed<'de>>(
+        self,
+        seed: T,
+    ) -> Result<T::Value, Self::Error> {
+        seed.deserialize(self.de)
+    }
+
+    fn tuple_variant<V: Visitor<'de>>(
+        self,
+        _len: usize,
+        visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        de::Deserializer::deserialize_seq(self.de, visitor)
+    }
+
+    fn struct_variant<V: Visitor<'de>>(
+        self,
+        _fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        de::Deserializer::deserialize_map(self.de, visitor)
+    }
+}
+
+fn deserialize_key<'de, K: DeserializeSeed<'de>>(
+    key: &'de [u8],
+    seed: K,
+) -> Result<K::Value, ParseError> {
+    // Dict keys are always byte strings
+    match std::str::from_utf8(key) {
+        Ok(key) => seed.deserialize(BorrowedStrDeserializer::new(key)),
+        Err(_) => seed.deserialize(BorrowedBytesDeserializer::new(key)),
+    }
+}
@@ -0,0 +1,362 @@
+use std::collections::BTreeMap;
+
+use serde::{ser, Serialize};
+
+use super::{BencodeText, BencodeValue, ParseError};
+
+pub fn to_value<T: Serialize + ?Sized>(value: &T) -> Result<BencodeValue, ParseError> {
+    value
+        .serialize(Serializer)?
+        .ok_or_else(|| ParseError::new("Cannot serialize empty value"))
+}
+
+pub fn to_bytes<T: Serialize + ?Sized>(value: &T) -> Result<Vec<u8>, ParseError> {
+    let mut buf = Vec::with_capacity(512);
+    to_value(value)?
+        .encode(&mut buf)
+        .expect("Fail to bencode value in memory");
+    Ok(buf)
+}
+
+// Serialize into `BencodeValue` so dict keys get sorted for free by the `BTreeMap`.
+// `None` means "no value" (ex: `Option::None` field) and is skipped by containers.
+struct Serializer;
+
+type SerResult = Result<Option<BencodeValue>, ParseError>;
+
+fn text(data: &[u8]) -> Option<BencodeValue> {
+    Some(BencodeValue::Data(BencodeText::new(data)))
+}
+
+fn single_key_dict(key: &str, value: Option<BencodeValue>) -> Option<BencodeValue> {
+    let mut dict = BTreeMap::new();
+    if let Some(value) = value {
+        dict.insert(BencodeText::new(key.as_bytes()), value);
+    }
+    Some(BencodeValue::Dict(dict))
+}
+
+impl ser::Serializer for Serializer {
+    type Ok = Option<BencodeValue>;
+    type Error = ParseError;
+
+    type SerializeSeq = ListSerializer;
+    type SerializeTuple = ListSerializer;
+    type SerializeTupleStruct = ListSerializer;
+    type SerializeTupleVariant = VariantSerializer<ListSerializer>;
+    type SerializeMap = DictSerializer;
+    type SerializeStruct = DictSerializer;
+    type SerializeStructVariant = VariantSerializer<DictSerializer>;
+
+    fn serialize_bool(self, v: bool) -> SerResult {
+        Ok(Some(BencodeValue::Integer(v as i64)))
+    }
+
+    fn serialize_i8(self, v: i8) -> SerResult {
+        self.serialize_i64(v as i64)
+    }
+
+    fn serialize_i16(self, v: i16) -> SerResult {
+        self.serialize_i64(v as i64)
+    }
+
+    fn serialize_i32(self, v: i32) -> SerResult {
+        self.serialize_i64(v as i64)
+    }
+
+    fn serialize_i64(self, v: i64) -> SerResult {
+        Ok(Some(BencodeValue::Integer(v)))
+    }
+
+    fn serialize_u8(self, v: u8) -> SerResult {
+        self.serialize_i64(v as i64)
+    }
+
+    fn serialize_u16(self, v: u16) -> SerResult {
+        self.serialize_i64(v as i64)
+    }
+
+    fn serialize_u32(self, v: u32) -> SerResult {
+        self.serialize_i64(v as i64)
+    }
+
+    fn serialize_u64(self, v: u64) -> SerResult {
+        let v = i64::try_from(v).map_err(|_| ParseError::new("Integer is too large"))?;
+        self.serialize_i64(v)
+    }
+
+    fn serialize_f32(self, _v: f32) -> SerResult {
+        Err(ParseError::new("Float are not supported"))
+    }
+
+    fn serialize_f64(self, _v: f64) -> SerResult {
+        Err(ParseError::new("Float are not supported"))
+    }
+
+    fn serialize_char(self, v: char) -> SerResult {
+        self.serialize_str(v.encode_utf8(&mut [0; 4]))
+    }
+
+    fn serialize_str(self, v: &str) -> SerResult {
+        Ok(text(v.as_bytes()))
+    }
+
+    fn serialize_bytes(self, v: &[u8]) -> SerResult {
+        Ok(text(v))
+    }
+
+    fn serialize_none(self) -> SerResult {
+        Ok(None)
+    }
+
+    fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> SerResult {
+        value.serialize(self)
+    }
+
+    fn serialize_unit(self) -> SerResult {
+        Ok(None)
+    }
+
+    fn serialize_unit_struct(self, _name: &'static str) -> SerResult {
+        Ok(None)
+    }
+
+    fn serialize_unit_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+    ) -> SerResult {
+        self.serialize_str(variant)
+    }
+
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        value: &T,
+    ) -> SerResult {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+        value: &T,
+    ) -> SerResult {
+        Ok(single_key_dict(variant, value.serialize(self)?))
+    }
+
+    fn serialize_seq(self, len: Option<usize>) -> Result<Self::SerializeSeq, Self::Error> {
+        Ok(ListSerializer(Vec::with_capacity(len.unwrap_or_default())))
+    }
+
+    fn serialize_tuple(self, len: usize) -> Result<Self::SerializeTuple, Self::Error> {
+        self.serialize_seq(Some(len))
+    }
+
+    fn serialize_tuple_struct(
+        self,
+        _name: &'static str,
+        len: usize,
+    ) -> Result<Self::SerializeTupleStruct, Self::Error> {
+        self.serialize_seq(Some(len))
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+        len: usize,
+    ) -> Result<Self::SerializeTupleVariant, Self::Error> {
+        Ok(VariantSerializer {
+            variant,
+            inner: self.serialize_seq(Some(len))?,
+        })
+    }
+
+    fn serialize_map(self, _len: Option<usize>) -> Result<Self::SerializeMap, Self::Error> {
+        Ok(DictSerializer::default())
+    }
+
+    fn serialize_struct(
+        self,
+        _name: &'static str,
+        len: usize,
+    ) -> Result<Self::SerializeStruct, Self::Error> {
+        self.serialize_map(Some(len))
+    }
+
+    fn serialize_struct_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+        len: usize,
+    ) -> Result<Self::SerializeStructVariant, Self::Error> {
+        Ok(VariantSerializer {
+            variant,
+            inner: self.serialize_map(Some(len))?,
+        })
+    }
+
+    fn is_human_readable(&self) -> bool {
+        false
+    }
+}
+
+struct ListSerializer(Vec<BencodeValue>);
+
+impl ListSerializer {
+    fn push<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), ParseError> {
+        if let Some(value) = value.serialize(Serializer)? {
+            self.0.push(value);
+        }
+        Ok(())
+    }
+}
+
+impl ser::SerializeSeq for ListSerializer {
+    type Ok = Option<BencodeValue>;
+    type Error = ParseError;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), ParseError> {
+        self.push(value)
+    }
+
+    fn end(self) -> SerResult {
+        Ok(Some(BencodeValue::List(self.0)))
+    }
+}
+
+impl ser::SerializeTuple for ListSerializer {
+    type Ok = Option<BencodeValue>;
+    type Error = ParseError;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), ParseError> {
+        self.push(value)
+    }
+
+    fn end(self) -> SerResult {
+        ser::SerializeSeq::end(self)
+    }
+}
+
+impl ser::SerializeTupleStruct for ListSerializer {
+    type Ok = Option<BencodeValue>;
+    type Error = ParseError;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), ParseError> {
+        self.push(value)
+    }
+
+    fn end(self) -> SerResult {
+        ser::SerializeSeq::end(self)
+    }
+}
+
+#[derive(Default)]
+struct DictSerializer {
+    dict: BTreeMap<BencodeText, BencodeValue>,
+    next_key: Option<BencodeText>,
+}
+
+impl DictSerializer {
+    fn insert<T: Serialize + ?Sized>(
+        &mut self,
+        key: BencodeText,
+        value: &T,
+    ) -> Result<(), ParseError> {
+        if let Some(value) = value.serialize(Serializer)? {
+            self.dict.insert(key, value);
+        }
+        Ok(())
+    }
+}
+
+impl ser::SerializeMap for DictSerializer {
+    type Ok = Option<BencodeValue>;
+    type Error = ParseError;
+
+    fn serialize_key<T: Serialize + ?Sized>(&mut self, key: &T) -> Result<(), ParseError> {
+        match key.serialize(Serializer)? {
+            Some(BencodeValue::Data(key)) => {
+                self.next_key = Some(key);
+                Ok(())
+            }
+            _ => Err(ParseError::new("Dict key must be a string")),
+        }
+    }
+
+    fn serialize_value<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), ParseError> {
+        let key = self
+            .next_key
+            .take()
+            .ok_or_else(|| ParseError::new("Dict value serialized before its key"))?;
+        self.insert(key, value)
+    }
+
+    fn end(self) -> SerResult {
+        Ok(Some(BencodeValue::Dict(self.dict)))
+    }
+}
+
+impl ser::SerializeStruct for DictSerializer {
+    type Ok = Option<BencodeValue>;
+    type Error = ParseError;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<(), ParseError> {
+        self.insert(BencodeText::new(key.as_bytes()), value)
+    }
+
+    fn end(self) -> SerResult {
+        ser::SerializeMap::end(self)
+    }
+}
+
+struct VariantSerializer<S> {
+    variant: &'static str,
+    inner: S,
+}
+
+impl ser::SerializeTupleVariant for VariantSerializer<ListSerializer> {
+    type Ok = Option<BencodeValue>;
+    type Error = ParseError;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), ParseError> {
+        self.inner.push(value)
+    }
+
+    fn end(self) -> SerResult {
+        Ok(single_key_dict(
+            self.variant,
+            ser::SerializeSeq::end(self.inner)?,
+        ))
+    }
+}
+
+impl ser::SerializeStructVariant for VariantSerializer<DictSerializer> {
+    type Ok = Option<BencodeValue>;
+    type Error = ParseError;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<(), ParseError> {
+        self.inner.insert(BencodeText::new(key.as_bytes()), value)
+    }
+
+    fn end(self) -> SerResult {
+        Ok(single_key_dict(
+            self.variant,
+            ser::SerializeMap::end(self.inner)?,
+        ))
+    }
+}
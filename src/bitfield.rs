@@ -0,0 +1,86 @@
+use crate::error::TorrentError;
+
+// One bit per piece, high bit of the first byte being piece 0 (BEP 3).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Bitfield {
+    bytes: Vec<u8>,
+    len: usize,
+}
+
+impl Bitfield {
+    pub fn new(len: usize) -> Self {
+        Self {
+            bytes: vec![0; (len + 7) >> 3],
+            len,
+        }
+    }
+
+    pub fn full(len: usize) -> Self {
+        let mut bitfield = Self::new(len);
+        for index in 0..len {
+            bitfield.set(index);
+        }
+        bitfield
+    }
+
+    pub fn from_bytes(bytes: &[u8], len: usize) -> Result<Self, TorrentError> {
+        let mut bitfield = Self::new(len);
+        if bytes.len() != bitfield.bytes.len() {
+            return Err(TorrentError::Peer(format!(
+                "Invalid bit field length: {} bytes for {len} pieces",
+                bytes.len()
+            )));
+        }
+        bitfield.bytes.copy_from_slice(bytes);
+
+        // Spare bits at the end must be cleared
+        if (len..bitfield.bytes.len() * 8).any(|index| bitfield.get_bit(index)) {
+            return Err(TorrentError::Peer(
+                "Bit field has spare bits set".to_string(),
+            ));
+        }
+        Ok(bitfield)
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn has(&self, index: usize) -> bool {
+        index < self.len && self.get_bit(index)
+    }
+
+    pub fn set(&mut self, index: usize) {
+        assert!(index < self.len, "Piece {index} out of bit field");
+        self.bytes[index / 8] |= 0x80 >> (index % 8);
+    }
+
+    pub fn unset(&mut self, index: usize) {
+        assert!(index < self.len, "Piece {index} out of bit field");
+        self.bytes[index / 8] &= !(0x80 >> (index % 8));
+    }
+
+    pub fn count_ones(&self) -> usize {
+        self.bytes.iter().map(|x| x.count_ones() as usize).sum()
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.count_ones() == self.len
+    }
+
+    pub fn ones(&self) -> impl Iterator<Item = usize> + '_ {
+        (0..self.len).filter(|&index| self.get_bit(index))
+    }
+
+    fn get_bit(&self, index: usize) -> bool {
+        self.bytes[index / 8] & (0x80 >> (index % 8)) != 0
+    }
+}
@@ -0,0 +1,101 @@
+use std::{
+    collections::HashSet,
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+use crate::utils::{random_u64, shuffle};
+
+#[derive(Debug, Clone)]
+pub struct ChokerConfig {
+    // Peers unchoked at the same time, the optimistic unchoke included
+    pub upload_slots: usize,
+    pub rechoke_interval: Duration,
+    pub optimistic_interval: Duration,
+}
+
+impl Default for ChokerConfig {
+    fn default() -> Self {
+        Self {
+            upload_slots: 4,
+            rechoke_interval: Duration::from_secs(10),
+            optimistic_interval: Duration::from_secs(30),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ChokeCandidate {
+    pub addr: SocketAddr,
+    pub interested: bool,
+    // Download rate from the peer, or upload rate to it when seeding
+    pub rate: u64,
+}
+
+/// Decide which peers we upload to (BEP 3 tit-for-tat).
+///
+/// The fastest interested peers get the upload slots but one, kept for an
+/// optimistic unchoke rotating among the others so new peers get a chance to
+/// show how fast they are.
+#[derive(Debug)]
+pub struct Choker {
+    config: ChokerConfig,
+    optimistic: Option<(SocketAddr, Instant)>,
+}
+
+impl Choker {
+    pub fn new(config: ChokerConfig) -> Self {
+        Self {
+            config,
+            optimistic: None,
+        }
+    }
+
+    pub fn config(&self) -> &ChokerConfig {
+        &self.config
+    }
+
+    pub fn optimistic(&self) -> Option<SocketAddr> {
+        self.optimistic.map(|(addr, _)| addr)
+    }
+
+    // Peers to unchoke, all the others are choked
+    pub fn rechoke(&mut self, peers: &[ChokeCandidate], now: Instant) -> HashSet<SocketAddr> {
+        let mut interested: Vec<_> = peers.iter().filter(|x| x.interested).collect();
+        // Break ties at random
+        shuffle(&mut interested);
+        interested.sort_by_key(|x| std::cmp::Reverse(x.rate));
+
+        let regular = self.config.upload_slots.saturating_sub(1);
+        let mut unchoked: HashSet<_> = interested.iter().take(regular).map(|x| x.addr).collect();
+        if self.config.upload_slots == 0 {
+            return unchoked;
+        }
+
+        let others: Vec<_> = interested
+            .iter()
+            .map(|x| x.addr)
+            .filter(|x| !unchoked.contains(x))
+            .collect();
+        let keep = self.optimistic.filter(|(addr, since)| {
+            others.contains(addr)
+                && now.saturating_duration_since(*since) < self.config.optimistic_interval
+        });
+        self.optimistic = keep.or_else(|| {
+            // Rotate to another peer when there is one
+            let current = self.optimistic();
+            let mut candidates: Vec<_> = others.iter().filter(|&&x| Some(x) != current).collect();
+            if candidates.is_empty() {
+                candidates = others.iter().collect();
+            }
+            if candidates.is_empty() {
+                return None;
+            }
+            let index = (random_u64() % candidates.len() as u64) as usize;
+            Some((*candidates[index], now))
+        });
+
+        unchoked.extend(self.optimistic());
+        unchoked
+    }
+}
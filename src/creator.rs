@@ -0,0 +1,204 @@
+use std::{
+    collections::BTreeMap,
+    fs::{self, File},
+    io::Read,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    bencode_format::{BencodeText, BencodeValue},
+    error::TorrentError,
+    utils::hash_sha1,
+};
+
+const MIN_PIECE_LENGTH: u32 = 16 << 10;
+const MAX_PIECE_LENGTH: u32 = 16 << 20;
+// Automatic piece length keeps the number of pieces around this
+const TARGET_PIECES_COUNT: u64 = 1500;
+
+#[derive(Debug, Clone, Default)]
+pub struct CreateOptions {
+    // Power of two, picked from the total length if not given
+    pub piece_length: Option<u32>,
+    // Tiers of trackers, the first one is also the `announce` URL
+    pub trackers: Vec<Vec<String>>,
+    pub comment: Option<String>,
+    pub created_by: Option<String>,
+    // Seconds since the Unix epoch
+    pub creation_date: Option<i64>,
+    // Only get peers from the trackers (BEP 27)
+    pub private: bool,
+    // HTTP servers having the files (BEP 19)
+    pub web_seeds: Vec<String>,
+}
+
+pub fn auto_piece_length(total_length: u64) -> u32 {
+    let mut piece_length = MIN_PIECE_LENGTH;
+    while piece_length < MAX_PIECE_LENGTH
+        && total_length / piece_length as u64 > TARGET_PIECES_COUNT
+    {
+        piece_length *= 2;
+    }
+    piece_length
+}
+
+/// Hash a file or a directory into a bencoded `.torrent` file.
+pub fn create_torrent(path: &Path, options: &CreateOptions) -> Result<Vec<u8>, TorrentError> {
+    let announce = options
+        .trackers
+        .first()
+        .and_then(|tier| tier.first())
+        .ok_or_else(|| TorrentError::Create("At least one tracker is required".to_string()))?;
+
+    let name = path
+        .file_name()
+        .and_then(|x| x.to_str())
+        .ok_or_else(|| TorrentError::Create(format!("Invalid name: {path:?}")))?;
+
+    // Multi file torrents list files relative to the directory, in a stable order
+    let metadata = fs::metadata(path)?;
+    let files = match metadata.is_dir() {
+        true => {
+            let mut files = Vec::new();
+            list_files(path, &mut Vec::new(), &mut files)?;
+            if files.is_empty() {
+                return Err(TorrentError::Create(format!("No file in {path:?}")));
+            }
+            files
+        }
+        false => vec![(Vec::new(), metadata.len())],
+    };
+    let total_length = files.iter().map(|(_, length)| length).sum();
+
+    let piece_length = match options.piece_length {
+        Some(length) if !length.is_power_of_two() || length < MIN_PIECE_LENGTH => {
+            return Err(TorrentError::Create(format!(
+                "Piece length must be a power of two of at least {MIN_PIECE_LENGTH}: {length}"
+            )))
+        }
+        Some(length) => length,
+        None => auto_piece_length(total_length),
+    };
+    let paths = files
+        .iter()
+        .map(|(components, _)| components.iter().fold(path.to_path_buf(), |x, y| x.join(y)));
+    let pieces = hash_pieces(paths, piece_length)?;
+
+    let mut info = BTreeMap::new();
+    info.insert(text("name"), data(name));
+    info.insert(
+        text("piece length"),
+        BencodeValue::Integer(piece_length as i64),
+    );
+    info.insert(
+        text("pieces"),
+        BencodeValue::Data(BencodeText::new(&pieces)),
+    );
+    if metadata.is_dir() {
+        let files = files
+            .into_iter()
+            .map(|(components, length)| {
+                let mut file = BTreeMap::new();
+                file.insert(text("length"), BencodeValue::Integer(length as i64));
+                file.insert(text("path"), list(&components));
+                BencodeValue::Dict(file)
+            })
+            .collect();
+        info.insert(text("files"), BencodeValue::List(files));
+    } else {
+        info.insert(text("length"), BencodeValue::Integer(total_length as i64));
+    }
+    if options.private {
+        info.insert(text("private"), BencodeValue::Integer(1));
+    }
+
+    let mut torrent = BTreeMap::new();
+    torrent.insert(text("announce"), data(announce));
+    torrent.insert(text("info"), BencodeValue::Dict(info));
+    if options.trackers.iter().flatten().count() > 1 {
+        let tiers = options.trackers.iter().map(|tier| list(tier)).collect();
+        torrent.insert(text("announce-list"), BencodeValue::List(tiers));
+    }
+    if let Some(comment) = &options.comment {
+        torrent.insert(text("comment"), data(comment));
+    }
+    if let Some(created_by) = &options.created_by {
+        torrent.insert(text("created by"), data(created_by));
+    }
+    if let Some(creation_date) = options.creation_date {
+        torrent.insert(text("creation date"), BencodeValue::Integer(creation_date));
+    }
+    if !options.web_seeds.is_empty() {
+        torrent.insert(text("url-list"), list(&options.web_seeds));
+    }
+
+    let mut output = Vec::new();
+    BencodeValue::Dict(torrent).encode(&mut output)?;
+    Ok(output)
+}
+
+// Regular files under `dir`, with their path components and length
+fn list_files(
+    dir: &Path,
+    prefix: &mut Vec<String>,
+    files: &mut Vec<(Vec<String>, u64)>,
+) -> Result<(), TorrentError> {
+    let mut entries = fs::read_dir(dir)?.collect::<Result<Vec<_>, _>>()?;
+    entries.sort_by_key(|entry| entry.file_name());
+
+    for entry in entries {
+        let name = entry
+            .file_name()
+            .into_string()
+            .map_err(|name| TorrentError::Create(format!("Invalid file name: {name:?}")))?;
+        let metadata = fs::metadata(entry.path())?;
+        prefix.push(name);
+        if metadata.is_dir() {
+            list_files(&entry.path(), prefix, files)?;
+        } else if metadata.is_file() {
+            files.push((prefix.clone(), metadata.len()));
+        }
+        prefix.pop();
+    }
+    Ok(())
+}
+
+// Pieces run over the files as if they were concatenated
+fn hash_pieces(
+    paths: impl Iterator<Item = PathBuf>,
+    piece_length: u32,
+) -> Result<Vec<u8>, TorrentError> {
+    let mut pieces = Vec::new();
+    let mut piece = Vec::with_capacity(piece_length as usize);
+    for path in paths {
+        let mut file = File::open(path)?;
+        loop {
+            let missing = piece_length as usize - piece.len();
+            let read = (&mut file).take(missing as u64).read_to_end(&mut piece)?;
+            if piece.len() == piece_length as usize {
+                pieces.extend_from_slice(&hash_sha1(&piece));
+                piece.clear();
+            }
+            if read < missing {
+                break;
+            }
+        }
+    }
+
+    if !piece.is_empty() {
+        pieces.extend_from_slice(&hash_sha1(&piece));
+    }
+    Ok(pieces)
+}
+
+fn text(value: &str) -> BencodeText {
+    BencodeText::new(value.as_bytes())
+}
+
+fn data(value: &str) -> BencodeValue {
+    BencodeValue::Data(text(value))
+}
+
+fn list(values: &[String]) -> BencodeValue {
+    BencodeValue::List(values.iter().map(|x| data(x)).collect())
+}
@@ -0,0 +1,572 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    env, fs,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU16, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+use tokio::{
+    net::UdpSocket,
+    sync::{mpsc, oneshot},
+    task::{JoinHandle, JoinSet},
+    time::{sleep, timeout},
+};
+
+use crate::{bencode_format, error::TorrentError, utils::random_u32};
+
+pub mod krpc;
+pub mod routing;
+pub mod tokens;
+
+use krpc::{Krpc, KrpcMessage, Query, Response, ERROR_PROTOCOL};
+use routing::{NodeId, NodeInfo, RoutingTable, K};
+use tokens::TokenManager;
+
+// Well known nodes to join the DHT through
+pub const BOOTSTRAP_NODES: &[(&str, u16)] = &[
+    ("router.bittorrent.com", 6881),
+    ("dht.transmissionbt.com", 6881),
+    ("router.utorrent.com", 6881),
+];
+
+// Peers sent in a single get_peers response
+const MAX_VALUES: usize = 50;
+
+// Peers stored for a single info hash
+const MAX_STORED_PEERS: usize = 1000;
+
+// Pause after the socket fails to receive, instead of failing again right away
+const RECEIVE_BACKOFF: Duration = Duration::from_millis(100);
+
+#[derive(Debug, Clone)]
+pub struct DhtConfig {
+    // Random by default, nodes should keep their ID across runs
+    pub id: Option<NodeId>,
+    pub query_timeout: Duration,
+    // Queries in flight during a lookup
+    pub alpha: usize,
+    pub token_interval: Duration,
+    // Announced peers are forgotten unless announced again
+    pub peer_ttl: Duration,
+    // Info hashes we store peers for, announces for new ones being ignored
+    pub max_info_hashes: usize,
+    // Buckets not changed for that long are refreshed with a lookup
+    pub refresh_interval: Duration,
+}
+
+impl Default for DhtConfig {
+    fn default() -> Self {
+        Self {
+            id: None,
+            query_timeout: Duration::from_secs(5),
+            alpha: 3,
+            token_interval: Duration::from_secs(5 * 60),
+            peer_ttl: Duration::from_secs(30 * 60),
+            max_info_hashes: 2000,
+            refresh_interval: Duration::from_secs(15 * 60),
+        }
+    }
+}
+
+/// Node of the mainline DHT (BEP 5), answering queries from other nodes
+/// until dropped.
+#[derive(Debug)]
+pub struct Dht {
+    node: Arc<Node>,
+    tasks: Vec<JoinHandle<()>>,
+}
+
+impl Dht {
+    pub async fn bind(addr: SocketAddr) -> Result<Self, TorrentError> {
+        Self::bind_with_config(addr, DhtConfig::default()).await
+    }
+
+    pub async fn bind_with_config(
+        addr: SocketAddr,
+        config: DhtConfig,
+    ) -> Result<Self, TorrentError> {
+        let socket = UdpSocket::bind(addr).await?;
+        let id = config.id.unwrap_or_else(NodeId::random);
+        let node = Arc::new(Node {
+            socket,
+            id,
+            table: Mutex::new(RoutingTable::new(id)),
+            tokens: Mutex::new(TokenManager::new(config.token_interval)),
+            peers: Mutex::new(HashMap::new()),
+            pending: Mutex::new(HashMap::new()),
+            next_transaction: AtomicU16::new(random_u32() as u16),
+            errors_tx: Mutex::new(None),
+            config,
+        });
+
+        let tasks = vec![
+            tokio::spawn(node.clone().receive()),
+            tokio::spawn(node.clone().refresh()),
+        ];
+        Ok(Self { node, tasks })
+    }
+
+    pub fn id(&self) -> NodeId {
+        self.node.id
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr, TorrentError> {
+        Ok(self.node.socket.local_addr()?)
+    }
+
+    pub fn nodes(&self) -> Vec<NodeInfo> {
+        self.node.table.lock().unwrap().nodes()
+    }
+
+    // Socket errors while answering other nodes, which do not stop the node
+    pub fn errors(&self) -> mpsc::UnboundedReceiver<TorrentError> {
+        let (errors_tx, errors_rx) = mpsc::unbounded_channel();
+        *self.node.errors_tx.lock().unwrap() = Some(errors_tx);
+        errors_rx
+    }
+
+    // Join the DHT through the given nodes, filling the routing table with
+    // the nodes closest to us.
+    pub async fn bootstrap(&self, addrs: &[SocketAddr]) -> Result<(), TorrentError> {
+        let mut queries = JoinSet::new();
+        for &addr in addrs {
+            let node = self.node.clone();
+            let query = Query::FindNode { target: node.id };
+            queries.spawn(async move { node.query(addr, query).await });
+        }
+        while queries.join_next().await.is_some() {}
+
+        self.node.lookup(self.node.id, false).await;
+        if self.node.table.lock().unwrap().is_empty() {
+            return Err(TorrentError::Dht("No node answered".to_string()));
+        }
+        Ok(())
+    }
+
+    // Single query to a node, which joins our routing table if it answers
+    pub async fn query(&self, addr: SocketAddr, query: Query) -> Result<Response, TorrentError> {
+        self.node.query(addr, query).await
+    }
+
+    pub async fn ping(&self, addr: SocketAddr) -> Result<NodeId, TorrentError> {
+        Ok(self.query(addr, Query::Ping).await?.id)
+    }
+
+    // Closest nodes to the target we can find
+    pub async fn find_node(&self, target: NodeId) -> Vec<NodeInfo> {
+        let lookup = self.node.lookup(target, false).await;
+        lookup.closest.into_iter().map(|x| x.0).collect()
+    }
+
+    pub async fn get_peers(&self, info_hash: [u8; 20]) -> Vec<SocketAddr> {
+        self.node.lookup(NodeId(info_hash), true).await.peers
+    }
+
+    // Tell the nodes closest to the info hash we have it, on the given port
+    // or the one we send from. Returns the peers found on the way.
+    pub async fn announce(
+        &self,
+        info_hash: [u8; 20],
+        port: Option<u16>,
+    ) -> Result<Vec<SocketAddr>, TorrentError> {
+        let lookup = self.node.lookup(NodeId(info_hash), true).await;
+
+        let mut queries = JoinSet::new();
+        for (node_info, token) in lookup.closest {
+            let Some(token) = token else {
+                continue;
+            };
+            let query = Query::AnnouncePeer {
+                info_hash,
+                port: port.unwrap_or_default(),
+                implied_port: port.is_none(),
+                token,
+            };
+            let node = self.node.clone();
+            queries.spawn(async move { node.query(node_info.addr, query).await });
+        }
+
+        let mut announced = 0;
+        while let Some(joined) = queries.join_next().await {
+            if let Ok(Ok(_)) = joined {
+                announced += 1;
+            }
+        }
+        if announced == 0 {
+            return Err(TorrentError::Dht(
+                "No node accepted the announce".to_string(),
+            ));
+        }
+        Ok(lookup.peers)
+    }
+
+    // What to save to rejoin the DHT on the next run
+    pub fn state(&self) -> DhtState {
+        DhtState {
+            id: self.node.id,
+            nodes: self.nodes(),
+        }
+    }
+}
+
+impl Drop for Dht {
+    fn drop(&mut self) {
+        for task in &self.tasks {
+            task.abort();
+        }
+    }
+}
+
+/// Node ID and routing table saved across runs.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DhtState {
+    pub id: NodeId,
+    pub nodes: Vec<NodeInfo>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct RawState {
+    #[serde(with = "serde_bytes")]
+    id: Vec<u8>,
+    // Compact node info, as in find_node responses
+    #[serde(with = "serde_bytes")]
+    nodes: Vec<u8>,
+    #[serde(with = "serde_bytes")]
+    nodes6: Vec<u8>,
+}
+
+impl DhtState {
+    // In the user state directory: a shared one would let other users plant
+    // a symlink where we write. None without a home directory.
+    pub fn default_path() -> Option<PathBuf> {
+        let state_dir = env::var_os("XDG_STATE_HOME")
+            .filter(|x| !x.is_empty())
+            .map(PathBuf::from)
+            .or_else(|| Some(PathBuf::from(env::var_os("HOME")?).join(".local/state")))?;
+        Some(state_dir.join("bittorrent").join("dht.state"))
+    }
+
+    pub fn load(path: &Path) -> Result<Self, TorrentError> {
+        let raw: RawState = bencode_format::from_bytes(&fs::read(path)?)?;
+        let invalid = || TorrentError::Dht("Invalid saved state".to_string());
+
+        let id = NodeId(raw.id.try_into().map_err(|_| invalid())?);
+        let mut nodes = NodeInfo::decode_compact(&raw.nodes, false).ok_or_else(invalid)?;
+        nodes.extend(NodeInfo::decode_compact(&raw.nodes6, true).ok_or_else(invalid)?);
+        Ok(Self { id, nodes })
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), TorrentError> {
+        let mut raw = RawState {
+            id: self.id.0.to_vec(),
+            nodes: Vec::new(),
+            nodes6: Vec::new(),
+        };
+        for node in &self.nodes {
+            let nodes = match node.addr {
+                SocketAddr::V4(_) => &mut raw.nodes,
+                SocketAddr::V6(_) => &mut raw.nodes6,
+            };
+            nodes.extend(node.encode_compact());
+        }
+
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        // Same as resume data: never leave a truncated file behind
+        let mut tmp_path = path.as_os_str().to_owned();
+        tmp_path.push(".tmp");
+        fs::write(&tmp_path, bencode_format::to_bytes(&raw)?)?;
+        fs::rename(tmp_path, path)?;
+        Ok(())
+    }
+
+    pub fn addrs(&self) -> Vec<SocketAddr> {
+        self.nodes.iter().map(|x| x.addr).collect()
+    }
+}
+
+#[derive(Debug, Default)]
+struct Lookup {
+    // Closest nodes that answered, with the token to announce to them
+    closest: Vec<(NodeInfo, Option<Vec<u8>>)>,
+    peers: Vec<SocketAddr>,
+}
+
+// Node asked and where to send its response, by transaction ID
+type PendingQueries = HashMap<Vec<u8>, (SocketAddr, oneshot::Sender<Krpc>)>;
+
+#[derive(Debug)]
+struct Node {
+    socket: UdpSocket,
+    id: NodeId,
+    config: DhtConfig,
+    table: Mutex<RoutingTable>,
+    tokens: Mutex<TokenManager>,
+    // Peers announced to us, with the time they were
+    peers: Mutex<HashMap<[u8; 20], HashMap<SocketAddr, Instant>>>,
+    // Queries waiting for a response
+    pending: Mutex<PendingQueries>,
+    next_transaction: AtomicU16,
+    errors_tx: Mutex<Option<mpsc::UnboundedSender<TorrentError>>>,
+}
+
+impl Node {
+    fn report(&self, err: TorrentError) {
+        if let Some(errors_tx) = &*self.errors_tx.lock().unwrap() {
+            let _ = errors_tx.send(err);
+        }
+    }
+
+    async fn query(&self, addr: SocketAddr, query: Query) -> Result<Response, TorrentError> {
+        let transaction = self
+            .next_transaction
+            .fetch_add(1, Ordering::Relaxed)
+            .to_be_bytes()
+            .to_vec();
+        let (tx, rx) = oneshot::channel();
+        self.pending
+            .lock()
+            .unwrap()
+            .insert(transaction.clone(), (addr, tx));
+
+        let msg = KrpcMessage {
+            transaction: transaction.clone(),
+            body: Krpc::Query { id: self.id, query },
+        };
+        let sent = self.send(addr, &msg).await;
+        let received = match sent {
+            Ok(()) => timeout(self.config.query_timeout, rx).await,
+            Err(err) => {
+                self.pending.lock().unwrap().remove(&transaction);
+                return Err(err);
+            }
+        };
+        self.pending.lock().unwrap().remove(&transaction);
+
+        match received {
+            Ok(Ok(Krpc::Response(response))) => {
+                self.table.lock().unwrap().insert(NodeInfo {
+                    id: response.id,
+                    addr,
+                });
+                Ok(response)
+            }
+            Ok(Ok(Krpc::Error { code, message })) => {
+                Err(TorrentError::Dht(format!("Node error {code}: {message}")))
+            }
+            _ => {
+                self.table.lock().unwrap().fail(addr);
+                Err(TorrentError::Dht(format!("Node {addr} did not answer")))
+            }
+        }
+    }
+
+    async fn send(&self, addr: SocketAddr, msg: &KrpcMessage) -> Result<(), TorrentError> {
+        self.socket.send_to(&msg.to_bytes()?, addr).await?;
+        Ok(())
+    }
+
+    async fn receive(self: Arc<Self>) {
+        let mut buf = vec![0; 64 << 10];
+        loop {
+            let (len, addr) = match self.socket.recv_from(&mut buf).await {
+                Ok(received) => received,
+                Err(err) => {
+                    self.report(TorrentError::Dht(format!("Receive failed: {err}")));
+                    sleep(RECEIVE_BACKOFF).await;
+                    continue;
+                }
+            };
+            // Nodes send all kinds of garbage: ignore what we do not understand
+            let Ok(msg) = KrpcMessage::from_bytes(&buf[..len]) else {
+                continue;
+            };
+
+            match msg.body {
+                Krpc::Query { id, query } => {
+                    let reply = KrpcMessage {
+                        transaction: msg.transaction,
+                        body: self.answer(addr, id, query),
+                    };
+                    if let Err(err) = self.send(addr, &reply).await {
+                        self.report(TorrentError::Dht(format!(
+                            "Fail to answer node {addr}: {err}"
+                        )));
+                    }
+                }
+                body => {
+                    let mut pending = self.pending.lock().unwrap();
+                    // Responses must come from the node we asked
+                    if pending.get(&msg.transaction).map(|x| x.0) == Some(addr) {
+                        let (_, tx) = pending.remove(&msg.transaction).unwrap();
+                        let _ = tx.send(body);
+                    }
+                }
+            }
+        }
+    }
+
+    fn answer(&self, addr: SocketAddr, id: NodeId, query: Query) -> Krpc {
+        self.table.lock().unwrap().insert(NodeInfo { id, addr });
+
+        let mut response = Response {
+            id: self.id,
+            ..Default::default()
+        };
+        match query {
+            Query::Ping => {}
+            Query::FindNode { target } => {
+                response.nodes = self.table.lock().unwrap().closest(&target, K);
+            }
+            Query::GetPeers { info_hash } => {
+                response.token = Some(self.tokens.lock().unwrap().token(addr.ip()));
+                response.values = self.stored_peers(&info_hash);
+                if response.values.is_empty() {
+                    let table = self.table.lock().unwrap();
+                    response.nodes = table.closest(&NodeId(info_hash), K);
+                }
+            }
+            Query::AnnouncePeer {
+                info_hash,
+                port,
+                implied_port,
+                token,
+            } => {
+                if !self.tokens.lock().unwrap().validate(addr.ip(), &token) {
+                    return Krpc::Error {
+                        code: ERROR_PROTOCOL,
+                        message: "Bad token".to_string(),
+                    };
+                }
+                let port = if implied_port { addr.port() } else { port };
+                let mut peers = self.peers.lock().unwrap();
+                if peers.len() >= self.config.max_info_hashes && !peers.contains_key(&info_hash) {
+                    return Krpc::Response(response);
+                }
+                let peers = peers.entry(info_hash).or_default();
+                if peers.len() < MAX_STORED_PEERS {
+                    peers.insert(SocketAddr::new(addr.ip(), port), Instant::now());
+                }
+            }
+        }
+        Krpc::Response(response)
+    }
+
+    fn stored_peers(&self, info_hash: &[u8; 20]) -> Vec<SocketAddr> {
+        let mut peers = self.peers.lock().unwrap();
+        let Some(announced) = peers.get_mut(info_hash) else {
+            return Vec::new();
+        };
+        announced.retain(|_, x| x.elapsed() < self.config.peer_ttl);
+        announced.keys().take(MAX_VALUES).copied().collect()
+    }
+
+    // Info hashes nobody asks for anymore would keep their expired peers
+    fn expire_peers(&self) {
+        let mut peers = self.peers.lock().unwrap();
+        for announced in peers.values_mut() {
+            announced.retain(|_, x| x.elapsed() < self.config.peer_ttl);
+        }
+        peers.retain(|_, x| !x.is_empty());
+    }
+
+    // Kademlia lookup: query the closest nodes we know of the target, which
+    // send back closer ones, until the closest that answered were all asked.
+    async fn lookup(self: &Arc<Self>, target: NodeId, get_peers: bool) -> Lookup {
+        let mut candidates: BTreeMap<NodeId, NodeInfo> = self
+            .table
+            .lock()
+            .unwrap()
+            .closest(&target, K)
+            .into_iter()
+            .map(|x| (target.distance(&x.id), x))
+            .collect();
+        let mut queried = HashSet::new();
+        let mut tokens = HashMap::new();
+        let mut peers = Vec::new();
+
+        loop {
+            let batch: Vec<NodeInfo> = candidates
+                .values()
+                .take(K)
+                .filter(|x| !queried.contains(&x.addr))
+                .take(self.config.alpha)
+                .copied()
+                .collect();
+            if batch.is_empty() {
+                break;
+            }
+
+            let mut queries = JoinSet::new();
+            for node_info in batch {
+                queried.insert(node_info.addr);
+                let query = if get_peers {
+                    Query::GetPeers {
+                        info_hash: target.0,
+                    }
+                } else {
+                    Query::FindNode { target }
+                };
+                let node = self.clone();
+                queries.spawn(async move { (node_info, node.query(node_info.addr, query).await) });
+            }
+
+            while let Some(joined) = queries.join_next().await {
+                let Ok((node_info, result)) = joined else {
+                    continue;
+                };
+                let Ok(response) = result else {
+                    candidates.remove(&target.distance(&node_info.id));
+                    continue;
+                };
+                if let Some(token) = response.token {
+                    tokens.insert(node_info.addr, token);
+                }
+                for peer in response.values {
+                    if !peers.contains(&peer) {
+                        peers.push(peer);
+                    }
+                }
+                for new in response.nodes {
+                    if new.id != self.id && !queried.contains(&new.addr) {
+                        candidates.entry(target.distance(&new.id)).or_insert(new);
+                    }
+                }
+            }
+        }
+
+        Lookup {
+            closest: candidates
+                .into_values()
+                .take(K)
+                .map(|x| (x, tokens.remove(&x.addr)))
+                .collect(),
+            peers,
+        }
+    }
+
+    // Keep the routing table and stored peers up to date while we are part of the DHT
+    async fn refresh(self: Arc<Self>) {
+        let mut interval = tokio::time::interval(self.config.refresh_interval);
+        interval.tick().await;
+        loop {
+            interval.tick().await;
+            self.expire_peers();
+            let targets = self
+                .table
+                .lock()
+                .unwrap()
+                .refresh_targets(self.config.refresh_interval);
+            for target in targets {
+                self.lookup(target, false).await;
+            }
+        }
+    }
+}
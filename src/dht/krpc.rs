@@ -0,0 +1,278 @@
+use std::net::SocketAddr;
+
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+
+use super::routing::{NodeId, NodeInfo};
+use crate::{
+    bencode_format,
+    error::TorrentError,
+    utils::{decode_compact_addrs, encode_compact_addr},
+};
+
+pub const ERROR_GENERIC: i64 = 201;
+pub const ERROR_SERVER: i64 = 202;
+pub const ERROR_PROTOCOL: i64 = 203;
+pub const ERROR_METHOD_UNKNOWN: i64 = 204;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Query {
+    Ping,
+    FindNode {
+        target: NodeId,
+    },
+    GetPeers {
+        info_hash: [u8; 20],
+    },
+    AnnouncePeer {
+        info_hash: [u8; 20],
+        port: u16,
+        // Use the port the query came from instead (for peers behind a NAT)
+        implied_port: bool,
+        token: Vec<u8>,
+    },
+}
+
+impl Query {
+    fn method(&self) -> &'static str {
+        match self {
+            Query::Ping => "ping",
+            Query::FindNode { .. } => "find_node",
+            Query::GetPeers { .. } => "get_peers",
+            Query::AnnouncePeer { .. } => "announce_peer",
+        }
+    }
+}
+
+/// Values of a response, the ones not sent by a method being left empty.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Response {
+    pub id: NodeId,
+    // Nodes closer to the target, IPv4 and IPv6 ones
+    pub nodes: Vec<NodeInfo>,
+    // Peers of the info hash
+    pub values: Vec<SocketAddr>,
+    pub token: Option<Vec<u8>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Krpc {
+    Query { id: NodeId, query: Query },
+    Response(Response),
+    Error { code: i64, message: String },
+}
+
+/// KRPC message (BEP 5): a bencoded dict sent in a single UDP packet.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KrpcMessage {
+    // Chosen by the querying node and echoed back in the response
+    pub transaction: Vec<u8>,
+    pub body: Krpc,
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+struct RawMessage {
+    #[serde(with = "serde_bytes")]
+    t: Vec<u8>,
+    y: String,
+    q: Option<String>,
+    a: Option<RawBody>,
+    r: Option<RawBody>,
+    e: Option<(i64, String)>,
+}
+
+// Query arguments and response values share the same dict
+#[derive(Debug, Default, Deserialize, Serialize)]
+struct RawBody {
+    #[serde(with = "serde_bytes")]
+    id: Vec<u8>,
+    #[serde(default, with = "serde_bytes")]
+    target: Option<Vec<u8>>,
+    #[serde(default, with = "serde_bytes")]
+    info_hash: Option<Vec<u8>>,
+    port: Option<i64>,
+    implied_port: Option<i64>,
+    #[serde(default, with = "serde_bytes")]
+    token: Option<Vec<u8>>,
+    #[serde(default, with = "serde_bytes")]
+    nodes: Option<Vec<u8>>,
+    #[serde(default, with = "serde_bytes")]
+    nodes6: Option<Vec<u8>>,
+    values: Option<Vec<ByteBuf>>,
+}
+
+impl KrpcMessage {
+    pub fn from_bytes(packet: &[u8]) -> Result<Self, TorrentError> {
+        let raw: RawMessage = bencode_format::from_bytes(packet)?;
+        let body = match raw.y.as_str() {
+            "q" => {
+                let (Some(method), Some(args)) = (raw.q, raw.a) else {
+                    return Err(invalid("Query without method or arguments"));
+                };
+                Krpc::Query {
+                    id: node_id(&args.id)?,
+                    query: parse_query(&method, args)?,
+                }
+            }
+            "r" => {
+                let values = raw.r.ok_or_else(|| invalid("Response without values"))?;
+                Krpc::Response(parse_response(values)?)
+            }
+            "e" => {
+                let (code, message) = raw.e.ok_or_else(|| invalid("Error without details"))?;
+                Krpc::Error { code, message }
+            }
+            kind => return Err(invalid(&format!("Unknown message type: {kind}"))),
+        };
+
+        Ok(Self {
+            transaction: raw.t,
+            body,
+        })
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>, TorrentError> {
+        let mut raw = RawMessage {
+            t: self.transaction.clone(),
+            ..Default::default()
+        };
+        match &self.body {
+            Krpc::Query { id, query } => {
+                raw.y = "q".to_string();
+                raw.q = Some(query.method().to_string());
+                raw.a = Some(query_args(*id, query));
+            }
+            Krpc::Response(response) => {
+                raw.y = "r".to_string();
+                raw.r = Some(response_values(response));
+            }
+            Krpc::Error { code, message } => {
+                raw.y = "e".to_string();
+                raw.e = Some((*code, message.clone()));
+            }
+        }
+        Ok(bencode_format::to_bytes(&raw)?)
+    }
+}
+
+fn invalid(msg: &str) -> TorrentError {
+    TorrentError::Dht(format!("Invalid KRPC message: {msg}"))
+}
+
+fn node_id(bytes: &[u8]) -> Result<NodeId, TorrentError> {
+    bytes
+        .try_into()
+        .map(NodeId)
+        .map_err(|_| invalid("IDs are 20 bytes"))
+}
+
+fn parse_query(method: &str, args: RawBody) -> Result<Query, TorrentError> {
+    let info_hash = || {
+        let info_hash = args
+            .info_hash
+            .as_deref()
+            .ok_or_else(|| invalid("Missing info hash"))?;
+        node_id(info_hash).map(|x| x.0)
+    };
+
+    match method {
+        "ping" => Ok(Query::Ping),
+        "find_node" => {
+            let target = args
+                .target
+                .as_deref()
+                .ok_or_else(|| invalid("Missing target"))?;
+            Ok(Query::FindNode {
+                target: node_id(target)?,
+            })
+        }
+        "get_peers" => Ok(Query::GetPeers {
+            info_hash: info_hash()?,
+        }),
+        "announce_peer" => Ok(Query::AnnouncePeer {
+            info_hash: info_hash()?,
+            port: args
+                .port
+                .and_then(|x| u16::try_from(x).ok())
+                .ok_or_else(|| invalid("Invalid port"))?,
+            implied_port: args.implied_port.unwrap_or(0) != 0,
+            token: args.token.clone().ok_or_else(|| invalid("Missing token"))?,
+        }),
+        _ => Err(TorrentError::Dht(format!("Unknown method: {method}"))),
+    }
+}
+
+fn parse_response(values: RawBody) -> Result<Response, TorrentError> {
+    let mut nodes = Vec::new();
+    for (compact, ipv6) in [(&values.nodes, false), (&values.nodes6, true)] {
+        if let Some(compact) = compact {
+            nodes.extend(
+                NodeInfo::decode_compact(compact, ipv6)
+                    .ok_or_else(|| invalid("Truncated nodes"))?,
+            );
+        }
+    }
+
+    // Peers are sent one per string, IPv4 and IPv6 ones mixed
+    let mut peers = Vec::new();
+    for value in values.values.unwrap_or_default() {
+        let ipv6 = value.len() == 18;
+        let peer = decode_compact_addrs(&value, ipv6)
+            .filter(|x| x.len() == 1)
+            .ok_or_else(|| invalid("Invalid peer"))?;
+        peers.extend(peer);
+    }
+
+    Ok(Response {
+        id: node_id(&values.id)?,
+        nodes,
+        values: peers,
+        token: values.token,
+    })
+}
+
+fn query_args(id: NodeId, query: &Query) -> RawBody {
+    let mut args = RawBody {
+        id: id.0.to_vec(),
+        ..Default::default()
+    };
+    match query {
+        Query::Ping => {}
+        Query::FindNode { target } => args.target = Some(target.0.to_vec()),
+        Query::GetPeers { info_hash } => args.info_hash = Some(info_hash.to_vec()),
+        Query::AnnouncePeer {
+            info_hash,
+            port,
+            implied_port,
+            token,
+        } => {
+            args.info_hash = Some(info_hash.to_vec());
+            args.port = Some(*port as i64);
+            args.implied_port = Some(*implied_port as i64);
+            args.token = Some(token.clone());
+        }
+    }
+    args
+}
+
+fn response_values(response: &Response) -> RawBody {
+    let mut values = RawBody {
+        id: response.id.0.to_vec(),
+        token: response.token.clone(),
+        ..Default::default()
+    };
+    for node in &response.nodes {
+        let nodes = match node.addr {
+            SocketAddr::V4(_) => &mut values.nodes,
+            SocketAddr::V6(_) => &mut values.nodes6,
+        };
+        nodes
+            .get_or_insert_with(Vec::new)
+            .extend(node.encode_compact());
+    }
+    if !response.values.is_empty() {
+        let peers = response.values.iter().map(encode_compact_addr);
+        values.values = Some(peers.map(ByteBuf::from).collect());
+    }
+    values
+}
@@ -0,0 +1,223 @@
+use std::{
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+use crate::utils::{decode_compact_addrs, encode_compact_addr, random_u64};
+
+// Nodes per bucket
+pub const K: usize = 8;
+
+// Queries a node can fail before it is replaced by new ones
+const MAX_FAILURES: u32 = 2;
+
+const ID_BITS: usize = 160;
+
+/// 160-bit ID of a node, in the same space as info hashes.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct NodeId(pub [u8; 20]);
+
+impl NodeId {
+    pub fn random() -> Self {
+        let mut id = [0; 20];
+        for chunk in id.chunks_mut(8) {
+            chunk.copy_from_slice(&random_u64().to_be_bytes()[..chunk.len()]);
+        }
+        Self(id)
+    }
+
+    // XOR metric: comparing distances is comparing the IDs they make
+    pub fn distance(&self, other: &NodeId) -> NodeId {
+        let mut distance = [0; 20];
+        for (idx, x) in distance.iter_mut().enumerate() {
+            *x = self.0[idx] ^ other.0[idx];
+        }
+        Self(distance)
+    }
+
+    // Leading bits shared with the other ID
+    pub fn common_prefix(&self, other: &NodeId) -> usize {
+        let distance = self.distance(other);
+        match distance.0.iter().position(|&x| x != 0) {
+            Some(idx) => idx * 8 + distance.0[idx].leading_zeros() as usize,
+            None => ID_BITS,
+        }
+    }
+
+    // Random ID sharing exactly `prefix` leading bits with this one
+    pub fn random_with_prefix(&self, prefix: usize) -> NodeId {
+        let mut id = NodeId::random();
+        for bit in 0..prefix.min(ID_BITS) {
+            let mask = 0x80 >> (bit % 8);
+            id.0[bit / 8] = (id.0[bit / 8] & !mask) | (self.0[bit / 8] & mask);
+        }
+        if prefix < ID_BITS {
+            id.0[prefix / 8] ^= (id.0[prefix / 8] ^ !self.0[prefix / 8]) & (0x80 >> (prefix % 8));
+        }
+        id
+    }
+}
+
+/// Contact information of a node.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct NodeInfo {
+    pub id: NodeId,
+    pub addr: SocketAddr,
+}
+
+impl NodeInfo {
+    // Compact format: ID then compact address, 26 bytes each (38 for IPv6)
+    pub fn decode_compact(data: &[u8], ipv6: bool) -> Option<Vec<NodeInfo>> {
+        let chunk_size = if ipv6 { 38 } else { 26 };
+        let chunks = data.chunks_exact(chunk_size);
+        if !chunks.remainder().is_empty() {
+            return None;
+        }
+
+        chunks
+            .map(|x| {
+                let addr = decode_compact_addrs(&x[20..], ipv6)?[0];
+                Some(NodeInfo {
+                    id: NodeId(x[..20].try_into().unwrap()),
+                    addr,
+                })
+            })
+            .collect()
+    }
+
+    pub fn encode_compact(&self) -> Vec<u8> {
+        let mut output = self.id.0.to_vec();
+        output.extend(encode_compact_addr(&self.addr));
+        output
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Entry {
+    node: NodeInfo,
+    last_seen: Instant,
+    failures: u32,
+}
+
+#[derive(Debug, Clone)]
+struct Bucket {
+    entries: Vec<Entry>,
+    last_changed: Instant,
+}
+
+/// Kademlia routing table: bucket `n` holds up to `K` nodes sharing exactly
+/// `n` leading bits with our ID, so we know more nodes the closer they are.
+#[derive(Debug, Clone)]
+pub struct RoutingTable {
+    id: NodeId,
+    buckets: Vec<Bucket>,
+}
+
+impl RoutingTable {
+    pub fn new(id: NodeId) -> Self {
+        let bucket = Bucket {
+            entries: Vec::new(),
+            last_changed: Instant::now(),
+        };
+        Self {
+            id,
+            buckets: vec![bucket; ID_BITS],
+        }
+    }
+
+    pub fn id(&self) -> NodeId {
+        self.id
+    }
+
+    // Node that answered or queried us. Old nodes are kept over new ones
+    // unless they stopped answering: false if its bucket has no room.
+    pub fn insert(&mut self, node: NodeInfo) -> bool {
+        if node.id == self.id {
+            return false;
+        }
+        let now = Instant::now();
+        let bucket = &mut self.buckets[self.id.common_prefix(&node.id)];
+
+        let entry = Entry {
+            node,
+            last_seen: now,
+            failures: 0,
+        };
+        if let Some(known) = bucket.entries.iter_mut().find(|x| x.node.id == node.id) {
+            *known = entry;
+        } else if bucket.entries.len() < K {
+            bucket.entries.push(entry);
+        } else if let Some(bad) = bucket
+            .entries
+            .iter_mut()
+            .find(|x| x.failures >= MAX_FAILURES)
+        {
+            *bad = entry;
+        } else {
+            return false;
+        }
+        bucket.last_changed = now;
+        true
+    }
+
+    // Query to the node timed out
+    pub fn fail(&mut self, addr: SocketAddr) {
+        for bucket in &mut self.buckets {
+            for entry in bucket.entries.iter_mut().filter(|x| x.node.addr == addr) {
+                entry.failures += 1;
+            }
+        }
+    }
+
+    pub fn get(&self, id: &NodeId) -> Option<NodeInfo> {
+        let bucket = self.buckets.get(self.id.common_prefix(id))?;
+        bucket
+            .entries
+            .iter()
+            .find(|x| x.node.id == *id)
+            .map(|x| x.node)
+    }
+
+    // Good nodes closest to the target, closest first
+    pub fn closest(&self, target: &NodeId, count: usize) -> Vec<NodeInfo> {
+        let mut nodes: Vec<_> = self
+            .buckets
+            .iter()
+            .flat_map(|x| &x.entries)
+            .filter(|x| x.failures < MAX_FAILURES)
+            .map(|x| x.node)
+            .collect();
+        nodes.sort_by_key(|x| target.distance(&x.id));
+        nodes.truncate(count);
+        nodes
+    }
+
+    // Most recently seen first
+    pub fn nodes(&self) -> Vec<NodeInfo> {
+        let mut entries: Vec<_> = self.buckets.iter().flat_map(|x| &x.entries).collect();
+        entries.sort_by_key(|x| std::cmp::Reverse(x.last_seen));
+        entries.into_iter().map(|x| x.node).collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.buckets.iter().map(|x| x.entries.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // IDs to look up for buckets not changed for a while. Buckets deeper
+    // than the last one with nodes have nobody to find.
+    pub fn refresh_targets(&self, max_age: Duration) -> Vec<NodeId> {
+        let Some(deepest) = self.buckets.iter().rposition(|x| !x.entries.is_empty()) else {
+            return Vec::new();
+        };
+        self.buckets[..=deepest]
+            .iter()
+            .enumerate()
+            .filter(|(_, x)| x.last_changed.elapsed() >= max_age)
+            .map(|(prefix, _)| self.id.random_with_prefix(prefix))
+            .collect()
+    }
+}
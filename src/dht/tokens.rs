@@ -0,0 +1,64 @@
+use std::{
+    net::IpAddr,
+    time::{Duration, Instant},
+};
+
+use crate::utils::{hash_sha1, random_u64};
+
+/// Tokens given with `get_peers` responses, required to `announce_peer`.
+///
+/// A token is a hash of the node IP with a secret changing every interval,
+/// tokens made with the previous secret being still accepted.
+#[derive(Debug)]
+pub struct TokenManager {
+    interval: Duration,
+    secret: u64,
+    previous: u64,
+    rotated_at: Instant,
+}
+
+impl TokenManager {
+    pub fn new(interval: Duration) -> Self {
+        let secret = random_u64();
+        Self {
+            interval,
+            secret,
+            previous: secret,
+            rotated_at: Instant::now(),
+        }
+    }
+
+    pub fn token(&mut self, ip: IpAddr) -> Vec<u8> {
+        self.rotate_if_due();
+        make_token(self.secret, ip)
+    }
+
+    pub fn validate(&mut self, ip: IpAddr, token: &[u8]) -> bool {
+        self.rotate_if_due();
+        [self.secret, self.previous]
+            .iter()
+            .any(|&secret| make_token(secret, ip) == token)
+    }
+
+    pub fn rotate(&mut self) {
+        self.previous = self.secret;
+        self.secret = random_u64();
+        self.rotated_at = Instant::now();
+    }
+
+    fn rotate_if_due(&mut self) {
+        if self.rotated_at.elapsed() >= self.interval {
+            self.rotate();
+        }
+    }
+}
+
+fn make_token(secret: u64, ip: IpAddr) -> Vec<u8> {
+    let mut input = secret.to_be_bytes().to_vec();
+    match ip {
+        IpAddr::V4(ip) => input.extend_from_slice(&ip.octets()),
+        IpAddr::V6(ip) => input.extend_from_slice(&ip.octets()),
+    }
+    // Shorter tokens keep responses small
+    hash_sha1(&input)[..8].to_vec()
+}
@@ -18,6 +18,33 @@ pub enum TorrentError {
     #[error("JSON: {0}")]
     Json(String),
 
+    #[error("Tracker: {0}")]
+    Tracker(String),
+
+    #[error("Tracker failure: {0}")]
+    TrackerFailure(String),
+
+    #[error("Peer: {0}")]
+    Peer(String),
+
+    #[error("Handshake: {0}")]
+    Handshake(String),
+
+    #[error("Piece {0} failed hash check")]
+    PieceHashMismatch(u32),
+
+    #[error("Torrent creation: {0}")]
+    Create(String),
+
+    #[error("Magnet link: {0}")]
+    Magnet(String),
+
+    #[error("Metadata: {0}")]
+    Metadata(String),
+
+    #[error("DHT: {0}")]
+    Dht(String),
+
     #[error("Invalid message ID")]
     InvalidMessageId,
 }
@@ -0,0 +1,122 @@
+use std::{
+    collections::BTreeMap,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{bencode_format, error::TorrentError, peers::PeerMessage};
+
+// Extended message ID of the handshake, other IDs are negotiated in it
+pub const HANDSHAKE_ID: u8 = 0;
+
+pub const CLIENT_VERSION: &str = concat!(env!("CARGO_PKG_NAME"), " ", env!("CARGO_PKG_VERSION"));
+
+/// Extension handshake (BEP 10), the payload of extended message 0.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct ExtensionHandshake {
+    // Extension names to the message ID to use when sending them to us, 0 disables one
+    #[serde(default)]
+    pub m: BTreeMap<String, i64>,
+    // Client name and version
+    pub v: Option<String>,
+    // Requests queued without being dropped
+    pub reqq: Option<i64>,
+    // Our IP address as seen by the peer, 4 or 16 bytes
+    #[serde(default, with = "serde_bytes")]
+    pub yourip: Option<Vec<u8>>,
+    // Size of the info dict, for ut_metadata
+    pub metadata_size: Option<i64>,
+}
+
+impl ExtensionHandshake {
+    pub fn from_payload(payload: &[u8]) -> Result<Self, TorrentError> {
+        Ok(bencode_format::from_bytes(payload)?)
+    }
+
+    pub fn to_message(&self) -> Result<PeerMessage, TorrentError> {
+        Ok(PeerMessage::Extended {
+            id: HANDSHAKE_ID,
+            payload: bencode_format::to_bytes(self)?,
+        })
+    }
+
+    // ID to use when sending this extension to the peer
+    pub fn extension_id(&self, name: &str) -> Option<u8> {
+        self.m
+            .get(name)
+            .and_then(|&id| u8::try_from(id).ok())
+            .filter(|&id| id != HANDSHAKE_ID)
+    }
+
+    pub fn reqq(&self) -> Option<usize> {
+        self.reqq
+            .and_then(|x| usize::try_from(x).ok())
+            .filter(|&x| x > 0)
+    }
+
+    pub fn your_ip(&self) -> Option<IpAddr> {
+        let ip = self.yourip.as_deref()?;
+        if let Ok(ip) = <[u8; 4]>::try_from(ip) {
+            return Some(IpAddr::V4(Ipv4Addr::from(ip)));
+        }
+        let ip = <[u8; 16]>::try_from(ip).ok()?;
+        Some(IpAddr::V6(Ipv6Addr::from(ip)))
+    }
+}
+
+/// Extensions we support, with the message IDs peers use to send them to us.
+#[derive(Debug, Clone, Default)]
+pub struct ExtensionRegistry {
+    // Extension with ID `n` is at index `n - 1`
+    names: Vec<String>,
+    reqq: Option<usize>,
+}
+
+impl ExtensionRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn register(&mut self, name: &str) -> u8 {
+        if let Some(id) = self.id(name) {
+            return id;
+        }
+        self.names.push(name.to_string());
+        self.names.len() as u8
+    }
+
+    // Advertise how many requests we queue from a peer
+    pub fn set_reqq(&mut self, reqq: usize) {
+        self.reqq = Some(reqq);
+    }
+
+    pub fn id(&self, name: &str) -> Option<u8> {
+        let index = self.names.iter().position(|x| x == name)?;
+        Some(index as u8 + 1)
+    }
+
+    // Extension of a message received from a peer
+    pub fn name(&self, id: u8) -> Option<&str> {
+        let index = (id as usize).checked_sub(1)?;
+        self.names.get(index).map(|x| x.as_str())
+    }
+
+    pub fn handshake(&self, peer_ip: IpAddr) -> ExtensionHandshake {
+        ExtensionHandshake {
+            m: self
+                .names
+                .iter()
+                .enumerate()
+                .map(|(index, name)| (name.clone(), index as i64 + 1))
+                .collect(),
+            v: Some(CLIENT_VERSION.to_string()),
+            reqq: self.reqq.map(|x| x as i64),
+            yourip: Some(match peer_ip {
+                IpAddr::V4(ip) => ip.octets().to_vec(),
+                IpAddr::V6(ip) => ip.octets().to_vec(),
+            }),
+            metadata_size: None,
+        }
+    }
+}
@@ -1,15 +1,35 @@
+use std::sync::OnceLock;
+
 pub mod bencode_format;
+pub mod bitfield;
+pub mod choker;
+pub mod creator;
+pub mod dht;
 pub mod error;
+pub mod extensions;
+pub mod magnet;
+pub mod metadata;
 pub mod peers;
+pub mod pex;
+pub mod picker;
+pub mod resume;
+pub mod seeder;
+pub mod session;
+pub mod storage;
 pub mod torrent_file;
 pub mod trackers;
 pub mod url_encode;
 pub mod utils;
 
-pub const PEER_ID: &str = "AL-20231215-1.0.0.00";
+// Azureus-style, random for each run so we can tell connections to ourselves
+pub fn peer_id() -> [u8; 20] {
+    static PEER_ID: OnceLock<[u8; 20]> = OnceLock::new();
 
-const _: () = {
-    if PEER_ID.as_bytes().len() != 20 {
-        panic!("Invalid PEER_ID length");
-    }
-};
+    *PEER_ID.get_or_init(|| {
+        let mut peer_id = *b"-AL0100-000000000000";
+        for x in &mut peer_id[8..] {
+            *x = b'0' + (utils::random_u32() % 10) as u8;
+        }
+        peer_id
+    })
+}
@@ -0,0 +1,126 @@
+use std::net::SocketAddr;
+
+use hex::ToHex;
+
+use crate::{
+    error::TorrentError, torrent_file::MetaInfoFile, url_encode::url_decode, utils::hash_sha1,
+};
+
+/// Magnet URI (BEP 9): `magnet:?xt=urn:btih:<info hash>&dn=<name>&tr=<tracker>`.
+///
+/// The info dict has to be fetched from peers before downloading.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MagnetLink {
+    pub info_hash: [u8; 20],
+    // Display name, only a hint until we have the metadata
+    pub name: Option<String>,
+    pub trackers: Vec<String>,
+    // Peers to try right away (`x.pe`)
+    pub peers: Vec<SocketAddr>,
+}
+
+impl MagnetLink {
+    pub fn parse(uri: &str) -> Result<Self, TorrentError> {
+        let query = uri
+            .strip_prefix("magnet:?")
+            .ok_or_else(|| TorrentError::Magnet("Not a magnet URI".to_string()))?;
+
+        let mut info_hash = None;
+        let mut name = None;
+        let mut trackers = Vec::new();
+        let mut peers = Vec::new();
+        for param in query.split('&').filter(|x| !x.is_empty()) {
+            let (key, value) = param.split_once('=').unwrap_or((param, ""));
+            let value = url_decode(value)
+                .and_then(|x| String::from_utf8(x).ok())
+                .ok_or_else(|| TorrentError::Magnet(format!("Invalid {key} value")))?;
+
+            // Some links number repeated keys: `tr.1`, `tr.2`...
+            let key = match key.rsplit_once('.') {
+                Some((base, index)) if index.parse::<u32>().is_ok() => base,
+                _ => key,
+            };
+            match key {
+                "xt" => {
+                    // Other hashes (BitTorrent v2, ed2k...) are not supported
+                    if let Some(hash) = value.strip_prefix("urn:btih:") {
+                        info_hash.get_or_insert(decode_info_hash(hash)?);
+                    }
+                }
+                "dn" => name = Some(value),
+                "tr" if !trackers.contains(&value) => trackers.push(value),
+                "x.pe" => {
+                    let peer = value
+                        .parse()
+                        .map_err(|_| TorrentError::Magnet(format!("Invalid peer: {value}")))?;
+                    peers.push(peer);
+                }
+                _ => {}
+            }
+        }
+
+        Ok(Self {
+            info_hash: info_hash
+                .ok_or_else(|| TorrentError::Magnet("Missing BitTorrent info hash".to_string()))?,
+            name,
+            trackers,
+            peers,
+        })
+    }
+
+    pub fn info_hash(&self) -> String {
+        self.info_hash.encode_hex()
+    }
+
+    // Trackers are all announced to, one tier each
+    pub fn tiers(&self) -> Vec<Vec<String>> {
+        self.trackers.iter().map(|x| vec![x.clone()]).collect()
+    }
+
+    // Torrent made of the info dict fetched from peers
+    pub fn to_meta_info(&self, info_bytes: Vec<u8>) -> Result<MetaInfoFile, TorrentError> {
+        if hash_sha1(&info_bytes) != self.info_hash {
+            return Err(TorrentError::Metadata(
+                "Info dict does not match the info hash".to_string(),
+            ));
+        }
+
+        let mut meta_info = MetaInfoFile::from_info_bytes(info_bytes)?;
+        meta_info.announce = self.trackers.first().cloned().unwrap_or_default();
+        meta_info.announce_list = (self.trackers.len() > 1).then(|| self.tiers());
+        Ok(meta_info)
+    }
+}
+
+// 40 hex digits or 32 base32 characters
+fn decode_info_hash(hash: &str) -> Result<[u8; 20], TorrentError> {
+    let decoded = match hash.len() {
+        40 => hex::decode(hash).ok(),
+        32 => decode_base32(hash),
+        _ => None,
+    };
+    decoded
+        .and_then(|x| x.try_into().ok())
+        .ok_or_else(|| TorrentError::Magnet(format!("Invalid info hash: {hash}")))
+}
+
+// RFC 4648 alphabet, without padding
+fn decode_base32(input: &str) -> Option<Vec<u8>> {
+    let mut output = Vec::with_capacity(input.len() * 5 / 8);
+    let mut buffer = 0u32;
+    let mut bits = 0;
+    for c in input.bytes() {
+        let value = match c.to_ascii_uppercase() {
+            c @ b'A'..=b'Z' => c - b'A',
+            c @ b'2'..=b'7' => c - b'2' + 26,
+            _ => return None,
+        };
+        buffer = (buffer << 5) | value as u32;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            output.push((buffer >> bits) as u8);
+        }
+    }
+    Some(output)
+}
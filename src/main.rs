@@ -1,15 +1,36 @@
-use std::{cmp, fs, net::SocketAddr, path::PathBuf};
+use std::{
+    fs,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 
 use bittorrent_starter_rust::{
     bencode_format::BencodeValue,
-    error::TorrentError,
-    peers::{Peer, PeerMessage},
+    choker::ChokerConfig,
+    creator::{create_torrent, CreateOptions},
+    dht::{Dht, DhtConfig, DhtState, BOOTSTRAP_NODES},
+    magnet::MagnetLink,
+    metadata::MetadataFetcher,
+    peers::Peer,
+    resume::{self, ResumeData},
+    seeder::{SeedTorrent, Seeder, SeederConfig},
+    session::{DownloadSession, SessionProgress},
+    storage::{FileStorage, MemoryStorage},
     torrent_file::MetaInfoFile,
-    trackers,
-    utils::hash_sha1,
+    trackers::{
+        self,
+        announcer::{Announcer, AnnouncerHandle, TransferStats},
+        tiers::{self, TrackerList},
+        AnnounceRequest,
+    },
 };
 use clap::{Parser, Subcommand};
-use hex::ToHex;
+use tokio::net::TcpListener;
+
+// Files written since the last save are hashed again when resuming after a crash
+const RESUME_SAVE_INTERVAL: Duration = Duration::from_secs(30);
 
 #[derive(Debug, Parser)]
 struct Args {
@@ -29,6 +50,9 @@ enum Commands {
     Peers {
         path: PathBuf,
     },
+    Scrape {
+        path: PathBuf,
+    },
     Handshake {
         path: PathBuf,
         addr: SocketAddr,
@@ -45,6 +69,50 @@ enum Commands {
         output_path: PathBuf,
         meta_info_path: PathBuf,
     },
+    #[clap(alias = "magnet_parse")]
+    MagnetParse {
+        uri: String,
+    },
+    // Fetch the metadata of a magnet link from peers
+    #[clap(alias = "magnet_info")]
+    MagnetInfo {
+        uri: String,
+    },
+    #[clap(alias = "magnet_download")]
+    MagnetDownload {
+        #[arg(short = 'o')]
+        output_path: PathBuf,
+        uri: String,
+    },
+    // Make a .torrent file out of a file or directory
+    Create {
+        #[arg(short = 'o')]
+        output_path: Option<PathBuf>,
+        // Tracker URL, comma separated URLs are in the same tier
+        #[arg(short = 'a', long = "announce", required = true)]
+        trackers: Vec<String>,
+        #[arg(long)]
+        piece_length: Option<u32>,
+        #[arg(long)]
+        comment: Option<String>,
+        #[arg(long, default_value = concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION")))]
+        created_by: String,
+        #[arg(long)]
+        private: bool,
+        #[arg(long = "web-seed")]
+        web_seeds: Vec<String>,
+        path: PathBuf,
+    },
+    // Share a completed download
+    Seed {
+        #[arg(short = 'p', long, default_value_t = trackers::DEFAULT_PORT)]
+        port: u16,
+        // Peers uploaded to at the same time
+        #[arg(long, default_value_t = ChokerConfig::default().upload_slots)]
+        upload_slots: usize,
+        meta_info_path: PathBuf,
+        path: PathBuf,
+    },
 }
 
 #[tokio::main]
@@ -58,28 +126,41 @@ async fn main() {
 
             println!("{decoded_json}");
         }
-        Commands::Info { path } => {
-            let meta_info = read_file(path);
-
-            println!("Tracker URL: {}", meta_info.announce);
-            println!("Length: {}", meta_info.info.length);
-            println!("Info Hash: {}", meta_info.info.info_hash());
-            println!("Piece Length: {}", meta_info.info.piece_length);
-            println!("Piece Hashes:");
-            for piece_hash in meta_info.info.pieces_hashes() {
-                println!("{piece_hash}");
-            }
-        }
+        Commands::Info { path } => print_info(&read_file(path)),
         Commands::Peers { path } => {
             let meta_info = read_file(path);
-            let tracker_response = trackers::query(&meta_info)
+            let responses = TrackerList::from_meta_info(&meta_info)
+                .announce(&AnnounceRequest::new(&meta_info))
                 .await
                 .expect("Fail to query tracker");
 
-            for peer_addr in tracker_response.peer_addrs() {
+            for warning in responses.iter().filter_map(|x| x.warning_message.as_ref()) {
+                eprintln!("Tracker warning: {warning}");
+            }
+            for peer_addr in tiers::merge_peer_addrs(&responses) {
                 println!("{peer_addr}");
             }
         }
+        Commands::Scrape { path } => {
+            let meta_info = read_file(path);
+            let info_hash = meta_info.info_hash_bytes();
+
+            // Ask each tracker in turn until one answers
+            let trackers = TrackerList::from_meta_info(&meta_info);
+            let stats = 'found: {
+                for url in trackers.tiers().iter().flatten() {
+                    match trackers::scrape(url, &[info_hash]).await {
+                        Ok(stats) => break 'found stats[0],
+                        Err(err) => eprintln!("Fail to scrape {url}: {err}"),
+                    }
+                }
+                panic!("No tracker answered the scrape request");
+            };
+
+            println!("Complete: {}", stats.complete);
+            println!("Incomplete: {}", stats.incomplete);
+            println!("Downloaded: {}", stats.downloaded);
+        }
         Commands::Handshake { path, addr } => {
             let meta_info = read_file(path);
 
@@ -95,140 +176,321 @@ async fn main() {
             piece_id,
             ..
         } => {
-            let meta_info = read_file(meta_info_path);
-            let mut peer = connect_any_peer_addr(&meta_info)
+            let meta_info = Arc::new(read_file(meta_info_path));
+            let peer_addrs = trackers::query_peers(&meta_info)
                 .await
-                .expect("Cannot connect to peer");
-            let contents = download_piece(&meta_info, &mut peer, piece_id)
+                .expect("Fail to query trackers");
+
+            let stats = Arc::new(TransferStats::new(meta_info.info.total_length()));
+            let session = DownloadSession::new(meta_info, stats, Arc::new(MemoryStorage::new()));
+            session.add_peers(peer_addrs);
+            let contents = session
+                .download_piece(piece_id)
                 .await
-                .expect("Fail to download msg piece");
+                .expect("Fail to download piece");
             fs::write(output_path, contents).expect("Fail to write data to disk");
         }
         Commands::Download {
             output_path,
             meta_info_path,
         } => {
-            let meta_info = read_file(meta_info_path.clone());
-            let mut peer = connect_any_peer_addr(&meta_info)
-                .await
-                .expect("Cannot connect to peer");
-            let contents = download(&meta_info, &mut peer)
+            let meta_info = Arc::new(read_file(meta_info_path.clone()));
+            download(meta_info, &output_path, Vec::new()).await;
+            println!("Downloaded {meta_info_path:?} to {output_path:?}.")
+        }
+        Commands::MagnetParse { uri } => {
+            let magnet = MagnetLink::parse(&uri).expect("Invalid magnet link");
+
+            if let Some(url) = magnet.trackers.first() {
+                println!("Tracker URL: {url}");
+            }
+            println!("Info Hash: {}", magnet.info_hash());
+            if let Some(name) = &magnet.name {
+                println!("Name: {name}");
+            }
+            for peer_addr in &magnet.peers {
+                println!("Peer: {peer_addr}");
+            }
+        }
+        Commands::MagnetInfo { uri } => print_info(&fetch_magnet(&uri).await.0),
+        Commands::MagnetDownload { output_path, uri } => {
+            let (meta_info, peer_addrs) = fetch_magnet(&uri).await;
+            let meta_info = Arc::new(meta_info);
+            download(meta_info.clone(), &output_path, peer_addrs).await;
+            println!("Downloaded {} to {output_path:?}.", meta_info.info.name)
+        }
+        Commands::Create {
+            output_path,
+            trackers,
+            piece_length,
+            comment,
+            created_by,
+            private,
+            web_seeds,
+            path,
+        } => {
+            let creation_date = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .expect("Invalid system time")
+                .as_secs() as i64;
+            let options = CreateOptions {
+                piece_length,
+                trackers: trackers
+                    .iter()
+                    .map(|tier| tier.split(',').map(|x| x.to_string()).collect())
+                    .collect(),
+                comment,
+                created_by: Some(created_by),
+                creation_date: Some(creation_date),
+                private,
+                web_seeds,
+            };
+            let torrent = create_torrent(&path, &options).expect("Fail to create torrent");
+            let meta_info = MetaInfoFile::from_bytes(&torrent).expect("Invalid torrent created");
+
+            let output_path = output_path
+                .unwrap_or_else(|| PathBuf::from(format!("{}.torrent", meta_info.info.name)));
+            fs::write(&output_path, torrent).expect("Fail to write torrent file");
+            println!("Created {output_path:?}.");
+            println!("Info Hash: {}", meta_info.info_hash());
+        }
+        Commands::Seed {
+            port,
+            upload_slots,
+            meta_info_path,
+            path,
+        } => {
+            let meta_info = Arc::new(read_file(meta_info_path));
+            let storage =
+                Arc::new(FileStorage::open(&meta_info.info, &path).expect("Fail to open files"));
+            let have = resume::load_pieces(&meta_info, &storage, &resume::resume_path(&path))
+                .expect("Fail to check files");
+            if !have.is_full() {
+                eprintln!(
+                    "Only {} of {} pieces available",
+                    have.count_ones(),
+                    have.len()
+                );
+            }
+
+            let left = (0..have.len() as u32)
+                .filter(|&index| !have.has(index as usize))
+                .map(|index| meta_info.info.piece_size(index) as u64)
+                .sum();
+            let stats = Arc::new(TransferStats::new(left));
+            let mut request = AnnounceRequest::new(&meta_info);
+            request.port = port;
+            let announcer = Announcer::new(
+                TrackerList::from_meta_info(&meta_info),
+                request,
+                stats.clone(),
+            )
+            .spawn();
+
+            let listener = TcpListener::bind(("0.0.0.0", port))
                 .await
-                .expect("Fail to download file");
-            fs::write(&output_path, contents).expect("Fail to write data to disk");
+                .expect("Fail to listen for peers");
+            let mut seeder = Seeder::with_config(SeederConfig {
+                choker: ChokerConfig {
+                    upload_slots,
+                    ..Default::default()
+                },
+                ..Default::default()
+            });
+            seeder.add_torrent(SeedTorrent {
+                meta_info,
+                storage,
+                have,
+                stats,
+            });
+            let mut errors = seeder.errors();
+            println!("Seeding {path:?} on port {port}.");
 
-            println!("Downloaded {meta_info_path:?} to {output_path:?}.")
+            let seeding = seeder.run(listener);
+            tokio::pin!(seeding);
+            loop {
+                tokio::select! {
+                    result = &mut seeding => break result.expect("Fail to accept peers"),
+                    Some(err) = errors.recv() => eprintln!("{err}"),
+                    _ = tokio::signal::ctrl_c() => break,
+                }
+            }
+            stop_announcer(announcer).await;
         }
     }
 }
 
-fn read_file(path: PathBuf) -> MetaInfoFile {
-    let encoded_data = fs::read(path).expect("Fail to read file");
-    let (_, decoded_value) = BencodeValue::parse(&encoded_data).expect("Invalid bencode value");
-    serde_json::from_value(decoded_value.into()).expect("Fail to decode torrent file")
-}
-
-async fn connect_any_peer_addr(meta_info: &MetaInfoFile) -> Result<Peer, TorrentError> {
-    // Query tracker
-    let tracker_response = trackers::query(meta_info).await?;
-
-    // Take 1st peer
-    let peer_addrs = tracker_response.peer_addrs();
-    let peer_addr = peer_addrs.first().expect("No peer for given .torrent");
+// Resume what a previous run did not download, saving progress until done or interrupted
+async fn download(meta_info: Arc<MetaInfoFile>, output_path: &Path, peer_addrs: Vec<SocketAddr>) {
+    let stats = Arc::new(TransferStats::new(meta_info.info.total_length()));
+    let storage = Arc::new(
+        FileStorage::create(&meta_info.info, output_path).expect("Fail to create output files"),
+    );
 
-    // Connect to it
-    let mut peer = Peer::connect(peer_addr, meta_info).await?;
+    // Only fetch what a previous run did not
+    let resume_path = resume::resume_path(output_path);
+    let have = resume::load_pieces(&meta_info, &storage, &resume_path)
+        .expect("Fail to check existing files");
+    let session = DownloadSession::new(meta_info.clone(), stats.clone(), storage.clone());
+    session.set_have(&have);
+    let progress = session.progress();
 
-    // Read first message which should be a bit field
-    assert!(matches!(
-        peer.read_message().await?,
-        PeerMessage::BitField(_)
-    ));
+    let trackers = TrackerList::from_meta_info(&meta_info);
+    if trackers.tiers().is_empty() && peer_addrs.is_empty() {
+        let peers = session.peers_sender();
+        let info_hash = meta_info.info_hash_bytes();
+        let nodes = meta_info.nodes.clone().unwrap_or_default();
+        tokio::spawn(async move {
+            let _ = peers.send(dht_peers(info_hash, &nodes).await);
+        });
+    }
+    session.add_peers(peer_addrs);
 
-    // Send interested message
-    peer.send_message(&PeerMessage::Interested).await?;
+    let mut announcer = Announcer::new(trackers, AnnounceRequest::new(&meta_info), stats).spawn();
 
-    // Wait for unchoke
-    while let Ok(msg) = peer.read_message().await {
-        if msg == PeerMessage::Unchoke {
-            break;
+    // Feed the session with peers as trackers give them. Once they gave up,
+    // the session fails when it has no peer left.
+    let mut peers = Some(session.peers_sender());
+    let mut errors = session.errors();
+    let download = session.download();
+    tokio::pin!(download);
+    let mut save_resume = tokio::time::interval(RESUME_SAVE_INTERVAL);
+    let result = loop {
+        tokio::select! {
+            result = &mut download => break result,
+            announced = announcer.next_peers(), if peers.is_some() => match announced {
+                Some(Ok(peer_addrs)) => {
+                    let _ = peers.as_ref().unwrap().send(peer_addrs);
+                }
+                Some(Err(err)) => eprintln!("Fail to announce to trackers: {err}"),
+                None => peers = None,
+            },
+            Some(err) = errors.recv() => eprintln!("{err}"),
+            _ = progress.peers_wanted() => announcer.request_peers(),
+            _ = save_resume.tick() => {
+                save_resume_data(&meta_info, &storage, &progress, &resume_path);
+            }
+            _ = tokio::signal::ctrl_c() => {
+                save_resume_data(&meta_info, &storage, &progress, &resume_path);
+                stop_announcer(announcer).await;
+                std::process::exit(130);
+            }
         }
-        eprintln!("Received unexpected message: {msg:?}");
+    };
+    save_resume_data(&meta_info, &storage, &progress, &resume_path);
+    if result.is_ok() {
+        announcer.completed();
     }
+    stop_announcer(announcer).await;
+    result.expect("Fail to download file");
+}
 
-    Ok(peer)
+async fn stop_announcer(announcer: AnnouncerHandle) {
+    if let Err(err) = announcer.stop().await {
+        eprintln!("Fail to announce stop to trackers: {err}");
+    }
 }
 
-async fn download_piece(
-    meta_info: &MetaInfoFile,
-    peer: &mut Peer,
-    piece_id: u32,
-) -> Result<Vec<u8>, TorrentError> {
-    // Send request for each piece and wait for its response.
-    // TODO: Add pipelining
-    const CHUNK_SIZE: u32 = 16 << 10;
-
-    let piece_length = cmp::min(
-        meta_info.info.piece_length * (piece_id + 1), // last byte for a given piece ID
-        meta_info.info.length,
-    )
-    .saturating_sub(meta_info.info.piece_length * piece_id);
-
-    let mut begin_byte = 0;
-    let mut chunk_count = 0;
-    loop {
-        let end_byte = cmp::min(begin_byte + CHUNK_SIZE, piece_length);
-        if end_byte <= begin_byte {
-            break;
+// Peers from the link itself and from its trackers, or the DHT without any,
+// are asked for the metadata and returned to download from them
+async fn fetch_magnet(uri: &str) -> (MetaInfoFile, Vec<SocketAddr>) {
+    let magnet = MagnetLink::parse(uri).expect("Invalid magnet link");
+
+    let mut peer_addrs = magnet.peers.clone();
+    if !magnet.trackers.is_empty() {
+        // The length is unknown until we have the metadata: anything but 0 so
+        // trackers do not take us for a seeder
+        let request = AnnounceRequest::with_info_hash(magnet.info_hash, 1);
+        match TrackerList::new(magnet.tiers()).announce(&request).await {
+            Ok(responses) => peer_addrs.extend(tiers::merge_peer_addrs(&responses)),
+            Err(err) => eprintln!("Fail to query trackers: {err}"),
         }
+    } else {
+        peer_addrs.extend(dht_peers(magnet.info_hash, &[]).await);
+    }
 
-        let req = PeerMessage::Request {
-            index: piece_id,
-            begin: begin_byte,
-            length: end_byte - begin_byte,
-        };
-        peer.send_message(&req).await?;
+    let info_bytes = MetadataFetcher::new(magnet.info_hash)
+        .fetch(peer_addrs.clone())
+        .await
+        .expect("Fail to fetch metadata");
+    let meta_info = magnet.to_meta_info(info_bytes).expect("Invalid metadata");
+    (meta_info, peer_addrs)
+}
 
-        begin_byte += CHUNK_SIZE;
-        chunk_count += 1;
-    }
+// Peers of a torrent without trackers, looked up in the DHT joined through
+// the nodes of the torrent. The node table is saved so the next run does not
+// only rely on the bootstrap nodes.
+async fn dht_peers(info_hash: [u8; 20], torrent_nodes: &[(String, u16)]) -> Vec<SocketAddr> {
+    let state_path = DhtState::default_path();
+    let state = state_path.as_ref().and_then(|x| DhtState::load(x).ok());
+    let config = DhtConfig {
+        id: state.as_ref().map(|x| x.id),
+        ..Default::default()
+    };
+    let dht = Dht::bind_with_config(SocketAddr::from(([0, 0, 0, 0], 0)), config)
+        .await
+        .expect("Fail to start DHT node");
+    let mut errors = dht.errors();
 
-    // Read response chunk
-    let mut chunks = Vec::with_capacity(chunk_count);
-
-    while chunks.len() != chunks.capacity() {
-        match peer.read_message().await? {
-            PeerMessage::Piece {
-                index,
-                begin,
-                block,
-            } => {
-                assert_eq!(index, piece_id, "Received bad piece ID");
-                chunks.push((begin, block));
-            }
-            msg => eprintln!("Received unexpected message: {msg:?}"),
+    let mut nodes: Vec<_> = state.iter().flat_map(|x| x.addrs()).collect();
+    let torrent_nodes = torrent_nodes
+        .iter()
+        .map(|(host, port)| (host.as_str(), *port));
+    for (host, port) in torrent_nodes.chain(BOOTSTRAP_NODES.iter().copied()) {
+        match tokio::net::lookup_host((host, port)).await {
+            Ok(addrs) => nodes.extend(addrs.filter(|x| x.is_ipv4())),
+            Err(err) => eprintln!("Fail to resolve {host}: {err}"),
         }
     }
+    if let Err(err) = dht.bootstrap(&nodes).await {
+        eprintln!("Fail to join the DHT: {err}");
+        return Vec::new();
+    }
 
-    // Reorder chunk and flatten the data
-    chunks.sort_by_key(|x| x.0);
-    let contents: Vec<_> = chunks.into_iter().flat_map(|x| x.1).collect();
-
-    // Check signature
-    let contents_hash = hash_sha1(&contents);
-    let expected_hash = &meta_info.info.pieces_hashes()[piece_id as usize];
-    assert_eq!(contents_hash.encode_hex::<String>(), *expected_hash);
-
-    Ok(contents)
+    let peer_addrs = dht.get_peers(info_hash).await;
+    while let Ok(err) = errors.try_recv() {
+        eprintln!("{err}");
+    }
+    if let Some(state_path) = state_path {
+        if let Err(err) = dht.state().save(&state_path) {
+            eprintln!("Fail to save DHT nodes: {err}");
+        }
+    }
+    peer_addrs
 }
 
-async fn download(meta_info: &MetaInfoFile, peer: &mut Peer) -> Result<Vec<u8>, TorrentError> {
-    let mut output = Vec::with_capacity(meta_info.info.length as usize);
+fn print_info(meta_info: &MetaInfoFile) {
+    println!("Tracker URL: {}", meta_info.announce);
+    println!("Length: {}", meta_info.info.total_length());
+    println!("Info Hash: {}", meta_info.info_hash());
+    println!("Piece Length: {}", meta_info.info.piece_length);
+    println!("Piece Hashes:");
+    for piece_hash in meta_info.info.pieces_hashes() {
+        println!("{piece_hash}");
+    }
+    if meta_info.info.is_multi_file() {
+        println!("Files:");
+        for file in meta_info.info.files() {
+            println!("{} ({} bytes)", file.path.display(), file.length);
+        }
+    }
+}
 
-    for piece_id in 0..meta_info.info.pieces_count() {
-        let piece_content = download_piece(meta_info, peer, piece_id as u32).await?;
-        output.extend(piece_content);
+fn save_resume_data(
+    meta_info: &MetaInfoFile,
+    storage: &FileStorage,
+    progress: &SessionProgress,
+    resume_path: &Path,
+) {
+    let result = storage
+        .mtimes()
+        .and_then(|mtimes| ResumeData::new(meta_info, &progress.have(), mtimes).save(resume_path));
+    if let Err(err) = result {
+        eprintln!("Fail to save resume data: {err}");
     }
+}
 
-    Ok(output)
+fn read_file(path: PathBuf) -> MetaInfoFile {
+    let encoded_data = fs::read(path).expect("Fail to read file");
+    MetaInfoFile::from_bytes(&encoded_data).expect("Fail to decode torrent file")
 }
@@ -0,0 +1,260 @@
+use std::{net::SocketAddr, time::Duration};
+
+use serde::{Deserialize, Serialize};
+use tokio::{task::JoinSet, time::timeout};
+
+use crate::{
+    bencode_format::{self, BencodeRef},
+    error::TorrentError,
+    extensions::{ExtensionHandshake, ExtensionRegistry, HANDSHAKE_ID},
+    peers::{Peer, PeerMessage},
+    utils::hash_sha1,
+};
+
+// The info dict is sent in pieces of 16 KiB, the last one may be shorter
+pub const METADATA_PIECE_SIZE: usize = 16 << 10;
+
+pub const UT_METADATA: &str = "ut_metadata";
+
+#[derive(Debug, Clone)]
+pub struct MetadataConfig {
+    // Peers asked at the same time
+    pub max_peers: usize,
+    pub connect_timeout: Duration,
+    pub peer_timeout: Duration,
+    // Larger metadata is refused, the size being announced by the peer
+    pub max_size: usize,
+}
+
+impl Default for MetadataConfig {
+    fn default() -> Self {
+        Self {
+            max_peers: 5,
+            connect_timeout: Duration::from_secs(10),
+            peer_timeout: Duration::from_secs(30),
+            max_size: 16 << 20,
+        }
+    }
+}
+
+/// Message of the `ut_metadata` extension (BEP 9).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MetadataMessage {
+    Request(u32),
+    Data {
+        piece: u32,
+        total_size: u64,
+        data: Vec<u8>,
+    },
+    Reject(u32),
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct MetadataHeader {
+    msg_type: i64,
+    piece: i64,
+    total_size: Option<i64>,
+}
+
+impl MetadataMessage {
+    const MSG_TYPE_REQUEST: i64 = 0;
+    const MSG_TYPE_DATA: i64 = 1;
+    const MSG_TYPE_REJECT: i64 = 2;
+
+    pub fn from_bytes(payload: &[u8]) -> Result<Self, TorrentError> {
+        // Data follows the bencoded dict
+        let (data, _) = BencodeRef::parse(payload)?;
+        let header: MetadataHeader =
+            bencode_format::from_bytes(&payload[..payload.len() - data.len()])?;
+        let piece = u32::try_from(header.piece)
+            .map_err(|_| TorrentError::Metadata(format!("Invalid piece: {}", header.piece)))?;
+
+        match header.msg_type {
+            Self::MSG_TYPE_REQUEST => Ok(Self::Request(piece)),
+            Self::MSG_TYPE_DATA => {
+                let total_size = header
+                    .total_size
+                    .and_then(|x| u64::try_from(x).ok())
+                    .ok_or_else(|| TorrentError::Metadata("Invalid total size".to_string()))?;
+                Ok(Self::Data {
+                    piece,
+                    total_size,
+                    data: data.to_vec(),
+                })
+            }
+            Self::MSG_TYPE_REJECT => Ok(Self::Reject(piece)),
+            msg_type => Err(TorrentError::Metadata(format!(
+                "Unknown message type: {msg_type}"
+            ))),
+        }
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>, TorrentError> {
+        let (msg_type, piece, total_size) = match self {
+            Self::Request(piece) => (Self::MSG_TYPE_REQUEST, *piece, None),
+            Self::Data {
+                piece, total_size, ..
+            } => (Self::MSG_TYPE_DATA, *piece, Some(*total_size as i64)),
+            Self::Reject(piece) => (Self::MSG_TYPE_REJECT, *piece, None),
+        };
+        let mut output = bencode_format::to_bytes(&MetadataHeader {
+            msg_type,
+            piece: piece as i64,
+            total_size,
+        })?;
+        if let Self::Data { data, .. } = self {
+            output.extend_from_slice(data);
+        }
+        Ok(output)
+    }
+}
+
+/// Fetch the info dict of a magnet link from peers supporting `ut_metadata`.
+#[derive(Debug)]
+pub struct MetadataFetcher {
+    info_hash: [u8; 20],
+    config: MetadataConfig,
+}
+
+impl MetadataFetcher {
+    pub fn new(info_hash: [u8; 20]) -> Self {
+        Self::with_config(info_hash, MetadataConfig::default())
+    }
+
+    pub fn with_config(info_hash: [u8; 20], config: MetadataConfig) -> Self {
+        Self { info_hash, config }
+    }
+
+    // Ask a few peers at a time, the first one sending the whole info dict wins
+    pub async fn fetch(&self, peer_addrs: Vec<SocketAddr>) -> Result<Vec<u8>, TorrentError> {
+        let mut peer_addrs = peer_addrs.into_iter();
+        let mut peers = JoinSet::new();
+        // Why each peer failed, reported if none sends the metadata
+        let mut failures = Vec::new();
+        loop {
+            while peers.len() < self.config.max_peers {
+                let Some(addr) = peer_addrs.next() else {
+                    break;
+                };
+                let info_hash = self.info_hash;
+                let config = self.config.clone();
+                peers.spawn(async move { (addr, fetch_from_peer(addr, info_hash, &config).await) });
+            }
+
+            match peers.join_next().await {
+                Some(Ok((_, Ok(info_bytes)))) => return Ok(info_bytes),
+                Some(Ok((addr, Err(err)))) => failures.push(format!("{addr}: {err}")),
+                Some(Err(err)) => failures.push(format!("Task failed: {err}")),
+                None if failures.is_empty() => {
+                    return Err(TorrentError::Metadata(
+                        "No peer sent the metadata".to_string(),
+                    ))
+                }
+                None => {
+                    return Err(TorrentError::Metadata(format!(
+                        "No peer sent the metadata ({})",
+                        failures.join("; ")
+                    )))
+                }
+            }
+        }
+    }
+}
+
+async fn fetch_from_peer(
+    addr: SocketAddr,
+    info_hash: [u8; 20],
+    config: &MetadataConfig,
+) -> Result<Vec<u8>, TorrentError> {
+    let mut peer = timeout(
+        config.connect_timeout,
+        Peer::connect_info_hash(&addr, &info_hash),
+    )
+    .await
+    .map_err(|_| TorrentError::Peer("Connection timed out".to_string()))??;
+
+    if !peer.supports_extensions() {
+        return Err(TorrentError::Metadata(
+            "Peer does not support extensions".to_string(),
+        ));
+    }
+    let mut extensions = ExtensionRegistry::new();
+    let ut_metadata_id = extensions.register(UT_METADATA);
+    peer.send_message(&extensions.handshake(addr.ip()).to_message()?)
+        .await?;
+
+    let mut pieces: Vec<Option<Vec<u8>>> = Vec::new();
+    let mut total_size = 0;
+    loop {
+        let msg = timeout(config.peer_timeout, peer.read_message())
+            .await
+            .map_err(|_| TorrentError::Peer("Peer timed out".to_string()))??;
+        let PeerMessage::Extended { id, payload } = msg else {
+            continue;
+        };
+
+        if id == HANDSHAKE_ID {
+            let handshake = ExtensionHandshake::from_payload(&payload)?;
+            let ut_metadata = handshake.extension_id(UT_METADATA).ok_or_else(|| {
+                TorrentError::Metadata("Peer does not support ut_metadata".to_string())
+            })?;
+            total_size = handshake
+                .metadata_size
+                .and_then(|x| usize::try_from(x).ok())
+                .filter(|&x| x > 0 && x <= config.max_size)
+                .ok_or_else(|| TorrentError::Metadata("Invalid metadata size".to_string()))?;
+
+            let count = (0..total_size).step_by(METADATA_PIECE_SIZE).count();
+            pieces = vec![None; count];
+            for piece in 0..count {
+                let request = MetadataMessage::Request(piece as u32);
+                peer.send_message(&PeerMessage::Extended {
+                    id: ut_metadata,
+                    payload: request.to_bytes()?,
+                })
+                .await?;
+            }
+            continue;
+        }
+        if id != ut_metadata_id {
+            continue;
+        }
+
+        match MetadataMessage::from_bytes(&payload)? {
+            MetadataMessage::Data {
+                piece,
+                total_size: size,
+                data,
+            } => {
+                let start = piece as usize * METADATA_PIECE_SIZE;
+                let expected = total_size.saturating_sub(start).min(METADATA_PIECE_SIZE);
+                if size as usize != total_size || piece as usize >= pieces.len() {
+                    return Err(TorrentError::Metadata(format!("Unexpected piece {piece}")));
+                }
+                if data.len() != expected {
+                    return Err(TorrentError::Metadata(format!(
+                        "Invalid piece {piece} size: {} bytes",
+                        data.len()
+                    )));
+                }
+                pieces[piece as usize] = Some(data);
+                if pieces.iter().all(|x| x.is_some()) {
+                    break;
+                }
+            }
+            MetadataMessage::Reject(piece) => {
+                return Err(TorrentError::Metadata(format!("Piece {piece} rejected")))
+            }
+            // We have nothing to share
+            MetadataMessage::Request(_) => {}
+        }
+    }
+
+    let info_bytes: Vec<u8> = pieces.into_iter().flatten().flatten().collect();
+    if hash_sha1(&info_bytes) != info_hash {
+        return Err(TorrentError::Metadata(
+            "Info dict does not match the info hash".to_string(),
+        ));
+    }
+    Ok(info_bytes)
+}
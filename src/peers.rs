@@ -2,47 +2,163 @@ use std::net::SocketAddr;
 
 use hex::ToHex;
 use tokio::{
-    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
-    net::TcpStream,
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader},
+    net::{
+        tcp::{OwnedReadHalf, OwnedWriteHalf},
+        TcpStream,
+    },
+    sync::mpsc::{self, error::TryRecvError},
+    task::JoinHandle,
 };
 
-use crate::{error::TorrentError, torrent_file::MetaInfoFile, PEER_ID};
+use crate::{error::TorrentError, peer_id, torrent_file::MetaInfoFile};
+
+/// First message sent on a connection, by both sides.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Handshake {
+    // Extensions supported
+    pub reserved: [u8; 8],
+    pub info_hash: [u8; 20],
+    pub peer_id: [u8; 20],
+}
+
+impl Handshake {
+    pub const LENGTH: usize = 68;
+    const PROTOCOL: &[u8; 20] = b"\x13BitTorrent protocol";
+    // Extension protocol (BEP 10)
+    const RESERVED: [u8; 8] = [0, 0, 0, 0, 0, 0x10, 0, 0];
+
+    // Ours, with the extensions we support
+    pub fn new(info_hash: [u8; 20], peer_id: [u8; 20]) -> Self {
+        Self {
+            reserved: Self::RESERVED,
+            info_hash,
+            peer_id,
+        }
+    }
+
+    pub fn from_bytes(bytes: &[u8; Self::LENGTH]) -> Result<Self, TorrentError> {
+        if &bytes[..20] != Self::PROTOCOL {
+            return Err(TorrentError::Handshake(format!(
+                "Unknown protocol: {:?}",
+                String::from_utf8_lossy(&bytes[1..20])
+            )));
+        }
+        Ok(Self {
+            reserved: bytes[20..28].try_into().unwrap(),
+            info_hash: bytes[28..48].try_into().unwrap(),
+            peer_id: bytes[48..].try_into().unwrap(),
+        })
+    }
+
+    pub fn to_bytes(&self) -> [u8; Self::LENGTH] {
+        let mut bytes = [0; Self::LENGTH];
+        bytes[..20].copy_from_slice(Self::PROTOCOL);
+        bytes[20..28].copy_from_slice(&self.reserved);
+        bytes[28..48].copy_from_slice(&self.info_hash);
+        bytes[48..].copy_from_slice(&self.peer_id);
+        bytes
+    }
+
+    pub fn supports_extensions(&self) -> bool {
+        self.reserved[5] & Self::RESERVED[5] != 0
+    }
+
+    async fn read(stream: &mut TcpStream) -> Result<Self, TorrentError> {
+        let mut bytes = [0; Self::LENGTH];
+        stream.read_exact(&mut bytes).await?;
+        Self::from_bytes(&bytes)
+    }
+
+    async fn write(&self, stream: &mut TcpStream) -> Result<(), TorrentError> {
+        stream.write_all(&self.to_bytes()).await?;
+        stream.flush().await?;
+        Ok(())
+    }
+}
 
 #[derive(Debug)]
 pub struct Peer {
     stream: TcpStream,
-    peer_id: [u8; 20],
+    // Received from the peer
+    handshake: Handshake,
 }
 
 impl Peer {
     pub async fn connect(
         addr: &SocketAddr,
         meta_info: &MetaInfoFile,
+    ) -> Result<Self, TorrentError> {
+        Self::connect_info_hash(addr, &meta_info.info_hash_bytes()).await
+    }
+
+    // Only the info hash is known before fetching the metadata of a magnet link
+    pub async fn connect_info_hash(
+        addr: &SocketAddr,
+        info_hash: &[u8; 20],
     ) -> Result<Self, TorrentError> {
         // TCP connect
         let mut stream = TcpStream::connect(addr).await?;
 
-        // Send handshake
-        stream.write_u8(19).await?;
-        stream.write_all(b"BitTorrent protocol").await?;
-        stream.write_all(&[0, 0, 0, 0, 0, 0, 0, 0]).await?;
-        stream.write_all(&meta_info.info.info_hash_bytes()).await?;
-        stream.write_all(PEER_ID.as_bytes()).await?;
-        stream.flush().await?;
+        let ours = Handshake::new(*info_hash, peer_id());
+        ours.write(&mut stream).await?;
+        let handshake = Handshake::read(&mut stream).await?;
+        if handshake.info_hash != ours.info_hash {
+            return Err(TorrentError::Handshake(format!(
+                "Info hash mismatch: {}",
+                handshake.info_hash.encode_hex::<String>()
+            )));
+        }
+        // Trackers may give us our own address
+        if handshake.peer_id == ours.peer_id {
+            return Err(TorrentError::Handshake(
+                "Connected to ourselves".to_string(),
+            ));
+        }
 
-        // Read handshake response header
-        let mut response_payload = [0; 48];
-        stream.read_exact(&mut response_payload).await?;
+        Ok(Self { stream, handshake })
+    }
 
-        // Read handshake response peer ID
-        let mut peer_id = [0; 20];
-        stream.read_exact(&mut peer_id).await?;
+    // Inbound connection: answer the handshake if we share the torrent,
+    // returning its info hash.
+    pub async fn accept(
+        mut stream: TcpStream,
+        info_hashes: &[[u8; 20]],
+        peer_id: &[u8; 20],
+    ) -> Result<(Self, [u8; 20]), TorrentError> {
+        let handshake = Handshake::read(&mut stream).await?;
+        if !info_hashes.contains(&handshake.info_hash) {
+            return Err(TorrentError::Handshake(format!(
+                "Unknown info hash: {}",
+                handshake.info_hash.encode_hex::<String>()
+            )));
+        }
+        if handshake.peer_id == *peer_id {
+            return Err(TorrentError::Handshake(
+                "Connected to ourselves".to_string(),
+            ));
+        }
+        Handshake::new(handshake.info_hash, *peer_id)
+            .write(&mut stream)
+            .await?;
 
-        Ok(Self { stream, peer_id })
+        Ok((Self { stream, handshake }, handshake.info_hash))
     }
 
     pub fn id(&self) -> String {
-        self.peer_id.encode_hex()
+        self.handshake.peer_id.encode_hex()
+    }
+
+    pub fn handshake(&self) -> &Handshake {
+        &self.handshake
+    }
+
+    pub fn reserved(&self) -> [u8; 8] {
+        self.handshake.reserved
+    }
+
+    pub fn supports_extensions(&self) -> bool {
+        self.handshake.supports_extensions()
     }
 
     pub async fn read_message(&mut self) -> Result<PeerMessage, TorrentError> {
@@ -52,10 +168,67 @@ impl Peer {
     pub async fn send_message(&mut self, msg: &PeerMessage) -> Result<(), TorrentError> {
         msg.write(&mut self.stream).await
     }
+
+    // Allow reading and writing messages from different tasks
+    pub fn into_split(self) -> (OwnedReadHalf, OwnedWriteHalf) {
+        self.stream.into_split()
+    }
+}
+
+/// Messages read from a peer in a background task.
+///
+/// Reading a message is not cancel safe: this allows waiting for messages
+/// in `select!` along with other events.
+#[derive(Debug)]
+pub struct MessageReader {
+    messages: mpsc::Receiver<Result<PeerMessage, TorrentError>>,
+    task: JoinHandle<()>,
+}
+
+impl MessageReader {
+    pub fn spawn(reader: OwnedReadHalf) -> Self {
+        let (messages_tx, messages) = mpsc::channel(64);
+        let task = tokio::spawn(async move {
+            let mut reader = BufReader::new(reader);
+            loop {
+                let msg = PeerMessage::read(&mut reader).await;
+                let failed = msg.is_err();
+                if messages_tx.send(msg).await.is_err() || failed {
+                    break;
+                }
+            }
+        });
+        Self { messages, task }
+    }
+
+    pub async fn recv(&mut self) -> Result<PeerMessage, TorrentError> {
+        self.messages
+            .recv()
+            .await
+            .ok_or_else(|| TorrentError::Peer("Connection closed".to_string()))?
+    }
+
+    // Message already received, if any
+    pub fn try_recv(&mut self) -> Result<Option<PeerMessage>, TorrentError> {
+        match self.messages.try_recv() {
+            Ok(msg) => msg.map(Some),
+            Err(TryRecvError::Empty) => Ok(None),
+            Err(TryRecvError::Disconnected) => {
+                Err(TorrentError::Peer("Connection closed".to_string()))
+            }
+        }
+    }
+}
+
+impl Drop for MessageReader {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
 }
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum PeerMessage {
+    KeepAlive,
     Choke,
     Unchoke,
     Interested,
@@ -77,6 +250,11 @@ pub enum PeerMessage {
         begin: u32,
         length: u32,
     },
+    // BEP 10, ID 0 is the extension handshake
+    Extended {
+        id: u8,
+        payload: Vec<u8>,
+    },
 }
 
 impl PeerMessage {
@@ -89,18 +267,38 @@ impl PeerMessage {
     const MSG_ID_REQUEST: u8 = 6;
     const MSG_ID_PIECE: u8 = 7;
     const MSG_ID_CANCEL: u8 = 8;
+    const MSG_ID_EXTENDED: u8 = 20;
+
+    // Large enough for a 16 KiB block or the bit field of a huge torrent
+    const MAX_MSG_SIZE: u32 = 1 << 20;
 
     pub async fn read<R: AsyncRead + Unpin>(reader: &mut R) -> Result<Self, TorrentError> {
         let msg_size = reader.read_u32().await?;
+        if msg_size == 0 {
+            return Ok(PeerMessage::KeepAlive);
+        }
+        if msg_size > Self::MAX_MSG_SIZE {
+            return Err(TorrentError::Peer(format!(
+                "Message is too large: {msg_size} bytes"
+            )));
+        }
         let msg_id_val = reader.read_u8().await?;
 
+        let check_size = |expected: u32, name: &str| {
+            if msg_size == expected {
+                Ok(())
+            } else {
+                Err(TorrentError::Peer(format!("Invalid {name} message size")))
+            }
+        };
+
         match msg_id_val {
             Self::MSG_ID_CHOKE => Ok(PeerMessage::Choke),
             Self::MSG_ID_UNCHOKE => Ok(PeerMessage::Unchoke),
             Self::MSG_ID_INTERESTED => Ok(PeerMessage::Interested),
             Self::MSG_ID_NOT_INTERESTED => Ok(PeerMessage::NotInterested),
             Self::MSG_ID_HAVE => {
-                assert_eq!(msg_size, 5, "Invalid have message size");
+                check_size(5, "have")?;
                 let piece_id = reader.read_u32().await?;
                 Ok(PeerMessage::Have(piece_id))
             }
@@ -110,7 +308,7 @@ impl PeerMessage {
                 Ok(PeerMessage::BitField(block))
             }
             Self::MSG_ID_REQUEST => {
-                assert_eq!(msg_size, 13, "Invalid request message size");
+                check_size(13, "request")?;
                 let index = reader.read_u32().await?;
                 let begin = reader.read_u32().await?;
                 let length = reader.read_u32().await?;
@@ -121,6 +319,9 @@ impl PeerMessage {
                 })
             }
             Self::MSG_ID_PIECE => {
+                if msg_size < 9 {
+                    return Err(TorrentError::Peer("Invalid piece message size".to_string()));
+                }
                 let index = reader.read_u32().await?;
                 let begin = reader.read_u32().await?;
                 let mut block = vec![0; (msg_size - 9) as usize];
@@ -132,7 +333,7 @@ impl PeerMessage {
                 })
             }
             Self::MSG_ID_CANCEL => {
-                assert_eq!(msg_size, 13, "Invalid cancel message size");
+                check_size(13, "cancel")?;
                 let index = reader.read_u32().await?;
                 let begin = reader.read_u32().await?;
                 let length = reader.read_u32().await?;
@@ -142,12 +343,26 @@ impl PeerMessage {
                     length,
                 })
             }
+            Self::MSG_ID_EXTENDED => {
+                if msg_size < 2 {
+                    return Err(TorrentError::Peer(
+                        "Invalid extended message size".to_string(),
+                    ));
+                }
+                let id = reader.read_u8().await?;
+                let mut payload = vec![0; (msg_size - 2) as usize];
+                reader.read_exact(&mut payload).await?;
+                Ok(PeerMessage::Extended { id, payload })
+            }
             _ => Err(TorrentError::InvalidMessageId),
         }
     }
 
     pub async fn write<W: AsyncWrite + Unpin>(&self, writer: &mut W) -> Result<(), TorrentError> {
         match self {
+            PeerMessage::KeepAlive => {
+                writer.write_u32(0).await?;
+            }
             PeerMessage::Choke => {
                 writer.write_u32(1).await?;
                 writer.write_u8(0).await?;
@@ -207,6 +422,12 @@ impl PeerMessage {
                 writer.write_u32(*begin).await?;
                 writer.write_u32(*length).await?;
             }
+            PeerMessage::Extended { id, payload } => {
+                writer.write_u32(payload.len() as u32 + 2).await?;
+                writer.write_u8(20).await?;
+                writer.write_u8(*id).await?;
+                writer.write_all(payload).await?;
+            }
         };
         Ok(())
     }
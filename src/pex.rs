@@ -0,0 +1,167 @@
+use std::{
+    collections::{HashMap, HashSet},
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    bencode_format,
+    error::TorrentError,
+    utils::{decode_compact_addrs, encode_compact_addr},
+};
+
+pub const UT_PEX: &str = "ut_pex";
+
+// Peers are not sent to a peer more than once a minute
+pub const PEX_INTERVAL: Duration = Duration::from_secs(60);
+
+// Added and dropped peers in a single message, more are ignored
+pub const MAX_PEX_PEERS: usize = 50;
+
+// Flags of added peers
+pub const FLAG_ENCRYPTION: u8 = 0x01;
+pub const FLAG_SEED: u8 = 0x02;
+pub const FLAG_UTP: u8 = 0x04;
+pub const FLAG_HOLEPUNCH: u8 = 0x08;
+// We connected to the peer: it accepts incoming connections
+pub const FLAG_REACHABLE: u8 = 0x10;
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+struct PexPayload {
+    // Compact peers, with one byte of flags each for the added ones
+    #[serde(default, with = "serde_bytes")]
+    added: Option<Vec<u8>>,
+    #[serde(rename = "added.f", default, with = "serde_bytes")]
+    added_flags: Option<Vec<u8>>,
+    #[serde(default, with = "serde_bytes")]
+    dropped: Option<Vec<u8>>,
+    #[serde(default, with = "serde_bytes")]
+    added6: Option<Vec<u8>>,
+    #[serde(rename = "added6.f", default, with = "serde_bytes")]
+    added6_flags: Option<Vec<u8>>,
+    #[serde(default, with = "serde_bytes")]
+    dropped6: Option<Vec<u8>>,
+}
+
+/// Message of the `ut_pex` extension (BEP 11): peers connected or
+/// disconnected since the previous one.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PexMessage {
+    pub added: Vec<(SocketAddr, u8)>,
+    pub dropped: Vec<SocketAddr>,
+}
+
+impl PexMessage {
+    pub fn from_bytes(payload: &[u8]) -> Result<Self, TorrentError> {
+        let payload: PexPayload = bencode_format::from_bytes(payload)?;
+        let decode = |peers: &Option<Vec<u8>>, ipv6| {
+            decode_compact_addrs(peers.as_deref().unwrap_or_default(), ipv6)
+                .ok_or_else(|| TorrentError::Peer("Invalid PEX peers".to_string()))
+        };
+
+        let mut added = Vec::new();
+        for (peers, flags, ipv6) in [
+            (&payload.added, &payload.added_flags, false),
+            (&payload.added6, &payload.added6_flags, true),
+        ] {
+            // Flags are optional
+            let flags = flags.as_deref().unwrap_or_default();
+            let peers = decode(peers, ipv6)?.into_iter().enumerate();
+            added.extend(peers.map(|(idx, addr)| (addr, flags.get(idx).copied().unwrap_or(0))));
+        }
+        let mut dropped = decode(&payload.dropped, false)?;
+        dropped.extend(decode(&payload.dropped6, true)?);
+
+        Ok(Self { added, dropped })
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>, TorrentError> {
+        let mut payload = PexPayload::default();
+        for &(addr, flags) in &self.added {
+            let (peers, peers_flags) = match addr {
+                SocketAddr::V4(_) => (&mut payload.added, &mut payload.added_flags),
+                SocketAddr::V6(_) => (&mut payload.added6, &mut payload.added6_flags),
+            };
+            peers
+                .get_or_insert_with(Vec::new)
+                .extend(encode_compact_addr(&addr));
+            peers_flags.get_or_insert_with(Vec::new).push(flags);
+        }
+        for addr in &self.dropped {
+            let peers = match addr {
+                SocketAddr::V4(_) => &mut payload.dropped,
+                SocketAddr::V6(_) => &mut payload.dropped6,
+            };
+            peers
+                .get_or_insert_with(Vec::new)
+                .extend(encode_compact_addr(addr));
+        }
+        // IPv4 keys are expected even when empty
+        payload.added.get_or_insert_with(Vec::new);
+        payload.added_flags.get_or_insert_with(Vec::new);
+        payload.dropped.get_or_insert_with(Vec::new);
+
+        Ok(bencode_format::to_bytes(&payload)?)
+    }
+}
+
+/// Peers already sent to one peer, so the next message only has the changes.
+#[derive(Debug)]
+pub struct PexState {
+    interval: Duration,
+    next_send: Instant,
+    sent: HashSet<SocketAddr>,
+}
+
+impl PexState {
+    // The first message can be sent right away
+    pub fn new(interval: Duration) -> Self {
+        Self {
+            interval,
+            next_send: Instant::now(),
+            sent: HashSet::new(),
+        }
+    }
+
+    pub fn next_send(&self) -> Instant {
+        self.next_send
+    }
+
+    // Changes since the last message, nothing before the interval is over.
+    // Peers over the limit are left for the next messages.
+    pub fn update(
+        &mut self,
+        connected: &HashMap<SocketAddr, u8>,
+        now: Instant,
+    ) -> Option<PexMessage> {
+        if now < self.next_send {
+            return None;
+        }
+        self.next_send = now + self.interval;
+
+        let added: Vec<_> = connected
+            .iter()
+            .filter(|(addr, _)| !self.sent.contains(addr))
+            .take(MAX_PEX_PEERS)
+            .map(|(&addr, &flags)| (addr, flags))
+            .collect();
+        let dropped: Vec<_> = self
+            .sent
+            .iter()
+            .filter(|addr| !connected.contains_key(addr))
+            .take(MAX_PEX_PEERS)
+            .copied()
+            .collect();
+        if added.is_empty() && dropped.is_empty() {
+            return None;
+        }
+
+        self.sent.extend(added.iter().map(|x| x.0));
+        for addr in &dropped {
+            self.sent.remove(addr);
+        }
+        Some(PexMessage { added, dropped })
+    }
+}
@@ -0,0 +1,145 @@
+use std::cmp::Ordering;
+
+use crate::{bitfield::Bitfield, utils::random_u64};
+
+/// Decide which piece to download next from a given peer.
+///
+/// Pieces are picked rarest-first across the connected peers, except for the
+/// first few ones: those are picked at random so we quickly have something
+/// to share instead of all racing for the same rare pieces.
+#[derive(Debug)]
+pub struct PiecePicker {
+    // Number of connected peers having each piece
+    availability: Vec<u32>,
+    have: Bitfield,
+    wanted: Bitfield,
+    // Assigned to a peer and not verified yet
+    in_progress: Bitfield,
+    remaining: usize,
+    random_first: usize,
+}
+
+impl PiecePicker {
+    pub fn new(pieces_count: usize, random_first: usize) -> Self {
+        Self {
+            availability: vec![0; pieces_count],
+            have: Bitfield::new(pieces_count),
+            wanted: Bitfield::full(pieces_count),
+            in_progress: Bitfield::new(pieces_count),
+            remaining: pieces_count,
+            random_first,
+        }
+    }
+
+    pub fn pieces_count(&self) -> usize {
+        self.availability.len()
+    }
+
+    pub fn have(&self) -> &Bitfield {
+        &self.have
+    }
+
+    pub fn availability(&self, index: usize) -> u32 {
+        self.availability[index]
+    }
+
+    // Pieces we still need to verify
+    pub fn remaining(&self) -> usize {
+        self.remaining
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.remaining == 0
+    }
+
+    // Only download the given pieces
+    pub fn set_wanted(&mut self, wanted: Bitfield) {
+        assert_eq!(wanted.len(), self.pieces_count(), "Invalid wanted length");
+        self.wanted = wanted;
+        self.remaining = (0..self.pieces_count())
+            .filter(|&index| self.is_wanted(index))
+            .count();
+    }
+
+    pub fn is_wanted(&self, index: usize) -> bool {
+        self.wanted.has(index) && !self.have.has(index)
+    }
+
+    // Every wanted piece is either verified or being downloaded
+    pub fn is_all_picked(&self) -> bool {
+        (0..self.pieces_count()).all(|index| !self.is_wanted(index) || self.in_progress.has(index))
+    }
+
+    pub fn is_interesting(&self, peer_pieces: &Bitfield) -> bool {
+        peer_pieces.ones().any(|index| self.is_wanted(index))
+    }
+
+    pub fn add_peer(&mut self, peer_pieces: &Bitfield) {
+        for index in peer_pieces.ones() {
+            self.availability[index] += 1;
+        }
+    }
+
+    pub fn remove_peer(&mut self, peer_pieces: &Bitfield) {
+        for index in peer_pieces.ones() {
+            self.availability[index] -= 1;
+        }
+    }
+
+    pub fn peer_has(&mut self, index: usize) {
+        self.availability[index] += 1;
+    }
+
+    pub fn pick(&mut self, peer_pieces: &Bitfield) -> Option<u32> {
+        let mut candidates = peer_pieces
+            .ones()
+            .filter(|&index| self.is_wanted(index) && !self.in_progress.has(index));
+
+        let index = if self.have.count_ones() < self.random_first {
+            choose_random(candidates)
+        } else {
+            // Break ties at random so peers do not all pick the same piece
+            let mut rarest = vec![candidates.next()?];
+            for index in candidates {
+                let availability = self.availability[index];
+                match availability.cmp(&self.availability[rarest[0]]) {
+                    Ordering::Less => rarest = vec![index],
+                    Ordering::Equal => rarest.push(index),
+                    Ordering::Greater => {}
+                }
+            }
+            choose_random(rarest.into_iter())
+        }?;
+
+        self.in_progress.set(index);
+        Some(index as u32)
+    }
+
+    // Piece given back by a peer before being verified
+    pub fn release(&mut self, index: u32) {
+        self.in_progress.unset(index as usize);
+    }
+
+    pub fn mark_have(&mut self, index: u32) {
+        let index = index as usize;
+        self.in_progress.unset(index);
+        if self.have.has(index) {
+            return;
+        }
+        if self.wanted.has(index) {
+            self.remaining -= 1;
+        }
+        self.have.set(index);
+    }
+}
+
+fn choose_random(candidates: impl Iterator<Item = usize>) -> Option<usize> {
+    // Reservoir sampling: every candidate has the same chance to be picked
+    let mut chosen = None;
+    for (count, index) in candidates.enumerate() {
+        if (random_u64() % (count as u64 + 1)) as usize == count {
+            chosen = Some(index);
+        }
+    }
+    chosen
+}
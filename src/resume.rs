@@ -0,0 +1,105 @@
+use std::{
+    ffi::OsString,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    bencode_format,
+    bitfield::Bitfield,
+    error::TorrentError,
+    storage::{FileStorage, Storage},
+    torrent_file::{Info, MetaInfoFile},
+    utils::hash_sha1,
+};
+
+/// Fast-resume data saved along with a download.
+///
+/// Pieces are only trusted if no file was modified since the data was saved,
+/// otherwise everything has to be hashed again.
+#[derive(Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct ResumeData {
+    #[serde(rename = "info-hash", with = "serde_bytes")]
+    pub info_hash: Vec<u8>,
+    // Bit field of the verified pieces
+    #[serde(with = "serde_bytes")]
+    pub pieces: Vec<u8>,
+    #[serde(rename = "file-mtimes")]
+    pub mtimes: Vec<i64>,
+}
+
+impl ResumeData {
+    pub fn new(meta_info: &MetaInfoFile, have: &Bitfield, mtimes: Vec<i64>) -> Self {
+        Self {
+            info_hash: meta_info.info_hash_bytes().to_vec(),
+            pieces: have.as_bytes().to_vec(),
+            mtimes,
+        }
+    }
+
+    pub fn load(path: &Path) -> Result<Self, TorrentError> {
+        Ok(bencode_format::from_bytes(&fs::read(path)?)?)
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), TorrentError> {
+        // Never leave a truncated file behind if killed while saving
+        let mut tmp_path = path.as_os_str().to_owned();
+        tmp_path.push(".tmp");
+        fs::write(&tmp_path, bencode_format::to_bytes(self)?)?;
+        fs::rename(tmp_path, path)?;
+        Ok(())
+    }
+
+    // Verified pieces, if the data is about this torrent and files are as we left them
+    pub fn validate(&self, meta_info: &MetaInfoFile, mtimes: &[i64]) -> Option<Bitfield> {
+        if self.info_hash != meta_info.info_hash_bytes() || self.mtimes != mtimes {
+            return None;
+        }
+        Bitfield::from_bytes(&self.pieces, meta_info.info.pieces_count()).ok()
+    }
+}
+
+// Saved next to the downloaded file or directory
+pub fn resume_path(output_path: &Path) -> PathBuf {
+    let mut file_name = output_path
+        .file_name()
+        .map(|x| x.to_owned())
+        .unwrap_or_else(|| OsString::from("download"));
+    file_name.push(".resume");
+    output_path.with_file_name(file_name)
+}
+
+// Hash every piece in the storage, the ones missing or unreadable are not verified
+pub fn check_pieces(storage: &dyn Storage, info: &Info) -> Bitfield {
+    let mut have = Bitfield::new(info.pieces_count());
+    for index in 0..info.pieces_count() {
+        let Ok(data) = storage.read_piece(index as u32) else {
+            continue;
+        };
+        if hash_sha1(&data) == info.piece_hash(index as u32) {
+            have.set(index);
+        }
+    }
+    have
+}
+
+// Pieces already downloaded in a previous run
+pub fn load_pieces(
+    meta_info: &MetaInfoFile,
+    storage: &FileStorage,
+    resume_path: &Path,
+) -> Result<Bitfield, TorrentError> {
+    if !storage.has_existing_data() {
+        return Ok(Bitfield::new(meta_info.info.pieces_count()));
+    }
+
+    // Missing or outdated resume data: check the existing files instead
+    if let Ok(resume_data) = ResumeData::load(resume_path) {
+        if let Some(have) = resume_data.validate(meta_info, &storage.mtimes()?) {
+            return Ok(have);
+        }
+    }
+    Ok(check_pieces(storage, &meta_info.info))
+}
@@ -0,0 +1,434 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use tokio::{
+    io::{AsyncWriteExt, BufWriter},
+    net::{tcp::OwnedWriteHalf, TcpListener, TcpStream},
+    sync::{mpsc, watch, Notify},
+    task::{self, JoinSet},
+    time::{interval, sleep, timeout},
+};
+
+use crate::{
+    bitfield::Bitfield,
+    choker::{ChokeCandidate, Choker, ChokerConfig},
+    error::TorrentError,
+    extensions::ExtensionRegistry,
+    peer_id,
+    peers::{MessageReader, Peer, PeerMessage},
+    storage::Storage,
+    torrent_file::MetaInfoFile,
+    trackers::announcer::TransferStats,
+};
+
+// Clients commonly ask for 16 KiB, never more than this
+const MAX_REQUEST_LENGTH: u32 = 128 << 10;
+// Requests queued per peer, advertised in the extension handshake
+const MAX_QUEUED_REQUESTS: usize = 250;
+// Pause after failing to accept a peer, e.g. when out of file descriptors
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+
+#[derive(Debug, Clone)]
+pub struct SeederConfig {
+    pub max_peers: usize,
+    pub handshake_timeout: Duration,
+    // Peers are expected to send a keep-alive at least every 2 minutes
+    pub peer_timeout: Duration,
+    pub choker: ChokerConfig,
+    // Sent in handshakes, inbound connections with the same one are from ourselves
+    pub peer_id: [u8; 20],
+}
+
+impl Default for SeederConfig {
+    fn default() -> Self {
+        Self {
+            max_peers: 50,
+            handshake_timeout: Duration::from_secs(10),
+            peer_timeout: Duration::from_secs(3 * 60),
+            choker: ChokerConfig::default(),
+            peer_id: peer_id(),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct SeedTorrent {
+    pub meta_info: Arc<MetaInfoFile>,
+    pub storage: Arc<dyn Storage>,
+    // Pieces we can upload
+    pub have: Bitfield,
+    pub stats: Arc<TransferStats>,
+}
+
+/// Accept inbound peers and upload the pieces we have.
+#[derive(Debug, Default)]
+pub struct Seeder {
+    torrents: HashMap<[u8; 20], Arc<SeedTorrent>>,
+    config: SeederConfig,
+    errors_tx: Option<mpsc::UnboundedSender<TorrentError>>,
+}
+
+impl Seeder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_config(config: SeederConfig) -> Self {
+        Self {
+            torrents: HashMap::new(),
+            config,
+            errors_tx: None,
+        }
+    }
+
+    // Peers disconnecting on errors and failed accepts, which do not stop the seeder
+    pub fn errors(&mut self) -> mpsc::UnboundedReceiver<TorrentError> {
+        let (errors_tx, errors_rx) = mpsc::unbounded_channel();
+        self.errors_tx = Some(errors_tx);
+        errors_rx
+    }
+
+    pub fn add_torrent(&mut self, torrent: SeedTorrent) {
+        let info_hash = torrent.meta_info.info_hash_bytes();
+        self.torrents.insert(info_hash, Arc::new(torrent));
+    }
+
+    pub async fn run(self, listener: TcpListener) -> Result<(), TorrentError> {
+        let torrents = Arc::new(self.torrents);
+        let swarm = Arc::new(Swarm::default());
+        let mut peers = JoinSet::new();
+        let mut choker = Choker::new(self.config.choker.clone());
+        let mut rechoke = interval(self.config.choker.rechoke_interval);
+        let report = |err| {
+            if let Some(errors_tx) = &self.errors_tx {
+                let _ = errors_tx.send(err);
+            }
+        };
+
+        loop {
+            tokio::select! {
+                accepted = listener.accept() => {
+                    let (stream, addr) = match accepted {
+                        Ok(accepted) => accepted,
+                        Err(err) => {
+                            report(TorrentError::Io(format!("Fail to accept peer: {err}")));
+                            sleep(ACCEPT_BACKOFF).await;
+                            continue;
+                        }
+                    };
+                    if peers.len() >= self.config.max_peers {
+                        continue;
+                    }
+                    let torrents = torrents.clone();
+                    let swarm = swarm.clone();
+                    let config = self.config.clone();
+                    peers.spawn(async move {
+                        let result = serve_peer(stream, addr, torrents, swarm.clone(), config).await;
+                        // Give its upload slot to someone else
+                        swarm.peers.lock().unwrap().remove(&addr);
+                        swarm.rechoke.notify_one();
+                        (addr, result)
+                    });
+                }
+                Some(joined) = peers.join_next() => match joined {
+                    Ok((addr, Err(err))) => {
+                        report(TorrentError::Peer(format!("{addr} disconnected: {err}")));
+                    }
+                    Ok((_, Ok(()))) => {}
+                    Err(err) => report(TorrentError::Peer(format!("Task failed: {err}"))),
+                },
+                _ = rechoke.tick() => swarm.rechoke_peers(&mut choker, true),
+                // Interest changed or a peer left: no need to wait for the next round
+                _ = swarm.rechoke.notified() => swarm.rechoke_peers(&mut choker, false),
+            }
+        }
+    }
+}
+
+// Peers connected to the seeder, for all torrents: upload slots are shared
+#[derive(Debug, Default)]
+struct Swarm {
+    peers: Mutex<HashMap<SocketAddr, Arc<UploadPeer>>>,
+    rechoke: Notify,
+}
+
+impl Swarm {
+    // Rates are only measured on the regular rounds: rounds triggered by events
+    // would make their windows too short to compare peers.
+    fn rechoke_peers(&self, choker: &mut Choker, measure: bool) {
+        let now = Instant::now();
+        let peers = self.peers.lock().unwrap();
+        let candidates: Vec<_> = peers
+            .iter()
+            .map(|(addr, peer)| {
+                if measure {
+                    peer.rate
+                        .store(peer.measure_rate(addr, now), Ordering::Relaxed);
+                }
+                ChokeCandidate {
+                    addr: *addr,
+                    interested: peer.interested.load(Ordering::Relaxed),
+                    rate: peer.rate.load(Ordering::Relaxed),
+                }
+            })
+            .collect();
+
+        let unchoked = choker.rechoke(&candidates, now);
+        for (addr, peer) in peers.iter() {
+            peer.unchoke.send_if_modified(|value| {
+                let changed = *value != unchoked.contains(addr);
+                *value = unchoked.contains(addr);
+                changed
+            });
+        }
+    }
+}
+
+#[derive(Debug)]
+struct UploadPeer {
+    seeding: bool,
+    stats: Arc<TransferStats>,
+    interested: AtomicBool,
+    // Uploaded since the last measure
+    uploaded: AtomicU64,
+    // Downloaded from the peer IP as of the last measure
+    downloaded: AtomicU64,
+    rate_start: Mutex<Instant>,
+    // Bytes per second over the last measured round
+    rate: AtomicU64,
+    // Choker decision, applied by the peer task
+    unchoke: watch::Sender<bool>,
+}
+
+impl UploadPeer {
+    // Rate since the previous measure, starting a new one
+    fn measure_rate(&self, addr: &SocketAddr, now: Instant) -> u64 {
+        let rate_start = std::mem::replace(&mut *self.rate_start.lock().unwrap(), now);
+        let elapsed = now.saturating_duration_since(rate_start);
+        let uploaded = self.uploaded.swap(0, Ordering::Relaxed);
+        // Tit-for-tat while downloading: peers giving us the most get our uploads
+        let transferred = match self.seeding {
+            true => uploaded,
+            false => {
+                let downloaded = self.stats.downloaded_from(addr.ip());
+                downloaded.saturating_sub(self.downloaded.swap(downloaded, Ordering::Relaxed))
+            }
+        };
+        (transferred as f64 / elapsed.as_secs_f64().max(1.0)) as u64
+    }
+}
+
+async fn serve_peer(
+    stream: TcpStream,
+    addr: SocketAddr,
+    torrents: Arc<HashMap<[u8; 20], Arc<SeedTorrent>>>,
+    swarm: Arc<Swarm>,
+    config: SeederConfig,
+) -> Result<(), TorrentError> {
+    let info_hashes: Vec<_> = torrents.keys().copied().collect();
+    let (peer, info_hash) = timeout(
+        config.handshake_timeout,
+        Peer::accept(stream, &info_hashes, &config.peer_id),
+    )
+    .await
+    .map_err(|_| TorrentError::Peer("Handshake timed out".to_string()))??;
+
+    let supports_extensions = peer.supports_extensions();
+    let torrent = torrents[&info_hash].clone();
+    let (unchoke_tx, unchoke) = watch::channel(false);
+    let upload_peer = Arc::new(UploadPeer {
+        seeding: torrent.have.is_full(),
+        stats: torrent.stats.clone(),
+        interested: AtomicBool::new(false),
+        uploaded: AtomicU64::new(0),
+        downloaded: AtomicU64::new(0),
+        rate_start: Mutex::new(Instant::now()),
+        rate: AtomicU64::new(0),
+        unchoke: unchoke_tx,
+    });
+    swarm
+        .peers
+        .lock()
+        .unwrap()
+        .insert(addr, upload_peer.clone());
+
+    let (reader, writer) = peer.into_split();
+    let mut uploader = Uploader {
+        addr,
+        torrent,
+        swarm,
+        peer: upload_peer,
+        writer: BufWriter::new(writer),
+        messages: MessageReader::spawn(reader),
+        unchoke,
+        choked: true,
+        queue: VecDeque::new(),
+    };
+    uploader.run(&config, supports_extensions).await
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct BlockRequest {
+    index: u32,
+    begin: u32,
+    length: u32,
+}
+
+#[derive(Debug)]
+struct Uploader {
+    addr: SocketAddr,
+    torrent: Arc<SeedTorrent>,
+    swarm: Arc<Swarm>,
+    peer: Arc<UploadPeer>,
+    writer: BufWriter<OwnedWriteHalf>,
+    messages: MessageReader,
+    unchoke: watch::Receiver<bool>,
+    // Whether we choke the peer
+    choked: bool,
+    // Requests not answered yet, in order
+    queue: VecDeque<BlockRequest>,
+}
+
+impl Uploader {
+    async fn run(
+        &mut self,
+        config: &SeederConfig,
+        supports_extensions: bool,
+    ) -> Result<(), TorrentError> {
+        if supports_extensions {
+            let mut extensions = ExtensionRegistry::new();
+            extensions.set_reqq(MAX_QUEUED_REQUESTS);
+            let handshake = extensions.handshake(self.addr.ip());
+            self.send(&handshake.to_message()?).await?;
+        }
+        let bitfield = PeerMessage::BitField(self.torrent.have.as_bytes().to_vec());
+        self.send(&bitfield).await?;
+
+        loop {
+            self.apply_choke().await?;
+
+            if self.queue.is_empty() {
+                tokio::select! {
+                    msg = timeout(config.peer_timeout, self.messages.recv()) => {
+                        let msg = msg.map_err(|_| TorrentError::Peer("Peer timed out".to_string()))??;
+                        self.handle_message(msg).await?;
+                    }
+                    // The sender lives as long as the peer
+                    _ = self.unchoke.changed() => {}
+                }
+                continue;
+            }
+
+            // Read everything already received first, cancels in particular
+            match self.messages.try_recv()? {
+                Some(msg) => self.handle_message(msg).await?,
+                None => self.upload_block().await?,
+            }
+        }
+    }
+
+    async fn apply_choke(&mut self) -> Result<(), TorrentError> {
+        let unchoke = *self.unchoke.borrow_and_update();
+        if unchoke != self.choked {
+            return Ok(());
+        }
+
+        self.choked = !unchoke;
+        if self.choked {
+            // Choked peers know their pending requests are dropped
+            self.queue.clear();
+            self.send(&PeerMessage::Choke).await
+        } else {
+            self.send(&PeerMessage::Unchoke).await
+        }
+    }
+
+    fn set_interested(&self, interested: bool) {
+        if self.peer.interested.swap(interested, Ordering::Relaxed) != interested {
+            self.swarm.rechoke.notify_one();
+        }
+    }
+
+    async fn handle_message(&mut self, msg: PeerMessage) -> Result<(), TorrentError> {
+        match msg {
+            PeerMessage::Interested => self.set_interested(true),
+            PeerMessage::NotInterested => self.set_interested(false),
+            PeerMessage::Request {
+                index,
+                begin,
+                length,
+            } => {
+                let info = &self.torrent.meta_info.info;
+                if !self.torrent.have.has(index as usize)
+                    || length == 0
+                    || length > MAX_REQUEST_LENGTH
+                    || begin as u64 + length as u64 > info.piece_size(index) as u64
+                {
+                    return Err(TorrentError::Peer(format!(
+                        "Invalid request for piece {index} at {begin}: {length} bytes"
+                    )));
+                }
+                // Requests sent before being choked are dropped, and so are
+                // the ones over the limit we advertised
+                if !self.choked && self.queue.len() < MAX_QUEUED_REQUESTS {
+                    self.queue.push_back(BlockRequest {
+                        index,
+                        begin,
+                        length,
+                    });
+                }
+            }
+            PeerMessage::Cancel {
+                index,
+                begin,
+                length,
+            } => {
+                let request = BlockRequest {
+                    index,
+                    begin,
+                    length,
+                };
+                self.queue.retain(|x| *x != request);
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    async fn upload_block(&mut self) -> Result<(), TorrentError> {
+        let Some(request) = self.queue.pop_front() else {
+            return Ok(());
+        };
+        // Disk reads would hold up the other peers on this thread
+        let storage = self.torrent.storage.clone();
+        let block = task::spawn_blocking(move || {
+            storage.read_block(request.index, request.begin, request.length)
+        })
+        .await
+        .map_err(|err| TorrentError::Io(format!("Block read failed: {err}")))??;
+        self.send(&PeerMessage::Piece {
+            index: request.index,
+            begin: request.begin,
+            block,
+        })
+        .await?;
+        self.torrent.stats.add_uploaded(request.length as u64);
+        self.peer
+            .uploaded
+            .fetch_add(request.length as u64, Ordering::Relaxed);
+        Ok(())
+    }
+
+    async fn send(&mut self, msg: &PeerMessage) -> Result<(), TorrentError> {
+        msg.write(&mut self.writer).await?;
+        self.writer.flush().await?;
+        Ok(())
+    }
+}
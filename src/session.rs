@@ -0,0 +1,687 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet, VecDeque},
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use tokio::{
+    sync::{mpsc, Notify},
+    task::JoinSet,
+};
+
+use crate::{
+    bitfield::Bitfield, error::TorrentError, pex::PEX_INTERVAL, picker::PiecePicker,
+    storage::Storage, torrent_file::MetaInfoFile, trackers::announcer::TransferStats,
+    utils::hash_sha1,
+};
+
+pub mod pipeline;
+mod worker;
+
+use pipeline::PipelineConfig;
+use worker::run_peer;
+
+pub const BLOCK_SIZE: u32 = 16 << 10;
+
+// Peers waiting for a connection slot, the extra ones being forgotten
+const MAX_CANDIDATES: usize = 1000;
+
+#[derive(Debug, Clone)]
+pub struct SessionConfig {
+    pub max_peers: usize,
+    // Ask for more peers when connected to fewer
+    pub min_peers: usize,
+    pub connect_timeout: Duration,
+    // Peers are expected to send a keep-alive at least every 2 minutes
+    pub peer_timeout: Duration,
+    // Sent to peers after that long without sending them anything
+    pub keep_alive_interval: Duration,
+    pub pipeline: PipelineConfig,
+    // Pieces picked at random before switching to rarest first
+    pub random_first_pieces: usize,
+    // Ban peers involved in that many pieces failing hash check
+    pub max_hash_failures: u32,
+    // Time between two ut_pex messages to a peer
+    pub pex_interval: Duration,
+}
+
+impl Default for SessionConfig {
+    fn default() -> Self {
+        Self {
+            max_peers: 50,
+            min_peers: 10,
+            connect_timeout: Duration::from_secs(10),
+            peer_timeout: Duration::from_secs(3 * 60),
+            keep_alive_interval: Duration::from_secs(90),
+            pipeline: PipelineConfig::default(),
+            random_first_pieces: 4,
+            max_hash_failures: 3,
+            pex_interval: PEX_INTERVAL,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct DownloadSession {
+    shared: Arc<Shared>,
+    config: SessionConfig,
+    peers_tx: mpsc::UnboundedSender<Vec<SocketAddr>>,
+    peers_rx: mpsc::UnboundedReceiver<Vec<SocketAddr>>,
+    // Peers learned from other peers
+    learned_rx: mpsc::UnboundedReceiver<Vec<SocketAddr>>,
+}
+
+impl DownloadSession {
+    pub fn new(
+        meta_info: Arc<MetaInfoFile>,
+        stats: Arc<TransferStats>,
+        storage: Arc<dyn Storage>,
+    ) -> Self {
+        Self::with_config(meta_info, stats, storage, SessionConfig::default())
+    }
+
+    pub fn with_config(
+        meta_info: Arc<MetaInfoFile>,
+        stats: Arc<TransferStats>,
+        storage: Arc<dyn Storage>,
+        config: SessionConfig,
+    ) -> Self {
+        let total_length = meta_info.info.total_length();
+        let state = SessionState {
+            picker: PiecePicker::new(meta_info.info.pieces_count(), config.random_first_pieces),
+            downloads: BTreeMap::new(),
+            left: total_length,
+            error: None,
+            hash_failures: HashMap::new(),
+            banned: HashSet::new(),
+            single_source: HashSet::new(),
+            failed_senders: HashMap::new(),
+            connected: HashMap::new(),
+        };
+        let (peers_tx, peers_rx) = mpsc::unbounded_channel();
+        let (learned_tx, learned_rx) = mpsc::unbounded_channel();
+
+        Self {
+            shared: Arc::new(Shared {
+                meta_info,
+                stats,
+                storage,
+                max_hash_failures: config.max_hash_failures,
+                state: Mutex::new(state),
+                changed: Notify::new(),
+                done: Notify::new(),
+                peers_wanted: Notify::new(),
+                learned_tx,
+                errors_tx: Mutex::new(None),
+            }),
+            config,
+            peers_tx,
+            peers_rx,
+            learned_rx,
+        }
+    }
+
+    // Pieces already verified, from a previous run
+    pub fn set_have(&self, have: &Bitfield) {
+        let info = &self.shared.meta_info.info;
+        let mut state = self.shared.state.lock().unwrap();
+        for index in have.ones() {
+            if !state.picker.have().has(index) {
+                state.picker.mark_have(index as u32);
+                state.left -= info.piece_size(index as u32) as u64;
+            }
+        }
+        self.shared.stats.set_left(state.left);
+    }
+
+    // Follow the download after it is started
+    pub fn progress(&self) -> SessionProgress {
+        SessionProgress {
+            shared: self.shared.clone(),
+        }
+    }
+
+    // Trackers keep sending peers while we are downloading
+    pub fn peers_sender(&self) -> mpsc::UnboundedSender<Vec<SocketAddr>> {
+        self.peers_tx.clone()
+    }
+
+    pub fn add_peers(&self, peer_addrs: Vec<SocketAddr>) {
+        let _ = self.peers_tx.send(peer_addrs);
+    }
+
+    // Peers disconnecting on errors, which the session recovers from
+    pub fn errors(&self) -> mpsc::UnboundedReceiver<TorrentError> {
+        let (errors_tx, errors_rx) = mpsc::unbounded_channel();
+        *self.shared.errors_tx.lock().unwrap() = Some(errors_tx);
+        errors_rx
+    }
+
+    // Pieces are written to the storage as soon as they are verified
+    pub async fn download(self) -> Result<(), TorrentError> {
+        let Self {
+            shared,
+            config,
+            peers_tx,
+            mut peers_rx,
+            mut learned_rx,
+        } = self;
+        // Only external senders may keep the session waiting for new peers
+        drop(peers_tx);
+
+        let mut workers = JoinSet::new();
+        let mut active = HashSet::new();
+        let mut candidates = Candidates::default();
+        let mut peers_open = true;
+
+        while !shared.is_complete() {
+            if let Some(err) = shared.state.lock().unwrap().error.take() {
+                return Err(err);
+            }
+            if workers.is_empty() && !peers_open {
+                return Err(TorrentError::Peer(
+                    "No peer left to download from".to_string(),
+                ));
+            }
+
+            let mut peer_left = false;
+            tokio::select! {
+                _ = shared.done.notified() => continue,
+                peer_addrs = peers_rx.recv(), if peers_open => match peer_addrs {
+                    Some(peer_addrs) => candidates.extend(peer_addrs, &active),
+                    None => peers_open = false,
+                },
+                // Sent by our own workers: never closed
+                Some(peer_addrs) = learned_rx.recv() => candidates.extend(peer_addrs, &active),
+                Some(joined) = workers.join_next() => {
+                    match joined {
+                        Ok((addr, result)) => {
+                            active.remove(&addr);
+                            if let Err(err) = result {
+                                shared.report(TorrentError::Peer(format!("{addr} disconnected: {err}")));
+                            }
+                        }
+                        Err(err) => shared.report(TorrentError::Peer(format!("Task failed: {err}"))),
+                    }
+                    peer_left = true;
+                }
+            }
+
+            // Fill free slots with the peers waiting for one
+            while active.len() < config.max_peers {
+                let Some(addr) = candidates.pop() else {
+                    break;
+                };
+                if shared.is_banned(addr) || !active.insert(addr) {
+                    continue;
+                }
+                let shared = shared.clone();
+                let config = config.clone();
+                workers.spawn(async move { (addr, run_peer(addr, shared, config).await) });
+            }
+            if peer_left && active.len() < config.min_peers && candidates.is_empty() {
+                shared.peers_wanted.notify_one();
+            }
+        }
+
+        // Remaining peers have nothing left to give us
+        workers.shutdown().await;
+        Ok(())
+    }
+
+    // Only download the given piece and return its contents
+    pub async fn download_piece(self, index: u32) -> Result<Vec<u8>, TorrentError> {
+        let info = &self.shared.meta_info.info;
+        if index as usize >= info.pieces_count() {
+            return Err(TorrentError::Peer(format!("Invalid piece ID: {index}")));
+        }
+        let mut wanted = Bitfield::new(info.pieces_count());
+        wanted.set(index as usize);
+        self.shared.state.lock().unwrap().picker.set_wanted(wanted);
+
+        let storage = self.shared.storage.clone();
+        self.download().await?;
+        storage.read_piece(index)
+    }
+}
+
+// Peers to connect to once a slot is free, in the order we learned them
+#[derive(Debug, Default)]
+struct Candidates {
+    queue: VecDeque<SocketAddr>,
+    queued: HashSet<SocketAddr>,
+}
+
+impl Candidates {
+    fn extend(&mut self, peer_addrs: Vec<SocketAddr>, active: &HashSet<SocketAddr>) {
+        for addr in peer_addrs {
+            if self.queue.len() >= MAX_CANDIDATES {
+                break;
+            }
+            if !active.contains(&addr) && self.queued.insert(addr) {
+                self.queue.push_back(addr);
+            }
+        }
+    }
+
+    fn pop(&mut self) -> Option<SocketAddr> {
+        let addr = self.queue.pop_front()?;
+        self.queued.remove(&addr);
+        Some(addr)
+    }
+
+    fn is_empty(&self) -> bool {
+        self.queue.is_empty()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SessionProgress {
+    shared: Arc<Shared>,
+}
+
+impl SessionProgress {
+    // Pieces verified and stored so far
+    pub fn have(&self) -> Bitfield {
+        self.shared.state.lock().unwrap().picker.have().clone()
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.shared.is_complete()
+    }
+
+    // Returns when the session runs low on peers
+    pub async fn peers_wanted(&self) {
+        self.shared.peers_wanted.notified().await
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+struct BlockRequest {
+    index: u32,
+    begin: u32,
+    length: u32,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum BlockState {
+    Free,
+    // More than one peer only in endgame mode
+    Requested(Vec<SocketAddr>),
+    Received,
+}
+
+#[derive(Debug)]
+struct PieceDownload {
+    // Peer the piece was given to, others only help in endgame mode
+    owner: Option<SocketAddr>,
+    data: Vec<u8>,
+    blocks: Vec<BlockState>,
+    received: usize,
+    // Peers that sent blocks, to blame if the piece is corrupt
+    senders: Vec<SocketAddr>,
+}
+
+impl PieceDownload {
+    fn new(owner: SocketAddr, piece_size: u32) -> Self {
+        let blocks_count = (0..piece_size).step_by(BLOCK_SIZE as usize).count();
+        Self {
+            owner: Some(owner),
+            data: vec![0; piece_size as usize],
+            blocks: vec![BlockState::Free; blocks_count],
+            received: 0,
+            senders: Vec::new(),
+        }
+    }
+
+    fn request(&mut self, index: u32, block: usize, peer: SocketAddr) -> BlockRequest {
+        match &mut self.blocks[block] {
+            BlockState::Requested(peers) => peers.push(peer),
+            state => *state = BlockState::Requested(vec![peer]),
+        }
+
+        let begin = block as u32 * BLOCK_SIZE;
+        BlockRequest {
+            index,
+            begin,
+            length: BLOCK_SIZE.min(self.data.len() as u32 - begin),
+        }
+    }
+
+    fn free_block(&self) -> Option<usize> {
+        self.blocks.iter().position(|x| *x == BlockState::Free)
+    }
+}
+
+#[derive(Debug)]
+enum BlockOutcome {
+    Stored,
+    Duplicate,
+    // Every block was received, the piece still needs to be checked
+    PieceDone {
+        data: Vec<u8>,
+        senders: Vec<SocketAddr>,
+    },
+}
+
+#[derive(Debug)]
+struct SessionState {
+    picker: PiecePicker,
+    downloads: BTreeMap<u32, PieceDownload>,
+    left: u64,
+    // Storage failed: no point going on with the download
+    error: Option<TorrentError>,
+    hash_failures: HashMap<SocketAddr, u32>,
+    banned: HashSet<SocketAddr>,
+    // Pieces that failed with blocks from several peers: downloaded again
+    // from a single one, to know who to blame
+    single_source: HashSet<u32>,
+    // Peers that sent pieces failing hash check, left to others when they can
+    failed_senders: HashMap<u32, HashSet<SocketAddr>>,
+    // Peers we are connected to, with their PEX flags
+    connected: HashMap<SocketAddr, u8>,
+}
+
+impl SessionState {
+    // Pieces the peer failed to send, which other connected peers have
+    fn avoided_pieces(&self, peer: SocketAddr) -> HashSet<u32> {
+        self.failed_senders
+            .iter()
+            .filter(|(_, failed)| failed.contains(&peer))
+            .filter(|(&index, failed)| {
+                let still_connected = failed.iter().filter(|x| self.connected.contains_key(x));
+                self.picker.availability(index as usize) as usize > still_connected.count()
+            })
+            .map(|(&index, _)| index)
+            .collect()
+    }
+}
+
+#[derive(Debug)]
+struct Shared {
+    meta_info: Arc<MetaInfoFile>,
+    stats: Arc<TransferStats>,
+    storage: Arc<dyn Storage>,
+    max_hash_failures: u32,
+    state: Mutex<SessionState>,
+    // Wakes up peers when pieces are released or blocks received
+    changed: Notify,
+    done: Notify,
+    peers_wanted: Notify,
+    learned_tx: mpsc::UnboundedSender<Vec<SocketAddr>>,
+    errors_tx: Mutex<Option<mpsc::UnboundedSender<TorrentError>>>,
+}
+
+impl Shared {
+    fn report(&self, err: TorrentError) {
+        if let Some(errors_tx) = &*self.errors_tx.lock().unwrap() {
+            let _ = errors_tx.send(err);
+        }
+    }
+
+    fn next_request(&self, peer: SocketAddr, peer_pieces: &Bitfield) -> Option<BlockRequest> {
+        let mut state = self.state.lock().unwrap();
+        let state = &mut *state;
+        let avoided = state.avoided_pieces(peer);
+        let peer_has = |index: &u32| peer_pieces.has(*index as usize) && !avoided.contains(index);
+
+        // Finish our own pieces first, then the ones given back by other peers
+        for owner in [Some(peer), None] {
+            for (&index, download) in state.downloads.iter_mut().filter(|x| peer_has(x.0)) {
+                if download.owner != owner {
+                    continue;
+                }
+                if let Some(block) = download.free_block() {
+                    download.owner = Some(peer);
+                    return Some(download.request(index, block, peer));
+                }
+            }
+        }
+
+        let mut pickable = None;
+        if !avoided.is_empty() {
+            let pieces = pickable.insert(peer_pieces.clone());
+            for &index in &avoided {
+                pieces.unset(index as usize);
+            }
+        }
+        if let Some(index) = state.picker.pick(pickable.as_ref().unwrap_or(peer_pieces)) {
+            let piece_size = self.meta_info.info.piece_size(index);
+            let download = state
+                .downloads
+                .entry(index)
+                .or_insert_with(|| PieceDownload::new(peer, piece_size));
+            return Some(download.request(index, 0, peer));
+        }
+
+        // Endgame: every piece is picked, ask this peer for the blocks it has,
+        // free ones first then the least requested ones.
+        if !state.picker.is_all_picked() {
+            return None;
+        }
+        let single_source = &state.single_source;
+        let (index, block, _) = state
+            .downloads
+            .iter()
+            .filter(|x| peer_has(x.0))
+            .filter(|(index, download)| {
+                download.owner == Some(peer) || !single_source.contains(index)
+            })
+            .flat_map(|(&index, download)| {
+                download
+                    .blocks
+                    .iter()
+                    .enumerate()
+                    .filter_map(move |(block, state)| match state {
+                        BlockState::Free => Some((index, block, 0)),
+                        BlockState::Requested(peers) if !peers.contains(&peer) => {
+                            Some((index, block, peers.len()))
+                        }
+                        _ => None,
+                    })
+            })
+            .min_by_key(|x| x.2)?;
+        let download = state.downloads.get_mut(&index).unwrap();
+        Some(download.request(index, block, peer))
+    }
+
+    // Requests the peer will not answer anymore (choked, disconnected...)
+    fn release_requests(&self, peer: SocketAddr, requests: impl Iterator<Item = BlockRequest>) {
+        let mut guard = self.state.lock().unwrap();
+        let state = &mut *guard;
+        for request in requests {
+            let Some(download) = state.downloads.get_mut(&request.index) else {
+                continue;
+            };
+            let block = &mut download.blocks[(request.begin / BLOCK_SIZE) as usize];
+            if let BlockState::Requested(peers) = block {
+                peers.retain(|x| *x != peer);
+                if peers.is_empty() {
+                    *block = BlockState::Free;
+                }
+            }
+        }
+
+        // Pieces nobody works on anymore go back to the picker. Single source
+        // ones start over, their blocks coming from this peer.
+        for (index, download) in &mut state.downloads {
+            if download.owner == Some(peer) {
+                download.owner = None;
+                if state.single_source.contains(index) {
+                    download.received = 0;
+                    download.senders.clear();
+                    download.blocks.fill(BlockState::Free);
+                }
+            }
+        }
+        let unused: Vec<_> = state
+            .downloads
+            .iter()
+            .filter(|(_, x)| x.received == 0 && x.blocks.iter().all(|x| *x == BlockState::Free))
+            .map(|(&index, _)| index)
+            .collect();
+        for index in unused {
+            state.downloads.remove(&index);
+            state.picker.release(index);
+        }
+        drop(guard);
+        self.changed.notify_waiters();
+    }
+
+    // Requests already answered by another peer
+    fn stale_requests(&self, requests: &HashSet<BlockRequest>) -> Vec<BlockRequest> {
+        let state = self.state.lock().unwrap();
+        requests
+            .iter()
+            .filter(|request| match state.downloads.get(&request.index) {
+                Some(download) => {
+                    download.blocks[(request.begin / BLOCK_SIZE) as usize] == BlockState::Received
+                }
+                None => true,
+            })
+            .copied()
+            .collect()
+    }
+
+    fn receive_block(
+        &self,
+        peer: SocketAddr,
+        index: u32,
+        begin: u32,
+        block: &[u8],
+    ) -> Result<BlockOutcome, TorrentError> {
+        let mut guard = self.state.lock().unwrap();
+        let state = &mut *guard;
+        let single_source = state.single_source.contains(&index);
+        let Some(download) = state
+            .downloads
+            .get_mut(&index)
+            .filter(|x| !single_source || x.owner == Some(peer))
+        else {
+            self.stats.add_duplicated(block.len() as u64);
+            return Ok(BlockOutcome::Duplicate);
+        };
+
+        let block_id = (begin / BLOCK_SIZE) as usize;
+        let begin = begin as usize;
+        if block_id as u32 * BLOCK_SIZE != begin as u32
+            || block_id >= download.blocks.len()
+            || block.len() != BLOCK_SIZE.min((download.data.len() - begin) as u32) as usize
+        {
+            return Err(TorrentError::Peer(format!(
+                "Invalid block for piece {index} at {begin}: {} bytes",
+                block.len()
+            )));
+        }
+
+        let shared_request = match &download.blocks[block_id] {
+            BlockState::Received => {
+                self.stats.add_duplicated(block.len() as u64);
+                return Ok(BlockOutcome::Duplicate);
+            }
+            BlockState::Requested(peers) => peers.len() > 1,
+            BlockState::Free => false,
+        };
+        download.data[begin..begin + block.len()].copy_from_slice(block);
+        download.blocks[block_id] = BlockState::Received;
+        self.stats
+            .add_downloaded_from(peer.ip(), block.len() as u64);
+        download.received += 1;
+        if !download.senders.contains(&peer) {
+            download.senders.push(peer);
+        }
+
+        let outcome = if download.received == download.blocks.len() {
+            let download = state.downloads.remove(&index).unwrap();
+            BlockOutcome::PieceDone {
+                data: download.data,
+                senders: download.senders,
+            }
+        } else {
+            BlockOutcome::Stored
+        };
+        drop(guard);
+
+        // Let the other peers cancel their request
+        if shared_request {
+            self.changed.notify_waiters();
+        }
+        Ok(outcome)
+    }
+
+    fn finish_piece(
+        &self,
+        index: u32,
+        data: &[u8],
+        senders: &[SocketAddr],
+    ) -> Result<(), TorrentError> {
+        if hash_sha1(data) != self.meta_info.info.piece_hash(index) {
+            // Download the piece again, from anybody not banned yet. Only a
+            // sole sender is to blame: otherwise the next try tells us which one.
+            let mut state = self.state.lock().unwrap();
+            state.picker.release(index);
+            let failed = state.failed_senders.entry(index).or_default();
+            failed.extend(senders.iter().copied());
+            if let [peer] = senders {
+                state.single_source.remove(&index);
+                let failures = state.hash_failures.entry(*peer).or_default();
+                *failures += 1;
+                if *failures >= self.max_hash_failures {
+                    state.banned.insert(*peer);
+                }
+            } else {
+                state.single_source.insert(index);
+            }
+            drop(state);
+            self.changed.notify_waiters();
+            return Err(TorrentError::PieceHashMismatch(index));
+        }
+
+        if let Err(err) = self.storage.write_piece(index, data) {
+            let mut state = self.state.lock().unwrap();
+            state.picker.release(index);
+            state.error = Some(err);
+            self.done.notify_one();
+            return Err(TorrentError::Io(format!("Fail to store piece {index}")));
+        }
+
+        let mut state = self.state.lock().unwrap();
+        state.picker.mark_have(index);
+        state.single_source.remove(&index);
+        state.failed_senders.remove(&index);
+        state.left -= data.len() as u64;
+
+        // Report progress to trackers
+        self.stats.add_downloaded(data.len() as u64);
+        self.stats.set_left(state.left);
+
+        if state.picker.is_complete() {
+            self.done.notify_one();
+        }
+        drop(state);
+        self.changed.notify_waiters();
+        Ok(())
+    }
+
+    fn is_complete(&self) -> bool {
+        self.state.lock().unwrap().picker.is_complete()
+    }
+
+    // Connection candidates sent to us by other peers
+    fn add_learned_peers(&self, peer_addrs: Vec<SocketAddr>) {
+        let _ = self.learned_tx.send(peer_addrs);
+    }
+
+    fn is_banned(&self, peer: SocketAddr) -> bool {
+        self.state.lock().unwrap().banned.contains(&peer)
+    }
+
+    fn is_interesting(&self, peer_pieces: &Bitfield) -> bool {
+        self.state
+            .lock()
+            .unwrap()
+            .picker
+            .is_interesting(peer_pieces)
+    }
+}
@@ -0,0 +1,89 @@
+use std::time::{Duration, Instant};
+
+use super::BLOCK_SIZE;
+
+// How often the download rate is measured to resize the window
+const RATE_PERIOD: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone)]
+pub struct PipelineConfig {
+    pub initial_window: usize,
+    pub min_window: usize,
+    pub max_window: usize,
+    // Keep enough requests queued at the peer to cover that long at the measured rate
+    pub queue_time: Duration,
+    // Give up on a peer not answering our requests for that long
+    pub request_timeout: Duration,
+}
+
+impl Default for PipelineConfig {
+    fn default() -> Self {
+        Self {
+            initial_window: 16,
+            min_window: 2,
+            max_window: 250,
+            queue_time: Duration::from_secs(2),
+            request_timeout: Duration::from_secs(60),
+        }
+    }
+}
+
+/// Number of block requests kept in flight with a peer.
+///
+/// Sized from the measured download rate, like libtorrent does: fast peers get
+/// a deep queue, slow ones do not hoard blocks other peers could download.
+#[derive(Debug, Clone)]
+pub struct RequestWindow {
+    size: usize,
+    min: usize,
+    max: usize,
+    queue_time: Duration,
+    period_start: Instant,
+    period_bytes: u64,
+}
+
+impl RequestWindow {
+    pub fn new(config: &PipelineConfig) -> Self {
+        Self::with_start(config, Instant::now())
+    }
+
+    pub fn with_start(config: &PipelineConfig, now: Instant) -> Self {
+        Self {
+            size: config
+                .initial_window
+                .clamp(config.min_window, config.max_window),
+            min: config.min_window,
+            max: config.max_window,
+            queue_time: config.queue_time,
+            period_start: now,
+            period_bytes: 0,
+        }
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    // Never more requests than the peer queues
+    pub fn limit(&mut self, max: usize) {
+        self.max = self.max.min(max).max(1);
+        self.min = self.min.min(self.max);
+        self.size = self.size.min(self.max);
+    }
+
+    pub fn on_block(&mut self, length: usize, now: Instant) {
+        self.period_bytes += length as u64;
+
+        let elapsed = now.saturating_duration_since(self.period_start);
+        if elapsed < RATE_PERIOD {
+            return;
+        }
+
+        let rate = self.period_bytes as f64 / elapsed.as_secs_f64();
+        let target = (rate * self.queue_time.as_secs_f64() / BLOCK_SIZE as f64).ceil();
+        self.size = (target as usize).clamp(self.min, self.max);
+
+        self.period_start = now;
+        self.period_bytes = 0;
+    }
+}
@@ -0,0 +1,377 @@
+use std::{collections::HashSet, net::SocketAddr, sync::Arc, time::Instant};
+
+use tokio::{
+    io::{AsyncWriteExt, BufWriter},
+    net::tcp::OwnedWriteHalf,
+    time::{sleep_until, timeout},
+};
+
+use super::{pipeline::RequestWindow, BlockOutcome, BlockRequest, SessionConfig, Shared};
+use crate::{
+    bitfield::Bitfield,
+    error::TorrentError,
+    extensions::{ExtensionHandshake, ExtensionRegistry, HANDSHAKE_ID},
+    peers::{MessageReader, Peer, PeerMessage},
+    pex::{PexMessage, PexState, FLAG_REACHABLE, FLAG_SEED, MAX_PEX_PEERS, UT_PEX},
+};
+
+pub(super) async fn run_peer(
+    addr: SocketAddr,
+    shared: Arc<Shared>,
+    config: SessionConfig,
+) -> Result<(), TorrentError> {
+    let peer = timeout(
+        config.connect_timeout,
+        Peer::connect(&addr, &shared.meta_info),
+    )
+    .await
+    .map_err(|_| TorrentError::Peer("Connection timed out".to_string()))??;
+
+    // We connected to it: others can too
+    shared
+        .state
+        .lock()
+        .unwrap()
+        .connected
+        .insert(addr, FLAG_REACHABLE);
+
+    let mut worker = PeerWorker::new(addr, peer, shared, &config);
+    let result = worker.run(&config).await;
+
+    // Let other peers download what we did not finish
+    let requests = std::mem::take(&mut worker.requests);
+    worker.shared.release_requests(addr, requests.into_iter());
+    let mut state = worker.shared.state.lock().unwrap();
+    state.picker.remove_peer(&worker.peer_pieces);
+    state.connected.remove(&addr);
+    drop(state);
+    result
+}
+
+#[derive(Debug)]
+struct PeerWorker {
+    addr: SocketAddr,
+    shared: Arc<Shared>,
+    writer: BufWriter<OwnedWriteHalf>,
+    messages: MessageReader,
+    choked: bool,
+    interested: bool,
+    // Peer pieces or ours changed since interest was last sent
+    check_interest: bool,
+    // Pieces advertised by the peer
+    peer_pieces: Bitfield,
+    window: RequestWindow,
+    // Blocks requested from this peer and not received yet
+    requests: HashSet<BlockRequest>,
+    last_block: Instant,
+    last_received: Instant,
+    last_sent: Instant,
+    supports_extensions: bool,
+    extensions: ExtensionRegistry,
+    // Extension handshake received from the peer
+    peer_extensions: Option<ExtensionHandshake>,
+    pex: PexState,
+}
+
+impl PeerWorker {
+    fn new(addr: SocketAddr, peer: Peer, shared: Arc<Shared>, config: &SessionConfig) -> Self {
+        let supports_extensions = peer.supports_extensions();
+        let (reader, writer) = peer.into_split();
+
+        let mut extensions = ExtensionRegistry::new();
+        if !shared.meta_info.is_private() {
+            extensions.register(UT_PEX);
+        }
+
+        let pieces_count = shared.meta_info.info.pieces_count();
+        Self {
+            addr,
+            shared,
+            writer: BufWriter::new(writer),
+            messages: MessageReader::spawn(reader),
+            choked: true,
+            interested: false,
+            check_interest: false,
+            peer_pieces: Bitfield::new(pieces_count),
+            window: RequestWindow::new(&config.pipeline),
+            requests: HashSet::new(),
+            last_block: Instant::now(),
+            last_received: Instant::now(),
+            last_sent: Instant::now(),
+            supports_extensions,
+            extensions,
+            peer_extensions: None,
+            pex: PexState::new(config.pex_interval),
+        }
+    }
+
+    async fn run(&mut self, config: &SessionConfig) -> Result<(), TorrentError> {
+        if self.supports_extensions {
+            let handshake = self.extensions.handshake(self.addr.ip());
+            self.send(&[handshake.to_message()?]).await?;
+        }
+
+        loop {
+            // Register before looking for work so no wake up is missed
+            let shared = self.shared.clone();
+            let changed = shared.changed.notified();
+            tokio::pin!(changed);
+            changed.as_mut().enable();
+
+            self.cancel_stale_requests().await?;
+            if shared.is_complete() {
+                return Ok(());
+            }
+            if shared.is_banned(self.addr) {
+                return Err(TorrentError::Peer(
+                    "Banned for sending bad data".to_string(),
+                ));
+            }
+            if !self.choked {
+                self.fill_pipeline().await?;
+            }
+            if self.check_interest {
+                self.update_interest().await?;
+            }
+
+            let in_flight = self.requests.len();
+            let idle = !self.choked && in_flight < self.window.size();
+            let request_deadline = self.last_block + config.pipeline.request_timeout;
+            let pex_id = self.pex_id();
+            let pex_deadline = self.pex.next_send();
+            // Measured from the last message: other events keep waking us up
+            let peer_deadline = self.last_received + config.peer_timeout;
+            let keep_alive_deadline = self.last_sent + config.keep_alive_interval;
+            tokio::select! {
+                msg = self.messages.recv() => {
+                    self.last_received = Instant::now();
+                    self.handle_message(msg?)?;
+                }
+                _ = sleep_until(peer_deadline.into()) => {
+                    return Err(TorrentError::Peer("Peer timed out".to_string()));
+                }
+                _ = sleep_until(keep_alive_deadline.into()) => {
+                    self.send(&[PeerMessage::KeepAlive]).await?;
+                }
+                // In endgame mode, other peers may answer our requests first
+                _ = changed, if idle || in_flight > 0 => {}
+                _ = sleep_until(request_deadline.into()), if in_flight > 0 => {
+                    self.cancel_requests().await?;
+                    return Err(TorrentError::Peer(
+                        "Peer stopped answering requests".to_string(),
+                    ));
+                }
+                _ = sleep_until(pex_deadline.into()), if pex_id.is_some() => self.send_pex().await?,
+            }
+        }
+    }
+
+    fn handle_message(&mut self, msg: PeerMessage) -> Result<(), TorrentError> {
+        match msg {
+            PeerMessage::Choke => {
+                // Peers drop our pending requests when choking us
+                self.choked = true;
+                let requests = std::mem::take(&mut self.requests);
+                self.shared
+                    .release_requests(self.addr, requests.into_iter());
+            }
+            PeerMessage::Unchoke => self.choked = false,
+            PeerMessage::BitField(bytes) => {
+                let peer_pieces = Bitfield::from_bytes(&bytes, self.peer_pieces.len())?;
+                let mut state = self.shared.state.lock().unwrap();
+                state.picker.remove_peer(&self.peer_pieces);
+                state.picker.add_peer(&peer_pieces);
+                drop(state);
+                self.peer_pieces = peer_pieces;
+                self.check_interest = true;
+                self.update_seed_flag();
+            }
+            PeerMessage::Have(index) => {
+                let index = index as usize;
+                if index >= self.peer_pieces.len() {
+                    return Err(TorrentError::Peer(format!("Invalid piece ID: {index}")));
+                }
+                if !self.peer_pieces.has(index) {
+                    self.peer_pieces.set(index);
+                    self.shared.state.lock().unwrap().picker.peer_has(index);
+                    self.check_interest |= !self.interested;
+                    self.update_seed_flag();
+                }
+            }
+            PeerMessage::Piece {
+                index,
+                begin,
+                block,
+            } => self.receive_block(index, begin, block)?,
+            PeerMessage::Extended {
+                id: HANDSHAKE_ID,
+                payload,
+            } => {
+                let handshake = ExtensionHandshake::from_payload(&payload)?;
+                // More requests would be dropped
+                if let Some(reqq) = handshake.reqq() {
+                    self.window.limit(reqq);
+                }
+                self.peer_extensions = Some(handshake);
+            }
+            PeerMessage::Extended { id, payload } if self.extensions.name(id) == Some(UT_PEX) => {
+                let msg = PexMessage::from_bytes(&payload)?;
+                let peer_addrs = msg.added.into_iter().take(MAX_PEX_PEERS);
+                self.shared
+                    .add_learned_peers(peer_addrs.map(|x| x.0).collect());
+            }
+            // We do not upload yet
+            _ => {}
+        }
+        Ok(())
+    }
+
+    // ID the peer wants ut_pex messages sent with, if both of us support it
+    fn pex_id(&self) -> Option<u8> {
+        self.extensions.id(UT_PEX)?;
+        self.peer_extensions.as_ref()?.extension_id(UT_PEX)
+    }
+
+    // Tell the peer who we connected to or lost since the last message
+    async fn send_pex(&mut self) -> Result<(), TorrentError> {
+        let Some(id) = self.pex_id() else {
+            return Ok(());
+        };
+        let mut connected = self.shared.state.lock().unwrap().connected.clone();
+        connected.remove(&self.addr);
+        let Some(msg) = self.pex.update(&connected, Instant::now()) else {
+            return Ok(());
+        };
+        let payload = msg.to_bytes()?;
+        self.send(&[PeerMessage::Extended { id, payload }]).await
+    }
+
+    fn update_seed_flag(&self) {
+        if self.peer_pieces.is_full() {
+            let mut state = self.shared.state.lock().unwrap();
+            if let Some(flags) = state.connected.get_mut(&self.addr) {
+                *flags |= FLAG_SEED;
+            }
+        }
+    }
+
+    // Keep the window full, moving on to the next piece as soon as the
+    // current one is fully requested.
+    async fn fill_pipeline(&mut self) -> Result<(), TorrentError> {
+        if self.requests.is_empty() {
+            self.last_block = Instant::now();
+        }
+
+        let mut messages = Vec::new();
+        while self.requests.len() < self.window.size() {
+            let Some(request) = self.shared.next_request(self.addr, &self.peer_pieces) else {
+                // Other peers may have downloaded everything this one has
+                self.check_interest = true;
+                break;
+            };
+            self.requests.insert(request);
+            messages.push(PeerMessage::Request {
+                index: request.index,
+                begin: request.begin,
+                length: request.length,
+            });
+        }
+
+        if messages.is_empty() {
+            return Ok(());
+        }
+        self.send(&messages).await
+    }
+
+    // Only ask to be unchoked by peers having pieces we want
+    async fn update_interest(&mut self) -> Result<(), TorrentError> {
+        self.check_interest = false;
+        let interested = self.shared.is_interesting(&self.peer_pieces);
+        if interested == self.interested {
+            return Ok(());
+        }
+
+        self.interested = interested;
+        if interested {
+            self.send(&[PeerMessage::Interested]).await
+        } else {
+            self.send(&[PeerMessage::NotInterested]).await
+        }
+    }
+
+    // Tell the peer we do not want the blocks still in flight
+    async fn cancel_requests(&mut self) -> Result<(), TorrentError> {
+        let requests = std::mem::take(&mut self.requests);
+        let cancels: Vec<_> = requests.iter().map(cancel_message).collect();
+        self.shared
+            .release_requests(self.addr, requests.into_iter());
+        self.send(&cancels).await
+    }
+
+    // Cancel the blocks another peer sent us first
+    async fn cancel_stale_requests(&mut self) -> Result<(), TorrentError> {
+        if self.requests.is_empty() {
+            return Ok(());
+        }
+        let stale = self.shared.stale_requests(&self.requests);
+        if stale.is_empty() {
+            return Ok(());
+        }
+
+        for request in &stale {
+            self.requests.remove(request);
+        }
+        let cancels: Vec<_> = stale.iter().map(cancel_message).collect();
+        self.send(&cancels).await
+    }
+
+    fn receive_block(
+        &mut self,
+        index: u32,
+        begin: u32,
+        block: Vec<u8>,
+    ) -> Result<(), TorrentError> {
+        let request = BlockRequest {
+            index,
+            begin,
+            length: block.len() as u32,
+        };
+        // Blocks may still arrive after a choke or a cancel: keep them if
+        // nobody sent them yet.
+        if self.requests.remove(&request) {
+            self.last_block = Instant::now();
+            self.window.on_block(block.len(), self.last_block);
+        }
+
+        let outcome = self.shared.receive_block(self.addr, index, begin, &block)?;
+        let BlockOutcome::PieceDone { data, senders } = outcome else {
+            return Ok(());
+        };
+        match self.shared.finish_piece(index, &data, &senders) {
+            // Only part of the piece may come from this peer: give it another chance
+            Err(err @ TorrentError::PieceHashMismatch(_)) if !self.shared.is_banned(self.addr) => {
+                self.shared
+                    .report(TorrentError::Peer(format!("{}: {err}", self.addr)));
+                Ok(())
+            }
+            result => result,
+        }
+    }
+
+    async fn send(&mut self, messages: &[PeerMessage]) -> Result<(), TorrentError> {
+        for msg in messages {
+            msg.write(&mut self.writer).await?;
+        }
+        self.writer.flush().await?;
+        self.last_sent = Instant::now();
+        Ok(())
+    }
+}
+
+fn cancel_message(request: &BlockRequest) -> PeerMessage {
+    PeerMessage::Cancel {
+        index: request.index,
+        begin: request.begin,
+        length: request.length,
+    }
+}
@@ -0,0 +1,252 @@
+use std::{
+    collections::BTreeMap,
+    fmt::Debug,
+    fs::{self, File, OpenOptions},
+    io::{Read, Seek, SeekFrom, Write},
+    ops::Range,
+    path::Path,
+    sync::Mutex,
+    time::UNIX_EPOCH,
+};
+
+use crate::{
+    error::TorrentError,
+    torrent_file::{FileEntry, Info},
+};
+
+/// Where verified pieces go.
+pub trait Storage: Debug + Send + Sync {
+    fn write_piece(&self, index: u32, data: &[u8]) -> Result<(), TorrentError>;
+
+    fn read_piece(&self, index: u32) -> Result<Vec<u8>, TorrentError>;
+
+    // Part of a piece, to upload it
+    fn read_block(&self, index: u32, begin: u32, length: u32) -> Result<Vec<u8>, TorrentError> {
+        let piece = self.read_piece(index)?;
+        let range = begin as usize..begin as usize + length as usize;
+        piece
+            .get(range)
+            .map(|block| block.to_vec())
+            .ok_or_else(|| TorrentError::Io(format!("Block out of piece {index}")))
+    }
+}
+
+/// Pieces written straight into the torrent files, at their offsets.
+#[derive(Debug)]
+pub struct FileStorage {
+    piece_length: u64,
+    total_length: u64,
+    files: Vec<(FileEntry, Mutex<File>)>,
+    // Some files were already there: they may hold valid pieces
+    existing_data: bool,
+}
+
+impl FileStorage {
+    // Existing data is kept, and never resized: files of another length are
+    // likely not ours.
+    pub fn create(info: &Info, output_path: &Path) -> Result<Self, TorrentError> {
+        let mut files = Vec::new();
+        let mut existing_data = false;
+        for entry in file_entries(info, output_path) {
+            if let Some(parent) = entry.path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+
+            let file = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(&entry.path)?;
+            let length = file.metadata()?.len();
+            if length == 0 {
+                // Sparse where supported: only written pieces take disk space
+                file.set_len(entry.length)?;
+            } else if length != entry.length {
+                return Err(wrong_length(&entry, length));
+            } else {
+                existing_data = true;
+            }
+            files.push((entry, Mutex::new(file)));
+        }
+
+        Ok(Self {
+            piece_length: info.piece_length as u64,
+            total_length: info.total_length(),
+            files,
+            existing_data,
+        })
+    }
+
+    // Files already downloaded, to seed them: missing files or files of
+    // another length are errors, and nothing can be written.
+    pub fn open(info: &Info, output_path: &Path) -> Result<Self, TorrentError> {
+        let mut files = Vec::new();
+        for entry in file_entries(info, output_path) {
+            let file = File::open(&entry.path).map_err(|err| {
+                TorrentError::Io(format!("Cannot open {}: {err}", entry.path.display()))
+            })?;
+            let length = file.metadata()?.len();
+            if length != entry.length {
+                return Err(wrong_length(&entry, length));
+            }
+            files.push((entry, Mutex::new(file)));
+        }
+
+        Ok(Self {
+            piece_length: info.piece_length as u64,
+            total_length: info.total_length(),
+            files,
+            existing_data: true,
+        })
+    }
+
+    pub fn files(&self) -> impl Iterator<Item = &FileEntry> {
+        self.files.iter().map(|(entry, _)| entry)
+    }
+
+    pub fn has_existing_data(&self) -> bool {
+        self.existing_data
+    }
+
+    // Last modification of each file, in seconds since the Unix epoch
+    pub fn mtimes(&self) -> Result<Vec<i64>, TorrentError> {
+        self.files
+            .iter()
+            .map(|(_, file)| {
+                let modified = file.lock().unwrap().metadata()?.modified()?;
+                let mtime = match modified.duration_since(UNIX_EPOCH) {
+                    Ok(elapsed) => elapsed.as_secs() as i64,
+                    Err(err) => -(err.duration().as_secs() as i64),
+                };
+                Ok(mtime)
+            })
+            .collect()
+    }
+
+    // Pieces may span several files
+    fn spans(&self, index: u32, begin: u32, length: usize) -> Result<Vec<Span<'_>>, TorrentError> {
+        let start = index as u64 * self.piece_length + begin as u64;
+        let end = start + length as u64;
+        if end > self.total_length {
+            return Err(TorrentError::Io(format!("Piece {index} out of storage")));
+        }
+
+        Ok(self
+            .files
+            .iter()
+            .filter(|(entry, _)| entry.offset < end && start < entry.offset + entry.length)
+            .map(|(entry, file)| {
+                let span_start = start.max(entry.offset);
+                let span_end = end.min(entry.offset + entry.length);
+                Span {
+                    file,
+                    offset: span_start - entry.offset,
+                    range: (span_start - start) as usize..(span_end - start) as usize,
+                }
+            })
+            .collect())
+    }
+
+    fn piece_size(&self, index: u32) -> usize {
+        let start = index as u64 * self.piece_length;
+        (self.total_length.saturating_sub(start)).min(self.piece_length) as usize
+    }
+}
+
+// Single file torrents are stored at `output_path`, multi file ones in a tree under it
+fn file_entries(info: &Info, output_path: &Path) -> Vec<FileEntry> {
+    let mut entries = info.files();
+    for entry in &mut entries {
+        entry.path = match info.is_multi_file() {
+            true => output_path.join(&entry.path),
+            false => output_path.to_path_buf(),
+        };
+    }
+    entries
+}
+
+fn wrong_length(entry: &FileEntry, length: u64) -> TorrentError {
+    TorrentError::Io(format!(
+        "{} has {length} bytes instead of {}",
+        entry.path.display(),
+        entry.length
+    ))
+}
+
+// Part of a piece stored in one file
+#[derive(Debug)]
+struct Span<'a> {
+    file: &'a Mutex<File>,
+    // Offset in the file
+    offset: u64,
+    // Range in the data read or written
+    range: Range<usize>,
+}
+
+impl Storage for FileStorage {
+    fn write_piece(&self, index: u32, data: &[u8]) -> Result<(), TorrentError> {
+        for span in self.spans(index, 0, data.len())? {
+            let mut file = span.file.lock().unwrap();
+            file.seek(SeekFrom::Start(span.offset))?;
+            file.write_all(&data[span.range])?;
+        }
+        Ok(())
+    }
+
+    fn read_piece(&self, index: u32) -> Result<Vec<u8>, TorrentError> {
+        self.read_block(index, 0, self.piece_size(index) as u32)
+    }
+
+    fn read_block(&self, index: u32, begin: u32, length: u32) -> Result<Vec<u8>, TorrentError> {
+        if begin as u64 + length as u64 > self.piece_length {
+            return Err(TorrentError::Io(format!("Block out of piece {index}")));
+        }
+        let mut data = vec![0; length as usize];
+        for span in self.spans(index, begin, data.len())? {
+            let mut file = span.file.lock().unwrap();
+            file.seek(SeekFrom::Start(span.offset))?;
+            file.read_exact(&mut data[span.range])?;
+        }
+        Ok(data)
+    }
+}
+
+/// Pieces kept in memory, for downloads not going to disk.
+#[derive(Debug, Default)]
+pub struct MemoryStorage {
+    pieces: Mutex<BTreeMap<u32, Vec<u8>>>,
+}
+
+impl MemoryStorage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Every written piece, in order
+    pub fn contents(&self) -> Vec<u8> {
+        self.pieces
+            .lock()
+            .unwrap()
+            .values()
+            .flatten()
+            .copied()
+            .collect()
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn write_piece(&self, index: u32, data: &[u8]) -> Result<(), TorrentError> {
+        self.pieces.lock().unwrap().insert(index, data.to_vec());
+        Ok(())
+    }
+
+    fn read_piece(&self, index: u32) -> Result<Vec<u8>, TorrentError> {
+        self.pieces
+            .lock()
+            .unwrap()
+            .get(&index)
+            .cloned()
+            .ok_or_else(|| TorrentError::Io(format!("Piece {index} not stored")))
+    }
+}
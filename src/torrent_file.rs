@@ -1,58 +1,183 @@
-use std::collections::BTreeMap;
+use std::{cmp, path::PathBuf};
 
 use hex::ToHex;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 
-use crate::{bencode_format::*, utils::hash_sha1};
+use crate::{
+    bencode_format::{self, BencodeRef},
+    error::TorrentError,
+    utils::hash_sha1,
+};
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Serialize)]
 pub struct MetaInfoFile {
+    // Empty for torrents relying on the DHT alone
+    #[serde(default)]
     pub announce: String,
-    pub info: InfoSingleFile,
+    #[serde(rename = "announce-list")]
+    pub announce_list: Option<Vec<Vec<String>>>,
+    pub info: Info,
+    #[serde(rename = "created by")]
     pub created_by: Option<String>,
     pub comment: Option<String>,
+    // DHT nodes to bootstrap from, as host and port (BEP 5)
+    pub nodes: Option<Vec<(String, u16)>>,
+    // Only known when parsed with `from_bytes`
+    #[serde(skip)]
+    info_bytes: Vec<u8>,
 }
 
-#[derive(Debug, Deserialize)]
-pub struct InfoSingleFile {
+impl MetaInfoFile {
+    pub fn from_bytes(data: &[u8]) -> Result<Self, TorrentError> {
+        // Keep `info` exactly as it was encoded: re-encoding it would drop unknown keys.
+        let (mut meta_info, info_span): (Self, _) =
+            bencode_format::from_bytes_with_span(data, b"info")?;
+        let info_span =
+            info_span.ok_or_else(|| TorrentError::Bencode("Missing info dict".to_string()))?;
+        meta_info.info_bytes = data[info_span].to_vec();
+        meta_info.info.check()?;
+
+        Ok(meta_info)
+    }
+
+    // Torrent made of an info dict alone, as fetched from peers
+    pub(crate) fn from_info_bytes(info_bytes: Vec<u8>) -> Result<Self, TorrentError> {
+        let info: Info = bencode_format::from_bytes(&info_bytes)?;
+        info.check()?;
+        Ok(Self {
+            announce: String::new(),
+            announce_list: None,
+            info,
+            created_by: None,
+            comment: None,
+            nodes: None,
+            info_bytes,
+        })
+    }
+
+    pub fn info_bytes(&self) -> &[u8] {
+        &self.info_bytes
+    }
+
+    pub fn info_hash_bytes(&self) -> [u8; 20] {
+        // Deserialized some other way: encoding `info` again is the best we can do
+        if self.info_bytes.is_empty() {
+            let info_bytes = bencode_format::to_bytes(&self.info).unwrap_or_default();
+            return hash_sha1(&info_bytes);
+        }
+        hash_sha1(&self.info_bytes)
+    }
+
+    pub fn info_hash(&self) -> String {
+        self.info_hash_bytes().encode_hex()
+    }
+
+    // Private torrents (BEP 27) only get peers from their trackers
+    pub fn is_private(&self) -> bool {
+        let Ok((_, info)) = BencodeRef::parse(&self.info_bytes) else {
+            return false;
+        };
+        info.get(b"private").and_then(|x| x.as_integer()) == Some(1)
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Info {
     pub name: String,
-    pub length: u32,
     #[serde(rename = "piece length")]
     pub piece_length: u32,
+    #[serde(with = "serde_bytes")]
     pub pieces: Vec<u8>,
+    // Single file mode
+    pub length: Option<u64>,
+    // Multi file mode
+    pub files: Option<Vec<InfoFile>>,
 }
 
-impl InfoSingleFile {
-    pub fn info_hash_bytes(&self) -> [u8; 20] {
-        let content = BencodeValue::Dict(BTreeMap::from([
-            (
-                BencodeText::new(b"name"),
-                BencodeValue::Data(BencodeText::new(self.name.as_bytes())),
-            ),
-            (
-                BencodeText::new(b"length"),
-                BencodeValue::Integer(self.length as i64),
-            ),
-            (
-                BencodeText::new(b"piece length"),
-                BencodeValue::Integer(self.piece_length as i64),
-            ),
-            (
-                BencodeText::new(b"pieces"),
-                BencodeValue::Data(BencodeText::new(&self.pieces)),
-            ),
-        ]));
-
-        let mut buf = Vec::with_capacity(512);
-        content
-            .encode(&mut buf)
-            .expect("Fail to bencode info in memory");
-
-        hash_sha1(&buf)
+#[derive(Debug, Deserialize, Serialize)]
+pub struct InfoFile {
+    pub length: u64,
+    pub path: Vec<String>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct FileEntry {
+    pub path: PathBuf,
+    pub length: u64,
+    pub offset: u64,
+}
+
+impl Info {
+    // Either single file or multi file mode, never both, with a hash for each piece
+    fn check(&self) -> Result<(), TorrentError> {
+        let invalid = |msg: &str| Err(TorrentError::Bencode(format!("Info dict {msg}")));
+        match (&self.length, &self.files) {
+            (Some(_), None) | (None, Some(_)) => {}
+            (Some(_), Some(_)) => return invalid("has both length and files"),
+            (None, None) => return invalid("has neither length nor files"),
+        }
+
+        if self.piece_length == 0 {
+            return invalid("has a piece length of 0");
+        }
+        if !self.pieces.chunks_exact(20).remainder().is_empty() {
+            return invalid("pieces is not a multiple of 20 bytes");
+        }
+        let (length, piece_length) = (self.total_length(), self.piece_length as u64);
+        let expected = length / piece_length + u64::from(length % piece_length != 0);
+        if self.pieces.len() as u64 / 20 != expected {
+            return invalid(&format!(
+                "has {} piece hashes instead of {expected}",
+                self.pieces.len() / 20
+            ));
+        }
+        Ok(())
     }
 
-    pub fn info_hash(&self) -> String {
-        self.info_hash_bytes().encode_hex()
+    pub fn is_multi_file(&self) -> bool {
+        self.files.is_some()
+    }
+
+    pub fn total_length(&self) -> u64 {
+        match &self.files {
+            Some(files) => files.iter().map(|f| f.length).sum(),
+            None => self.length.unwrap_or_default(),
+        }
+    }
+
+    pub fn files(&self) -> Vec<FileEntry> {
+        let root = sanitize_path_component(&self.name);
+
+        let Some(files) = &self.files else {
+            return vec![FileEntry {
+                path: PathBuf::from(root),
+                length: self.total_length(),
+                offset: 0,
+            }];
+        };
+
+        let mut offset = 0;
+        files
+            .iter()
+            .map(|file| {
+                let mut path = PathBuf::from(&root);
+                path.extend(file.path.iter().map(|x| sanitize_path_component(x)));
+
+                let entry = FileEntry {
+                    path,
+                    length: file.length,
+                    offset,
+                };
+                offset += file.length;
+                entry
+            })
+            .collect()
+    }
+
+    pub fn piece_size(&self, piece_id: u32) -> u32 {
+        let start = self.piece_length as u64 * piece_id as u64;
+        let end = cmp::min(start + self.piece_length as u64, self.total_length());
+        end.saturating_sub(start) as u32
     }
 
     pub fn pieces_count(&self) -> usize {
@@ -60,6 +185,11 @@ impl InfoSingleFile {
         self.pieces.len() / 20
     }
 
+    pub fn piece_hash(&self, piece_id: u32) -> &[u8] {
+        let start = piece_id as usize * 20;
+        &self.pieces[start..start + 20]
+    }
+
     pub fn pieces_hashes(&self) -> Vec<String> {
         assert_eq!(self.pieces.len() % 20, 0, "pieces is not a multiple of 20");
         self.pieces
@@ -68,3 +198,20 @@ impl InfoSingleFile {
             .collect()
     }
 }
+
+// File holding the byte at `offset` of the torrent, and where in that file.
+// `files` come from `Info::files`, built once for all the lookups.
+pub fn locate(files: &[FileEntry], offset: u64) -> Option<(usize, u64)> {
+    // Empty files share their offset with the next one: skip them.
+    let idx = files.partition_point(|file| file.offset + file.length <= offset);
+    let file = files.get(idx)?;
+    Some((idx, offset - file.offset))
+}
+
+fn sanitize_path_component(component: &str) -> String {
+    // Never let a torrent escape the output directory.
+    match component {
+        "" | "." | ".." => "_".to_string(),
+        _ => component.replace(['/', '\\'], "_"),
+    }
+}
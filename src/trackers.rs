@@ -1,62 +1,311 @@
-use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::{
+    collections::BTreeMap,
+    net::{IpAddr, SocketAddr},
+    time::Duration,
+};
 
-use serde::Deserialize;
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use serde_bytes::{ByteBuf, Bytes};
 
 use crate::{
-    bencode_format::BencodeValue, error::TorrentError, torrent_file::MetaInfoFile,
-    url_encode::url_encode, PEER_ID,
+    bencode_format,
+    error::TorrentError,
+    peer_id,
+    torrent_file::MetaInfoFile,
+    url_encode::url_encode,
+    utils::{decode_compact_addrs, encode_compact_addr},
 };
 
+pub mod announcer;
+pub mod tiers;
+pub mod udp;
+
+pub const DEFAULT_PORT: u16 = 6881;
+
+// Trackers not answering within this are considered down
+pub const TRACKER_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AnnounceEvent {
+    None,
+    Completed,
+    Started,
+    Stopped,
+}
+
+impl AnnounceEvent {
+    fn as_str(&self) -> Option<&'static str> {
+        match self {
+            AnnounceEvent::None => None,
+            AnnounceEvent::Completed => Some("completed"),
+            AnnounceEvent::Started => Some("started"),
+            AnnounceEvent::Stopped => Some("stopped"),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct AnnounceRequest {
+    pub info_hash: [u8; 20],
+    pub peer_id: [u8; 20],
+    pub port: u16,
+    pub uploaded: u64,
+    pub downloaded: u64,
+    pub left: u64,
+    pub event: AnnounceEvent,
+}
+
+impl AnnounceRequest {
+    pub fn new(meta_info: &MetaInfoFile) -> Self {
+        Self::with_info_hash(meta_info.info_hash_bytes(), meta_info.info.total_length())
+    }
+
+    // Before the metadata of a magnet link is known
+    pub fn with_info_hash(info_hash: [u8; 20], left: u64) -> Self {
+        Self {
+            info_hash,
+            peer_id: peer_id(),
+            port: DEFAULT_PORT,
+            uploaded: 0,
+            downloaded: 0,
+            left,
+            event: AnnounceEvent::None,
+        }
+    }
+}
+
 pub async fn query(meta_info: &MetaInfoFile) -> Result<TrackerResponse, TorrentError> {
-    let client = reqwest::Client::new();
-    let raw_data = client
+    announce(&meta_info.announce, &AnnounceRequest::new(meta_info)).await
+}
+
+pub async fn query_peers(meta_info: &MetaInfoFile) -> Result<Vec<SocketAddr>, TorrentError> {
+    let mut trackers = tiers::TrackerList::from_meta_info(meta_info);
+    let responses = trackers.announce(&AnnounceRequest::new(meta_info)).await?;
+    Ok(tiers::merge_peer_addrs(&responses))
+}
+
+pub async fn announce(
+    url: &str,
+    request: &AnnounceRequest,
+) -> Result<TrackerResponse, TorrentError> {
+    // Pick protocol from announce URL scheme
+    match url.split_once("://").map(|x| x.0) {
+        Some("http") | Some("https") => http_announce(url, request).await,
+        Some("udp") => {
+            let mut client = udp::UdpTrackerClient::connect(url).await?;
+            client.announce(request).await
+        }
+        _ => Err(TorrentError::Tracker(format!(
+            "Unsupported tracker URL: {url}"
+        ))),
+    }
+}
+
+async fn http_announce(
+    url: &str,
+    request: &AnnounceRequest,
+) -> Result<TrackerResponse, TorrentError> {
+    let mut builder = http_client()?
         .get(format!(
-            "{}?info_hash={}",
-            meta_info.announce,
-            url_encode(&meta_info.info.info_hash_bytes()),
+            "{url}{}info_hash={}&peer_id={}",
+            if url.contains('?') { '&' } else { '?' },
+            url_encode(&request.info_hash),
+            url_encode(&request.peer_id),
         ))
-        .query(&[("left", meta_info.info.length.to_string())])
         .query(&[
-            ("peer_id", PEER_ID),
-            ("port", "6881"),
-            ("uploaded", "0"),
-            ("downloaded", "0"),
-            ("compact", "1"),
-        ])
+            ("port", request.port.to_string()),
+            ("uploaded", request.uploaded.to_string()),
+            ("downloaded", request.downloaded.to_string()),
+            ("left", request.left.to_string()),
+            ("compact", "1".to_string()),
+        ]);
+    if let Some(event) = request.event.as_str() {
+        builder = builder.query(&[("event", event)]);
+    }
+
+    let raw_data = builder.send().await?.error_for_status()?.bytes().await?;
+
+    let response: TrackerResponse = bencode_format::from_bytes(&raw_data)?;
+
+    response.into_result()
+}
+
+pub async fn scrape(url: &str, info_hashes: &[[u8; 20]]) -> Result<Vec<ScrapeStats>, TorrentError> {
+    match url.split_once("://").map(|x| x.0) {
+        Some("http") | Some("https") => http_scrape(url, info_hashes).await,
+        Some("udp") => {
+            let mut client = udp::UdpTrackerClient::connect(url).await?;
+            client.scrape(info_hashes).await
+        }
+        _ => Err(TorrentError::Tracker(format!(
+            "Unsupported tracker URL: {url}"
+        ))),
+    }
+}
+
+fn http_client() -> Result<reqwest::Client, TorrentError> {
+    Ok(reqwest::Client::builder()
+        .timeout(TRACKER_TIMEOUT)
+        .build()?)
+}
+
+// Trackers supporting scrape have an announce URL whose last path segment
+// starts with "announce": it is replaced by "scrape" (ex: /x/announce.php -> /x/scrape.php).
+pub fn scrape_url(announce_url: &str) -> Option<String> {
+    let mut url = reqwest::Url::parse(announce_url).ok()?;
+
+    let path = url.path();
+    let (dir, segment) = path.split_at(path.rfind('/')? + 1);
+    let suffix = segment.strip_prefix("announce")?;
+    let path = format!("{dir}scrape{suffix}");
+
+    url.set_path(&path);
+    Some(url.to_string())
+}
+
+async fn http_scrape(
+    url: &str,
+    info_hashes: &[[u8; 20]],
+) -> Result<Vec<ScrapeStats>, TorrentError> {
+    let scrape_url = scrape_url(url)
+        .ok_or_else(|| TorrentError::Tracker(format!("Tracker does not support scrape: {url}")))?;
+
+    let mut full_url = scrape_url.clone();
+    for (idx, info_hash) in info_hashes.iter().enumerate() {
+        let separator = if idx == 0 && !scrape_url.contains('?') {
+            '?'
+        } else {
+            '&'
+        };
+        full_url.push(separator);
+        full_url.push_str("info_hash=");
+        full_url.push_str(&url_encode(info_hash));
+    }
+
+    let raw_data = http_client()?
+        .get(full_url)
         .send()
         .await?
         .error_for_status()?
         .bytes()
         .await?;
 
-    let (_, content) = BencodeValue::parse(&raw_data)?;
-    let response = serde_json::from_value(content.into())?;
+    let response: ScrapeResponse = bencode_format::from_bytes(&raw_data)?;
+    if let Some(reason) = response.failure_reason {
+        return Err(TorrentError::TrackerFailure(reason));
+    }
 
-    Ok(response)
+    // Torrents unknown to the tracker are missing from the response
+    Ok(info_hashes
+        .iter()
+        .map(|info_hash| {
+            response
+                .files
+                .get(Bytes::new(info_hash))
+                .copied()
+                .unwrap_or_default()
+        })
+        .collect())
 }
 
 #[derive(Debug, Deserialize)]
+struct ScrapeResponse {
+    #[serde(rename = "failure reason")]
+    failure_reason: Option<String>,
+    #[serde(default)]
+    files: BTreeMap<ByteBuf, ScrapeStats>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(default)]
+pub struct ScrapeStats {
+    pub complete: u32,
+    pub downloaded: u32,
+    pub incomplete: u32,
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
 pub struct TrackerResponse {
-    pub interval: i64,
+    #[serde(rename = "failure reason")]
+    pub failure_reason: Option<String>,
+    #[serde(rename = "warning message")]
+    pub warning_message: Option<String>,
     #[serde(default)]
-    peers: Vec<u8>,
+    pub interval: i64,
+    #[serde(rename = "min interval")]
+    pub min_interval: Option<i64>,
+    #[serde(rename = "tracker id")]
+    pub tracker_id: Option<String>,
+    pub complete: Option<i64>,
+    pub incomplete: Option<i64>,
+    #[serde(
+        default,
+        deserialize_with = "deserialize_peers",
+        serialize_with = "serialize_peers"
+    )]
+    peers: Vec<SocketAddr>,
+    #[serde(
+        default,
+        deserialize_with = "deserialize_peers6",
+        serialize_with = "serialize_peers"
+    )]
+    peers6: Vec<SocketAddr>,
 }
 
 impl TrackerResponse {
     pub fn peer_addrs(&self) -> Vec<SocketAddr> {
-        assert_eq!(
-            self.peers.len() % 6,
-            0,
-            "Peers is not a multiple of 6 bytes"
-        );
-
-        self.peers
-            .chunks(6)
-            .map(|n| {
-                let ip = IpAddr::V4(Ipv4Addr::new(n[0], n[1], n[2], n[3]));
-                let port = u16::from_be_bytes([n[4], n[5]]);
-                SocketAddr::new(ip, port)
+        self.peers.iter().chain(&self.peers6).copied().collect()
+    }
+
+    fn into_result(self) -> Result<Self, TorrentError> {
+        if let Some(reason) = self.failure_reason {
+            return Err(TorrentError::TrackerFailure(reason));
+        }
+        // Warnings are left to the caller, the response is still valid
+        Ok(self)
+    }
+}
+
+// Trackers either send compact peers or a list of dicts (BEP 3 / BEP 23).
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum PeersModel {
+    Compact(#[serde(with = "serde_bytes")] Vec<u8>),
+    Dict(Vec<PeerDict>),
+}
+
+#[derive(Deserialize)]
+struct PeerDict {
+    ip: String,
+    port: u16,
+}
+
+fn deserialize_peers<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Vec<SocketAddr>, D::Error> {
+    match PeersModel::deserialize(deserializer)? {
+        PeersModel::Compact(data) => decode_compact_addrs(&data, false)
+            .ok_or_else(|| de::Error::custom("Compact peers is not a multiple of 6 bytes")),
+        // Hostnames are not resolved: they are very rare in practice.
+        PeersModel::Dict(peers) => Ok(peers
+            .into_iter()
+            .filter_map(|peer| {
+                let ip: IpAddr = peer.ip.parse().ok()?;
+                Some(SocketAddr::new(ip, peer.port))
             })
-            .collect()
+            .collect()),
     }
 }
+
+fn deserialize_peers6<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Vec<SocketAddr>, D::Error> {
+    let data: Vec<u8> = serde_bytes::deserialize(deserializer)?;
+    decode_compact_addrs(&data, true)
+        .ok_or_else(|| de::Error::custom("Compact peers6 is not a multiple of 18 bytes"))
+}
+
+fn serialize_peers<S: Serializer>(peers: &[SocketAddr], serializer: S) -> Result<S::Ok, S::Error> {
+    let data: Vec<u8> = peers.iter().flat_map(encode_compact_addr).collect();
+    serializer.serialize_bytes(&data)
+}
@@ -0,0 +1,301 @@
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use tokio::{
+    sync::mpsc,
+    task::JoinHandle,
+    time::{sleep_until, Instant},
+};
+
+use crate::error::TorrentError;
+
+use super::{
+    tiers::{merge_peer_addrs, TrackerList},
+    AnnounceEvent, AnnounceRequest,
+};
+
+// Used until a tracker tells us otherwise
+const DEFAULT_INTERVAL: Duration = Duration::from_secs(30 * 60);
+const DEFAULT_MIN_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone)]
+pub struct AnnouncerConfig {
+    // Time before announcing again after a failure
+    pub retry_interval: Duration,
+    // Give up after that many failures in a row if no announce ever worked
+    pub max_failures: u32,
+}
+
+impl Default for AnnouncerConfig {
+    fn default() -> Self {
+        Self {
+            retry_interval: Duration::from_secs(30),
+            max_failures: 5,
+        }
+    }
+}
+
+type AnnounceResult = Result<Vec<SocketAddr>, TorrentError>;
+
+#[derive(Debug, Default)]
+pub struct TransferStats {
+    uploaded: AtomicU64,
+    downloaded: AtomicU64,
+    left: AtomicU64,
+    // Received more than once, in endgame mode for instance
+    duplicated: AtomicU64,
+    // Received from each peer, by IP: peers we download from connect to us
+    // from another port. Uploads go to the fastest ones.
+    downloaded_from: Mutex<HashMap<IpAddr, u64>>,
+}
+
+impl TransferStats {
+    pub fn new(left: u64) -> Self {
+        Self {
+            left: AtomicU64::new(left),
+            ..Default::default()
+        }
+    }
+
+    pub fn uploaded(&self) -> u64 {
+        self.uploaded.load(Ordering::Relaxed)
+    }
+
+    pub fn downloaded(&self) -> u64 {
+        self.downloaded.load(Ordering::Relaxed)
+    }
+
+    pub fn left(&self) -> u64 {
+        self.left.load(Ordering::Relaxed)
+    }
+
+    pub fn duplicated(&self) -> u64 {
+        self.duplicated.load(Ordering::Relaxed)
+    }
+
+    pub fn add_uploaded(&self, count: u64) {
+        self.uploaded.fetch_add(count, Ordering::Relaxed);
+    }
+
+    pub fn add_downloaded(&self, count: u64) {
+        self.downloaded.fetch_add(count, Ordering::Relaxed);
+    }
+
+    pub fn add_duplicated(&self, count: u64) {
+        self.duplicated.fetch_add(count, Ordering::Relaxed);
+    }
+
+    pub fn set_left(&self, left: u64) {
+        self.left.store(left, Ordering::Relaxed);
+    }
+
+    pub fn downloaded_from(&self, ip: IpAddr) -> u64 {
+        let downloaded_from = self.downloaded_from.lock().unwrap();
+        downloaded_from.get(&ip).copied().unwrap_or_default()
+    }
+
+    pub fn add_downloaded_from(&self, ip: IpAddr, count: u64) {
+        *self.downloaded_from.lock().unwrap().entry(ip).or_default() += count;
+    }
+}
+
+#[derive(Debug)]
+pub struct Announcer {
+    trackers: TrackerList,
+    request: AnnounceRequest,
+    stats: Arc<TransferStats>,
+    config: AnnouncerConfig,
+    interval: Duration,
+    min_interval: Duration,
+    last_announce: Option<Instant>,
+}
+
+impl Announcer {
+    pub fn new(trackers: TrackerList, request: AnnounceRequest, stats: Arc<TransferStats>) -> Self {
+        Self::with_config(trackers, request, stats, AnnouncerConfig::default())
+    }
+
+    pub fn with_config(
+        trackers: TrackerList,
+        request: AnnounceRequest,
+        stats: Arc<TransferStats>,
+        config: AnnouncerConfig,
+    ) -> Self {
+        Self {
+            trackers,
+            request,
+            stats,
+            config,
+            interval: DEFAULT_INTERVAL,
+            min_interval: DEFAULT_MIN_INTERVAL,
+            last_announce: None,
+        }
+    }
+
+    pub fn interval(&self) -> Duration {
+        self.interval
+    }
+
+    pub fn min_interval(&self) -> Duration {
+        self.min_interval
+    }
+
+    pub async fn announce(
+        &mut self,
+        event: AnnounceEvent,
+    ) -> Result<Vec<SocketAddr>, TorrentError> {
+        let request = AnnounceRequest {
+            uploaded: self.stats.uploaded(),
+            downloaded: self.stats.downloaded(),
+            left: self.stats.left(),
+            event,
+            ..self.request.clone()
+        };
+
+        self.last_announce = Some(Instant::now());
+        let responses = self.trackers.announce(&request).await?;
+
+        // Be conservative when trackers disagree. A missing interval reads as 0:
+        // do not take it for a request to announce all the time.
+        self.interval = match responses
+            .iter()
+            .map(|x| x.interval)
+            .filter(|&x| x > 0)
+            .max()
+        {
+            Some(interval) => Duration::from_secs(interval as u64),
+            None => DEFAULT_INTERVAL,
+        };
+        if let Some(min_interval) = responses.iter().filter_map(|x| x.min_interval).max() {
+            self.min_interval = Duration::from_secs(min_interval.max(0) as u64);
+        }
+
+        Ok(merge_peer_addrs(&responses))
+    }
+
+    pub fn spawn(self) -> AnnouncerHandle {
+        let (commands_tx, commands_rx) = mpsc::unbounded_channel();
+        let (peers_tx, peers_rx) = mpsc::unbounded_channel();
+        let task = tokio::spawn(self.run(commands_rx, peers_tx));
+
+        AnnouncerHandle {
+            commands: commands_tx,
+            peers: peers_rx,
+            task,
+        }
+    }
+
+    // Peers, or why trackers could not give any, are sent after each announce.
+    // Closes the channel when there is nothing to announce to or when giving up.
+    async fn run(
+        mut self,
+        mut commands: mpsc::UnboundedReceiver<AnnouncerCommand>,
+        peers: mpsc::UnboundedSender<AnnounceResult>,
+    ) -> Result<(), TorrentError> {
+        if self.trackers.tiers().is_empty() {
+            return Ok(());
+        }
+
+        let mut started = false;
+        let mut completed = false;
+        // Seeders starting with everything never complete the download
+        let downloading = self.stats.left() > 0;
+        let mut failures = 0;
+        let mut next_announce = Instant::now();
+
+        loop {
+            let event = tokio::select! {
+                _ = sleep_until(next_announce) => {
+                    if !started {
+                        AnnounceEvent::Started
+                    } else if downloading && !completed && self.stats.left() == 0 {
+                        AnnounceEvent::Completed
+                    } else {
+                        AnnounceEvent::None
+                    }
+                }
+                command = commands.recv() => match command {
+                    Some(AnnouncerCommand::Completed) if started && !completed => {
+                        AnnounceEvent::Completed
+                    }
+                    Some(AnnouncerCommand::Completed) => continue,
+                    Some(AnnouncerCommand::RequestPeers) => {
+                        // Trackers ask us not to re-announce before `min interval`
+                        let earliest = self.last_announce.map(|x| x + self.min_interval);
+                        next_announce = next_announce.min(earliest.unwrap_or_else(Instant::now));
+                        continue;
+                    }
+                    Some(AnnouncerCommand::Stop) | None => break,
+                },
+            };
+
+            match self.announce(event).await {
+                Ok(peer_addrs) => {
+                    started = true;
+                    completed |= event == AnnounceEvent::Completed;
+                    failures = 0;
+                    next_announce = Instant::now() + self.interval;
+                    let _ = peers.send(Ok(peer_addrs));
+                }
+                Err(err) => {
+                    failures += 1;
+                    next_announce = Instant::now() + self.config.retry_interval.min(self.interval);
+                    let _ = peers.send(Err(err));
+                    if !started && failures >= self.config.max_failures {
+                        return Ok(());
+                    }
+                }
+            }
+        }
+
+        // Let the swarm know we are leaving
+        if started {
+            self.announce(AnnounceEvent::Stopped).await?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+enum AnnouncerCommand {
+    Completed,
+    RequestPeers,
+    Stop,
+}
+
+#[derive(Debug)]
+pub struct AnnouncerHandle {
+    commands: mpsc::UnboundedSender<AnnouncerCommand>,
+    peers: mpsc::UnboundedReceiver<AnnounceResult>,
+    task: JoinHandle<Result<(), TorrentError>>,
+}
+
+impl AnnouncerHandle {
+    // None once the announcer gave up
+    pub async fn next_peers(&mut self) -> Option<AnnounceResult> {
+        self.peers.recv().await
+    }
+
+    pub fn request_peers(&self) {
+        let _ = self.commands.send(AnnouncerCommand::RequestPeers);
+    }
+
+    pub fn completed(&self) {
+        let _ = self.commands.send(AnnouncerCommand::Completed);
+    }
+
+    // Fails when the `stopped` announce does
+    pub async fn stop(self) -> Result<(), TorrentError> {
+        let _ = self.commands.send(AnnouncerCommand::Stop);
+        self.task
+            .await
+            .map_err(|err| TorrentError::Tracker(format!("Announcer task failed: {err}")))?
+    }
+}
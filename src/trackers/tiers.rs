@@ -0,0 +1,114 @@
+use std::{collections::HashSet, net::SocketAddr, time::Duration};
+
+use tokio::{task::JoinSet, time::timeout};
+
+use crate::{error::TorrentError, torrent_file::MetaInfoFile, utils::shuffle};
+
+use super::{announce, AnnounceRequest, TrackerResponse, TRACKER_TIMEOUT};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TrackerList {
+    tiers: Vec<Vec<String>>,
+    timeout: Duration,
+}
+
+impl TrackerList {
+    pub fn new(mut tiers: Vec<Vec<String>>) -> Self {
+        tiers.retain(|tier| !tier.is_empty());
+        for tier in &mut tiers {
+            shuffle(tier);
+        }
+        Self {
+            tiers,
+            timeout: TRACKER_TIMEOUT,
+        }
+    }
+
+    // Time given to each tracker to answer an announce
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    pub fn from_meta_info(meta_info: &MetaInfoFile) -> Self {
+        // `announce-list` takes precedence over `announce` when present (BEP 12)
+        match &meta_info.announce_list {
+            Some(tiers) if tiers.iter().any(|tier| !tier.is_empty()) => Self::new(tiers.clone()),
+            // Magnet links may come without trackers
+            _ if meta_info.announce.is_empty() => Self::new(Vec::new()),
+            _ => Self::new(vec![vec![meta_info.announce.clone()]]),
+        }
+    }
+
+    pub fn tiers(&self) -> &[Vec<String>] {
+        &self.tiers
+    }
+
+    pub async fn announce(
+        &mut self,
+        request: &AnnounceRequest,
+    ) -> Result<Vec<TrackerResponse>, TorrentError> {
+        // Announce to the first working tracker of each tier, so we get a wider view of the swarm.
+        // Tiers are independent, so a slow one does not hold the others back.
+        let mut tasks = JoinSet::new();
+        for (tier_idx, tier) in self.tiers.iter().enumerate() {
+            let tier = tier.clone();
+            let request = request.clone();
+            let timeout = self.timeout;
+            tasks.spawn(async move { (tier_idx, announce_tier(&tier, &request, timeout).await) });
+        }
+
+        let mut results = Vec::new();
+        while let Some(joined) = tasks.join_next().await {
+            if let Ok(result) = joined {
+                results.push(result);
+            }
+        }
+        results.sort_by_key(|(tier_idx, _)| *tier_idx);
+
+        let mut responses = Vec::new();
+        let mut last_err = TorrentError::Tracker("No tracker to announce to".to_string());
+        for (tier_idx, result) in results {
+            match result {
+                Ok((idx, response)) => {
+                    let tier = &mut self.tiers[tier_idx];
+                    let url = tier.remove(idx);
+                    tier.insert(0, url);
+                    responses.push(response);
+                }
+                Err(err) => last_err = err,
+            }
+        }
+
+        if responses.is_empty() {
+            return Err(last_err);
+        }
+        Ok(responses)
+    }
+}
+
+// Trackers of a tier are tried in order, until one answers
+async fn announce_tier(
+    tier: &[String],
+    request: &AnnounceRequest,
+    limit: Duration,
+) -> Result<(usize, TrackerResponse), TorrentError> {
+    let mut last_err = TorrentError::Tracker("No tracker to announce to".to_string());
+    for (idx, url) in tier.iter().enumerate() {
+        match timeout(limit, announce(url, request)).await {
+            Ok(Ok(response)) => return Ok((idx, response)),
+            Ok(Err(err)) => last_err = err,
+            Err(_) => last_err = TorrentError::Tracker(format!("Tracker timed out: {url}")),
+        }
+    }
+    Err(last_err)
+}
+
+pub fn merge_peer_addrs(responses: &[TrackerResponse]) -> Vec<SocketAddr> {
+    let mut seen = HashSet::new();
+    responses
+        .iter()
+        .flat_map(|response| response.peer_addrs())
+        .filter(|addr| seen.insert(*addr))
+        .collect()
+}
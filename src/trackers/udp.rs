@@ -0,0 +1,273 @@
+use std::{
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+use tokio::{net::UdpSocket, time::timeout_at};
+
+use crate::{
+    error::TorrentError,
+    utils::{decode_compact_addrs, random_u32},
+};
+
+use super::{AnnounceEvent, AnnounceRequest, ScrapeStats, TrackerResponse};
+
+const PROTOCOL_ID: u64 = 0x41727101980;
+
+const ACTION_CONNECT: u32 = 0;
+const ACTION_ANNOUNCE: u32 = 1;
+const ACTION_SCRAPE: u32 = 2;
+const ACTION_ERROR: u32 = 3;
+
+#[derive(Debug, Clone)]
+pub struct UdpTrackerConfig {
+    // Retransmit timeout is `base_timeout * 2 ^ n`
+    pub base_timeout: Duration,
+    pub max_retries: u32,
+    // Deadline of a whole request, retries included
+    pub request_timeout: Option<Duration>,
+    pub connection_ttl: Duration,
+}
+
+impl UdpTrackerConfig {
+    // Full BEP 15 schedule: 15s and n up to 8, so up to an hour per request
+    pub fn bep15() -> Self {
+        Self {
+            base_timeout: Duration::from_secs(15),
+            max_retries: 8,
+            request_timeout: None,
+            ..Default::default()
+        }
+    }
+}
+
+impl Default for UdpTrackerConfig {
+    fn default() -> Self {
+        Self {
+            base_timeout: Duration::from_secs(5),
+            max_retries: 2,
+            request_timeout: Some(Duration::from_secs(30)),
+            connection_ttl: Duration::from_secs(60),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct UdpTrackerClient {
+    socket: UdpSocket,
+    config: UdpTrackerConfig,
+    connection: Option<(u64, Instant)>,
+    key: u32,
+}
+
+impl UdpTrackerClient {
+    pub async fn connect(url: &str) -> Result<Self, TorrentError> {
+        Self::connect_with_config(url, UdpTrackerConfig::default()).await
+    }
+
+    pub async fn connect_with_config(
+        url: &str,
+        config: UdpTrackerConfig,
+    ) -> Result<Self, TorrentError> {
+        let addr = resolve_url(url).await?;
+
+        let bind_addr = if addr.is_ipv4() {
+            "0.0.0.0:0"
+        } else {
+            "[::]:0"
+        };
+        let socket = UdpSocket::bind(bind_addr).await?;
+        socket.connect(addr).await?;
+
+        Ok(Self {
+            socket,
+            config,
+            connection: None,
+            key: random_u32(),
+        })
+    }
+
+    pub async fn announce(
+        &mut self,
+        request: &AnnounceRequest,
+    ) -> Result<TrackerResponse, TorrentError> {
+        let event = match request.event {
+            AnnounceEvent::None => 0,
+            AnnounceEvent::Completed => 1,
+            AnnounceEvent::Started => 2,
+            AnnounceEvent::Stopped => 3,
+        };
+
+        let mut payload = Vec::with_capacity(82);
+        payload.extend_from_slice(&request.info_hash);
+        payload.extend_from_slice(&request.peer_id);
+        payload.extend_from_slice(&request.downloaded.to_be_bytes());
+        payload.extend_from_slice(&request.left.to_be_bytes());
+        payload.extend_from_slice(&request.uploaded.to_be_bytes());
+        payload.extend_from_slice(&(event as u32).to_be_bytes());
+        payload.extend_from_slice(&0u32.to_be_bytes()); // IP: use sender address
+        payload.extend_from_slice(&self.key.to_be_bytes());
+        payload.extend_from_slice(&(-1i32).to_be_bytes()); // num want: default
+        payload.extend_from_slice(&request.port.to_be_bytes());
+
+        let response = self.send_request(ACTION_ANNOUNCE, &payload).await?;
+        if response.len() < 12 {
+            return Err(TorrentError::Tracker(
+                "UDP announce response is too short".to_string(),
+            ));
+        }
+
+        // Peers use 18 bytes each when the tracker is reached over IPv6
+        let ipv6 = self.socket.peer_addr()?.is_ipv6();
+        let peer_addrs = decode_compact_addrs(&response[12..], ipv6).ok_or_else(|| {
+            TorrentError::Tracker("UDP announce response has truncated peers".to_string())
+        })?;
+        let (peers, peers6) = if ipv6 {
+            (Vec::new(), peer_addrs)
+        } else {
+            (peer_addrs, Vec::new())
+        };
+
+        Ok(TrackerResponse {
+            interval: read_u32(&response, 0) as i64,
+            incomplete: Some(read_u32(&response, 4) as i64),
+            complete: Some(read_u32(&response, 8) as i64),
+            peers,
+            peers6,
+            ..Default::default()
+        })
+    }
+
+    pub async fn scrape(
+        &mut self,
+        info_hashes: &[[u8; 20]],
+    ) -> Result<Vec<ScrapeStats>, TorrentError> {
+        let payload = info_hashes.concat();
+
+        let response = self.send_request(ACTION_SCRAPE, &payload).await?;
+        if response.len() < info_hashes.len() * 12 {
+            return Err(TorrentError::Tracker(
+                "UDP scrape response is too short".to_string(),
+            ));
+        }
+
+        Ok(response
+            .chunks_exact(12)
+            .take(info_hashes.len())
+            .map(|x| ScrapeStats {
+                complete: read_u32(x, 0),
+                downloaded: read_u32(x, 4),
+                incomplete: read_u32(x, 8),
+            })
+            .collect())
+    }
+
+    async fn send_request(&mut self, action: u32, payload: &[u8]) -> Result<Vec<u8>, TorrentError> {
+        let request_deadline = self.config.request_timeout.map(|x| Instant::now() + x);
+        for attempt in 0..=self.config.max_retries {
+            let mut deadline = Instant::now() + self.config.base_timeout * 2u32.pow(attempt);
+            match request_deadline {
+                Some(request_deadline) if request_deadline <= Instant::now() => break,
+                Some(request_deadline) => deadline = deadline.min(request_deadline),
+                None => {}
+            }
+
+            // Connection ID may have expired while we were retrying
+            let connection_id = match self.connection {
+                Some((id, obtained_at)) if obtained_at.elapsed() < self.config.connection_ttl => id,
+                _ => {
+                    let mut packet = Vec::with_capacity(16);
+                    packet.extend_from_slice(&PROTOCOL_ID.to_be_bytes());
+                    let Some(response) =
+                        self.transact(packet, ACTION_CONNECT, &[], deadline).await?
+                    else {
+                        continue;
+                    };
+                    if response.len() < 8 {
+                        return Err(TorrentError::Tracker(
+                            "UDP connect response is too short".to_string(),
+                        ));
+                    }
+
+                    let id = read_u64(&response, 0);
+                    self.connection = Some((id, Instant::now()));
+                    id
+                }
+            };
+
+            let packet = connection_id.to_be_bytes().to_vec();
+            if let Some(response) = self.transact(packet, action, payload, deadline).await? {
+                return Ok(response);
+            }
+        }
+
+        Err(TorrentError::Tracker(
+            "UDP tracker did not respond".to_string(),
+        ))
+    }
+
+    async fn transact(
+        &self,
+        mut packet: Vec<u8>,
+        action: u32,
+        payload: &[u8],
+        deadline: Instant,
+    ) -> Result<Option<Vec<u8>>, TorrentError> {
+        let transaction_id = random_u32();
+        packet.extend_from_slice(&action.to_be_bytes());
+        packet.extend_from_slice(&transaction_id.to_be_bytes());
+        packet.extend_from_slice(payload);
+        self.socket.send(&packet).await?;
+
+        let mut buf = vec![0; 64 << 10];
+        loop {
+            let Ok(received) = timeout_at(deadline.into(), self.socket.recv(&mut buf)).await else {
+                return Ok(None);
+            };
+            let received = received?;
+
+            // Ignore late responses from previous attempts
+            if received < 8 || read_u32(&buf, 4) != transaction_id {
+                continue;
+            }
+
+            let body = &buf[8..received];
+            return match read_u32(&buf, 0) {
+                val if val == action => Ok(Some(body.to_vec())),
+                ACTION_ERROR => Err(TorrentError::TrackerFailure(
+                    String::from_utf8_lossy(body).to_string(),
+                )),
+                val => Err(TorrentError::Tracker(format!(
+                    "Unexpected UDP tracker action: {val}"
+                ))),
+            };
+        }
+    }
+}
+
+async fn resolve_url(url: &str) -> Result<SocketAddr, TorrentError> {
+    let parsed = reqwest::Url::parse(url)
+        .map_err(|err| TorrentError::Tracker(format!("Invalid tracker URL {url}: {err}")))?;
+
+    let host = parsed
+        .host_str()
+        .ok_or_else(|| TorrentError::Tracker(format!("Missing host in tracker URL: {url}")))?;
+    let port = parsed
+        .port()
+        .ok_or_else(|| TorrentError::Tracker(format!("Missing port in tracker URL: {url}")))?;
+
+    // IPv6 hosts come back from the URL with their brackets
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    let mut addrs = tokio::net::lookup_host((host, port)).await?;
+    addrs
+        .next()
+        .ok_or_else(|| TorrentError::Tracker(format!("Cannot resolve tracker host: {host}")))
+}
+
+fn read_u32(buf: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes(buf[offset..offset + 4].try_into().unwrap())
+}
+
+fn read_u64(buf: &[u8], offset: usize) -> u64 {
+    u64::from_be_bytes(buf[offset..offset + 8].try_into().unwrap())
+}
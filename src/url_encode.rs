@@ -8,3 +8,23 @@ pub fn url_encode(input: &[u8]) -> String {
 
     output
 }
+
+// Query string values, where `+` stands for a space
+pub fn url_decode(input: &str) -> Option<Vec<u8>> {
+    let mut output = Vec::with_capacity(input.len());
+
+    let mut bytes = input.bytes();
+    while let Some(b) = bytes.next() {
+        match b {
+            b'%' => {
+                let high = (bytes.next()? as char).to_digit(16)?;
+                let low = (bytes.next()? as char).to_digit(16)?;
+                output.push((high * 16 + low) as u8);
+            }
+            b'+' => output.push(b' '),
+            _ => output.push(b),
+        }
+    }
+
+    Some(output)
+}
@@ -1,7 +1,66 @@
+use std::{
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hasher},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::atomic::{AtomicU64, Ordering},
+};
+
 use sha1::{Digest, Sha1};
 
 pub fn hash_sha1(input: &[u8]) -> [u8; 20] {
     let mut hasher = Sha1::new();
-    hasher.update(&input);
+    hasher.update(input);
     hasher.finalize().into()
 }
+
+pub fn random_u64() -> u64 {
+    // `RandomState` is randomly seeded by std: good enough for IDs and shuffling,
+    // and saves us from pulling a RNG crate.
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u64(COUNTER.fetch_add(1, Ordering::Relaxed));
+    hasher.finish()
+}
+
+pub fn random_u32() -> u32 {
+    random_u64() as u32
+}
+
+pub fn shuffle<T>(items: &mut [T]) {
+    // Fisher-Yates
+    for idx in (1..items.len()).rev() {
+        let other = (random_u64() % (idx as u64 + 1)) as usize;
+        items.swap(idx, other);
+    }
+}
+
+pub fn decode_compact_addrs(data: &[u8], ipv6: bool) -> Option<Vec<SocketAddr>> {
+    // Compact format: IP then port, big endian
+    let chunk_size = if ipv6 { 18 } else { 6 };
+    let chunks = data.chunks_exact(chunk_size);
+    if !chunks.remainder().is_empty() {
+        return None;
+    }
+
+    let addrs = chunks
+        .map(|n| {
+            let (ip, port) = n.split_at(chunk_size - 2);
+            let ip = match <[u8; 16]>::try_from(ip) {
+                Ok(ip) => IpAddr::V6(Ipv6Addr::from(ip)),
+                Err(_) => IpAddr::V4(Ipv4Addr::new(ip[0], ip[1], ip[2], ip[3])),
+            };
+            SocketAddr::new(ip, u16::from_be_bytes([port[0], port[1]]))
+        })
+        .collect();
+    Some(addrs)
+}
+
+pub fn encode_compact_addr(addr: &SocketAddr) -> Vec<u8> {
+    let mut output = match addr.ip() {
+        IpAddr::V4(ip) => ip.octets().to_vec(),
+        IpAddr::V6(ip) => ip.octets().to_vec(),
+    };
+    output.extend_from_slice(&addr.port().to_be_bytes());
+    output
+}
@@ -0,0 +1,344 @@
+// Helpers shared by integration tests: each test binary only uses a subset of them.
+#![allow(dead_code)]
+
+use std::{
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use bittorrent_starter_rust::{
+    bencode_format,
+    bitfield::Bitfield,
+    extensions::ExtensionHandshake,
+    metadata::{MetadataMessage, METADATA_PIECE_SIZE},
+    peers::PeerMessage,
+    pex::PexMessage,
+    torrent_file::{Info, MetaInfoFile},
+    utils::hash_sha1,
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+};
+
+// Minimal HTTP tracker answering every request with the same bencoded body.
+// Returns the announce URL and the list of received request lines.
+pub async fn spawn_http_tracker(body: Vec<u8>) -> (String, Arc<Mutex<Vec<String>>>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}/announce", listener.local_addr().unwrap());
+    let requests = Arc::new(Mutex::new(Vec::new()));
+
+    let task_requests = requests.clone();
+    tokio::spawn(async move {
+        loop {
+            let (mut stream, _) = listener.accept().await.unwrap();
+
+            let mut request = Vec::new();
+            let mut buf = [0; 1024];
+            while !request.ends_with(b"\r\n\r\n") {
+                let len = stream.read(&mut buf).await.unwrap();
+                if len == 0 {
+                    break;
+                }
+                request.extend_from_slice(&buf[..len]);
+            }
+
+            let request = String::from_utf8_lossy(&request).to_string();
+            let request_line = request.lines().next().unwrap_or_default().to_string();
+            task_requests.lock().unwrap().push(request_line);
+
+            let header = format!(
+                "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                body.len()
+            );
+            stream.write_all(header.as_bytes()).await.unwrap();
+            stream.write_all(&body).await.unwrap();
+            stream.shutdown().await.unwrap();
+        }
+    });
+
+    (url, requests)
+}
+
+// URL of a tracker that refuses connections right away.
+pub async fn dead_tracker_url() -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    drop(listener);
+    format!("http://{addr}/announce")
+}
+
+// Tracker accepting connections but never answering
+pub async fn hanging_tracker_url() -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        let mut streams = Vec::new();
+        while let Ok((stream, _)) = listener.accept().await {
+            streams.push(stream);
+        }
+    });
+    format!("http://{addr}/announce")
+}
+
+pub fn compact_peers_response(interval: i64, peers: &[[u8; 6]]) -> Vec<u8> {
+    let mut body = format!("d8:intervali{interval}e5:peers{}:", peers.len() * 6).into_bytes();
+    for peer in peers {
+        body.extend_from_slice(peer);
+    }
+    body.push(b'e');
+    body
+}
+
+// Single file torrent for `contents`, going through bencode like a real file.
+pub fn make_torrent(contents: &[u8], piece_length: u32) -> MetaInfoFile {
+    let info = Info {
+        name: "sample.bin".to_string(),
+        piece_length,
+        pieces: contents
+            .chunks(piece_length as usize)
+            .flat_map(hash_sha1)
+            .collect(),
+        length: Some(contents.len() as u64),
+        files: None,
+    };
+    let mut data = b"d8:announce28:http://tracker.test/announce4:info".to_vec();
+    data.extend(bencode_format::to_bytes(&info).unwrap());
+    data.push(b'e');
+    MetaInfoFile::from_bytes(&data).unwrap()
+}
+
+pub fn sample_contents(len: usize) -> Vec<u8> {
+    (0..len).map(|x| (x % 251) as u8).collect()
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct SeederBehavior {
+    // Send garbage instead of this piece
+    pub corrupt_piece: Option<u32>,
+    // Drop the connection after serving that many blocks
+    pub disconnect_after: Option<usize>,
+    pub block_delay: Duration,
+    // Keep interested peers choked that long
+    pub unchoke_delay: Duration,
+    // Never answer requests
+    pub stall: bool,
+    // Pieces advertised in the bit field, all of them by default
+    pub pieces: Option<Vec<u32>>,
+    // Info dict sent over ut_metadata, which is not supported without it
+    pub metadata: Option<Vec<u8>>,
+    // Requests queued, advertised in the extension handshake
+    pub reqq: Option<i64>,
+    // Peers sent over ut_pex after the extension handshake
+    pub pex_peers: Vec<SocketAddr>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct SeederStats {
+    pub connections: Arc<AtomicUsize>,
+    pub blocks_served: Arc<AtomicUsize>,
+    pub keep_alives: Arc<AtomicUsize>,
+    // Requests and cancels received, in order
+    pub requests: Arc<Mutex<Vec<PeerMessage>>>,
+    pub pex_messages: Arc<Mutex<Vec<PexMessage>>>,
+}
+
+impl SeederStats {
+    pub fn connections(&self) -> usize {
+        self.connections.load(Ordering::SeqCst)
+    }
+
+    pub fn blocks_served(&self) -> usize {
+        self.blocks_served.load(Ordering::SeqCst)
+    }
+
+    pub fn keep_alives(&self) -> usize {
+        self.keep_alives.load(Ordering::SeqCst)
+    }
+
+    pub fn requests(&self) -> Vec<PeerMessage> {
+        self.requests.lock().unwrap().clone()
+    }
+
+    pub fn pex_messages(&self) -> Vec<PexMessage> {
+        self.pex_messages.lock().unwrap().clone()
+    }
+}
+
+// Peer having the whole torrent and serving every request it gets.
+pub async fn spawn_seeder(
+    meta_info: &MetaInfoFile,
+    contents: Vec<u8>,
+    behavior: SeederBehavior,
+) -> (SocketAddr, SeederStats) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let stats = SeederStats::default();
+
+    let info_hash = meta_info.info_hash_bytes();
+    let piece_length = meta_info.info.piece_length;
+    let pieces_count = meta_info.info.pieces_count();
+    let contents = Arc::new(contents);
+
+    let task_stats = stats.clone();
+    tokio::spawn(async move {
+        loop {
+            let (stream, _) = listener.accept().await.unwrap();
+            task_stats.connections.fetch_add(1, Ordering::SeqCst);
+
+            let contents = contents.clone();
+            let behavior = behavior.clone();
+            let stats = task_stats.clone();
+            tokio::spawn(async move {
+                let _ = serve_peer(
+                    stream,
+                    info_hash,
+                    piece_length,
+                    pieces_count,
+                    &contents,
+                    &behavior,
+                    &stats,
+                )
+                .await;
+            });
+        }
+    });
+
+    (addr, stats)
+}
+
+async fn serve_peer(
+    stream: TcpStream,
+    info_hash: [u8; 20],
+    piece_length: u32,
+    pieces_count: usize,
+    contents: &[u8],
+    behavior: &SeederBehavior,
+    stats: &SeederStats,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let (reader, mut writer) = stream.into_split();
+    let mut reader = BufReader::new(reader);
+
+    let mut handshake = [0; 68];
+    reader.read_exact(&mut handshake).await?;
+    assert_eq!(handshake[28..48], info_hash);
+    handshake[48..].copy_from_slice(b"-SEEDER-000000000000");
+    writer.write_all(&handshake).await?;
+
+    let mut bitfield = Bitfield::new(pieces_count);
+    match &behavior.pieces {
+        Some(pieces) => pieces.iter().for_each(|&x| bitfield.set(x as usize)),
+        None => (0..pieces_count).for_each(|x| bitfield.set(x)),
+    }
+    PeerMessage::BitField(bitfield.as_bytes().to_vec())
+        .write(&mut writer)
+        .await?;
+
+    let mut served = 0;
+    let mut peer_ut_metadata = None;
+    loop {
+        let msg = PeerMessage::read(&mut reader).await?;
+        if matches!(
+            msg,
+            PeerMessage::Request { .. } | PeerMessage::Cancel { .. }
+        ) {
+            stats.requests.lock().unwrap().push(msg.clone());
+        }
+
+        match msg {
+            PeerMessage::Interested => {
+                tokio::time::sleep(behavior.unchoke_delay).await;
+                PeerMessage::Unchoke.write(&mut writer).await?;
+            }
+            PeerMessage::KeepAlive => {
+                stats.keep_alives.fetch_add(1, Ordering::SeqCst);
+            }
+            PeerMessage::Request { .. } if behavior.stall => {}
+            PeerMessage::Request {
+                index,
+                begin,
+                length,
+            } => {
+                assert!(bitfield.has(index as usize), "Piece {index} not advertised");
+                if behavior.disconnect_after == Some(served) {
+                    return Ok(());
+                }
+                tokio::time::sleep(behavior.block_delay).await;
+
+                let start = index as usize * piece_length as usize + begin as usize;
+                let mut block = contents[start..start + length as usize].to_vec();
+                if behavior.corrupt_piece == Some(index) {
+                    block.iter_mut().for_each(|x| *x = !*x);
+                }
+                PeerMessage::Piece {
+                    index,
+                    begin,
+                    block,
+                }
+                .write(&mut writer)
+                .await?;
+
+                served += 1;
+                stats.blocks_served.fetch_add(1, Ordering::SeqCst);
+            }
+            PeerMessage::Extended { id: 0, payload } => {
+                let handshake = ExtensionHandshake::from_payload(&payload)?;
+                peer_ut_metadata = handshake.extension_id("ut_metadata");
+                let peer_ut_pex = handshake.extension_id("ut_pex");
+                let mut handshake = ExtensionHandshake {
+                    reqq: behavior.reqq,
+                    ..Default::default()
+                };
+                handshake.m.insert("ut_pex".to_string(), 4);
+                if let Some(metadata) = &behavior.metadata {
+                    handshake.m.insert("ut_metadata".to_string(), 3);
+                    handshake.metadata_size = Some(metadata.len() as i64);
+                }
+                handshake.to_message()?.write(&mut writer).await?;
+
+                if let Some(id) = peer_ut_pex.filter(|_| !behavior.pex_peers.is_empty()) {
+                    let msg = PexMessage {
+                        added: behavior.pex_peers.iter().map(|&x| (x, 0)).collect(),
+                        dropped: Vec::new(),
+                    };
+                    PeerMessage::Extended {
+                        id,
+                        payload: msg.to_bytes()?,
+                    }
+                    .write(&mut writer)
+                    .await?;
+                }
+            }
+            PeerMessage::Extended { id: 4, payload } => {
+                let msg = PexMessage::from_bytes(&payload)?;
+                stats.pex_messages.lock().unwrap().push(msg);
+            }
+            PeerMessage::Extended { id: 3, payload } => {
+                let (Some(metadata), Some(peer_id)) = (&behavior.metadata, peer_ut_metadata) else {
+                    continue;
+                };
+                let MetadataMessage::Request(piece) = MetadataMessage::from_bytes(&payload)? else {
+                    continue;
+                };
+                let start = piece as usize * METADATA_PIECE_SIZE;
+                let end = (start + METADATA_PIECE_SIZE).min(metadata.len());
+                let msg = MetadataMessage::Data {
+                    piece,
+                    total_size: metadata.len() as u64,
+                    data: metadata[start..end].to_vec(),
+                };
+                PeerMessage::Extended {
+                    id: peer_id,
+                    payload: msg.to_bytes()?,
+                }
+                .write(&mut writer)
+                .await?;
+            }
+            _ => {}
+        }
+    }
+}
@@ -0,0 +1,268 @@
+mod common;
+
+use std::{sync::Arc, time::Duration};
+
+use bittorrent_starter_rust::trackers::{
+    announcer::{Announcer, AnnouncerConfig, TransferStats},
+    tiers::TrackerList,
+    AnnounceEvent, AnnounceRequest,
+};
+use common::{dead_tracker_url, spawn_http_tracker};
+use tokio::time::{timeout, Instant};
+
+fn announce_request() -> AnnounceRequest {
+    AnnounceRequest {
+        info_hash: [1; 20],
+        peer_id: [2; 20],
+        port: 6881,
+        uploaded: 0,
+        downloaded: 0,
+        left: 0,
+        event: AnnounceEvent::None,
+    }
+}
+
+fn query_param<'a>(request_line: &'a str, key: &str) -> Option<&'a str> {
+    let query = request_line.split_whitespace().nth(1)?.split_once('?')?.1;
+    query
+        .split('&')
+        .filter_map(|x| x.split_once('='))
+        .find(|(k, _)| *k == key)
+        .map(|(_, v)| v)
+}
+
+#[test]
+fn test_transfer_stats() {
+    let stats = TransferStats::new(100);
+    assert_eq!(stats.left(), 100);
+
+    stats.add_downloaded(10);
+    stats.add_downloaded(5);
+    stats.add_uploaded(7);
+    stats.set_left(85);
+
+    assert_eq!(stats.downloaded(), 15);
+    assert_eq!(stats.uploaded(), 7);
+    assert_eq!(stats.left(), 85);
+
+    let ip = "127.0.0.2".parse().unwrap();
+    stats.add_downloaded_from(ip, 10);
+    stats.add_downloaded_from(ip, 6);
+    assert_eq!(stats.downloaded_from(ip), 16);
+    assert_eq!(stats.downloaded_from("127.0.0.3".parse().unwrap()), 0);
+}
+
+#[tokio::test]
+async fn test_announce_reports_stats() {
+    let (url, requests) = spawn_http_tracker(
+        b"d8:intervali900e12:min intervali60e5:peers6:\x7f\x00\x00\x01\x00\x50e".to_vec(),
+    )
+    .await;
+    let stats = Arc::new(TransferStats::new(1000));
+    stats.add_downloaded(200);
+    stats.add_uploaded(50);
+    stats.set_left(800);
+
+    let mut announcer =
+        Announcer::new(TrackerList::new(vec![vec![url]]), announce_request(), stats);
+    let peers = announcer.announce(AnnounceEvent::Started).await.unwrap();
+
+    assert_eq!(peers, vec!["127.0.0.1:80".parse().unwrap()]);
+    assert_eq!(announcer.interval(), Duration::from_secs(900));
+    assert_eq!(announcer.min_interval(), Duration::from_secs(60));
+
+    let requests = requests.lock().unwrap();
+    assert_eq!(query_param(&requests[0], "event"), Some("started"));
+    assert_eq!(query_param(&requests[0], "downloaded"), Some("200"));
+    assert_eq!(query_param(&requests[0], "uploaded"), Some("50"));
+    assert_eq!(query_param(&requests[0], "left"), Some("800"));
+    assert_eq!(query_param(&requests[0], "port"), Some("6881"));
+}
+
+#[tokio::test]
+async fn test_missing_interval() {
+    let (url, _) = spawn_http_tracker(b"d5:peers0:e".to_vec()).await;
+    let mut announcer = Announcer::new(
+        TrackerList::new(vec![vec![url]]),
+        announce_request(),
+        Arc::new(TransferStats::new(1000)),
+    );
+    announcer.announce(AnnounceEvent::Started).await.unwrap();
+    assert_eq!(announcer.interval(), Duration::from_secs(30 * 60));
+
+    let (url, _) = spawn_http_tracker(b"d8:intervali0e5:peers0:e".to_vec()).await;
+    let mut announcer = Announcer::new(
+        TrackerList::new(vec![vec![url]]),
+        announce_request(),
+        Arc::new(TransferStats::new(1000)),
+    );
+    announcer.announce(AnnounceEvent::Started).await.unwrap();
+    assert_eq!(announcer.interval(), Duration::from_secs(30 * 60));
+}
+
+#[tokio::test]
+async fn test_lifecycle() {
+    let (url, requests) =
+        spawn_http_tracker(b"d8:intervali1e5:peers6:\x7f\x00\x00\x01\x00\x50e".to_vec()).await;
+    let stats = Arc::new(TransferStats::new(1000));
+
+    let mut handle = Announcer::new(
+        TrackerList::new(vec![vec![url]]),
+        announce_request(),
+        stats.clone(),
+    )
+    .spawn();
+
+    // Started
+    assert_eq!(handle.next_peers().await.unwrap().unwrap().len(), 1);
+
+    // Periodic update honoring interval
+    stats.add_downloaded(600);
+    stats.set_left(400);
+    let start = Instant::now();
+    handle.next_peers().await.unwrap().unwrap();
+    assert!(start.elapsed() >= Duration::from_millis(900));
+
+    // Completed then stopped
+    stats.add_downloaded(400);
+    stats.set_left(0);
+    handle.completed();
+    handle.next_peers().await.unwrap().unwrap();
+    handle.stop().await.unwrap();
+
+    let requests = requests.lock().unwrap();
+    let events: Vec<_> = requests.iter().map(|x| query_param(x, "event")).collect();
+    assert_eq!(
+        events,
+        vec![Some("started"), None, Some("completed"), Some("stopped")]
+    );
+    assert_eq!(query_param(&requests[1], "downloaded"), Some("600"));
+    assert_eq!(query_param(&requests[1], "left"), Some("400"));
+    assert_eq!(query_param(&requests[3], "downloaded"), Some("1000"));
+    assert_eq!(query_param(&requests[3], "left"), Some("0"));
+}
+
+#[tokio::test]
+async fn test_completed_once_downloaded() {
+    let (url, requests) = spawn_http_tracker(b"d8:intervali1e5:peers0:e".to_vec()).await;
+    let stats = Arc::new(TransferStats::new(1000));
+    let mut handle = Announcer::new(
+        TrackerList::new(vec![vec![url]]),
+        announce_request(),
+        stats.clone(),
+    )
+    .spawn();
+    handle.next_peers().await.unwrap().unwrap();
+
+    // Noticed on the next announce without being told
+    stats.set_left(0);
+    for _ in 0..2 {
+        handle.next_peers().await.unwrap().unwrap();
+    }
+    handle.stop().await.unwrap();
+
+    let requests = requests.lock().unwrap();
+    let events: Vec<_> = requests.iter().map(|x| query_param(x, "event")).collect();
+    assert_eq!(
+        events,
+        vec![Some("started"), Some("completed"), None, Some("stopped")]
+    );
+}
+
+#[tokio::test]
+async fn test_seeder_never_completes() {
+    let (url, requests) = spawn_http_tracker(b"d8:intervali1e5:peers0:e".to_vec()).await;
+    let mut handle = Announcer::new(
+        TrackerList::new(vec![vec![url]]),
+        announce_request(),
+        Arc::new(TransferStats::new(0)),
+    )
+    .spawn();
+    for _ in 0..3 {
+        handle.next_peers().await.unwrap().unwrap();
+    }
+    handle.stop().await.unwrap();
+
+    let requests = requests.lock().unwrap();
+    let events: Vec<_> = requests.iter().map(|x| query_param(x, "event")).collect();
+    assert_eq!(events, vec![Some("started"), None, None, Some("stopped")]);
+}
+
+#[tokio::test]
+async fn test_request_peers_honor_min_interval() {
+    let (url, requests) = spawn_http_tracker(
+        b"d8:intervali3600e12:min intervali1e5:peers6:\x7f\x00\x00\x01\x00\x50e".to_vec(),
+    )
+    .await;
+
+    let mut handle = Announcer::new(
+        TrackerList::new(vec![vec![url]]),
+        announce_request(),
+        Arc::new(TransferStats::new(1000)),
+    )
+    .spawn();
+    handle.next_peers().await.unwrap().unwrap();
+
+    let start = Instant::now();
+    handle.request_peers();
+    timeout(Duration::from_secs(5), handle.next_peers())
+        .await
+        .unwrap()
+        .unwrap()
+        .unwrap();
+    assert!(start.elapsed() >= Duration::from_millis(900));
+
+    handle.stop().await.unwrap();
+    assert_eq!(requests.lock().unwrap().len(), 3);
+}
+
+#[tokio::test]
+async fn test_no_stop_before_start() {
+    let (url, requests) = spawn_http_tracker(b"d8:intervali60e5:peers0:e".to_vec()).await;
+
+    let handle = Announcer::new(
+        TrackerList::new(vec![vec![url]]),
+        announce_request(),
+        Arc::new(TransferStats::new(1000)),
+    )
+    .spawn();
+    handle.stop().await.unwrap();
+
+    // Stop may win the race against the started announce: never send `stopped` alone
+    let requests = requests.lock().unwrap();
+    if let Some(first) = requests.first() {
+        assert_eq!(query_param(first, "event"), Some("started"));
+    }
+}
+
+#[tokio::test]
+async fn test_give_up_without_working_tracker() {
+    let config = AnnouncerConfig {
+        retry_interval: Duration::from_millis(10),
+        max_failures: 3,
+    };
+    let mut handle = Announcer::with_config(
+        TrackerList::new(vec![vec![dead_tracker_url().await]]),
+        announce_request(),
+        Arc::new(TransferStats::new(1000)),
+        config,
+    )
+    .spawn();
+
+    for _ in 0..3 {
+        let announced = timeout(Duration::from_secs(5), handle.next_peers()).await;
+        assert!(announced.unwrap().unwrap().is_err());
+    }
+    assert!(handle.next_peers().await.is_none());
+    handle.stop().await.unwrap();
+
+    // Nothing to announce to
+    let mut handle = Announcer::new(
+        TrackerList::new(vec![]),
+        announce_request(),
+        Arc::new(TransferStats::new(1000)),
+    )
+    .spawn();
+    assert!(handle.next_peers().await.is_none());
+    handle.stop().await.unwrap();
+}
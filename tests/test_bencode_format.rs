@@ -1,6 +1,7 @@
 use std::collections::BTreeMap;
 
-use bittorrent_starter_rust::bencode_format::*;
+use bittorrent_starter_rust::bencode_format::{ParseErrorKind::*, *};
+use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
 
 #[test]
@@ -12,13 +13,17 @@ fn test_derive_error() {
     // Debug
     assert_eq!(
         format!("{:?}", ParseError::new("foo")),
-        "ParseError(\"foo\")"
+        "ParseError { kind: Custom(\"foo\"), offset: 0 }"
     );
 
     // Display + Error
     assert_eq!(
         format!("{}", ParseError::new("foo")),
-        "Bencode parse error: foo"
+        "Bencode parse error at byte 0: foo"
+    );
+    assert_eq!(
+        format!("{}", err(UnexpectedByte(b'x'), 4)),
+        "Bencode parse error at byte 4: unexpected byte 'x'"
     );
 }
 
@@ -55,15 +60,19 @@ fn check(input: &[u8], expected: Value, rem_len: usize) {
     assert_eq!(rem.len(), rem_len);
 }
 
-fn check_err(input: &str, expected: &str) {
-    let err = BencodeValue::parse(input.as_bytes()).unwrap_err();
-    assert_eq!(err, ParseError::new(expected));
+fn err(kind: ParseErrorKind, offset: usize) -> ParseError {
+    ParseError { kind, offset }
+}
+
+fn check_err(input: &str, kind: ParseErrorKind, offset: usize) {
+    let parse_err = BencodeValue::parse(input.as_bytes()).unwrap_err();
+    assert_eq!(parse_err, err(kind, offset));
 }
 
 #[test]
 fn test_parse_invalid_content() {
-    check_err("", "Input is empty: []");
-    check_err("h", "Invalid Bencode content: [104]");
+    check_err("", UnexpectedEof, 0);
+    check_err("h", UnexpectedByte(b'h'), 0);
 }
 
 #[test]
@@ -81,10 +90,10 @@ fn test_parse_string_valid() {
 
 #[test]
 fn test_parse_string_invalid() {
-    check_err("4e", "String num is not a number=101: [52, 101]");
-    check_err("42", "String num does not contains end tag: [52, 50]");
-    check_err("42:", "String payload is too short: []");
-    check_err("2:h", "String payload is too short: [104]");
+    check_err("4e", UnexpectedByte(b'e'), 1);
+    check_err("42", UnexpectedEof, 2);
+    check_err("42:", UnexpectedEof, 3);
+    check_err("2:h", UnexpectedEof, 2);
 }
 
 #[test]
@@ -115,10 +124,10 @@ fn test_parse_int_valid() {
 
 #[test]
 fn test_parse_int_invalid() {
-    check_err("ie", "Number cannot be empty: [101]");
-    check_err("i-e", "Number cannot be empty: [101]");
-    check_err("ixe", "String num is not a number=120: [120, 101]");
-    check_err("i42", "String num does not contains end tag: [52, 50]");
+    check_err("ie", EmptyNumber, 1);
+    check_err("i-e", EmptyNumber, 2);
+    check_err("ixe", UnexpectedByte(b'x'), 1);
+    check_err("i42", UnexpectedEof, 3);
 }
 
 #[test]
@@ -151,8 +160,8 @@ fn test_parse_list_valid() {
 
 #[test]
 fn test_parse_list_invalid() {
-    check_err("l", "List miss end tag: []");
-    check_err("l3:foo2:bare", "Invalid Bencode content: [114, 101]");
+    check_err("l", UnexpectedEof, 1);
+    check_err("l3:foo2:bare", UnexpectedByte(b'r'), 10);
 }
 
 #[test]
@@ -194,10 +203,10 @@ fn test_parse_dict_valid() {
 
 #[test]
 fn test_parse_dict_invalid() {
-    check_err("d", "Dict miss end tag: []");
-    check_err("d3:foode", "Dict miss end tag: []");
-    check_err("dlee", "String num is not a number=108: [108, 101, 101]");
-    check_err("d3:fooe", "Invalid Bencode content: [101]");
+    check_err("d", UnexpectedEof, 1);
+    check_err("d3:foode", UnexpectedEof, 8);
+    check_err("dlee", UnexpectedByte(b'l'), 1);
+    check_err("d3:fooe", UnexpectedByte(b'e'), 6);
 }
 
 #[test]
@@ -217,3 +226,289 @@ fn test_encode_dict() {
     .unwrap();
     assert_eq!(buf, b"d2:hilee");
 }
+
+#[derive(Debug, PartialEq, Eq, Deserialize, Serialize)]
+struct Sample {
+    name: String,
+    #[serde(rename = "piece length")]
+    piece_length: u32,
+    #[serde(with = "serde_bytes")]
+    pieces: Vec<u8>,
+    tags: Vec<String>,
+    comment: Option<String>,
+    private: bool,
+}
+
+#[test]
+fn test_from_bytes_struct() {
+    let sample: Sample = from_bytes(
+        b"d7:comment2:hi4:name4:test12:piece lengthi256e6:pieces3:\xfd\xc4\x007:privatei1e4:tagsl1:a1:bee",
+    )
+    .unwrap();
+
+    assert_eq!(
+        sample,
+        Sample {
+            name: "test".to_string(),
+            piece_length: 256,
+            pieces: vec![253, 196, 0],
+            tags: vec!["a".to_string(), "b".to_string()],
+            comment: Some("hi".to_string()),
+            private: true,
+        }
+    );
+}
+
+#[test]
+fn test_from_bytes_missing_and_unknown_fields() {
+    let sample: Sample = from_bytes(
+        b"d5:extrad1:xli1eee4:name4:test12:piece lengthi256e6:pieces0:7:privatei0e4:tagslee",
+    )
+    .unwrap();
+
+    assert_eq!(sample.comment, None);
+    assert!(!sample.private);
+}
+
+#[test]
+fn test_from_bytes_borrowed() {
+    let input = b"l5:hello3:\xff\x00\x01e";
+    let (text, data): (&str, &[u8]) = from_bytes(input).unwrap();
+    assert_eq!(text, "hello");
+    assert_eq!(data, &[255, 0, 1]);
+}
+
+#[test]
+fn test_from_bytes_invalid() {
+    assert_eq!(
+        from_bytes::<i64>(b"i42eabc").unwrap_err(),
+        err(TrailingData, 4)
+    );
+    assert_eq!(
+        from_bytes::<Vec<i64>>(b"li42e").unwrap_err(),
+        err(UnexpectedEof, 5)
+    );
+    assert_eq!(
+        from_bytes::<u8>(b"i300e").unwrap_err(),
+        ParseError::custom("invalid value: integer `300`, expected u8", 5)
+    );
+    assert!(from_bytes::<String>(b"2:\xff\xfe").is_err());
+}
+
+#[test]
+fn test_to_bytes_struct() {
+    let sample = Sample {
+        name: "test".to_string(),
+        piece_length: 256,
+        pieces: vec![253, 196, 0],
+        tags: vec!["a".to_string()],
+        comment: None,
+        private: false,
+    };
+
+    let encoded = to_bytes(&sample).unwrap();
+    assert_eq!(
+        encoded,
+        b"d4:name4:test12:piece lengthi256e6:pieces3:\xfd\xc4\x007:privatei0e4:tagsl1:aee"
+    );
+    assert_eq!(from_bytes::<Sample>(&encoded).unwrap(), sample);
+}
+
+#[test]
+fn test_to_bytes_invalid() {
+    assert_eq!(
+        to_bytes(&1.5).unwrap_err(),
+        ParseError::new("Float are not supported")
+    );
+    assert_eq!(
+        to_bytes(&u64::MAX).unwrap_err(),
+        ParseError::new("Integer is too large")
+    );
+    assert_eq!(
+        to_bytes(&Option::<i64>::None).unwrap_err(),
+        ParseError::new("Cannot serialize empty value")
+    );
+}
+
+#[test]
+fn test_enum_round_trip() {
+    #[derive(Debug, PartialEq, Eq, Deserialize, Serialize)]
+    enum Event {
+        Started,
+        Progress(i64),
+        Stats { up: i64, down: i64 },
+    }
+
+    for (event, encoded) in [
+        (Event::Started, &b"7:Started"[..]),
+        (Event::Progress(42), b"d8:Progressi42ee"),
+        (
+            Event::Stats { up: 1, down: 2 },
+            b"d5:Statsd4:downi2e2:upi1eee",
+        ),
+    ] {
+        assert_eq!(to_bytes(&event).unwrap(), encoded);
+        assert_eq!(from_bytes::<Event>(encoded).unwrap(), event);
+    }
+}
+
+#[test]
+fn test_from_bytes_with_span() {
+    let input = b"d3:fooi42e4:infod1:xli1eee3:zzz0:e";
+    let (value, span) = from_bytes_with_span::<Value>(input, b"foo").unwrap();
+    assert_eq!(value, json!({"foo": 42, "info": {"x": [1]}, "zzz": ""}));
+    assert_eq!(span, Some(6..10));
+
+    let (_, span) = from_bytes_with_span::<Value>(input, b"info").unwrap();
+    assert_eq!(span, Some(16..26));
+    assert_eq!(&input[16..26], b"d1:xli1eee");
+
+    // Only top level keys count
+    let (_, span) = from_bytes_with_span::<Value>(input, b"x").unwrap();
+    assert_eq!(span, None);
+
+    // Values skipped by serde are located all the same
+    #[derive(Debug, Deserialize)]
+    struct OnlyFoo {
+        foo: i64,
+    }
+    let (value, span) = from_bytes_with_span::<OnlyFoo>(input, b"info").unwrap();
+    assert_eq!(value.foo, 42);
+    assert_eq!(span, Some(16..26));
+
+    assert_eq!(
+        from_bytes_with_span::<Value>(b"d3:fooi1e", b"bar").unwrap_err(),
+        err(UnexpectedEof, 9)
+    );
+}
+
+#[test]
+fn test_parse_ref() {
+    let input = b"d3:fooli42e3:bare4:spami-7ee";
+    let (rem, value) = BencodeRef::parse(input).unwrap();
+    assert!(rem.is_empty());
+
+    // Byte strings borrow from the input buffer
+    let foo = value.get(b"foo").unwrap().as_list().unwrap();
+    assert_eq!(foo[0].as_integer(), Some(42));
+    assert_eq!(foo[1].as_bytes().unwrap().as_ptr(), input[13..].as_ptr());
+    assert_eq!(foo[1].as_str(), Some("bar"));
+    assert_eq!(value.get(b"spam").unwrap().as_integer(), Some(-7));
+    assert_eq!(value.get(b"eggs"), None);
+    assert_eq!(foo[0].as_bytes(), None);
+    assert_eq!(foo[0].as_dict(), None);
+    assert_eq!(foo[0].get(b"foo"), None);
+}
+
+#[test]
+fn test_parse_ref_with_extra() {
+    // Extension messages carry raw data after the bencoded dict
+    let (rem, value) = BencodeRef::parse(b"d8:msg_typei1ee\x01\x02\x03").unwrap();
+    assert_eq!(value.get(b"msg_type").unwrap().as_integer(), Some(1));
+    assert_eq!(rem, &[1, 2, 3]);
+}
+
+#[test]
+fn test_ref_into_owned() {
+    let input = b"d3:fooli42e3:bare4:spamdee";
+    let (_, value) = BencodeRef::parse(input).unwrap();
+    let (_, expected) = BencodeValue::parse(input).unwrap();
+    assert_eq!(value.clone().into_owned(), expected);
+
+    // Encoding gives back the original bytes
+    let mut buf = Vec::new();
+    value.encode(&mut buf).unwrap();
+    assert_eq!(buf, input);
+}
+
+#[test]
+fn test_parse_ref_invalid() {
+    assert_eq!(BencodeRef::parse(b"").unwrap_err(), err(UnexpectedEof, 0));
+    assert_eq!(
+        BencodeRef::parse(b"l3:foo").unwrap_err(),
+        err(UnexpectedEof, 6)
+    );
+}
+
+fn check_options_err(input: &[u8], options: ParseOptions, kind: ParseErrorKind, offset: usize) {
+    let parse_err = BencodeRef::parse_with_options(input, options).unwrap_err();
+    assert_eq!(parse_err, err(kind, offset));
+}
+
+#[test]
+fn test_parse_int_overflow() {
+    check(b"i9223372036854775807e", json!(i64::MAX), 0);
+    check(b"i-9223372036854775808e", json!(i64::MIN), 0);
+
+    check_err("i9223372036854775808e", IntegerOverflow, 1);
+    check_err("i-9223372036854775809e", IntegerOverflow, 1);
+    check_err("i99999999999999999999999e", IntegerOverflow, 1);
+    check_err("99999999999999999999999:a", IntegerOverflow, 0);
+}
+
+#[test]
+fn test_parse_depth_limit() {
+    let options = ParseOptions {
+        max_depth: 3,
+        ..Default::default()
+    };
+    assert!(BencodeRef::parse_with_options(b"llleee", options).is_ok());
+    check_options_err(b"lllleeee", options, DepthLimitExceeded, 3);
+    check_options_err(
+        b"ld1:xdeee",
+        ParseOptions {
+            max_depth: 2,
+            ..options
+        },
+        DepthLimitExceeded,
+        5,
+    );
+
+    // Default options must protect the stack against hostile payloads
+    let hostile = vec![b'l'; 100_000];
+    check_options_err(&hostile, ParseOptions::default(), DepthLimitExceeded, 64);
+    assert_eq!(
+        from_bytes::<Value>(&hostile).unwrap_err(),
+        err(DepthLimitExceeded, 64)
+    );
+}
+
+#[test]
+fn test_parse_string_limit() {
+    let options = ParseOptions {
+        max_string_length: 4,
+        ..Default::default()
+    };
+    assert!(BencodeRef::parse_with_options(b"4:spam", options).is_ok());
+    check_options_err(b"l5:hello", options, StringTooLong(5), 1);
+}
+
+#[test]
+fn test_parse_strict() {
+    let strict = ParseOptions::strict();
+
+    // Lenient mode accepts non canonical forms
+    check(b"i-0e", json!(0), 0);
+    check(b"i007e", json!(7), 0);
+    check(b"03:foo", json!("foo"), 0);
+    check(b"d1:bi1e1:ai2ee", json!({"a": 2, "b": 1}), 0);
+    check(b"d1:ai1e1:ai2ee", json!({"a": 2}), 0);
+
+    // Strict mode rejects them
+    assert!(BencodeRef::parse_with_options(b"d1:ai0e1:bi-1ee", strict).is_ok());
+    check_options_err(b"i-0e", strict, NonCanonicalInteger, 1);
+    check_options_err(b"i007e", strict, NonCanonicalInteger, 1);
+    check_options_err(b"03:foo", strict, NonCanonicalInteger, 0);
+    check_options_err(b"d1:bi1e1:ai2ee", strict, UnsortedKey, 7);
+    check_options_err(b"d1:ai1e1:ai2ee", strict, DuplicateKey, 7);
+
+    // Serde path applies the same rules
+    assert_eq!(
+        from_bytes_with_options::<BTreeMap<String, i64>>(b"d1:bi1e1:ai2ee", strict).unwrap_err(),
+        err(UnsortedKey, 7)
+    );
+    assert_eq!(
+        from_bytes_with_options::<i64>(b"i-0e", strict).unwrap_err(),
+        err(NonCanonicalInteger, 1)
+    );
+}
@@ -0,0 +1,53 @@
+use bittorrent_starter_rust::bitfield::Bitfield;
+
+#[test]
+fn test_set_and_has() {
+    let mut bitfield = Bitfield::new(10);
+    assert_eq!(bitfield.len(), 10);
+    assert_eq!(bitfield.as_bytes(), [0, 0]);
+    assert!(!bitfield.has(0));
+
+    bitfield.set(0);
+    bitfield.set(9);
+    assert_eq!(bitfield.as_bytes(), [0b1000_0000, 0b0100_0000]);
+    assert!(bitfield.has(0));
+    assert!(bitfield.has(9));
+    assert!(!bitfield.has(10));
+    assert_eq!(bitfield.count_ones(), 2);
+    assert_eq!(bitfield.ones().collect::<Vec<_>>(), vec![0, 9]);
+
+    bitfield.unset(0);
+    assert_eq!(bitfield.ones().collect::<Vec<_>>(), vec![9]);
+    assert!(!bitfield.is_full());
+}
+
+#[test]
+fn test_full() {
+    let bitfield = Bitfield::full(10);
+    assert_eq!(bitfield.as_bytes(), [0xff, 0b1100_0000]);
+    assert!(bitfield.is_full());
+
+    assert!(Bitfield::new(0).is_empty());
+    assert!(Bitfield::new(0).is_full());
+}
+
+#[test]
+fn test_from_bytes() {
+    let bitfield = Bitfield::from_bytes(&[0b1010_0000, 0b1000_0000], 9).unwrap();
+    assert_eq!(bitfield.ones().collect::<Vec<_>>(), vec![0, 2, 8]);
+
+    let err = Bitfield::from_bytes(&[0xff], 9).unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "Peer: Invalid bit field length: 1 bytes for 9 pieces"
+    );
+
+    let err = Bitfield::from_bytes(&[0xff, 0b1100_0000], 9).unwrap_err();
+    assert_eq!(err.to_string(), "Peer: Bit field has spare bits set");
+}
+
+#[test]
+#[should_panic = "Piece 10 out of bit field"]
+fn test_set_out_of_range() {
+    Bitfield::new(10).set(10);
+}
@@ -0,0 +1,106 @@
+use std::{
+    collections::HashSet,
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+use bittorrent_starter_rust::choker::{ChokeCandidate, Choker, ChokerConfig};
+
+fn addr(port: u16) -> SocketAddr {
+    SocketAddr::from(([127, 0, 0, 1], port))
+}
+
+fn candidate(port: u16, interested: bool, rate: u64) -> ChokeCandidate {
+    ChokeCandidate {
+        addr: addr(port),
+        interested,
+        rate,
+    }
+}
+
+fn choker(upload_slots: usize) -> Choker {
+    Choker::new(ChokerConfig {
+        upload_slots,
+        ..Default::default()
+    })
+}
+
+#[test]
+fn test_fastest_peers_unchoked() {
+    let mut choker = choker(3);
+    let peers = [
+        candidate(1, true, 100),
+        candidate(2, true, 300),
+        candidate(3, false, 1000),
+        candidate(4, true, 200),
+        candidate(5, true, 50),
+    ];
+
+    let unchoked = choker.rechoke(&peers, Instant::now());
+    assert_eq!(unchoked.len(), 3);
+    assert!(unchoked.contains(&addr(2)));
+    assert!(unchoked.contains(&addr(4)));
+    // Not interested peers are never unchoked
+    assert!(!unchoked.contains(&addr(3)));
+
+    // Last slot is the optimistic unchoke, among the slower peers
+    let optimistic = choker.optimistic().unwrap();
+    assert!([addr(1), addr(5)].contains(&optimistic));
+    assert!(unchoked.contains(&optimistic));
+}
+
+#[test]
+fn test_optimistic_unchoke_rotation() {
+    let mut choker = choker(2);
+    let peers = [
+        candidate(1, true, 1000),
+        candidate(2, true, 0),
+        candidate(3, true, 0),
+        candidate(4, true, 0),
+    ];
+    let start = Instant::now();
+
+    choker.rechoke(&peers, start);
+    let optimistic = choker.optimistic().unwrap();
+    assert_ne!(optimistic, addr(1));
+
+    // Kept between regular rechokes
+    for seconds in [10, 20] {
+        let unchoked = choker.rechoke(&peers, start + Duration::from_secs(seconds));
+        assert_eq!(unchoked, HashSet::from([addr(1), optimistic]));
+    }
+
+    // Then moved to another peer
+    let unchoked = choker.rechoke(&peers, start + Duration::from_secs(30));
+    let next = choker.optimistic().unwrap();
+    assert_ne!(next, optimistic);
+    assert_eq!(unchoked, HashSet::from([addr(1), next]));
+}
+
+#[test]
+fn test_optimistic_unchoke_lost_interest() {
+    let mut choker = choker(1);
+    let now = Instant::now();
+
+    choker.rechoke(&[candidate(1, true, 0)], now);
+    assert_eq!(choker.optimistic(), Some(addr(1)));
+
+    let unchoked = choker.rechoke(&[candidate(1, false, 0), candidate(2, true, 0)], now);
+    assert_eq!(unchoked, HashSet::from([addr(2)]));
+}
+
+#[test]
+fn test_no_upload_slots() {
+    let mut choker = choker(0);
+    let unchoked = choker.rechoke(&[candidate(1, true, 100)], Instant::now());
+    assert!(unchoked.is_empty());
+    assert_eq!(choker.optimistic(), None);
+}
+
+#[test]
+fn test_few_peers() {
+    let mut choker = choker(4);
+    let peers = [candidate(1, true, 0), candidate(2, true, 10)];
+    let unchoked = choker.rechoke(&peers, Instant::now());
+    assert_eq!(unchoked, HashSet::from([addr(1), addr(2)]));
+}
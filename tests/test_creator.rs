@@ -0,0 +1,197 @@
+mod common;
+
+use std::{fs, path::Path};
+
+use bittorrent_starter_rust::{
+    bencode_format::BencodeRef,
+    bitfield::Bitfield,
+    creator::{auto_piece_length, create_torrent, CreateOptions},
+    error::TorrentError,
+    resume::check_pieces,
+    storage::FileStorage,
+    torrent_file::MetaInfoFile,
+    utils::hash_sha1,
+};
+use common::sample_contents;
+use tempfile::tempdir;
+
+fn options(trackers: &[&[&str]]) -> CreateOptions {
+    CreateOptions {
+        trackers: trackers
+            .iter()
+            .map(|tier| tier.iter().map(|x| x.to_string()).collect())
+            .collect(),
+        ..CreateOptions::default()
+    }
+}
+
+fn write_file(path: &Path, contents: &[u8]) {
+    fs::create_dir_all(path.parent().unwrap()).unwrap();
+    fs::write(path, contents).unwrap();
+}
+
+#[test]
+fn test_create_single_file() {
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("sample.bin");
+    let contents = sample_contents(40_000);
+    write_file(&path, &contents);
+
+    let mut options = options(&[&["http://tracker.test/announce"]]);
+    options.piece_length = Some(16 << 10);
+    let torrent = create_torrent(&path, &options).unwrap();
+    let meta_info = MetaInfoFile::from_bytes(&torrent).unwrap();
+
+    assert_eq!(meta_info.announce, "http://tracker.test/announce");
+    assert_eq!(meta_info.announce_list, None);
+    assert_eq!(meta_info.info.name, "sample.bin");
+    assert_eq!(meta_info.info.length, Some(40_000));
+    assert_eq!(meta_info.info.piece_length, 16 << 10);
+    let hashes: Vec<u8> = contents.chunks(16 << 10).flat_map(hash_sha1).collect();
+    assert_eq!(meta_info.info.pieces, hashes);
+}
+
+#[test]
+fn test_create_multi_file() {
+    let dir = tempdir().unwrap();
+    let root = dir.path().join("root");
+    let contents = sample_contents(50_000);
+    // Listed by name: pieces span b.bin, the empty file and sub/c.bin
+    write_file(&root.join("sub/c.bin"), &contents[30_000..]);
+    write_file(&root.join("b.bin"), &contents[..30_000]);
+    write_file(&root.join("empty"), b"");
+
+    let mut options = options(&[&["http://tracker.test/announce"]]);
+    options.piece_length = Some(16 << 10);
+    let torrent = create_torrent(&root, &options).unwrap();
+    let meta_info = MetaInfoFile::from_bytes(&torrent).unwrap();
+
+    assert_eq!(meta_info.info.name, "root");
+    assert_eq!(meta_info.info.length, None);
+    let files: Vec<_> = meta_info
+        .info
+        .files()
+        .into_iter()
+        .map(|x| (x.path, x.length))
+        .collect();
+    assert_eq!(
+        files,
+        vec![
+            (Path::new("root/b.bin").to_path_buf(), 30_000),
+            (Path::new("root/empty").to_path_buf(), 0),
+            (Path::new("root/sub/c.bin").to_path_buf(), 20_000),
+        ]
+    );
+    let hashes: Vec<u8> = contents.chunks(16 << 10).flat_map(hash_sha1).collect();
+    assert_eq!(meta_info.info.pieces, hashes);
+
+    // The files we hashed are a complete download of the torrent
+    let storage = FileStorage::create(&meta_info.info, dir.path()).unwrap();
+    assert_eq!(check_pieces(&storage, &meta_info.info), Bitfield::full(4));
+}
+
+#[test]
+fn test_create_optional_keys() {
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("sample.bin");
+    write_file(&path, &sample_contents(1000));
+
+    let options = CreateOptions {
+        comment: Some("Nightly build".to_string()),
+        created_by: Some("tests".to_string()),
+        creation_date: Some(1_700_000_000),
+        private: true,
+        web_seeds: vec!["http://mirror.test/files/".to_string()],
+        ..options(&[
+            &["http://a.test/announce", "http://b.test/announce"],
+            &["udp://c.test:80"],
+        ])
+    };
+    let torrent = create_torrent(&path, &options).unwrap();
+
+    let meta_info = MetaInfoFile::from_bytes(&torrent).unwrap();
+    assert_eq!(meta_info.announce, "http://a.test/announce");
+    assert_eq!(
+        meta_info.announce_list,
+        Some(vec![
+            vec![
+                "http://a.test/announce".to_string(),
+                "http://b.test/announce".to_string()
+            ],
+            vec!["udp://c.test:80".to_string()],
+        ])
+    );
+    assert_eq!(meta_info.comment.as_deref(), Some("Nightly build"));
+    assert_eq!(meta_info.created_by.as_deref(), Some("tests"));
+
+    let (_, value) = BencodeRef::parse(&torrent).unwrap();
+    let creation_date = value.get(b"creation date").and_then(|x| x.as_integer());
+    assert_eq!(creation_date, Some(1_700_000_000));
+    let web_seeds = value.get(b"url-list").and_then(|x| x.as_list()).unwrap();
+    assert_eq!(web_seeds[0].as_str(), Some("http://mirror.test/files/"));
+    let info = value.get(b"info").unwrap();
+    assert_eq!(info.get(b"private").and_then(|x| x.as_integer()), Some(1));
+    assert_eq!(
+        info.get(b"piece length").and_then(|x| x.as_integer()),
+        Some(16 << 10)
+    );
+}
+
+#[test]
+fn test_create_defaults_leave_keys_out() {
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("sample.bin");
+    write_file(&path, &sample_contents(1000));
+
+    let torrent = create_torrent(&path, &options(&[&["http://tracker.test/announce"]])).unwrap();
+    let (_, value) = BencodeRef::parse(&torrent).unwrap();
+    for key in [
+        &b"announce-list"[..],
+        b"comment",
+        b"created by",
+        b"creation date",
+        b"url-list",
+    ] {
+        assert!(value.get(key).is_none());
+    }
+    assert!(value.get(b"info").unwrap().get(b"private").is_none());
+}
+
+#[test]
+fn test_create_errors() {
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("sample.bin");
+    write_file(&path, &sample_contents(1000));
+
+    let result = create_torrent(&path, &CreateOptions::default());
+    assert!(matches!(result, Err(TorrentError::Create(_))));
+
+    for piece_length in [0, 1000, 8 << 10] {
+        let mut options = options(&[&["http://tracker.test/announce"]]);
+        options.piece_length = Some(piece_length);
+        let result = create_torrent(&path, &options);
+        assert!(matches!(result, Err(TorrentError::Create(_))));
+    }
+
+    let empty = dir.path().join("empty");
+    fs::create_dir(&empty).unwrap();
+    let result = create_torrent(&empty, &options(&[&["http://tracker.test/announce"]]));
+    assert!(matches!(result, Err(TorrentError::Create(_))));
+
+    let missing = dir.path().join("missing");
+    let result = create_torrent(&missing, &options(&[&["http://tracker.test/announce"]]));
+    assert!(matches!(result, Err(TorrentError::Io(_))));
+}
+
+#[test]
+fn test_auto_piece_length() {
+    assert_eq!(auto_piece_length(0), 16 << 10);
+    assert_eq!(auto_piece_length(1 << 20), 16 << 10);
+    assert_eq!(auto_piece_length(1 << 30), 1 << 20);
+    assert_eq!(auto_piece_length(1 << 40), 16 << 20);
+    for length in [1u64 << 20, 100 << 20, 1 << 30, 4 << 30] {
+        let piece_length = auto_piece_length(length);
+        assert!(piece_length.is_power_of_two());
+        assert!(length / piece_length as u64 <= 1500);
+    }
+}
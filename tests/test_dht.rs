@@ -0,0 +1,505 @@
+mod common;
+
+use std::{net::SocketAddr, sync::Arc, time::Duration};
+
+use bittorrent_starter_rust::{
+    dht::{
+        krpc::{Krpc, KrpcMessage, Query, Response, ERROR_GENERIC, ERROR_PROTOCOL},
+        routing::{NodeId, NodeInfo, RoutingTable, K},
+        tokens::TokenManager,
+        Dht, DhtConfig, DhtState,
+    },
+    error::TorrentError,
+    session::DownloadSession,
+    storage::MemoryStorage,
+    torrent_file::MetaInfoFile,
+    trackers::{announcer::TransferStats, tiers::TrackerList},
+};
+use common::{sample_contents, spawn_seeder, SeederBehavior};
+use tempfile::tempdir;
+use tokio::{
+    net::{lookup_host, UdpSocket},
+    time::{sleep, timeout},
+};
+
+fn addr(s: &str) -> SocketAddr {
+    s.parse().unwrap()
+}
+
+// ID sharing `prefix` bits with `id`, then differing
+fn id_with_prefix(id: &NodeId, prefix: usize, last: u8) -> NodeId {
+    let mut other = id.random_with_prefix(prefix);
+    other.0[19] = last;
+    other
+}
+
+fn fast_config() -> DhtConfig {
+    DhtConfig {
+        query_timeout: Duration::from_millis(500),
+        ..Default::default()
+    }
+}
+
+// Local network of nodes all joining through the first one
+async fn spawn_nodes(count: usize) -> Vec<Dht> {
+    let mut nodes: Vec<Dht> = Vec::new();
+    for _ in 0..count {
+        let node = Dht::bind_with_config(addr("127.0.0.1:0"), fast_config())
+            .await
+            .unwrap();
+        if let Some(first) = nodes.first() {
+            node.bootstrap(&[first.local_addr().unwrap()])
+                .await
+                .unwrap();
+        }
+        nodes.push(node);
+    }
+    nodes
+}
+
+#[test]
+fn test_krpc_messages() {
+    // Examples from BEP 5
+    let ping = KrpcMessage {
+        transaction: b"aa".to_vec(),
+        body: Krpc::Query {
+            id: NodeId(*b"abcdefghij0123456789"),
+            query: Query::Ping,
+        },
+    };
+    let bytes = ping.to_bytes().unwrap();
+    assert_eq!(
+        bytes,
+        b"d1:ad2:id20:abcdefghij0123456789e1:q4:ping1:t2:aa1:y1:qe"
+    );
+    assert_eq!(KrpcMessage::from_bytes(&bytes).unwrap(), ping);
+
+    let pong = KrpcMessage {
+        transaction: b"aa".to_vec(),
+        body: Krpc::Response(Response {
+            id: NodeId(*b"mnopqrstuvwxyz123456"),
+            ..Default::default()
+        }),
+    };
+    let bytes = pong.to_bytes().unwrap();
+    assert_eq!(bytes, b"d1:rd2:id20:mnopqrstuvwxyz123456e1:t2:aa1:y1:re");
+    assert_eq!(KrpcMessage::from_bytes(&bytes).unwrap(), pong);
+
+    let error = KrpcMessage {
+        transaction: b"aa".to_vec(),
+        body: Krpc::Error {
+            code: ERROR_GENERIC,
+            message: "A Generic Error Ocurred".to_string(),
+        },
+    };
+    let bytes = error.to_bytes().unwrap();
+    assert_eq!(
+        bytes,
+        b"d1:eli201e23:A Generic Error Ocurrede1:t2:aa1:y1:ee"
+    );
+    assert_eq!(KrpcMessage::from_bytes(&bytes).unwrap(), error);
+
+    let announce = KrpcMessage {
+        transaction: b"aa".to_vec(),
+        body: Krpc::Query {
+            id: NodeId(*b"abcdefghij0123456789"),
+            query: Query::AnnouncePeer {
+                info_hash: *b"mnopqrstuvwxyz123456",
+                port: 6881,
+                implied_port: true,
+                token: b"aoeusnth".to_vec(),
+            },
+        },
+    };
+    let bytes = announce.to_bytes().unwrap();
+    assert_eq!(
+        bytes,
+        &b"d1:ad2:id20:abcdefghij012345678912:implied_porti1e9:info_hash20:mnopqrstuvwxyz1234564:porti6881e5:token8:aoeusnthe1:q13:announce_peer1:t2:aa1:y1:qe"[..]
+    );
+    assert_eq!(KrpcMessage::from_bytes(&bytes).unwrap(), announce);
+
+    // Other queries and responses survive a round trip
+    for body in [
+        Krpc::Query {
+            id: NodeId([1; 20]),
+            query: Query::FindNode {
+                target: NodeId([2; 20]),
+            },
+        },
+        Krpc::Query {
+            id: NodeId([1; 20]),
+            query: Query::GetPeers { info_hash: [3; 20] },
+        },
+        Krpc::Response(Response {
+            id: NodeId([1; 20]),
+            nodes: vec![
+                NodeInfo {
+                    id: NodeId([4; 20]),
+                    addr: addr("10.0.0.1:6881"),
+                },
+                NodeInfo {
+                    id: NodeId([5; 20]),
+                    addr: addr("[2001:db8::1]:6882"),
+                },
+            ],
+            values: vec![addr("10.0.0.2:51413"), addr("[2001:db8::2]:51413")],
+            token: Some(b"token".to_vec()),
+        }),
+    ] {
+        let msg = KrpcMessage {
+            transaction: vec![0, 1],
+            body,
+        };
+        assert_eq!(
+            KrpcMessage::from_bytes(&msg.to_bytes().unwrap()).unwrap(),
+            msg
+        );
+    }
+
+    for packet in [
+        &b"d1:ad2:id20:abcdefghij0123456789e1:q4:pong1:t2:aa1:y1:qe"[..],
+        b"d1:ad2:id3:abce1:q4:ping1:t2:aa1:y1:qe",
+        b"d1:ad2:id20:abcdefghij0123456789e1:q9:find_node1:t2:aa1:y1:qe",
+        b"d1:rd2:id20:mnopqrstuvwxyz1234565:nodes3:abce1:t2:aa1:y1:re",
+        b"d1:t2:aa1:y1:xe",
+        b"d1:t2:aa1:y1:ee",
+        b"garbage",
+    ] {
+        assert!(matches!(
+            KrpcMessage::from_bytes(packet),
+            Err(TorrentError::Dht(_) | TorrentError::Bencode(_))
+        ));
+    }
+}
+
+#[test]
+fn test_node_id() {
+    let a = NodeId([0; 20]);
+    let mut b = NodeId([0; 20]);
+    b.0[2] = 0x10;
+    assert_eq!(a.distance(&b), b);
+    assert_eq!(b.distance(&b), a);
+    assert_eq!(a.common_prefix(&b), 19);
+    assert_eq!(a.common_prefix(&a), 160);
+
+    let id = NodeId::random();
+    assert_ne!(id, NodeId::random());
+    for prefix in [0, 1, 7, 8, 100, 159] {
+        assert_eq!(id.common_prefix(&id.random_with_prefix(prefix)), prefix);
+    }
+}
+
+#[test]
+fn test_compact_node_info() {
+    let nodes = vec![
+        NodeInfo {
+            id: NodeId([1; 20]),
+            addr: addr("1.2.3.4:6881"),
+        },
+        NodeInfo {
+            id: NodeId([2; 20]),
+            addr: addr("5.6.7.8:80"),
+        },
+    ];
+    let compact: Vec<u8> = nodes.iter().flat_map(|x| x.encode_compact()).collect();
+    assert_eq!(compact.len(), 52);
+    assert_eq!(&compact[20..26], &[1, 2, 3, 4, 0x1a, 0xe1]);
+    assert_eq!(NodeInfo::decode_compact(&compact, false), Some(nodes));
+    assert_eq!(NodeInfo::decode_compact(&compact[1..], false), None);
+}
+
+#[test]
+fn test_routing_table() {
+    let id = NodeId::random();
+    let mut table = RoutingTable::new(id);
+    assert!(!table.insert(NodeInfo {
+        id,
+        addr: addr("10.0.0.1:1"),
+    }));
+
+    // Far nodes all land in the first bucket, which fills up
+    let far: Vec<_> = (0..K as u8 + 1)
+        .map(|x| NodeInfo {
+            id: id_with_prefix(&id, 0, x),
+            addr: addr(&format!("10.0.0.{}:1000", x + 1)),
+        })
+        .collect();
+    for node in &far[..K] {
+        assert!(table.insert(*node));
+    }
+    assert!(!table.insert(far[K]));
+    assert_eq!(table.len(), K);
+    // Known nodes are only refreshed
+    assert!(table.insert(far[0]));
+    assert_eq!(table.len(), K);
+
+    // Nodes that stop answering make room for new ones
+    table.fail(far[1].addr);
+    assert!(!table.insert(far[K]));
+    table.fail(far[1].addr);
+    assert!(!table.closest(&id, 100).contains(&far[1]));
+    assert!(table.insert(far[K]));
+    assert_eq!(table.len(), K);
+
+    // Closer nodes have buckets of their own
+    let near = NodeInfo {
+        id: id_with_prefix(&id, 150, 0),
+        addr: addr("10.0.1.1:1000"),
+    };
+    assert!(table.insert(near));
+    assert_eq!(table.get(&near.id), Some(near));
+    let closest = table.closest(&id, 3);
+    assert_eq!(closest.len(), 3);
+    assert_eq!(closest[0], near);
+    assert!(id.distance(&closest[1].id) <= id.distance(&closest[2].id));
+    assert_eq!(table.nodes()[0], near);
+
+    // Every bucket down to the near node was just changed
+    assert!(table.refresh_targets(Duration::from_secs(60)).is_empty());
+    let targets = table.refresh_targets(Duration::ZERO);
+    assert_eq!(targets.len(), 151);
+    assert_eq!(id.common_prefix(&targets[42]), 42);
+}
+
+#[test]
+fn test_tokens() {
+    let mut tokens = TokenManager::new(Duration::from_secs(300));
+    let ip = "10.0.0.1".parse().unwrap();
+    let token = tokens.token(ip);
+    assert!(tokens.validate(ip, &token));
+    assert!(!tokens.validate("10.0.0.2".parse().unwrap(), &token));
+    assert!(!tokens.validate(ip, b"garbage"));
+
+    // Tokens last until the secret changed twice
+    tokens.rotate();
+    assert!(tokens.validate(ip, &token));
+    assert_ne!(tokens.token(ip), token);
+    tokens.rotate();
+    assert!(!tokens.validate(ip, &token));
+}
+
+#[tokio::test]
+async fn test_ping() {
+    let nodes = spawn_nodes(2).await;
+    let id = nodes[0].ping(nodes[1].local_addr().unwrap()).await.unwrap();
+    assert_eq!(id, nodes[1].id());
+    // Nodes learn about each other
+    assert!(nodes[0].nodes().iter().any(|x| x.id == nodes[1].id()));
+    assert!(nodes[1].nodes().iter().any(|x| x.id == nodes[0].id()));
+
+    // Nobody answers there
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let result = nodes[0].ping(socket.local_addr().unwrap()).await;
+    assert!(matches!(result, Err(TorrentError::Dht(_))));
+}
+
+#[tokio::test]
+async fn test_bootstrap() {
+    let nodes = spawn_nodes(10).await;
+    // Nodes joining later are found through the closest ones
+    for node in &nodes {
+        assert!(node.nodes().len() >= 5, "Only {} nodes", node.nodes().len());
+    }
+
+    let target = nodes[7].id();
+    let closest = timeout(Duration::from_secs(5), nodes[9].find_node(target))
+        .await
+        .unwrap();
+    assert_eq!(closest[0].id, target);
+    assert_eq!(closest[0].addr, nodes[7].local_addr().unwrap());
+
+    let lonely = Dht::bind_with_config(addr("127.0.0.1:0"), fast_config())
+        .await
+        .unwrap();
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let result = lonely.bootstrap(&[socket.local_addr().unwrap()]).await;
+    assert!(matches!(result, Err(TorrentError::Dht(_))));
+}
+
+#[tokio::test]
+async fn test_announce_and_get_peers() {
+    let nodes = spawn_nodes(8).await;
+    let info_hash = [7; 20];
+    assert!(nodes[5].get_peers(info_hash).await.is_empty());
+
+    let found = nodes[3].announce(info_hash, Some(6881)).await.unwrap();
+    assert!(found.is_empty());
+    // Without a port, the one we send from is used
+    nodes[4].announce(info_hash, None).await.unwrap();
+
+    let peers = timeout(Duration::from_secs(5), nodes[6].get_peers(info_hash))
+        .await
+        .unwrap();
+    assert!(peers.contains(&addr("127.0.0.1:6881")));
+    assert!(peers.contains(&nodes[4].local_addr().unwrap()));
+    assert!(nodes[6].get_peers([8; 20]).await.is_empty());
+}
+
+#[tokio::test]
+async fn test_announce_needs_token() {
+    let nodes = spawn_nodes(2).await;
+    let target = nodes[1].local_addr().unwrap();
+    let info_hash = [7; 20];
+    let announce = |token: Vec<u8>| Query::AnnouncePeer {
+        info_hash,
+        port: 6881,
+        implied_port: false,
+        token,
+    };
+
+    let result = nodes[0].query(target, announce(b"guess".to_vec())).await;
+    match result {
+        Err(err) => assert_eq!(
+            err.to_string(),
+            format!("DHT: Node error {ERROR_PROTOCOL}: Bad token")
+        ),
+        Ok(_) => panic!("Announce accepted without a token"),
+    }
+
+    let response = nodes[0]
+        .query(target, Query::GetPeers { info_hash })
+        .await
+        .unwrap();
+    assert!(response.values.is_empty());
+    let token = response.token.unwrap();
+    nodes[0].query(target, announce(token)).await.unwrap();
+
+    let response = nodes[0]
+        .query(target, Query::GetPeers { info_hash })
+        .await
+        .unwrap();
+    assert_eq!(response.values, vec![addr("127.0.0.1:6881")]);
+}
+
+#[tokio::test]
+async fn test_stored_info_hashes() {
+    let node = spawn_nodes(1).await.remove(0);
+    let config = DhtConfig {
+        peer_ttl: Duration::from_millis(200),
+        refresh_interval: Duration::from_millis(100),
+        max_info_hashes: 2,
+        ..fast_config()
+    };
+    let dht = Dht::bind_with_config(addr("127.0.0.1:0"), config)
+        .await
+        .unwrap();
+    let dht_addr = dht.local_addr().unwrap();
+
+    let get_peers = |info_hash| node.query(dht_addr, Query::GetPeers { info_hash });
+    let token = get_peers([1; 20]).await.unwrap().token.unwrap();
+    let announce = |info_hash| {
+        node.query(
+            dht_addr,
+            Query::AnnouncePeer {
+                info_hash,
+                port: 6881,
+                implied_port: false,
+                token: token.clone(),
+            },
+        )
+    };
+    for info_hash in [[1; 20], [2; 20], [3; 20]] {
+        announce(info_hash).await.unwrap();
+    }
+    assert_eq!(get_peers([2; 20]).await.unwrap().values.len(), 1);
+    assert!(get_peers([3; 20]).await.unwrap().values.is_empty());
+
+    // Expired peers are swept even if nobody asks for them
+    sleep(Duration::from_millis(500)).await;
+    announce([3; 20]).await.unwrap();
+    assert_eq!(get_peers([3; 20]).await.unwrap().values.len(), 1);
+}
+
+#[tokio::test]
+async fn test_download_trackerless_torrent() {
+    let nodes = spawn_nodes(4).await;
+    let node_port = nodes[0].local_addr().unwrap().port();
+
+    // No announce key, the DHT nodes to join instead
+    let contents = sample_contents(100 << 10);
+    let info = common::make_torrent(&contents, 32 << 10)
+        .info_bytes()
+        .to_vec();
+    let mut data = b"d4:info".to_vec();
+    data.extend(info);
+    data.extend(format!("5:nodesll9:127.0.0.1i{node_port}eeee").as_bytes());
+    let meta_info = Arc::new(MetaInfoFile::from_bytes(&data).unwrap());
+    assert!(TrackerList::from_meta_info(&meta_info).tiers().is_empty());
+
+    let info_hash = meta_info.info_hash_bytes();
+    let (seeder_addr, _) =
+        spawn_seeder(&meta_info, contents.clone(), SeederBehavior::default()).await;
+    nodes[3]
+        .announce(info_hash, Some(seeder_addr.port()))
+        .await
+        .unwrap();
+
+    let dht = Dht::bind_with_config(addr("127.0.0.1:0"), fast_config())
+        .await
+        .unwrap();
+    let mut bootstrap = Vec::new();
+    for (host, port) in meta_info.nodes.clone().unwrap() {
+        bootstrap.extend(lookup_host((host.as_str(), port)).await.unwrap());
+    }
+    dht.bootstrap(&bootstrap).await.unwrap();
+    let peer_addrs = dht.get_peers(info_hash).await;
+    assert_eq!(peer_addrs, vec![seeder_addr]);
+
+    let storage = Arc::new(MemoryStorage::new());
+    let stats = Arc::new(TransferStats::new(contents.len() as u64));
+    let session = DownloadSession::new(meta_info, stats, storage.clone());
+    session.add_peers(peer_addrs);
+    timeout(Duration::from_secs(10), session.download())
+        .await
+        .expect("Download timed out")
+        .unwrap();
+    assert_eq!(storage.contents(), contents);
+}
+
+#[test]
+fn test_state_default_path() {
+    // Never shared with other users
+    if let Some(path) = DhtState::default_path() {
+        assert!(!path.starts_with(std::env::temp_dir()));
+        assert!(path.ends_with("bittorrent/dht.state"));
+    }
+}
+
+#[tokio::test]
+async fn test_saved_state() {
+    let dir = tempdir().unwrap();
+    // Its directory is created when missing
+    let path = dir.path().join("state").join("dht.state");
+    let nodes = spawn_nodes(4).await;
+
+    let mut state = nodes[3].state();
+    assert_eq!(state.id, nodes[3].id());
+    assert!(!state.nodes.is_empty());
+    state.nodes.push(NodeInfo {
+        id: NodeId([9; 20]),
+        addr: addr("[2001:db8::1]:6881"),
+    });
+    state.save(&path).unwrap();
+    let loaded = DhtState::load(&path).unwrap();
+    assert_eq!(loaded.id, state.id);
+    assert_eq!(loaded.nodes.len(), state.nodes.len());
+    for node in &state.nodes {
+        assert!(loaded.nodes.contains(node));
+    }
+
+    // Same ID on the next run, joining through the nodes we knew
+    let config = DhtConfig {
+        id: Some(loaded.id),
+        ..fast_config()
+    };
+    let restarted = Dht::bind_with_config(addr("127.0.0.1:0"), config)
+        .await
+        .unwrap();
+    assert_eq!(restarted.id(), nodes[3].id());
+    let addrs: Vec<_> = loaded.addrs().into_iter().filter(|x| x.is_ipv4()).collect();
+    restarted.bootstrap(&addrs).await.unwrap();
+    assert!(restarted.nodes().len() >= 3);
+
+    std::fs::write(&path, b"d2:id3:abc5:nodes0:6:nodes60:e").unwrap();
+    assert!(matches!(DhtState::load(&path), Err(TorrentError::Dht(_))));
+}
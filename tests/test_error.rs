@@ -14,3 +14,27 @@ fn test_derive() {
         "HTTP: foo"
     );
 }
+
+#[test]
+fn test_piece_hash_mismatch() {
+    assert_eq!(
+        TorrentError::PieceHashMismatch(3).to_string(),
+        "Piece 3 failed hash check"
+    );
+}
+
+#[test]
+fn test_handshake() {
+    assert_eq!(
+        TorrentError::Handshake("Connected to ourselves".to_string()).to_string(),
+        "Handshake: Connected to ourselves"
+    );
+}
+
+#[test]
+fn test_dht() {
+    assert_eq!(
+        TorrentError::Dht("No node answered".to_string()).to_string(),
+        "DHT: No node answered"
+    );
+}
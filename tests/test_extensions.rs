@@ -0,0 +1,90 @@
+use std::net::IpAddr;
+
+use bittorrent_starter_rust::{
+    extensions::{ExtensionHandshake, ExtensionRegistry, CLIENT_VERSION, HANDSHAKE_ID},
+    peers::PeerMessage,
+};
+
+#[test]
+fn test_parse_handshake() {
+    let handshake = ExtensionHandshake::from_payload(
+        b"d1:md11:ut_metadatai3e6:ut_pexi0ee13:metadata_sizei31235e1:pi6881e\
+        4:reqqi500e1:v14:Transmission 46:yourip4:\x7f\x00\x00\x01e",
+    )
+    .unwrap();
+    assert_eq!(handshake.metadata_size, Some(31235));
+    assert_eq!(handshake.extension_id("ut_metadata"), Some(3));
+    // Disabled
+    assert_eq!(handshake.extension_id("ut_pex"), None);
+    assert_eq!(handshake.extension_id("lt_donthave"), None);
+    assert_eq!(handshake.v.as_deref(), Some("Transmission 4"));
+    assert_eq!(handshake.reqq(), Some(500));
+    assert_eq!(handshake.your_ip(), Some("127.0.0.1".parse().unwrap()));
+
+    // Every key is optional
+    let handshake = ExtensionHandshake::from_payload(b"de").unwrap();
+    assert_eq!(handshake, ExtensionHandshake::default());
+    assert_eq!(handshake.reqq(), None);
+    assert_eq!(handshake.your_ip(), None);
+
+    assert!(ExtensionHandshake::from_payload(b"d1:mi1ee").is_err());
+    assert!(ExtensionHandshake::from_payload(b"le").is_err());
+}
+
+#[test]
+fn test_your_ip() {
+    let handshake = |yourip: &[u8]| ExtensionHandshake {
+        yourip: Some(yourip.to_vec()),
+        ..Default::default()
+    };
+    let ipv6: IpAddr = "2001:db8::1".parse().unwrap();
+    let IpAddr::V6(octets) = ipv6 else {
+        unreachable!()
+    };
+    assert_eq!(handshake(&octets.octets()).your_ip(), Some(ipv6));
+    assert_eq!(handshake(&[1, 2, 3]).your_ip(), None);
+}
+
+#[test]
+fn test_registry() {
+    let mut registry = ExtensionRegistry::new();
+    assert_eq!(registry.register("ut_metadata"), 1);
+    assert_eq!(registry.register("ut_pex"), 2);
+    assert_eq!(registry.register("ut_metadata"), 1);
+
+    assert_eq!(registry.id("ut_pex"), Some(2));
+    assert_eq!(registry.id("lt_donthave"), None);
+    assert_eq!(registry.name(HANDSHAKE_ID), None);
+    assert_eq!(registry.name(1), Some("ut_metadata"));
+    assert_eq!(registry.name(3), None);
+}
+
+#[test]
+fn test_registry_handshake() {
+    let mut registry = ExtensionRegistry::new();
+    registry.register("ut_metadata");
+    registry.register("ut_pex");
+    registry.set_reqq(250);
+
+    let handshake = registry.handshake("10.0.0.2".parse().unwrap());
+    assert_eq!(handshake.extension_id("ut_metadata"), Some(1));
+    assert_eq!(handshake.extension_id("ut_pex"), Some(2));
+    assert_eq!(handshake.v.as_deref(), Some(CLIENT_VERSION));
+    assert_eq!(handshake.reqq(), Some(250));
+    assert_eq!(handshake.yourip, Some(vec![10, 0, 0, 2]));
+
+    let PeerMessage::Extended { id, payload } = handshake.to_message().unwrap() else {
+        panic!("Expected an extended message");
+    };
+    assert_eq!(id, HANDSHAKE_ID);
+    assert_eq!(
+        ExtensionHandshake::from_payload(&payload).unwrap(),
+        handshake
+    );
+
+    // Nothing about queued requests unless told
+    let handshake = ExtensionRegistry::new().handshake("::1".parse().unwrap());
+    assert!(handshake.m.is_empty());
+    assert_eq!(handshake.reqq, None);
+    assert_eq!(handshake.your_ip(), Some("::1".parse().unwrap()));
+}
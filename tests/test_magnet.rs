@@ -0,0 +1,110 @@
+mod common;
+
+use bittorrent_starter_rust::{
+    error::TorrentError, magnet::MagnetLink, trackers::tiers::TrackerList,
+};
+use common::{make_torrent, sample_contents};
+
+const INFO_HASH: &str = "d69f91e6b2ae4c542468d1073a71d4ea13879a7f";
+
+#[test]
+fn test_parse_hex() {
+    let magnet = MagnetLink::parse(&format!(
+        "magnet:?xt=urn:btih:{INFO_HASH}&dn=sample.txt\
+         &tr=http%3A%2F%2Fbittorrent-test-tracker.codecrafters.io%2Fannounce"
+    ))
+    .unwrap();
+
+    assert_eq!(magnet.info_hash(), INFO_HASH);
+    assert_eq!(magnet.name.as_deref(), Some("sample.txt"));
+    assert_eq!(
+        magnet.trackers,
+        vec!["http://bittorrent-test-tracker.codecrafters.io/announce"]
+    );
+    assert!(magnet.peers.is_empty());
+}
+
+#[test]
+fn test_parse_base32() {
+    let magnet = MagnetLink::parse("magnet:?xt=urn:btih:22PZDZVSVZGFIJDI2EDTU4OU5IJYPGT7").unwrap();
+    assert_eq!(magnet.info_hash(), INFO_HASH);
+
+    // Case does not matter
+    let magnet = MagnetLink::parse("magnet:?xt=urn:btih:22pzdzvsvzgfijdi2edtu4ou5ijypgt7").unwrap();
+    assert_eq!(magnet.info_hash(), INFO_HASH);
+    let magnet =
+        MagnetLink::parse(&format!("magnet:?xt=urn:btih:{}", INFO_HASH.to_uppercase())).unwrap();
+    assert_eq!(magnet.info_hash(), INFO_HASH);
+}
+
+#[test]
+fn test_parse_trackers_and_peers() {
+    let magnet = MagnetLink::parse(&format!(
+        "magnet:?dn=My+file&xt=urn:btmh:1220abcd&xt=urn:btih:{INFO_HASH}\
+         &tr=udp%3A%2F%2Fa.test%3A80&tr.1=http%3A%2F%2Fb.test%2Fannounce\
+         &tr=udp%3A%2F%2Fa.test%3A80&x.pe=127.0.0.1%3A6881&x.pe=[::1]:51413&ws=http%3A%2F%2Fws.test"
+    ))
+    .unwrap();
+
+    assert_eq!(magnet.info_hash(), INFO_HASH);
+    assert_eq!(magnet.name.as_deref(), Some("My file"));
+    assert_eq!(
+        magnet.trackers,
+        vec!["udp://a.test:80", "http://b.test/announce"]
+    );
+    assert_eq!(
+        magnet.peers,
+        vec![
+            "127.0.0.1:6881".parse().unwrap(),
+            "[::1]:51413".parse().unwrap()
+        ]
+    );
+    assert_eq!(
+        TrackerList::new(magnet.tiers()).tiers().len(),
+        magnet.trackers.len()
+    );
+}
+
+#[test]
+fn test_parse_errors() {
+    for uri in [
+        "http://example.com",
+        "magnet:?dn=no-hash",
+        "magnet:?xt=urn:btmh:1220abcd",
+        "magnet:?xt=urn:btih:1234",
+        "magnet:?xt=urn:btih:d69f91e6b2ae4c542468d1073a71d4ea13879a7g",
+        "magnet:?xt=urn:btih:11111111111111111111111111111111",
+        "magnet:?xt=urn:btih:d69f91e6b2ae4c542468d1073a71d4ea13879a7f&dn=%zz",
+        "magnet:?xt=urn:btih:d69f91e6b2ae4c542468d1073a71d4ea13879a7f&x.pe=localhost",
+    ] {
+        let result = MagnetLink::parse(uri);
+        assert!(matches!(result, Err(TorrentError::Magnet(_))), "{uri}");
+    }
+}
+
+#[test]
+fn test_to_meta_info() {
+    let torrent = make_torrent(&sample_contents(1000), 256);
+    let magnet = MagnetLink::parse(&format!(
+        "magnet:?xt=urn:btih:{}&tr=http%3A%2F%2Fa.test%2Fannounce&tr=udp%3A%2F%2Fb.test%3A80",
+        torrent.info_hash()
+    ))
+    .unwrap();
+
+    let meta_info = magnet.to_meta_info(torrent.info_bytes().to_vec()).unwrap();
+    assert_eq!(meta_info.info_hash(), torrent.info_hash());
+    assert_eq!(meta_info.info.name, torrent.info.name);
+    assert_eq!(meta_info.info.pieces, torrent.info.pieces);
+    assert_eq!(meta_info.announce, "http://a.test/announce");
+    assert_eq!(
+        meta_info.announce_list,
+        Some(vec![
+            vec!["http://a.test/announce".to_string()],
+            vec!["udp://b.test:80".to_string()],
+        ])
+    );
+
+    let other = make_torrent(&sample_contents(2000), 256);
+    let result = magnet.to_meta_info(other.info_bytes().to_vec());
+    assert!(matches!(result, Err(TorrentError::Metadata(_))));
+}
@@ -0,0 +1,176 @@
+mod common;
+
+use std::{sync::Arc, time::Duration};
+
+use bittorrent_starter_rust::{
+    error::TorrentError,
+    magnet::MagnetLink,
+    metadata::{MetadataConfig, MetadataFetcher, MetadataMessage, METADATA_PIECE_SIZE},
+    session::DownloadSession,
+    storage::MemoryStorage,
+    trackers::announcer::TransferStats,
+};
+use common::{make_torrent, sample_contents, spawn_seeder, SeederBehavior};
+use tokio::{net::TcpListener, time::timeout};
+
+fn fast_config() -> MetadataConfig {
+    MetadataConfig {
+        connect_timeout: Duration::from_secs(1),
+        peer_timeout: Duration::from_secs(1),
+        ..MetadataConfig::default()
+    }
+}
+
+#[test]
+fn test_metadata_message() {
+    let request = MetadataMessage::Request(2);
+    let bytes = request.to_bytes().unwrap();
+    assert_eq!(bytes, b"d8:msg_typei0e5:piecei2ee");
+    assert_eq!(MetadataMessage::from_bytes(&bytes).unwrap(), request);
+
+    let data = MetadataMessage::Data {
+        piece: 0,
+        total_size: 3,
+        data: b"abc".to_vec(),
+    };
+    let bytes = data.to_bytes().unwrap();
+    assert_eq!(bytes, b"d8:msg_typei1e5:piecei0e10:total_sizei3eeabc");
+    assert_eq!(MetadataMessage::from_bytes(&bytes).unwrap(), data);
+
+    let reject = MetadataMessage::Reject(1);
+    assert_eq!(
+        MetadataMessage::from_bytes(&reject.to_bytes().unwrap()).unwrap(),
+        reject
+    );
+
+    for payload in [
+        &b"d8:msg_typei3e5:piecei0ee"[..],
+        b"d8:msg_typei1e5:piecei0ee",
+        b"d8:msg_typei0e5:piecei-1ee",
+        b"i0e",
+        b"d8:msg_type",
+    ] {
+        assert!(MetadataMessage::from_bytes(payload).is_err());
+    }
+}
+
+#[tokio::test]
+async fn test_fetch_metadata() {
+    // Over 16 KiB of piece hashes: the info dict is sent in two pieces
+    let contents = sample_contents(1000 * 256);
+    let torrent = make_torrent(&contents, 256);
+    assert!(torrent.info_bytes().len() > METADATA_PIECE_SIZE);
+
+    let behavior = SeederBehavior {
+        metadata: Some(torrent.info_bytes().to_vec()),
+        ..Default::default()
+    };
+    let (addr, _) = spawn_seeder(&torrent, contents, behavior).await;
+
+    let fetcher = MetadataFetcher::with_config(torrent.info_hash_bytes(), fast_config());
+    let info_bytes = timeout(Duration::from_secs(5), fetcher.fetch(vec![addr]))
+        .await
+        .expect("Fetch timed out")
+        .unwrap();
+    assert_eq!(info_bytes, torrent.info_bytes());
+}
+
+#[tokio::test]
+async fn test_fetch_metadata_skips_bad_peers() {
+    let contents = sample_contents(5000);
+    let torrent = make_torrent(&contents, 1024);
+    let other = make_torrent(&sample_contents(6000), 1024);
+
+    // Refusing connections, without ut_metadata, sending some other info dict
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let closed = listener.local_addr().unwrap();
+    drop(listener);
+    let (unsupported, _) = spawn_seeder(&torrent, contents.clone(), Default::default()).await;
+    let wrong = SeederBehavior {
+        metadata: Some(other.info_bytes().to_vec()),
+        ..Default::default()
+    };
+    let (wrong, _) = spawn_seeder(&torrent, contents.clone(), wrong).await;
+    let good = SeederBehavior {
+        metadata: Some(torrent.info_bytes().to_vec()),
+        ..Default::default()
+    };
+    let (good, _) = spawn_seeder(&torrent, contents.clone(), good).await;
+
+    // One peer at a time: all of them are tried in turn
+    let config = MetadataConfig {
+        max_peers: 1,
+        ..fast_config()
+    };
+    let fetcher = MetadataFetcher::with_config(torrent.info_hash_bytes(), config.clone());
+    let info_bytes = timeout(
+        Duration::from_secs(5),
+        fetcher.fetch(vec![closed, unsupported, wrong, good]),
+    )
+    .await
+    .expect("Fetch timed out")
+    .unwrap();
+    assert_eq!(info_bytes, torrent.info_bytes());
+
+    let fetcher = MetadataFetcher::with_config(torrent.info_hash_bytes(), config);
+    let result = fetcher.fetch(vec![closed, unsupported, wrong]).await;
+    let Err(TorrentError::Metadata(err)) = result else {
+        panic!("Expected a metadata error");
+    };
+    // Each peer tells why it failed
+    for addr in [closed, unsupported, wrong] {
+        assert!(err.contains(&addr.to_string()));
+    }
+    let result = fetcher.fetch(vec![]).await;
+    assert!(matches!(result, Err(TorrentError::Metadata(_))));
+}
+
+#[tokio::test]
+async fn test_fetch_metadata_too_large() {
+    let contents = sample_contents(1000 * 256);
+    let torrent = make_torrent(&contents, 256);
+    let behavior = SeederBehavior {
+        metadata: Some(torrent.info_bytes().to_vec()),
+        ..Default::default()
+    };
+    let (addr, _) = spawn_seeder(&torrent, contents, behavior).await;
+
+    let config = MetadataConfig {
+        max_size: METADATA_PIECE_SIZE,
+        ..fast_config()
+    };
+    let fetcher = MetadataFetcher::with_config(torrent.info_hash_bytes(), config);
+    let result = fetcher.fetch(vec![addr]).await;
+    assert!(matches!(result, Err(TorrentError::Metadata(_))));
+}
+
+#[tokio::test]
+async fn test_magnet_download() {
+    let contents = sample_contents(3 * (32 << 10) + 1000);
+    let torrent = make_torrent(&contents, 32 << 10);
+    let behavior = SeederBehavior {
+        metadata: Some(torrent.info_bytes().to_vec()),
+        ..Default::default()
+    };
+    let (addr, _) = spawn_seeder(&torrent, contents.clone(), behavior).await;
+
+    let magnet = MagnetLink::parse(&format!(
+        "magnet:?xt=urn:btih:{}&x.pe={addr}",
+        torrent.info_hash(),
+    ))
+    .unwrap();
+    let fetcher = MetadataFetcher::with_config(magnet.info_hash, fast_config());
+    let info_bytes = fetcher.fetch(magnet.peers.clone()).await.unwrap();
+    let meta_info = Arc::new(magnet.to_meta_info(info_bytes).unwrap());
+    assert_eq!(meta_info.info.total_length(), contents.len() as u64);
+
+    let stats = Arc::new(TransferStats::new(meta_info.info.total_length()));
+    let storage = Arc::new(MemoryStorage::new());
+    let session = DownloadSession::new(meta_info, stats, storage.clone());
+    session.add_peers(vec![addr]);
+    timeout(Duration::from_secs(10), session.download())
+        .await
+        .expect("Download timed out")
+        .unwrap();
+    assert_eq!(storage.contents(), contents);
+}
@@ -1,5 +1,12 @@
-use bittorrent_starter_rust::peers::PeerMessage;
-use tokio::io::{BufReader, BufWriter};
+use bittorrent_starter_rust::{
+    error::TorrentError,
+    peer_id,
+    peers::{Handshake, Peer, PeerMessage},
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt, BufReader, BufWriter},
+    net::TcpListener,
+};
 
 #[test]
 fn test_peer_message_derive() {
@@ -79,4 +86,122 @@ async fn test_peer_message_rw() {
         },
     )
     .await;
+
+    // Extended
+    check_rw(
+        &[0, 0, 0, 5, 20, 0, b'd', b'e', 1],
+        PeerMessage::Extended {
+            id: 0,
+            payload: b"de\x01".to_vec(),
+        },
+    )
+    .await;
+    check_rw(
+        &[0, 0, 0, 2, 20, 3],
+        PeerMessage::Extended {
+            id: 3,
+            payload: vec![],
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn test_reserved_bits() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        for reserved in [[0; 8], [0, 0, 0, 0, 0, 0x10, 0, 0x05]] {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut handshake = [0; 68];
+            stream.read_exact(&mut handshake).await.unwrap();
+            // We support the extension protocol
+            assert_eq!(handshake[20..28], [0, 0, 0, 0, 0, 0x10, 0, 0]);
+            handshake[20..28].copy_from_slice(&reserved);
+            handshake[48..].copy_from_slice(b"-TEST00-000000000000");
+            stream.write_all(&handshake).await.unwrap();
+        }
+    });
+
+    let info_hash = [1; 20];
+    let peer = Peer::connect_info_hash(&addr, &info_hash).await.unwrap();
+    assert_eq!(peer.reserved(), [0; 8]);
+    assert!(!peer.supports_extensions());
+
+    let peer = Peer::connect_info_hash(&addr, &info_hash).await.unwrap();
+    assert_eq!(peer.reserved(), [0, 0, 0, 0, 0, 0x10, 0, 0x05]);
+    assert!(peer.supports_extensions());
+}
+
+#[test]
+fn test_handshake_bytes() {
+    let handshake = Handshake::new([1; 20], *b"-TEST00-000000000000");
+    assert!(handshake.supports_extensions());
+
+    let bytes = handshake.to_bytes();
+    assert_eq!(&bytes[..20], b"\x13BitTorrent protocol");
+    assert_eq!(bytes[20..28], [0, 0, 0, 0, 0, 0x10, 0, 0]);
+    assert_eq!(bytes[28..48], [1; 20]);
+    assert_eq!(&bytes[48..], b"-TEST00-000000000000");
+    assert_eq!(Handshake::from_bytes(&bytes).unwrap(), handshake);
+
+    let mut bytes = bytes;
+    bytes[5] = b'X';
+    let result = Handshake::from_bytes(&bytes);
+    assert!(matches!(result, Err(TorrentError::Handshake(_))));
+    bytes[0] = 18;
+    let result = Handshake::from_bytes(&bytes);
+    assert!(matches!(result, Err(TorrentError::Handshake(_))));
+}
+
+// Peer answering the handshake with `reply` built from ours
+async fn spawn_peer(reply: fn(&mut [u8; 68])) -> std::net::SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut handshake = [0; 68];
+        stream.read_exact(&mut handshake).await.unwrap();
+        reply(&mut handshake);
+        stream.write_all(&handshake).await.unwrap();
+    });
+    addr
+}
+
+#[tokio::test]
+async fn test_handshake_validated() {
+    let info_hash = [1; 20];
+
+    let addr = spawn_peer(|x| x[48..].copy_from_slice(b"-TEST00-000000000000")).await;
+    let peer = Peer::connect_info_hash(&addr, &info_hash).await.unwrap();
+    assert_eq!(peer.handshake().info_hash, info_hash);
+    assert_eq!(&peer.handshake().peer_id, b"-TEST00-000000000000");
+
+    let addr = spawn_peer(|x| x[1..20].copy_from_slice(b"BitTorrent protocoL")).await;
+    let result = Peer::connect_info_hash(&addr, &info_hash).await;
+    assert!(matches!(result, Err(TorrentError::Handshake(_))));
+
+    let addr = spawn_peer(|x| {
+        x[28] ^= 0xff;
+        x[48..].copy_from_slice(b"-TEST00-000000000000");
+    })
+    .await;
+    let result = Peer::connect_info_hash(&addr, &info_hash).await;
+    assert!(matches!(result, Err(TorrentError::Handshake(_))));
+
+    // Our own handshake sent back
+    let addr = spawn_peer(|_| {}).await;
+    let err = Peer::connect_info_hash(&addr, &info_hash)
+        .await
+        .unwrap_err();
+    assert_eq!(err.to_string(), "Handshake: Connected to ourselves");
+}
+
+#[test]
+fn test_peer_id() {
+    let id = peer_id();
+    assert_eq!(&id[..8], b"-AL0100-");
+    assert!(id[8..].iter().all(|x| x.is_ascii_digit()));
+    // Same for the whole run
+    assert_eq!(peer_id(), id);
 }
@@ -0,0 +1,212 @@
+mod common;
+
+use std::{
+    collections::HashMap,
+    fs,
+    net::SocketAddr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use bittorrent_starter_rust::{
+    creator::{create_torrent, CreateOptions},
+    pex::{PexMessage, PexState, FLAG_REACHABLE, FLAG_SEED, MAX_PEX_PEERS},
+    session::{DownloadSession, SessionConfig},
+    storage::MemoryStorage,
+    torrent_file::MetaInfoFile,
+    trackers::announcer::TransferStats,
+};
+use common::{make_torrent, sample_contents, spawn_seeder, SeederBehavior};
+use tempfile::tempdir;
+use tokio::time::timeout;
+
+const PIECE_LENGTH: u32 = 32 << 10;
+
+fn addr(s: &str) -> SocketAddr {
+    s.parse().unwrap()
+}
+
+async fn download(meta_info: Arc<MetaInfoFile>, peer_addrs: Vec<SocketAddr>) -> Vec<u8> {
+    let config = SessionConfig {
+        pex_interval: Duration::from_millis(20),
+        ..Default::default()
+    };
+    let storage = Arc::new(MemoryStorage::new());
+    let session = DownloadSession::with_config(
+        meta_info.clone(),
+        Arc::new(TransferStats::new(meta_info.info.total_length())),
+        storage.clone(),
+        config,
+    );
+    session.add_peers(peer_addrs);
+    timeout(Duration::from_secs(10), session.download())
+        .await
+        .expect("Download timed out")
+        .unwrap();
+    storage.contents()
+}
+
+#[test]
+fn test_pex_message() {
+    let msg = PexMessage {
+        added: vec![(addr("1.2.3.4:6881"), FLAG_SEED)],
+        dropped: vec![addr("5.6.7.8:80")],
+    };
+    let bytes = msg.to_bytes().unwrap();
+    assert_eq!(
+        bytes,
+        b"d5:added6:\x01\x02\x03\x04\x1a\xe17:added.f1:\x027:dropped6:\x05\x06\x07\x08\x00\x50e"
+    );
+    assert_eq!(PexMessage::from_bytes(&bytes).unwrap(), msg);
+
+    let msg = PexMessage {
+        added: vec![
+            (addr("10.0.0.1:1000"), FLAG_REACHABLE),
+            (addr("[::1]:2000"), FLAG_SEED | FLAG_REACHABLE),
+            (addr("[2001:db8::2]:3000"), 0),
+        ],
+        dropped: vec![addr("10.0.0.2:1000"), addr("[2001:db8::3]:4000")],
+    };
+    assert_eq!(
+        PexMessage::from_bytes(&msg.to_bytes().unwrap()).unwrap(),
+        msg
+    );
+    assert_eq!(
+        PexMessage::from_bytes(&PexMessage::default().to_bytes().unwrap()).unwrap(),
+        PexMessage::default()
+    );
+
+    // Flags are optional
+    let msg = PexMessage::from_bytes(b"d5:added6:\x01\x02\x03\x04\x1a\xe1e").unwrap();
+    assert_eq!(msg.added, vec![(addr("1.2.3.4:6881"), 0)]);
+
+    for payload in [
+        &b"d5:added5:\x01\x02\x03\x04\x1ae"[..],
+        b"d7:dropped6:abcdefg",
+        b"i0e",
+    ] {
+        assert!(PexMessage::from_bytes(payload).is_err());
+    }
+}
+
+#[test]
+fn test_pex_state() {
+    let interval = Duration::from_secs(60);
+    let mut state = PexState::new(interval);
+    assert!(state.next_send() <= Instant::now());
+    let a = addr("10.0.0.1:1000");
+    let b = addr("10.0.0.2:1000");
+
+    // Nothing to tell
+    let start = state.next_send();
+    assert_eq!(state.update(&HashMap::new(), start), None);
+    assert_eq!(state.next_send(), start + interval);
+
+    // Not before the interval is over
+    let connected = HashMap::from([(a, FLAG_REACHABLE)]);
+    let now = state.next_send() - Duration::from_secs(1);
+    assert_eq!(state.update(&connected, now), None);
+    let now = state.next_send();
+    let msg = state.update(&connected, now).unwrap();
+    assert_eq!(msg.added, vec![(a, FLAG_REACHABLE)]);
+    assert!(msg.dropped.is_empty());
+
+    // Only the changes are sent
+    let connected = HashMap::from([(b, 0)]);
+    let now = state.next_send();
+    let msg = state.update(&connected, now).unwrap();
+    assert_eq!(msg.added, vec![(b, 0)]);
+    assert_eq!(msg.dropped, vec![a]);
+    let now = state.next_send();
+    assert_eq!(state.update(&connected, now), None);
+
+    // Peers over the limit are sent later
+    let connected: HashMap<_, _> = (0..MAX_PEX_PEERS as u16 + 10)
+        .map(|port| (addr(&format!("10.0.1.1:{port}")), 0))
+        .collect();
+    let now = state.next_send();
+    let msg = state.update(&connected, now).unwrap();
+    assert_eq!(msg.added.len(), MAX_PEX_PEERS);
+    assert_eq!(msg.dropped, vec![b]);
+    let now = state.next_send();
+    let msg = state.update(&connected, now).unwrap();
+    assert_eq!(msg.added.len(), 10);
+    assert!(msg.dropped.is_empty());
+}
+
+#[tokio::test]
+async fn test_learn_peers_from_pex() {
+    let contents = sample_contents(4 * PIECE_LENGTH as usize);
+    let meta_info = Arc::new(make_torrent(&contents, PIECE_LENGTH));
+
+    // Only the first peer is known, it has nothing but knows a seeder
+    let (seeder, seeder_stats) =
+        spawn_seeder(&meta_info, contents.clone(), Default::default()).await;
+    let behavior = SeederBehavior {
+        pieces: Some(vec![]),
+        pex_peers: vec![seeder],
+        ..Default::default()
+    };
+    let (empty, _) = spawn_seeder(&meta_info, contents.clone(), behavior).await;
+
+    let output = download(meta_info, vec![empty]).await;
+    assert_eq!(output, contents);
+    assert!(seeder_stats.blocks_served() > 0);
+}
+
+#[tokio::test]
+async fn test_send_pex() {
+    let contents = sample_contents(1 << 20);
+    let meta_info = Arc::new(make_torrent(&contents, PIECE_LENGTH));
+    let slow = SeederBehavior {
+        block_delay: Duration::from_millis(5),
+        ..Default::default()
+    };
+    let (a, a_stats) = spawn_seeder(&meta_info, contents.clone(), slow.clone()).await;
+    let (b, b_stats) = spawn_seeder(&meta_info, contents.clone(), slow).await;
+
+    let output = download(meta_info, vec![a, b]).await;
+    assert_eq!(output, contents);
+
+    // Each peer is told about the other one, never about itself
+    for (stats, own, other) in [(a_stats, a, b), (b_stats, b, a)] {
+        let added: Vec<_> = stats
+            .pex_messages()
+            .into_iter()
+            .flat_map(|x| x.added)
+            .collect();
+        assert!(added.iter().all(|x| x.0 != own));
+        let (_, flags) = added.iter().find(|x| x.0 == other).unwrap();
+        assert_ne!(flags & FLAG_REACHABLE, 0);
+    }
+}
+
+#[tokio::test]
+async fn test_no_pex_for_private_torrents() {
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("sample.bin");
+    let contents = sample_contents(1 << 20);
+    fs::write(&path, &contents).unwrap();
+    let options = CreateOptions {
+        trackers: vec![vec!["http://tracker.test/announce".to_string()]],
+        piece_length: Some(PIECE_LENGTH),
+        private: true,
+        ..Default::default()
+    };
+    let meta_info = MetaInfoFile::from_bytes(&create_torrent(&path, &options).unwrap()).unwrap();
+    assert!(meta_info.is_private());
+    assert!(!make_torrent(&contents, PIECE_LENGTH).is_private());
+    let meta_info = Arc::new(meta_info);
+
+    let slow = SeederBehavior {
+        block_delay: Duration::from_millis(5),
+        ..Default::default()
+    };
+    let (a, a_stats) = spawn_seeder(&meta_info, contents.clone(), slow.clone()).await;
+    let (b, b_stats) = spawn_seeder(&meta_info, contents.clone(), slow).await;
+
+    let output = download(meta_info, vec![a, b]).await;
+    assert_eq!(output, contents);
+    assert!(a_stats.pex_messages().is_empty());
+    assert!(b_stats.pex_messages().is_empty());
+}
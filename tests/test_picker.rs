@@ -0,0 +1,137 @@
+use std::collections::HashSet;
+
+use bittorrent_starter_rust::{bitfield::Bitfield, picker::PiecePicker};
+
+fn bitfield(len: usize, pieces: &[usize]) -> Bitfield {
+    let mut bitfield = Bitfield::new(len);
+    pieces.iter().for_each(|&x| bitfield.set(x));
+    bitfield
+}
+
+#[test]
+fn test_rarest_first() {
+    let mut picker = PiecePicker::new(4, 0);
+    let seeder = Bitfield::full(4);
+    picker.add_peer(&seeder);
+    picker.add_peer(&seeder);
+    picker.add_peer(&bitfield(4, &[0, 1, 3]));
+    picker.add_peer(&bitfield(4, &[1]));
+    assert_eq!(picker.availability(1), 4);
+
+    // 2 (two peers), then 0 and 3 (3 peers), then 1 (4 peers)
+    assert_eq!(picker.pick(&seeder), Some(2));
+    let next: HashSet<_> = [picker.pick(&seeder), picker.pick(&seeder)].into();
+    assert_eq!(next, HashSet::from([Some(0), Some(3)]));
+    assert_eq!(picker.pick(&seeder), Some(1));
+
+    // Everything is in progress
+    assert_eq!(picker.pick(&seeder), None);
+}
+
+#[test]
+fn test_availability_updates() {
+    let mut picker = PiecePicker::new(3, 0);
+    let seeder = Bitfield::full(3);
+    let partial = bitfield(3, &[0, 1]);
+    picker.add_peer(&seeder);
+    picker.add_peer(&partial);
+    assert_eq!(picker.pick(&seeder), Some(2));
+    picker.release(2);
+
+    // Piece 2 gets more common than the others
+    picker.peer_has(2);
+    picker.peer_has(2);
+    assert_ne!(picker.pick(&seeder), Some(2));
+
+    picker.remove_peer(&partial);
+    assert_eq!(picker.availability(0), 1);
+    assert_eq!(picker.availability(2), 3);
+}
+
+#[test]
+fn test_only_peer_pieces() {
+    let mut picker = PiecePicker::new(4, 0);
+    let peer = bitfield(4, &[1, 3]);
+    picker.add_peer(&peer);
+
+    let picked: HashSet<_> = [picker.pick(&peer), picker.pick(&peer)].into();
+    assert_eq!(picked, HashSet::from([Some(1), Some(3)]));
+    assert_eq!(picker.pick(&peer), None);
+    assert!(picker.pick(&bitfield(4, &[])).is_none());
+}
+
+#[test]
+fn test_random_first() {
+    // Rarest first would always pick piece 0 first
+    let mut picked = HashSet::new();
+    for _ in 0..200 {
+        let mut picker = PiecePicker::new(8, 2);
+        picker.add_peer(&bitfield(8, &[0]));
+        picker.add_peer(&Bitfield::full(8));
+        picked.insert(picker.pick(&Bitfield::full(8)).unwrap());
+    }
+    assert!(picked.len() > 1);
+
+    // Back to rarest first after 2 pieces
+    let mut picker = PiecePicker::new(8, 2);
+    picker.add_peer(&bitfield(8, &[0, 1, 2, 3, 4, 6, 7]));
+    picker.add_peer(&Bitfield::full(8));
+    picker.mark_have(0);
+    picker.mark_have(1);
+    assert_eq!(picker.pick(&Bitfield::full(8)), Some(5));
+}
+
+#[test]
+fn test_have_and_release() {
+    let mut picker = PiecePicker::new(2, 0);
+    let seeder = Bitfield::full(2);
+    picker.add_peer(&seeder);
+    assert_eq!(picker.remaining(), 2);
+    assert!(picker.is_interesting(&seeder));
+
+    let first = picker.pick(&seeder).unwrap();
+    let second = picker.pick(&seeder).unwrap();
+
+    // Failed pieces can be picked again
+    picker.release(second);
+    assert_eq!(picker.pick(&seeder), Some(second));
+
+    picker.mark_have(first);
+    picker.mark_have(second);
+    assert_eq!(picker.remaining(), 0);
+    assert!(picker.is_complete());
+    assert!(!picker.is_interesting(&seeder));
+    assert_eq!(picker.have(), &seeder);
+}
+
+#[test]
+fn test_wanted() {
+    let mut picker = PiecePicker::new(4, 0);
+    let seeder = Bitfield::full(4);
+    picker.add_peer(&seeder);
+    picker.set_wanted(bitfield(4, &[2]));
+    assert_eq!(picker.remaining(), 1);
+
+    assert_eq!(picker.pick(&seeder), Some(2));
+    assert_eq!(picker.pick(&seeder), None);
+    assert!(!picker.is_interesting(&bitfield(4, &[0, 1, 3])));
+
+    picker.mark_have(2);
+    assert!(picker.is_complete());
+}
+
+#[test]
+fn test_all_picked() {
+    let mut picker = PiecePicker::new(3, 0);
+    let seeder = Bitfield::full(3);
+    picker.add_peer(&seeder);
+    picker.set_wanted(bitfield(3, &[0, 1]));
+
+    picker.mark_have(0);
+    assert!(!picker.is_all_picked());
+    assert_eq!(picker.pick(&seeder), Some(1));
+    assert!(picker.is_all_picked());
+
+    picker.release(1);
+    assert!(!picker.is_all_picked());
+}
@@ -0,0 +1,71 @@
+use std::time::{Duration, Instant};
+
+use bittorrent_starter_rust::session::{
+    pipeline::{PipelineConfig, RequestWindow},
+    BLOCK_SIZE,
+};
+
+fn config() -> PipelineConfig {
+    PipelineConfig {
+        initial_window: 4,
+        min_window: 2,
+        max_window: 100,
+        queue_time: Duration::from_secs(2),
+        ..Default::default()
+    }
+}
+
+#[test]
+fn test_initial_window() {
+    let window = RequestWindow::new(&config());
+    assert_eq!(window.size(), 4);
+
+    // Clamped into the configured bounds
+    let window = RequestWindow::new(&PipelineConfig {
+        initial_window: 1000,
+        ..config()
+    });
+    assert_eq!(window.size(), 100);
+}
+
+#[test]
+fn test_window_follows_rate() {
+    let start = Instant::now();
+    let mut window = RequestWindow::with_start(&config(), start);
+
+    // Not resized before a full measure period
+    for _ in 0..19 {
+        window.on_block(BLOCK_SIZE as usize, start + Duration::from_millis(500));
+    }
+    assert_eq!(window.size(), 4);
+
+    // 20 blocks per second and 2 seconds of queue
+    window.on_block(BLOCK_SIZE as usize, start + Duration::from_secs(1));
+    assert_eq!(window.size(), 40);
+
+    // Very fast peer
+    window.on_block(1000 * BLOCK_SIZE as usize, start + Duration::from_secs(2));
+    assert_eq!(window.size(), 100);
+
+    // Slow peer
+    window.on_block(BLOCK_SIZE as usize, start + Duration::from_secs(10));
+    assert_eq!(window.size(), 2);
+}
+
+#[test]
+fn test_window_limit() {
+    let start = Instant::now();
+    let mut window = RequestWindow::with_start(&config(), start);
+    window.limit(3);
+    assert_eq!(window.size(), 3);
+
+    // Fast peers are capped too
+    window.on_block(1000 * BLOCK_SIZE as usize, start + Duration::from_secs(1));
+    assert_eq!(window.size(), 3);
+
+    // Below the minimum window
+    window.limit(1);
+    assert_eq!(window.size(), 1);
+    window.on_block(BLOCK_SIZE as usize, start + Duration::from_secs(10));
+    assert_eq!(window.size(), 1);
+}
@@ -0,0 +1,96 @@
+mod common;
+
+use std::fs;
+
+use bittorrent_starter_rust::{
+    bitfield::Bitfield,
+    resume::{check_pieces, load_pieces, resume_path, ResumeData},
+    storage::{FileStorage, MemoryStorage, Storage},
+};
+use common::{make_torrent, sample_contents};
+use tempfile::tempdir;
+
+const PIECE_LENGTH: u32 = 1024;
+
+#[test]
+fn test_resume_path() {
+    assert_eq!(
+        resume_path("out/sample.bin".as_ref()),
+        std::path::PathBuf::from("out/sample.bin.resume")
+    );
+}
+
+#[test]
+fn test_save_and_load() {
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("sample.resume");
+    let meta_info = make_torrent(&sample_contents(3000), PIECE_LENGTH);
+    let mut have = Bitfield::new(3);
+    have.set(1);
+
+    let resume_data = ResumeData::new(&meta_info, &have, vec![1700000000]);
+    resume_data.save(&path).unwrap();
+    assert_eq!(ResumeData::load(&path).unwrap(), resume_data);
+    assert!(!dir.path().join("sample.resume.tmp").exists());
+
+    assert_eq!(resume_data.validate(&meta_info, &[1700000000]), Some(have));
+    // Files modified since
+    assert_eq!(resume_data.validate(&meta_info, &[1700000001]), None);
+    // Another torrent
+    let other = make_torrent(&sample_contents(2000), PIECE_LENGTH);
+    assert_eq!(resume_data.validate(&other, &[1700000000]), None);
+}
+
+#[test]
+fn test_check_pieces() {
+    let contents = sample_contents(3000);
+    let meta_info = make_torrent(&contents, PIECE_LENGTH);
+    let storage = MemoryStorage::new();
+    storage.write_piece(0, &contents[..1024]).unwrap();
+    storage.write_piece(1, &[0; 1024]).unwrap();
+    storage.write_piece(2, &contents[2048..]).unwrap();
+
+    let have = check_pieces(&storage, &meta_info.info);
+    assert_eq!(have.ones().collect::<Vec<_>>(), [0, 2]);
+}
+
+#[test]
+fn test_load_pieces() {
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("sample.bin");
+    let resume = resume_path(&path);
+    let contents = sample_contents(3000);
+    let meta_info = make_torrent(&contents, PIECE_LENGTH);
+
+    // Nothing on disk yet
+    let storage = FileStorage::create(&meta_info.info, &path).unwrap();
+    assert_eq!(
+        load_pieces(&meta_info, &storage, &resume).unwrap(),
+        Bitfield::new(3)
+    );
+
+    // Interrupted download without resume data: files are hashed
+    let mut partial = contents.clone();
+    partial[1024..2048].fill(0);
+    fs::write(&path, &partial).unwrap();
+    let storage = FileStorage::create(&meta_info.info, &path).unwrap();
+    let have = load_pieces(&meta_info, &storage, &resume).unwrap();
+    assert_eq!(have.ones().collect::<Vec<_>>(), [0, 2]);
+
+    // Resume data is trusted as long as files are untouched
+    let mut only_first = Bitfield::new(3);
+    only_first.set(0);
+    ResumeData::new(&meta_info, &only_first, storage.mtimes().unwrap())
+        .save(&resume)
+        .unwrap();
+    let storage = FileStorage::create(&meta_info.info, &path).unwrap();
+    assert_eq!(
+        load_pieces(&meta_info, &storage, &resume).unwrap(),
+        only_first
+    );
+
+    ResumeData::new(&meta_info, &only_first, vec![0])
+        .save(&resume)
+        .unwrap();
+    assert_eq!(load_pieces(&meta_info, &storage, &resume).unwrap(), have);
+}
@@ -0,0 +1,337 @@
+mod common;
+
+use std::{
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use bittorrent_starter_rust::{
+    bitfield::Bitfield,
+    choker::ChokerConfig,
+    error::TorrentError,
+    extensions::ExtensionHandshake,
+    peers::{Handshake, Peer, PeerMessage},
+    seeder::{SeedTorrent, Seeder, SeederConfig},
+    session::{DownloadSession, BLOCK_SIZE},
+    storage::{MemoryStorage, Storage},
+    torrent_file::MetaInfoFile,
+    trackers::announcer::TransferStats,
+};
+use common::{make_torrent, sample_contents};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpSocket},
+    time::{sleep, timeout},
+};
+
+const PIECE_LENGTH: u32 = 32 << 10;
+// Downloaders in the same process use the default peer ID
+const SEEDER_ID: [u8; 20] = *b"-SEEDER-000000000000";
+
+async fn spawn_seeder(
+    meta_info: &Arc<MetaInfoFile>,
+    contents: &[u8],
+    have: Bitfield,
+) -> (SocketAddr, Arc<TransferStats>) {
+    let storage = MemoryStorage::new();
+    for index in have.ones() {
+        let start = index * PIECE_LENGTH as usize;
+        let end = (start + PIECE_LENGTH as usize).min(contents.len());
+        storage
+            .write_piece(index as u32, &contents[start..end])
+            .unwrap();
+    }
+
+    let stats = Arc::new(TransferStats::new(0));
+    let mut seeder = Seeder::with_config(SeederConfig {
+        peer_id: SEEDER_ID,
+        ..Default::default()
+    });
+    seeder.add_torrent(SeedTorrent {
+        meta_info: meta_info.clone(),
+        storage: Arc::new(storage),
+        have,
+        stats: stats.clone(),
+    });
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(seeder.run(listener));
+    (addr, stats)
+}
+
+#[tokio::test]
+async fn test_download_from_seeder() {
+    let contents = sample_contents(3 * PIECE_LENGTH as usize + 1000);
+    let meta_info = Arc::new(make_torrent(&contents, PIECE_LENGTH));
+    let (addr, seeder_stats) = spawn_seeder(&meta_info, &contents, Bitfield::full(4)).await;
+
+    let storage = Arc::new(MemoryStorage::new());
+    let session = DownloadSession::new(
+        meta_info,
+        Arc::new(TransferStats::new(contents.len() as u64)),
+        storage.clone(),
+    );
+    session.add_peers(vec![addr]);
+    timeout(Duration::from_secs(10), session.download())
+        .await
+        .expect("Download timed out")
+        .unwrap();
+
+    assert_eq!(storage.contents(), contents);
+    assert_eq!(seeder_stats.uploaded(), contents.len() as u64);
+}
+
+#[tokio::test]
+async fn test_serve_requests() {
+    let contents = sample_contents(2 * PIECE_LENGTH as usize);
+    let meta_info = Arc::new(make_torrent(&contents, PIECE_LENGTH));
+    let mut have = Bitfield::new(2);
+    have.set(1);
+    let (addr, _stats) = spawn_seeder(&meta_info, &contents, have.clone()).await;
+
+    let mut peer = Peer::connect(&addr, &meta_info).await.unwrap();
+    assert!(peer.supports_extensions());
+    let PeerMessage::Extended { id: 0, payload } = peer.read_message().await.unwrap() else {
+        panic!("Expected an extension handshake");
+    };
+    let handshake = ExtensionHandshake::from_payload(&payload).unwrap();
+    assert_eq!(handshake.reqq(), Some(250));
+    assert_eq!(handshake.your_ip(), Some(addr.ip()));
+    assert_eq!(
+        peer.read_message().await.unwrap(),
+        PeerMessage::BitField(have.as_bytes().to_vec())
+    );
+    peer.send_message(&PeerMessage::Interested).await.unwrap();
+    assert_eq!(peer.read_message().await.unwrap(), PeerMessage::Unchoke);
+
+    let request = |begin| PeerMessage::Request {
+        index: 1,
+        begin,
+        length: BLOCK_SIZE,
+    };
+    peer.send_message(&request(BLOCK_SIZE)).await.unwrap();
+    let start = (PIECE_LENGTH + BLOCK_SIZE) as usize;
+    assert_eq!(
+        peer.read_message().await.unwrap(),
+        PeerMessage::Piece {
+            index: 1,
+            begin: BLOCK_SIZE,
+            block: contents[start..start + BLOCK_SIZE as usize].to_vec(),
+        }
+    );
+
+    // Pieces we do not have are not served
+    peer.send_message(&PeerMessage::Request {
+        index: 0,
+        begin: 0,
+        length: BLOCK_SIZE,
+    })
+    .await
+    .unwrap();
+    assert!(peer.read_message().await.is_err());
+}
+
+#[tokio::test]
+async fn test_unknown_torrent() {
+    let contents = sample_contents(PIECE_LENGTH as usize);
+    let meta_info = Arc::new(make_torrent(&contents, PIECE_LENGTH));
+    let (addr, _stats) = spawn_seeder(&meta_info, &contents, Bitfield::full(1)).await;
+
+    let other = make_torrent(&sample_contents(100), PIECE_LENGTH);
+    let result = Peer::connect(&addr, &other).await;
+    assert!(result.is_err());
+}
+
+#[tokio::test]
+async fn test_report_peer_errors() {
+    let contents = sample_contents(PIECE_LENGTH as usize);
+    let meta_info = Arc::new(make_torrent(&contents, PIECE_LENGTH));
+    let mut seeder = Seeder::with_config(SeederConfig {
+        peer_id: SEEDER_ID,
+        ..Default::default()
+    });
+    seeder.add_torrent(SeedTorrent {
+        meta_info: meta_info.clone(),
+        storage: Arc::new(MemoryStorage::new()),
+        have: Bitfield::new(1),
+        stats: Arc::new(TransferStats::new(0)),
+    });
+    let mut errors = seeder.errors();
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(seeder.run(listener));
+
+    let other = make_torrent(&sample_contents(100), PIECE_LENGTH);
+    assert!(Peer::connect(&addr, &other).await.is_err());
+    let err = timeout(Duration::from_secs(5), errors.recv())
+        .await
+        .expect("No error reported")
+        .unwrap();
+    assert!(matches!(err, TorrentError::Peer(_)));
+
+    // Still accepting peers
+    assert!(Peer::connect(&addr, &meta_info).await.is_ok());
+}
+
+#[tokio::test]
+async fn test_self_connection() {
+    let contents = sample_contents(PIECE_LENGTH as usize);
+    let meta_info = Arc::new(make_torrent(&contents, PIECE_LENGTH));
+    let mut seeder = Seeder::new();
+    seeder.add_torrent(SeedTorrent {
+        meta_info: meta_info.clone(),
+        storage: Arc::new(MemoryStorage::new()),
+        have: Bitfield::new(1),
+        stats: Arc::new(TransferStats::new(0)),
+    });
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(seeder.run(listener));
+
+    // Dropped without answering our handshake
+    let result = Peer::connect(&addr, &meta_info).await;
+    assert!(matches!(result, Err(TorrentError::Io(_))));
+}
+
+#[tokio::test]
+async fn test_upload_slots() {
+    let contents = sample_contents(PIECE_LENGTH as usize);
+    let meta_info = Arc::new(make_torrent(&contents, PIECE_LENGTH));
+    let storage = MemoryStorage::new();
+    storage.write_piece(0, &contents).unwrap();
+
+    // A single slot, for the optimistic unchoke
+    let mut seeder = Seeder::with_config(SeederConfig {
+        choker: ChokerConfig {
+            upload_slots: 1,
+            ..Default::default()
+        },
+        peer_id: SEEDER_ID,
+        ..Default::default()
+    });
+    seeder.add_torrent(SeedTorrent {
+        meta_info: meta_info.clone(),
+        storage: Arc::new(storage),
+        have: Bitfield::full(1),
+        stats: Arc::new(TransferStats::new(0)),
+    });
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(seeder.run(listener));
+
+    let mut peers = Vec::new();
+    for _ in 0..2 {
+        let mut peer = Peer::connect(&addr, &meta_info).await.unwrap();
+        assert!(matches!(
+            peer.read_message().await.unwrap(),
+            PeerMessage::Extended { id: 0, .. }
+        ));
+        assert!(matches!(
+            peer.read_message().await.unwrap(),
+            PeerMessage::BitField(_)
+        ));
+        peer.send_message(&PeerMessage::Interested).await.unwrap();
+        peers.push(peer);
+    }
+
+    // Only one of them is unchoked
+    let (first, second) = peers.split_at_mut(1);
+    let unchoked = tokio::select! {
+        msg = first[0].read_message() => { assert_eq!(msg.unwrap(), PeerMessage::Unchoke); 0 }
+        msg = second[0].read_message() => { assert_eq!(msg.unwrap(), PeerMessage::Unchoke); 1 }
+    };
+    let mut choked = peers.remove(1 - unchoked);
+    let wait = timeout(Duration::from_millis(200), choked.read_message()).await;
+    assert!(wait.is_err());
+
+    // Its slot goes to the other peer once it leaves
+    drop(peers);
+    let msg = timeout(Duration::from_secs(5), choked.read_message())
+        .await
+        .unwrap();
+    assert_eq!(msg.unwrap(), PeerMessage::Unchoke);
+}
+
+// Peer connecting from its own loopback IP, interested in everything.
+// Returns whether the seeder currently unchokes it.
+async fn spawn_interested_peer(
+    ip: [u8; 4],
+    addr: SocketAddr,
+    meta_info: &MetaInfoFile,
+) -> Arc<Mutex<bool>> {
+    let socket = TcpSocket::new_v4().unwrap();
+    socket.bind(SocketAddr::from((ip, 0))).unwrap();
+    let mut stream = socket.connect(addr).await.unwrap();
+    let handshake = Handshake::new(meta_info.info_hash_bytes(), [ip[3]; 20]);
+    stream.write_all(&handshake.to_bytes()).await.unwrap();
+    let mut reply = [0; Handshake::LENGTH];
+    stream.read_exact(&mut reply).await.unwrap();
+    PeerMessage::Interested.write(&mut stream).await.unwrap();
+
+    let unchoked = Arc::new(Mutex::new(false));
+    let task_unchoked = unchoked.clone();
+    tokio::spawn(async move {
+        while let Ok(msg) = PeerMessage::read(&mut stream).await {
+            match msg {
+                PeerMessage::Unchoke => *task_unchoked.lock().unwrap() = true,
+                PeerMessage::Choke => *task_unchoked.lock().unwrap() = false,
+                _ => {}
+            }
+        }
+    });
+    unchoked
+}
+
+#[tokio::test]
+async fn test_unchoke_peers_we_download_from() {
+    let contents = sample_contents(2 * PIECE_LENGTH as usize);
+    let meta_info = Arc::new(make_torrent(&contents, PIECE_LENGTH));
+    let storage = MemoryStorage::new();
+    storage
+        .write_piece(0, &contents[..PIECE_LENGTH as usize])
+        .unwrap();
+    let mut have = Bitfield::new(2);
+    have.set(0);
+
+    // One regular slot, and the optimistic unchoke
+    let stats = Arc::new(TransferStats::new(PIECE_LENGTH as u64));
+    let mut seeder = Seeder::with_config(SeederConfig {
+        choker: ChokerConfig {
+            upload_slots: 2,
+            rechoke_interval: Duration::from_millis(100),
+            ..Default::default()
+        },
+        peer_id: SEEDER_ID,
+        ..Default::default()
+    });
+    seeder.add_torrent(SeedTorrent {
+        meta_info: meta_info.clone(),
+        storage: Arc::new(storage),
+        have,
+        stats: stats.clone(),
+    });
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(seeder.run(listener));
+
+    let mut peers = Vec::new();
+    for ip in [[127, 0, 0, 2], [127, 0, 0, 3], [127, 0, 0, 4]] {
+        peers.push(spawn_interested_peer(ip, addr, &meta_info).await);
+    }
+
+    // Our download session keeps receiving blocks from the second one
+    let download_stats = stats.clone();
+    let downloading = tokio::spawn(async move {
+        loop {
+            download_stats.add_downloaded_from("127.0.0.3".parse().unwrap(), BLOCK_SIZE as u64);
+            sleep(Duration::from_millis(10)).await;
+        }
+    });
+
+    // Whoever gets the optimistic unchoke, the regular slot is for it
+    sleep(Duration::from_millis(550)).await;
+    assert!(*peers[1].lock().unwrap());
+    downloading.abort();
+}
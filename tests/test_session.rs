@@ -0,0 +1,738 @@
+mod common;
+
+use std::{collections::HashSet, net::SocketAddr, sync::Arc, time::Duration};
+
+use bittorrent_starter_rust::{
+    bitfield::Bitfield,
+    error::TorrentError,
+    peers::PeerMessage,
+    session::{pipeline::PipelineConfig, DownloadSession, SessionConfig, BLOCK_SIZE},
+    storage::{FileStorage, MemoryStorage, Storage},
+    trackers::announcer::TransferStats,
+};
+use common::{make_torrent, sample_contents, spawn_seeder, SeederBehavior};
+use tempfile::tempdir;
+use tokio::{net::TcpListener, time::timeout};
+
+const PIECE_LENGTH: u32 = 32 << 10;
+
+async fn download(
+    contents_len: usize,
+    behaviors: Vec<SeederBehavior>,
+) -> (
+    Vec<u8>,
+    Vec<u8>,
+    Vec<common::SeederStats>,
+    Arc<TransferStats>,
+) {
+    let contents = sample_contents(contents_len);
+    let meta_info = Arc::new(make_torrent(&contents, PIECE_LENGTH));
+
+    let mut peer_addrs = Vec::new();
+    let mut seeders = Vec::new();
+    for behavior in behaviors {
+        let (addr, stats) = spawn_seeder(&meta_info, contents.clone(), behavior).await;
+        peer_addrs.push(addr);
+        seeders.push(stats);
+    }
+
+    let stats = Arc::new(TransferStats::new(meta_info.info.total_length()));
+    let storage = Arc::new(MemoryStorage::new());
+    let session = DownloadSession::new(meta_info, stats.clone(), storage.clone());
+    session.add_peers(peer_addrs);
+    timeout(Duration::from_secs(10), session.download())
+        .await
+        .expect("Download timed out")
+        .unwrap();
+    let output = storage.contents();
+
+    (contents, output, seeders, stats)
+}
+
+#[tokio::test]
+async fn test_download_from_many_peers() {
+    let slow = SeederBehavior {
+        block_delay: Duration::from_millis(5),
+        ..Default::default()
+    };
+    let (contents, output, seeders, stats) =
+        download(1 << 20, vec![slow.clone(), slow.clone(), slow]).await;
+
+    assert_eq!(output, contents);
+    assert_eq!(stats.downloaded(), 1 << 20);
+    assert_eq!(stats.left(), 0);
+
+    // Every peer got some of the work
+    for seeder in &seeders {
+        assert!(seeder.blocks_served() > 0);
+    }
+    // Endgame mode may download the last blocks more than once
+    let served: usize = seeders.iter().map(|x| x.blocks_served()).sum();
+    assert!(served >= 64);
+    assert!(stats.duplicated() <= (served as u64 - 64) * BLOCK_SIZE as u64);
+}
+
+#[tokio::test]
+async fn test_download_last_piece_shorter() {
+    let (contents, output, _seeders, _stats) = download(
+        3 * PIECE_LENGTH as usize + 1000,
+        vec![SeederBehavior::default()],
+    )
+    .await;
+    assert_eq!(output, contents);
+}
+
+#[tokio::test]
+async fn test_requeue_on_disconnect() {
+    let flaky = SeederBehavior {
+        disconnect_after: Some(3),
+        ..Default::default()
+    };
+    let slow = SeederBehavior {
+        block_delay: Duration::from_millis(2),
+        ..Default::default()
+    };
+    let (contents, output, seeders, _stats) = download(1 << 20, vec![flaky, slow]).await;
+
+    assert_eq!(output, contents);
+    assert!(seeders[0].blocks_served() <= 3);
+}
+
+#[tokio::test]
+async fn test_report_peer_errors() {
+    let contents = sample_contents(1 << 20);
+    let meta_info = Arc::new(make_torrent(&contents, PIECE_LENGTH));
+    let flaky = SeederBehavior {
+        disconnect_after: Some(3),
+        ..Default::default()
+    };
+    let slow = SeederBehavior {
+        block_delay: Duration::from_millis(2),
+        ..Default::default()
+    };
+    let (flaky_addr, _) = spawn_seeder(&meta_info, contents.clone(), flaky).await;
+    let (addr, _) = spawn_seeder(&meta_info, contents.clone(), slow).await;
+
+    let stats = Arc::new(TransferStats::new(meta_info.info.total_length()));
+    let session = DownloadSession::new(meta_info, stats, Arc::new(MemoryStorage::new()));
+    let mut errors = session.errors();
+    session.add_peers(vec![flaky_addr, addr]);
+    timeout(Duration::from_secs(10), session.download())
+        .await
+        .expect("Download timed out")
+        .unwrap();
+
+    // The download went on without the peer that left
+    let err = errors.try_recv().expect("No error reported");
+    assert!(matches!(err, TorrentError::Peer(msg) if msg.contains(&flaky_addr.to_string())));
+}
+
+#[tokio::test]
+async fn test_keep_alive_while_choked() {
+    let contents = sample_contents(PIECE_LENGTH as usize);
+    let meta_info = Arc::new(make_torrent(&contents, PIECE_LENGTH));
+    let choking = SeederBehavior {
+        unchoke_delay: Duration::from_millis(500),
+        ..Default::default()
+    };
+    let (addr, seeder) = spawn_seeder(&meta_info, contents.clone(), choking).await;
+
+    let config = SessionConfig {
+        keep_alive_interval: Duration::from_millis(100),
+        ..Default::default()
+    };
+    let stats = Arc::new(TransferStats::new(contents.len() as u64));
+    let session =
+        DownloadSession::with_config(meta_info, stats, Arc::new(MemoryStorage::new()), config);
+    session.add_peers(vec![addr]);
+    timeout(Duration::from_secs(10), session.download())
+        .await
+        .expect("Download timed out")
+        .unwrap();
+    assert!(seeder.keep_alives() >= 3);
+}
+
+#[tokio::test]
+async fn test_silent_peer_times_out() {
+    let contents = sample_contents(PIECE_LENGTH as usize);
+    let meta_info = Arc::new(make_torrent(&contents, PIECE_LENGTH));
+    let silent = SeederBehavior {
+        unchoke_delay: Duration::from_secs(60),
+        ..Default::default()
+    };
+    let (addr, _) = spawn_seeder(&meta_info, contents.clone(), silent).await;
+
+    // PEX wakes the peer up regularly, which must not reset its timeout
+    let config = SessionConfig {
+        peer_timeout: Duration::from_millis(300),
+        pex_interval: Duration::from_millis(50),
+        ..Default::default()
+    };
+    let stats = Arc::new(TransferStats::new(contents.len() as u64));
+    let session =
+        DownloadSession::with_config(meta_info, stats, Arc::new(MemoryStorage::new()), config);
+    let mut errors = session.errors();
+    session.add_peers(vec![addr]);
+    let result = timeout(Duration::from_secs(5), session.download())
+        .await
+        .expect("Silent peer never timed out");
+    assert!(result.is_err());
+    let err = errors.try_recv().unwrap();
+    assert!(matches!(err, TorrentError::Peer(msg) if msg.ends_with("Peer timed out")));
+}
+
+#[tokio::test]
+async fn test_requeue_on_bad_data() {
+    let corrupt = SeederBehavior {
+        corrupt_piece: Some(0),
+        ..Default::default()
+    };
+    let (contents, output, _seeders, stats) = download(
+        4 * PIECE_LENGTH as usize,
+        vec![corrupt, SeederBehavior::default()],
+    )
+    .await;
+
+    assert_eq!(output, contents);
+    // Only verified pieces are reported
+    assert_eq!(stats.downloaded(), 4 * PIECE_LENGTH as u64);
+}
+
+#[tokio::test]
+async fn test_no_peer_left() {
+    let contents = sample_contents(PIECE_LENGTH as usize);
+    let meta_info = Arc::new(make_torrent(&contents, PIECE_LENGTH));
+
+    // Nobody listens there anymore
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr: SocketAddr = listener.local_addr().unwrap();
+    drop(listener);
+
+    let config = SessionConfig {
+        connect_timeout: Duration::from_secs(1),
+        ..Default::default()
+    };
+    let session = DownloadSession::with_config(
+        meta_info,
+        Arc::new(TransferStats::new(contents.len() as u64)),
+        Arc::new(MemoryStorage::new()),
+        config,
+    );
+    session.add_peers(vec![addr]);
+
+    let err = session.download().await.unwrap_err();
+    assert_eq!(err.to_string(), "Peer: No peer left to download from");
+}
+
+#[tokio::test]
+async fn test_queue_peers_over_limit() {
+    let contents = sample_contents(1 << 20);
+    let meta_info = Arc::new(make_torrent(&contents, PIECE_LENGTH));
+    let flaky = SeederBehavior {
+        disconnect_after: Some(3),
+        ..Default::default()
+    };
+    let (flaky_addr, flaky) = spawn_seeder(&meta_info, contents.clone(), flaky).await;
+    let (addr, seeder) = spawn_seeder(&meta_info, contents.clone(), Default::default()).await;
+
+    let config = SessionConfig {
+        max_peers: 1,
+        ..Default::default()
+    };
+    let storage = Arc::new(MemoryStorage::new());
+    let session = DownloadSession::with_config(
+        meta_info,
+        Arc::new(TransferStats::new(contents.len() as u64)),
+        storage.clone(),
+        config,
+    );
+    // Duplicates are only queued once
+    session.add_peers(vec![flaky_addr, addr, flaky_addr, addr]);
+
+    timeout(Duration::from_secs(10), session.download())
+        .await
+        .expect("Download timed out")
+        .unwrap();
+    assert_eq!(storage.contents(), contents);
+    assert_eq!(flaky.connections(), 1);
+    assert_eq!(seeder.connections(), 1);
+}
+
+#[tokio::test]
+async fn test_peers_wanted() {
+    let contents = sample_contents(PIECE_LENGTH as usize);
+    let meta_info = Arc::new(make_torrent(&contents, PIECE_LENGTH));
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr: SocketAddr = listener.local_addr().unwrap();
+    drop(listener);
+
+    let config = SessionConfig {
+        min_peers: 2,
+        ..Default::default()
+    };
+    let session = DownloadSession::with_config(
+        meta_info,
+        Arc::new(TransferStats::new(contents.len() as u64)),
+        Arc::new(MemoryStorage::new()),
+        config,
+    );
+    let progress = session.progress();
+    let _peers = session.peers_sender();
+    session.add_peers(vec![addr]);
+    let download = tokio::spawn(session.download());
+
+    // The only peer is gone
+    timeout(Duration::from_secs(5), progress.peers_wanted())
+        .await
+        .unwrap();
+    download.abort();
+}
+
+#[tokio::test]
+async fn test_peers_added_while_downloading() {
+    let contents = sample_contents(4 * PIECE_LENGTH as usize);
+    let meta_info = Arc::new(make_torrent(&contents, PIECE_LENGTH));
+    let (addr, _stats) = spawn_seeder(&meta_info, contents.clone(), Default::default()).await;
+
+    let storage = Arc::new(MemoryStorage::new());
+    let session = DownloadSession::new(
+        meta_info,
+        Arc::new(TransferStats::new(contents.len() as u64)),
+        storage.clone(),
+    );
+    let peers = session.peers_sender();
+    let download = tokio::spawn(session.download());
+
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    peers.send(vec![addr]).unwrap();
+
+    timeout(Duration::from_secs(10), download)
+        .await
+        .unwrap()
+        .unwrap()
+        .unwrap();
+    assert_eq!(storage.contents(), contents);
+}
+
+#[tokio::test]
+async fn test_requests_limited_by_reqq() {
+    let contents = sample_contents(8 * PIECE_LENGTH as usize);
+    let meta_info = Arc::new(make_torrent(&contents, PIECE_LENGTH));
+    let stall = SeederBehavior {
+        stall: true,
+        reqq: Some(3),
+        ..Default::default()
+    };
+    let (addr, seeder) = spawn_seeder(&meta_info, contents.clone(), stall).await;
+
+    let config = SessionConfig {
+        pipeline: PipelineConfig {
+            initial_window: 16,
+            request_timeout: Duration::from_millis(300),
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    let session = DownloadSession::with_config(
+        meta_info,
+        Arc::new(TransferStats::new(contents.len() as u64)),
+        Arc::new(MemoryStorage::new()),
+        config,
+    );
+    session.add_peers(vec![addr]);
+    assert!(session.download().await.is_err());
+
+    let requests = seeder.requests();
+    let sent = requests
+        .iter()
+        .filter(|x| matches!(x, PeerMessage::Request { .. }))
+        .count();
+    assert_eq!(sent, 3);
+}
+
+#[tokio::test]
+async fn test_requests_pipelined_across_pieces() {
+    let contents = sample_contents(8 * PIECE_LENGTH as usize);
+    let meta_info = Arc::new(make_torrent(&contents, PIECE_LENGTH));
+    let stall = SeederBehavior {
+        stall: true,
+        ..Default::default()
+    };
+    let (addr, seeder) = spawn_seeder(&meta_info, contents.clone(), stall).await;
+
+    let config = SessionConfig {
+        pipeline: PipelineConfig {
+            initial_window: 5,
+            request_timeout: Duration::from_millis(300),
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    let session = DownloadSession::with_config(
+        meta_info,
+        Arc::new(TransferStats::new(contents.len() as u64)),
+        Arc::new(MemoryStorage::new()),
+        config,
+    );
+    session.add_peers(vec![addr]);
+
+    // The stalled peer is dropped once its requests time out
+    let err = session.download().await.unwrap_err();
+    assert_eq!(err.to_string(), "Peer: No peer left to download from");
+
+    // Give the seeder some time to read the cancels
+    let mut requests = seeder.requests();
+    for _ in 0..100 {
+        if requests.len() >= 10 {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        requests = seeder.requests();
+    }
+    let (sent, cancelled) = requests.split_at(5);
+    let sent: Vec<_> = sent
+        .iter()
+        .map(|msg| match msg {
+            PeerMessage::Request {
+                index,
+                begin,
+                length: BLOCK_SIZE,
+            } => (*index, *begin),
+            msg => panic!("Unexpected message {msg:?}"),
+        })
+        .collect();
+
+    // Pieces are picked in random order but requested block after block
+    let (first, second, third) = (sent[0].0, sent[2].0, sent[4].0);
+    assert_eq!(
+        sent,
+        [
+            (first, 0),
+            (first, BLOCK_SIZE),
+            (second, 0),
+            (second, BLOCK_SIZE),
+            (third, 0)
+        ]
+    );
+    assert_eq!(HashSet::from([first, second, third]).len(), 3);
+
+    // Every outstanding request got cancelled
+    let mut cancelled: Vec<_> = cancelled
+        .iter()
+        .map(|msg| match msg {
+            PeerMessage::Cancel {
+                index,
+                begin,
+                length: BLOCK_SIZE,
+            } => (*index, *begin),
+            msg => panic!("Unexpected message {msg:?}"),
+        })
+        .collect();
+    let mut sent = sent;
+    sent.sort();
+    cancelled.sort();
+    assert_eq!(cancelled, sent);
+}
+
+#[tokio::test]
+async fn test_download_single_piece() {
+    let contents = sample_contents(4 * PIECE_LENGTH as usize);
+    let meta_info = Arc::new(make_torrent(&contents, PIECE_LENGTH));
+    let (addr, seeder) = spawn_seeder(&meta_info, contents.clone(), Default::default()).await;
+
+    let session = DownloadSession::new(
+        meta_info,
+        Arc::new(TransferStats::new(contents.len() as u64)),
+        Arc::new(MemoryStorage::new()),
+    );
+    session.add_peers(vec![addr]);
+
+    let piece = session.download_piece(2).await.unwrap();
+    assert_eq!(
+        piece,
+        contents[2 * PIECE_LENGTH as usize..3 * PIECE_LENGTH as usize]
+    );
+    assert_eq!(seeder.blocks_served(), 2);
+}
+
+#[tokio::test]
+async fn test_only_advertised_pieces_requested() {
+    let first_half = SeederBehavior {
+        pieces: Some(vec![0, 1, 2, 3]),
+        ..Default::default()
+    };
+    let second_half = SeederBehavior {
+        pieces: Some(vec![4, 5, 6, 7]),
+        ..Default::default()
+    };
+    let (contents, output, seeders, _stats) =
+        download(8 * PIECE_LENGTH as usize, vec![first_half, second_half]).await;
+    assert_eq!(output, contents);
+
+    // Seeders panic on requests for pieces they do not have
+    for (seeder, pieces) in seeders.iter().zip([0..4, 4..8]) {
+        assert_eq!(seeder.blocks_served(), 8);
+        for msg in seeder.requests() {
+            let PeerMessage::Request { index, .. } = msg else {
+                panic!("Unexpected message {msg:?}");
+            };
+            assert!(pieces.contains(&index));
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_endgame_requests_stalled_blocks_again() {
+    let contents = sample_contents(4 * PIECE_LENGTH as usize);
+    let meta_info = Arc::new(make_torrent(&contents, PIECE_LENGTH));
+    let stall = SeederBehavior {
+        stall: true,
+        ..Default::default()
+    };
+    let (stalled_addr, stalled) = spawn_seeder(&meta_info, contents.clone(), stall).await;
+    let (addr, seeder) = spawn_seeder(&meta_info, contents.clone(), Default::default()).await;
+
+    let stats = Arc::new(TransferStats::new(contents.len() as u64));
+    let storage = Arc::new(MemoryStorage::new());
+    let session = DownloadSession::new(meta_info, stats.clone(), storage.clone());
+    let peers = session.peers_sender();
+    let download = tokio::spawn(session.download());
+
+    // The stalled peer gets every block requested first
+    peers.send(vec![stalled_addr]).unwrap();
+    let mut requests = stalled.requests();
+    for _ in 0..100 {
+        if requests.len() >= 8 {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        requests = stalled.requests();
+    }
+    assert_eq!(requests.len(), 8);
+
+    // Without endgame mode, this would wait for the request timeout
+    peers.send(vec![addr]).unwrap();
+    timeout(Duration::from_secs(10), download)
+        .await
+        .expect("Download timed out")
+        .unwrap()
+        .unwrap();
+    assert_eq!(storage.contents(), contents);
+    assert_eq!(seeder.blocks_served(), 8);
+    assert_eq!(stats.duplicated(), 0);
+
+    // Blocks received from the other peer are cancelled
+    let mut requests = stalled.requests();
+    for _ in 0..100 {
+        if requests.len() > 8 {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        requests = stalled.requests();
+    }
+    let (sent, cancelled) = requests.split_at(8);
+    assert!(!cancelled.is_empty());
+    for msg in cancelled {
+        let PeerMessage::Cancel {
+            index,
+            begin,
+            length,
+        } = *msg
+        else {
+            panic!("Unexpected message {msg:?}");
+        };
+        assert!(sent.contains(&PeerMessage::Request {
+            index,
+            begin,
+            length
+        }));
+    }
+}
+
+#[tokio::test]
+async fn test_download_to_disk() {
+    let contents = sample_contents(3 * PIECE_LENGTH as usize + 1000);
+    let meta_info = Arc::new(make_torrent(&contents, PIECE_LENGTH));
+    let (addr, _seeder) = spawn_seeder(&meta_info, contents.clone(), Default::default()).await;
+
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("sample.bin");
+    let storage = FileStorage::create(&meta_info.info, &path).unwrap();
+    let session = DownloadSession::new(
+        meta_info,
+        Arc::new(TransferStats::new(contents.len() as u64)),
+        Arc::new(storage),
+    );
+    session.add_peers(vec![addr]);
+
+    timeout(Duration::from_secs(10), session.download())
+        .await
+        .expect("Download timed out")
+        .unwrap();
+    assert_eq!(std::fs::read(path).unwrap(), contents);
+}
+
+#[tokio::test]
+async fn test_only_missing_pieces_downloaded() {
+    let contents = sample_contents(4 * PIECE_LENGTH as usize);
+    let meta_info = Arc::new(make_torrent(&contents, PIECE_LENGTH));
+    let (addr, seeder) = spawn_seeder(&meta_info, contents.clone(), Default::default()).await;
+
+    let storage = Arc::new(MemoryStorage::new());
+    let stats = Arc::new(TransferStats::new(contents.len() as u64));
+    let session = DownloadSession::new(meta_info, stats.clone(), storage.clone());
+    let mut have = Bitfield::new(4);
+    for index in [0, 3] {
+        let start = index * PIECE_LENGTH as usize;
+        let piece = &contents[start..start + PIECE_LENGTH as usize];
+        storage.write_piece(index as u32, piece).unwrap();
+        have.set(index);
+    }
+    session.set_have(&have);
+    assert_eq!(stats.left(), 2 * PIECE_LENGTH as u64);
+
+    let progress = session.progress();
+    session.add_peers(vec![addr]);
+    timeout(Duration::from_secs(10), session.download())
+        .await
+        .expect("Download timed out")
+        .unwrap();
+
+    assert_eq!(storage.contents(), contents);
+    assert_eq!(seeder.blocks_served(), 4);
+    assert!(progress.is_complete());
+    assert!(progress.have().is_full());
+}
+
+#[tokio::test]
+async fn test_ban_peer_sending_bad_data() {
+    let contents = sample_contents(PIECE_LENGTH as usize);
+    let meta_info = Arc::new(make_torrent(&contents, PIECE_LENGTH));
+    let corrupt = SeederBehavior {
+        corrupt_piece: Some(0),
+        ..Default::default()
+    };
+    let (corrupt_addr, corrupt) = spawn_seeder(&meta_info, contents.clone(), corrupt).await;
+    let (addr, _seeder) = spawn_seeder(&meta_info, contents.clone(), Default::default()).await;
+
+    let config = SessionConfig {
+        max_hash_failures: 2,
+        ..Default::default()
+    };
+    let storage = Arc::new(MemoryStorage::new());
+    let session = DownloadSession::with_config(
+        meta_info,
+        Arc::new(TransferStats::new(contents.len() as u64)),
+        storage.clone(),
+        config,
+    );
+    let mut errors = session.errors();
+    let peers = session.peers_sender();
+    let download = tokio::spawn(session.download());
+
+    // The piece is downloaded again after the first failure
+    peers.send(vec![corrupt_addr]).unwrap();
+    for _ in 0..100 {
+        if corrupt.blocks_served() >= 4 {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    assert_eq!(corrupt.blocks_served(), 4);
+    assert_eq!(
+        errors.recv().await.unwrap().to_string(),
+        format!("Peer: {corrupt_addr}: Piece 0 failed hash check")
+    );
+
+    // Banned peers are not connected again
+    peers.send(vec![corrupt_addr]).unwrap();
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert_eq!(corrupt.connections(), 1);
+    assert_eq!(corrupt.blocks_served(), 4);
+
+    peers.send(vec![addr]).unwrap();
+    timeout(Duration::from_secs(10), download)
+        .await
+        .expect("Download timed out")
+        .unwrap()
+        .unwrap();
+    assert_eq!(storage.contents(), contents);
+}
+
+#[tokio::test]
+async fn test_corrupt_piece_from_another_peer() {
+    let contents = sample_contents(PIECE_LENGTH as usize);
+    let meta_info = Arc::new(make_torrent(&contents, PIECE_LENGTH));
+    let corrupt = SeederBehavior {
+        corrupt_piece: Some(0),
+        ..Default::default()
+    };
+    // Only sends once the corrupt peer failed
+    let late = SeederBehavior {
+        unchoke_delay: Duration::from_millis(300),
+        ..Default::default()
+    };
+    let (corrupt_addr, corrupt) = spawn_seeder(&meta_info, contents.clone(), corrupt).await;
+    let (addr, seeder) = spawn_seeder(&meta_info, contents.clone(), late).await;
+
+    let config = SessionConfig {
+        max_hash_failures: 100,
+        ..Default::default()
+    };
+    let storage = Arc::new(MemoryStorage::new());
+    let session = DownloadSession::with_config(
+        meta_info,
+        Arc::new(TransferStats::new(contents.len() as u64)),
+        storage.clone(),
+        config,
+    );
+    session.add_peers(vec![corrupt_addr, addr]);
+    timeout(Duration::from_secs(10), session.download())
+        .await
+        .expect("Download timed out")
+        .unwrap();
+
+    // The corrupt peer is not asked again while the other one has the piece
+    assert_eq!(storage.contents(), contents);
+    assert_eq!(corrupt.blocks_served(), 2);
+    assert_eq!(seeder.blocks_served(), 2);
+}
+
+#[tokio::test]
+async fn test_only_blame_sole_sender() {
+    // Single piece: endgame right away, both peers likely send blocks of it
+    let contents = sample_contents(4 * PIECE_LENGTH as usize);
+    let meta_info = Arc::new(make_torrent(&contents, 4 * PIECE_LENGTH));
+    let slow = SeederBehavior {
+        block_delay: Duration::from_millis(5),
+        ..Default::default()
+    };
+    let corrupt = SeederBehavior {
+        corrupt_piece: Some(0),
+        ..slow.clone()
+    };
+    let (corrupt_addr, corrupt) = spawn_seeder(&meta_info, contents.clone(), corrupt).await;
+    let (addr, seeder) = spawn_seeder(&meta_info, contents.clone(), slow).await;
+
+    // The honest peer is never banned along the corrupt one
+    let config = SessionConfig {
+        max_hash_failures: 1,
+        ..Default::default()
+    };
+    let storage = Arc::new(MemoryStorage::new());
+    let session = DownloadSession::with_config(
+        meta_info,
+        Arc::new(TransferStats::new(contents.len() as u64)),
+        storage.clone(),
+        config,
+    );
+    session.add_peers(vec![corrupt_addr, addr]);
+
+    timeout(Duration::from_secs(10), session.download())
+        .await
+        .expect("Download timed out")
+        .unwrap();
+    assert_eq!(storage.contents(), contents);
+    assert_eq!(corrupt.connections(), 1);
+    assert_eq!(seeder.connections(), 1);
+}
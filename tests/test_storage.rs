@@ -0,0 +1,173 @@
+mod common;
+
+use std::fs;
+
+use bittorrent_starter_rust::{
+    bencode_format::from_bytes,
+    storage::{FileStorage, MemoryStorage, Storage},
+    torrent_file::Info,
+};
+use common::sample_contents;
+use tempfile::tempdir;
+
+// Files of 120, 0 and 130 bytes with 100 bytes pieces: pieces 1 and 2 span files
+fn multi_file_info() -> Info {
+    Info {
+        name: "root".to_string(),
+        piece_length: 100,
+        pieces: vec![0; 60],
+        length: None,
+        files: Some(
+            from_bytes(
+                b"ld6:lengthi120e4:pathl5:a.txteed6:lengthi0e4:pathl5:emptye\
+                ed6:lengthi130e4:pathl3:sub5:b.txteee",
+            )
+            .unwrap(),
+        ),
+    }
+}
+
+#[test]
+fn test_preallocate_files() {
+    let temp = tempdir().unwrap();
+    let dir = temp.path();
+    let storage = FileStorage::create(&multi_file_info(), dir).unwrap();
+
+    assert_eq!(storage.files().count(), 3);
+    assert_eq!(fs::metadata(dir.join("root/a.txt")).unwrap().len(), 120);
+    assert_eq!(fs::metadata(dir.join("root/empty")).unwrap().len(), 0);
+    assert_eq!(fs::metadata(dir.join("root/sub/b.txt")).unwrap().len(), 130);
+}
+
+#[test]
+fn test_pieces_spanning_files() {
+    let temp = tempdir().unwrap();
+    let dir = temp.path();
+    let contents = sample_contents(250);
+    let storage = FileStorage::create(&multi_file_info(), dir).unwrap();
+
+    // Out of order, like pieces completing in a download
+    for index in [2, 0, 1] {
+        let start = index as usize * 100;
+        let end = (start + 100).min(contents.len());
+        storage.write_piece(index, &contents[start..end]).unwrap();
+    }
+
+    assert_eq!(fs::read(dir.join("root/a.txt")).unwrap(), contents[..120]);
+    assert_eq!(
+        fs::read(dir.join("root/sub/b.txt")).unwrap(),
+        contents[120..]
+    );
+    assert_eq!(storage.read_piece(1).unwrap(), contents[100..200]);
+    assert_eq!(storage.read_piece(2).unwrap(), contents[200..]);
+}
+
+#[test]
+fn test_single_file() {
+    let temp = tempdir().unwrap();
+    let dir = temp.path();
+    let contents = sample_contents(150);
+    let info = Info {
+        name: "ignored.bin".to_string(),
+        piece_length: 100,
+        pieces: vec![0; 40],
+        length: Some(150),
+        files: None,
+    };
+    let path = dir.join("output.bin");
+    let storage = FileStorage::create(&info, &path).unwrap();
+
+    storage.write_piece(1, &contents[100..]).unwrap();
+    let written = fs::read(&path).unwrap();
+    assert_eq!(written.len(), 150);
+    assert_eq!(written[..100], [0; 100]);
+    assert_eq!(written[100..], contents[100..]);
+
+    // Existing data is kept when opening the files again
+    let storage = FileStorage::create(&info, &path).unwrap();
+    assert_eq!(storage.read_piece(1).unwrap(), contents[100..]);
+}
+
+#[test]
+fn test_existing_files_not_resized() {
+    let temp = tempdir().unwrap();
+    let dir = temp.path();
+    fs::create_dir_all(dir.join("root/sub")).unwrap();
+    fs::write(dir.join("root/a.txt"), [1; 120]).unwrap();
+    fs::write(dir.join("root/sub/b.txt"), [2; 10]).unwrap();
+
+    let err = FileStorage::create(&multi_file_info(), dir).unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        format!(
+            "I/O: {} has 10 bytes instead of 130",
+            dir.join("root/sub/b.txt").display()
+        )
+    );
+    assert_eq!(fs::read(dir.join("root/sub/b.txt")).unwrap(), [2; 10]);
+
+    // Empty files hold nothing to lose
+    fs::write(dir.join("root/sub/b.txt"), []).unwrap();
+    let storage = FileStorage::create(&multi_file_info(), dir).unwrap();
+    assert!(storage.has_existing_data());
+    assert_eq!(fs::read(dir.join("root/a.txt")).unwrap(), [1; 120]);
+    assert_eq!(fs::metadata(dir.join("root/sub/b.txt")).unwrap().len(), 130);
+}
+
+#[test]
+fn test_open_existing_files() {
+    let temp = tempdir().unwrap();
+    let dir = temp.path();
+    let contents = sample_contents(250);
+
+    let err = FileStorage::open(&multi_file_info(), dir).unwrap_err();
+    assert!(err.to_string().starts_with(&format!(
+        "I/O: Cannot open {}",
+        dir.join("root/a.txt").display()
+    )));
+    assert!(!dir.join("root").exists());
+
+    fs::create_dir_all(dir.join("root/sub")).unwrap();
+    fs::write(dir.join("root/a.txt"), &contents[..120]).unwrap();
+    fs::write(dir.join("root/empty"), []).unwrap();
+    fs::write(dir.join("root/sub/b.txt"), &contents[120..200]).unwrap();
+    let err = FileStorage::open(&multi_file_info(), dir).unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        format!(
+            "I/O: {} has 80 bytes instead of 130",
+            dir.join("root/sub/b.txt").display()
+        )
+    );
+
+    fs::write(dir.join("root/sub/b.txt"), &contents[120..]).unwrap();
+    let storage = FileStorage::open(&multi_file_info(), dir).unwrap();
+    assert!(storage.has_existing_data());
+    assert_eq!(storage.read_piece(1).unwrap(), contents[100..200]);
+    assert_eq!(storage.read_block(2, 10, 20).unwrap(), contents[210..230]);
+    assert!(storage.write_piece(0, &contents[..100]).is_err());
+}
+
+#[test]
+fn test_piece_out_of_storage() {
+    let temp = tempdir().unwrap();
+    let dir = temp.path();
+    let storage = FileStorage::create(&multi_file_info(), dir).unwrap();
+
+    let err = storage.write_piece(2, &[0; 100]).unwrap_err();
+    assert_eq!(err.to_string(), "I/O: Piece 2 out of storage");
+}
+
+#[test]
+fn test_memory_storage() {
+    let storage = MemoryStorage::new();
+    storage.write_piece(1, b"world").unwrap();
+    storage.write_piece(0, b"hello ").unwrap();
+
+    assert_eq!(storage.contents(), b"hello world");
+    assert_eq!(storage.read_piece(1).unwrap(), b"world");
+    assert_eq!(
+        storage.read_piece(2).unwrap_err().to_string(),
+        "I/O: Piece 2 not stored"
+    );
+}
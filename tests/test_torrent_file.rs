@@ -1,4 +1,10 @@
-use bittorrent_starter_rust::torrent_file::{InfoSingleFile, MetaInfoFile};
+use bittorrent_starter_rust::{
+    bencode_format::{from_bytes, to_bytes},
+    torrent_file::{locate, FileEntry, Info, MetaInfoFile},
+    utils::hash_sha1,
+};
+use std::path::PathBuf;
+
 use serde_json::json;
 
 const TEST_PIECES: [u8; 60] = [
@@ -25,27 +31,94 @@ fn test_derive() {
     .unwrap();
 
     // Debug
-    assert_eq!(format!("{meta_info:?}"), "MetaInfoFile { announce: \"http://test.torrent.com\", info: InfoSingleFile { name: \"test.txt\", length: 296, piece_length: 312, pieces: [1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19, 20] }, created_by: None, comment: None }");
+    assert_eq!(format!("{meta_info:?}"), "MetaInfoFile { announce: \"http://test.torrent.com\", announce_list: None, info: Info { name: \"test.txt\", piece_length: 312, pieces: [1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19, 20], length: Some(296), files: None }, created_by: None, comment: None, nodes: None, info_bytes: [] }");
+}
+
+fn encode_meta_info(info_entries: &[u8]) -> Vec<u8> {
+    let mut data = b"d8:announce23:http://test.torrent.com4:infod".to_vec();
+    data.extend_from_slice(info_entries);
+    data.extend_from_slice(b"ee");
+    data
+}
+
+fn encode_info_entries(extra: &[u8]) -> Vec<u8> {
+    let mut entries = b"6:lengthi296e4:name8:test.txt12:piece lengthi100e6:pieces60:".to_vec();
+    entries.extend_from_slice(&TEST_PIECES);
+    entries.extend_from_slice(extra);
+    entries
 }
 
 #[test]
 fn test_info_hash() {
-    let info = InfoSingleFile {
-        name: "test.txt".to_string(),
-        length: 296,
-        piece_length: 312,
-        pieces: TEST_PIECES.to_vec(),
-    };
+    let data = encode_meta_info(&encode_info_entries(b""));
+    let meta_info = MetaInfoFile::from_bytes(&data).unwrap();
+
+    assert_eq!(
+        meta_info.info_hash(),
+        "f2732cb6450796af9571194844d8670929347803"
+    );
+}
+
+#[test]
+fn test_info_hash_without_info_bytes() {
+    // Deserialized without `from_bytes`: the original info dict is unknown
+    let data = encode_meta_info(&encode_info_entries(b""));
+    let meta_info: MetaInfoFile = from_bytes(&data).unwrap();
+
+    assert!(meta_info.info_bytes().is_empty());
+    assert_eq!(
+        meta_info.info_hash(),
+        "f2732cb6450796af9571194844d8670929347803"
+    );
+}
+
+#[test]
+fn test_info_hash_extra_keys() {
+    let entries = encode_info_entries(b"7:privatei1e6:source4:test");
+    let data = encode_meta_info(&entries);
+    let meta_info = MetaInfoFile::from_bytes(&data).unwrap();
 
-    assert_eq!(info.info_hash(), "a8d8cc6ac9e649158452dee9800c15571c491656");
+    // Hash must cover unknown keys exactly as they were encoded
+    let mut info_bytes = b"d".to_vec();
+    info_bytes.extend_from_slice(&entries);
+    info_bytes.push(b'e');
+    assert_eq!(meta_info.info_bytes(), info_bytes);
+    assert_eq!(meta_info.info_hash_bytes(), hash_sha1(&info_bytes));
+    assert_ne!(
+        meta_info.info_hash(),
+        "f2732cb6450796af9571194844d8670929347803"
+    );
+}
+
+#[test]
+fn test_from_bytes_invalid() {
+    assert!(MetaInfoFile::from_bytes(b"d8:announce3:fooe").is_err());
+    assert!(MetaInfoFile::from_bytes(b"le").is_err());
+}
+
+#[test]
+fn test_trackerless() {
+    let mut data = b"d4:infod".to_vec();
+    data.extend(encode_info_entries(b""));
+    data.extend(b"e5:nodesll9:127.0.0.1i6881eel7:dht.fooi6882eeee");
+    let meta_info = MetaInfoFile::from_bytes(&data).unwrap();
+    assert!(meta_info.announce.is_empty());
+    assert_eq!(
+        meta_info.nodes,
+        Some(vec![
+            ("127.0.0.1".to_string(), 6881),
+            ("dht.foo".to_string(), 6882)
+        ])
+    );
 }
 
 #[test]
 #[should_panic = "pieces is not a multiple of 20"]
 fn test_bad_pieces_count() {
-    let info = InfoSingleFile {
+    let info = Info {
         name: "test.txt".to_string(),
-        length: 296,
+        length: Some(296),
+        files: None,
         piece_length: 312,
         pieces: TEST_PIECES[..43].to_vec(),
     };
@@ -55,9 +128,10 @@ fn test_bad_pieces_count() {
 #[test]
 #[should_panic = "pieces is not a multiple of 20"]
 fn test_bad_pieces_hashes() {
-    let info = InfoSingleFile {
+    let info = Info {
         name: "test.txt".to_string(),
-        length: 296,
+        length: Some(296),
+        files: None,
         piece_length: 312,
         pieces: TEST_PIECES[..43].to_vec(),
     };
@@ -66,9 +140,10 @@ fn test_bad_pieces_hashes() {
 
 #[test]
 fn test_pieces_hashes() {
-    let info = InfoSingleFile {
+    let info = Info {
         name: "test.txt".to_string(),
-        length: 296,
+        length: Some(296),
+        files: None,
         piece_length: 312,
         pieces: TEST_PIECES.to_vec(),
     };
@@ -83,3 +158,179 @@ fn test_pieces_hashes() {
         ]
     );
 }
+
+#[test]
+fn test_sample_round_trip() {
+    let data = include_bytes!("../sample.torrent");
+    let meta_info = MetaInfoFile::from_bytes(data).unwrap();
+
+    assert_eq!(
+        meta_info.announce,
+        "http://bittorrent-test-tracker.codecrafters.io/announce"
+    );
+    assert_eq!(meta_info.info.length, Some(2549700));
+    assert_eq!(meta_info.info.total_length(), 2549700);
+    assert_eq!(meta_info.info.piece_length, 262144);
+    assert_eq!(meta_info.info.pieces_count(), 10);
+    assert_eq!(
+        meta_info.info_hash(),
+        "70edcac2611a8829ebf467a6849f5d8408d9d8f4"
+    );
+
+    // Binary pieces must survive a round trip
+    let encoded = to_bytes(&meta_info).unwrap();
+    let decoded: MetaInfoFile = from_bytes(&encoded).unwrap();
+    assert_eq!(decoded.info.pieces, meta_info.info.pieces);
+}
+
+fn multi_file_info() -> Info {
+    Info {
+        name: "root".to_string(),
+        piece_length: 100,
+        pieces: TEST_PIECES.to_vec(),
+        length: None,
+        files: Some(
+            from_bytes(
+                b"ld6:lengthi120e4:pathl5:a.txteed6:lengthi0e4:pathl5:emptye\
+                ed6:lengthi130e4:pathl3:sub5:b.txteee",
+            )
+            .unwrap(),
+        ),
+    }
+}
+
+#[test]
+fn test_multi_file_decode() {
+    let mut data =
+        b"d8:announce3:foo4:infod5:filesld6:lengthi4e4:pathl1:aeed6:lengthi2e4:pathl1:b1:ceee\
+        4:name4:root12:piece lengthi4e6:pieces40:"
+            .to_vec();
+    data.extend_from_slice(&TEST_PIECES[..40]);
+    data.extend_from_slice(b"ee");
+    let meta_info = MetaInfoFile::from_bytes(&data).unwrap();
+
+    assert!(meta_info.info.is_multi_file());
+    assert_eq!(meta_info.info.total_length(), 6);
+    assert_eq!(
+        meta_info.info.files(),
+        vec![
+            FileEntry {
+                path: PathBuf::from("root/a"),
+                length: 4,
+                offset: 0
+            },
+            FileEntry {
+                path: PathBuf::from("root/b/c"),
+                length: 2,
+                offset: 4
+            },
+        ]
+    );
+}
+
+#[test]
+fn test_single_file_layout() {
+    let info = Info {
+        name: "test.txt".to_string(),
+        length: Some(296),
+        files: None,
+        piece_length: 312,
+        pieces: TEST_PIECES.to_vec(),
+    };
+
+    assert!(!info.is_multi_file());
+    assert_eq!(info.total_length(), 296);
+    let files = info.files();
+    assert_eq!(
+        files,
+        vec![FileEntry {
+            path: PathBuf::from("test.txt"),
+            length: 296,
+            offset: 0
+        }]
+    );
+    assert_eq!(locate(&files, 0), Some((0, 0)));
+    assert_eq!(locate(&files, 295), Some((0, 295)));
+    assert_eq!(locate(&files, 296), None);
+}
+
+#[test]
+fn test_multi_file_locate() {
+    let info = multi_file_info();
+    let files = info.files();
+
+    assert_eq!(info.total_length(), 250);
+    assert_eq!(locate(&files, 0), Some((0, 0)));
+    assert_eq!(locate(&files, 119), Some((0, 119)));
+    assert_eq!(locate(&files, 120), Some((2, 0))); // Empty file is skipped
+    assert_eq!(locate(&files, 249), Some((2, 129)));
+    assert_eq!(locate(&files, 250), None);
+}
+
+#[test]
+fn test_length_or_files() {
+    let err = MetaInfoFile::from_bytes(
+        b"d8:announce3:foo4:infod5:filesld6:lengthi4e4:pathl1:aeee6:lengthi4e\
+        4:name4:root12:piece lengthi4e6:pieces0:ee",
+    )
+    .unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "Bencode: Info dict has both length and files"
+    );
+
+    let err = MetaInfoFile::from_bytes(
+        b"d8:announce3:foo4:infod4:name4:root12:piece lengthi4e6:pieces0:ee",
+    )
+    .unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "Bencode: Info dict has neither length nor files"
+    );
+}
+
+#[test]
+fn test_pieces_match_length() {
+    let info_error = |entries: &[u8]| {
+        let data = encode_meta_info(entries);
+        MetaInfoFile::from_bytes(&data).unwrap_err().to_string()
+    };
+
+    let mut entries = b"6:lengthi10e4:name1:a12:piece lengthi100e6:pieces40:".to_vec();
+    entries.extend_from_slice(&TEST_PIECES[..40]);
+    assert_eq!(
+        info_error(&entries),
+        "Bencode: Info dict has 2 piece hashes instead of 1"
+    );
+
+    let mut entries = b"6:lengthi10e4:name1:a12:piece lengthi0e6:pieces20:".to_vec();
+    entries.extend_from_slice(&TEST_PIECES[..20]);
+    assert_eq!(
+        info_error(&entries),
+        "Bencode: Info dict has a piece length of 0"
+    );
+
+    assert_eq!(
+        info_error(b"6:lengthi10e4:name1:a12:piece lengthi100e6:pieces3:abc"),
+        "Bencode: Info dict pieces is not a multiple of 20 bytes"
+    );
+}
+
+#[test]
+fn test_piece_size() {
+    let info = multi_file_info();
+
+    assert_eq!(info.piece_size(0), 100);
+    assert_eq!(info.piece_size(1), 100);
+    assert_eq!(info.piece_size(2), 50);
+    assert_eq!(info.piece_size(3), 0);
+}
+
+#[test]
+fn test_files_sanitized() {
+    let mut info = multi_file_info();
+    info.name = "..".to_string();
+    info.files = Some(from_bytes(b"ld6:lengthi1e4:pathl2:..3:a/b1:.eee").unwrap());
+
+    assert_eq!(info.files()[0].path, PathBuf::from("_/_/a_b/_"));
+}
@@ -0,0 +1,169 @@
+mod common;
+
+use std::{
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+use bittorrent_starter_rust::{
+    error::TorrentError,
+    torrent_file::MetaInfoFile,
+    trackers::{
+        tiers::{merge_peer_addrs, TrackerList},
+        AnnounceEvent, AnnounceRequest,
+    },
+};
+use common::{compact_peers_response, dead_tracker_url, hanging_tracker_url, spawn_http_tracker};
+
+fn announce_request() -> AnnounceRequest {
+    AnnounceRequest {
+        info_hash: [1; 20],
+        peer_id: [2; 20],
+        port: 6881,
+        uploaded: 0,
+        downloaded: 0,
+        left: 100,
+        event: AnnounceEvent::None,
+    }
+}
+
+fn sorted(mut items: Vec<String>) -> Vec<String> {
+    items.sort();
+    items
+}
+
+#[test]
+fn test_from_meta_info() {
+    let meta_info = MetaInfoFile::from_bytes(
+        b"d8:announce5:main013:announce-listll2:a12:a2el2:b1ee4:infod6:lengthi0e4:name1:a\
+        12:piece lengthi1e6:pieces0:ee",
+    )
+    .unwrap();
+    let trackers = TrackerList::from_meta_info(&meta_info);
+
+    // Tiers keep their order, trackers are shuffled inside a tier
+    assert_eq!(trackers.tiers().len(), 2);
+    assert_eq!(sorted(trackers.tiers()[0].clone()), vec!["a1", "a2"]);
+    assert_eq!(trackers.tiers()[1], vec!["b1"]);
+
+    // Fallback on `announce`
+    let meta_info = MetaInfoFile::from_bytes(
+        b"d8:announce5:main013:announce-listllee4:infod6:lengthi0e4:name1:a\
+        12:piece lengthi1e6:pieces0:ee",
+    )
+    .unwrap();
+    let trackers = TrackerList::from_meta_info(&meta_info);
+    assert_eq!(trackers.tiers(), &[vec!["main0".to_string()]]);
+
+    // No tracker at all
+    let meta_info = MetaInfoFile::from_bytes(
+        b"d8:announce0:4:infod6:lengthi0e4:name1:a12:piece lengthi1e6:pieces0:ee",
+    )
+    .unwrap();
+    assert!(TrackerList::from_meta_info(&meta_info).tiers().is_empty());
+}
+
+#[test]
+fn test_empty_tiers_removed() {
+    let trackers = TrackerList::new(vec![vec![], vec!["a".to_string()], vec![]]);
+    assert_eq!(trackers.tiers(), &[vec!["a".to_string()]]);
+}
+
+#[tokio::test]
+async fn test_promote_working_tracker() {
+    let (alive_url, alive_requests) =
+        spawn_http_tracker(compact_peers_response(60, &[[127, 0, 0, 1, 0, 80]])).await;
+    let dead_urls = [dead_tracker_url().await, dead_tracker_url().await];
+
+    let mut trackers = TrackerList::new(vec![vec![
+        dead_urls[0].clone(),
+        alive_url.clone(),
+        dead_urls[1].clone(),
+    ]]);
+
+    let responses = trackers.announce(&announce_request()).await.unwrap();
+    assert_eq!(responses.len(), 1);
+    assert_eq!(trackers.tiers()[0][0], alive_url);
+    assert_eq!(
+        sorted(trackers.tiers()[0].clone()),
+        sorted(vec![
+            alive_url.clone(),
+            dead_urls[0].clone(),
+            dead_urls[1].clone()
+        ])
+    );
+
+    // Working tracker is now tried first
+    trackers.announce(&announce_request()).await.unwrap();
+    assert_eq!(alive_requests.lock().unwrap().len(), 2);
+}
+
+#[tokio::test]
+async fn test_merge_peers_from_all_tiers() {
+    let (url1, _) = spawn_http_tracker(compact_peers_response(
+        60,
+        &[[127, 0, 0, 1, 0, 80], [127, 0, 0, 2, 0, 80]],
+    ))
+    .await;
+    let (url2, _) = spawn_http_tracker(compact_peers_response(
+        60,
+        &[[127, 0, 0, 2, 0, 80], [127, 0, 0, 3, 0, 80]],
+    ))
+    .await;
+    let dead_url = dead_tracker_url().await;
+
+    let mut trackers = TrackerList::new(vec![vec![url1], vec![dead_url], vec![url2]]);
+    let responses = trackers.announce(&announce_request()).await.unwrap();
+    assert_eq!(responses.len(), 2);
+
+    let peers: Vec<SocketAddr> = ["127.0.0.1:80", "127.0.0.2:80", "127.0.0.3:80"]
+        .iter()
+        .map(|x| x.parse().unwrap())
+        .collect();
+    assert_eq!(merge_peer_addrs(&responses), peers);
+}
+
+#[tokio::test]
+async fn test_all_trackers_down() {
+    let mut trackers = TrackerList::new(vec![
+        vec![dead_tracker_url().await],
+        vec![dead_tracker_url().await],
+    ]);
+
+    let err = trackers.announce(&announce_request()).await.unwrap_err();
+    assert!(matches!(err, TorrentError::Http(_)));
+
+    let mut trackers = TrackerList::new(vec![]);
+    let err = trackers.announce(&announce_request()).await.unwrap_err();
+    assert_eq!(err.to_string(), "Tracker: No tracker to announce to");
+}
+
+#[tokio::test]
+async fn test_hanging_tracker_times_out() {
+    let (url1, _) = spawn_http_tracker(compact_peers_response(60, &[[127, 0, 0, 1, 0, 80]])).await;
+    let (url2, _) = spawn_http_tracker(compact_peers_response(60, &[[127, 0, 0, 2, 0, 80]])).await;
+    let hanging_url = hanging_tracker_url().await;
+
+    // Hanging trackers neither block their tier nor the other ones
+    let mut trackers = TrackerList::new(vec![
+        vec![hanging_url.clone()],
+        vec![url1],
+        vec![hanging_url.clone(), hanging_url.clone(), url2],
+    ])
+    .with_timeout(Duration::from_millis(200));
+
+    let start = Instant::now();
+    let responses = trackers.announce(&announce_request()).await.unwrap();
+    assert!(start.elapsed() < Duration::from_secs(2));
+
+    let peers: Vec<SocketAddr> = ["127.0.0.1:80", "127.0.0.2:80"]
+        .iter()
+        .map(|x| x.parse().unwrap())
+        .collect();
+    assert_eq!(merge_peer_addrs(&responses), peers);
+
+    let mut trackers =
+        TrackerList::new(vec![vec![hanging_url]]).with_timeout(Duration::from_millis(50));
+    let err = trackers.announce(&announce_request()).await.unwrap_err();
+    assert!(err.to_string().contains("Tracker timed out"));
+}
@@ -1,6 +1,12 @@
-use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+mod common;
 
-use bittorrent_starter_rust::trackers::TrackerResponse;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use bittorrent_starter_rust::{
+    bencode_format::{from_bytes, to_bytes},
+    error::TorrentError,
+    trackers::{self, AnnounceEvent, AnnounceRequest, ScrapeStats, TrackerResponse},
+};
 use serde_json::json;
 
 #[test]
@@ -20,20 +26,29 @@ fn test_response_derive() {
     // Debug
     assert_eq!(
         format!("{response:?}"),
-        "TrackerResponse { interval: 60, peers: [127, 0, 0, 1, 86, 20, 255, 10, 10, 36, 38, 42] }"
+        "TrackerResponse { failure_reason: None, warning_message: None, interval: 60, min_interval: None, \
+        tracker_id: None, complete: None, incomplete: None, peers: [127.0.0.1:22036, 255.10.10.36:9770], peers6: [] }"
     );
 }
 
 #[test]
-#[should_panic = "Peers is not a multiple of 6 bytes"]
 fn test_peers_invalid() {
-    let response: TrackerResponse = serde_json::from_value(json!({
+    let err = serde_json::from_value::<TrackerResponse>(json!({
         "interval": 60,
         "peers": [127, 0],
     }))
-    .unwrap();
+    .unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "Compact peers is not a multiple of 6 bytes"
+    );
 
-    response.peer_addrs();
+    let err = from_bytes::<TrackerResponse>(b"d8:intervali60e6:peers65:\x7f\x00\x00\x01\x56e")
+        .unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "Bencode parse error at byte 30: Compact peers6 is not a multiple of 18 bytes"
+    );
 }
 
 #[test]
@@ -57,3 +72,186 @@ fn test_peers_valid() {
         ]
     );
 }
+
+#[test]
+fn test_response_bencode() {
+    let response: TrackerResponse =
+        from_bytes(b"d8:intervali60e5:peers12:\x7f\x00\x00\x01\x56\x14\xff\x0a\x0a\x24\x26\x2ae")
+            .unwrap();
+
+    assert_eq!(response.interval, 60);
+    assert_eq!(
+        response.peer_addrs(),
+        vec![
+            SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), (86 << 8) + 20),
+            SocketAddr::new(IpAddr::V4(Ipv4Addr::new(255, 10, 10, 36)), (38 << 8) + 42)
+        ]
+    );
+}
+
+#[test]
+fn test_response_dict_peers() {
+    let response: TrackerResponse = from_bytes(
+        b"d8:intervali60e5:peersl\
+        d2:ip9:127.0.0.17:peer id20:aaaaaaaaaaaaaaaaaaaa4:porti6881ee\
+        d2:ip3:::14:porti6882ee\
+        d2:ip15:tracker.invalid4:porti6883ee\
+        ee",
+    )
+    .unwrap();
+
+    // Hostnames are skipped
+    assert_eq!(
+        response.peer_addrs(),
+        vec![
+            SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 6881),
+            SocketAddr::new(IpAddr::V6(Ipv6Addr::LOCALHOST), 6882),
+        ]
+    );
+}
+
+#[test]
+fn test_response_peers6() {
+    let mut body = b"d8:intervali60e5:peers6:\x7f\x00\x00\x01\x1a\xe16:peers618:".to_vec();
+    body.extend_from_slice(&Ipv6Addr::LOCALHOST.octets());
+    body.extend_from_slice(&[0x1a, 0xe2]);
+    body.push(b'e');
+
+    let response: TrackerResponse = from_bytes(&body).unwrap();
+    assert_eq!(
+        response.peer_addrs(),
+        vec![
+            SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 6881),
+            SocketAddr::new(IpAddr::V6(Ipv6Addr::LOCALHOST), 6882),
+        ]
+    );
+
+    // Serialized back in compact form
+    assert_eq!(to_bytes(&response).unwrap(), body);
+}
+
+#[test]
+fn test_response_optional_fields() {
+    let response: TrackerResponse = from_bytes(
+        b"d8:completei5e10:incompletei3e8:intervali900e12:min intervali60e\
+        5:peers0:10:tracker id3:abc15:warning message4:slowe",
+    )
+    .unwrap();
+
+    assert_eq!(response.failure_reason, None);
+    assert_eq!(response.warning_message.as_deref(), Some("slow"));
+    assert_eq!(response.interval, 900);
+    assert_eq!(response.min_interval, Some(60));
+    assert_eq!(response.tracker_id.as_deref(), Some("abc"));
+    assert_eq!(response.complete, Some(5));
+    assert_eq!(response.incomplete, Some(3));
+    assert!(response.peer_addrs().is_empty());
+}
+
+fn announce_request() -> AnnounceRequest {
+    AnnounceRequest {
+        info_hash: [1; 20],
+        peer_id: [2; 20],
+        port: 6881,
+        uploaded: 0,
+        downloaded: 0,
+        left: 0,
+        event: AnnounceEvent::None,
+    }
+}
+
+#[tokio::test]
+async fn test_announce_failure_reason() {
+    // Failing trackers usually omit every other key
+    let (url, _requests) =
+        common::spawn_http_tracker(b"d14:failure reason22:torrent not registerede".to_vec()).await;
+
+    let err = trackers::announce(&url, &announce_request())
+        .await
+        .unwrap_err();
+    assert!(matches!(err, TorrentError::TrackerFailure(_)));
+    assert_eq!(err.to_string(), "Tracker failure: torrent not registered");
+}
+
+#[tokio::test]
+async fn test_announce_warning_message() {
+    let (url, _requests) = common::spawn_http_tracker(
+        b"d8:intervali60e5:peers6:\x7f\x00\x00\x01\x1a\xe115:warning message4:slowe".to_vec(),
+    )
+    .await;
+
+    let response = trackers::announce(&url, &announce_request()).await.unwrap();
+    assert_eq!(response.warning_message.as_deref(), Some("slow"));
+    assert_eq!(response.peer_addrs().len(), 1);
+}
+
+#[test]
+fn test_scrape_url() {
+    assert_eq!(
+        trackers::scrape_url("http://example.com/announce").as_deref(),
+        Some("http://example.com/scrape")
+    );
+    assert_eq!(
+        trackers::scrape_url("http://example.com/x/announce.php?key=1").as_deref(),
+        Some("http://example.com/x/scrape.php?key=1")
+    );
+    assert_eq!(
+        trackers::scrape_url("http://example.com/announce?x2%0644").as_deref(),
+        Some("http://example.com/scrape?x2%0644")
+    );
+    assert_eq!(trackers::scrape_url("http://example.com/a"), None);
+    assert_eq!(trackers::scrape_url("http://example.com/announce/x"), None);
+    assert_eq!(
+        trackers::scrape_url("http://example.com/x%064announce"),
+        None
+    );
+}
+
+#[tokio::test]
+async fn test_http_scrape() {
+    let mut body = b"d5:filesd20:".to_vec();
+    body.extend_from_slice(&[1; 20]);
+    body.extend_from_slice(b"d8:completei5e10:downloadedi50e10:incompletei10eeee");
+    let (url, requests) = common::spawn_http_tracker(body).await;
+
+    let stats = trackers::scrape(&url, &[[1; 20], [2; 20]]).await.unwrap();
+    assert_eq!(
+        stats,
+        vec![
+            ScrapeStats {
+                complete: 5,
+                downloaded: 50,
+                incomplete: 10
+            },
+            // Unknown to the tracker
+            ScrapeStats::default(),
+        ]
+    );
+
+    let requests = requests.lock().unwrap();
+    assert_eq!(
+        requests[0],
+        format!(
+            "GET /scrape?info_hash={}&info_hash={} HTTP/1.1",
+            "%01".repeat(20),
+            "%02".repeat(20)
+        )
+    );
+}
+
+#[tokio::test]
+async fn test_http_scrape_failure() {
+    let (url, _requests) =
+        common::spawn_http_tracker(b"d14:failure reason16:scrape forbiddene".to_vec()).await;
+
+    let err = trackers::scrape(&url, &[[1; 20]]).await.unwrap_err();
+    assert_eq!(err.to_string(), "Tracker failure: scrape forbidden");
+
+    let err = trackers::scrape("http://example.com/tracker", &[[1; 20]])
+        .await
+        .unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "Tracker: Tracker does not support scrape: http://example.com/tracker"
+    );
+}
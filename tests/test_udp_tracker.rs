@@ -0,0 +1,307 @@
+use std::{
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use bittorrent_starter_rust::{
+    error::TorrentError,
+    trackers::{
+        self,
+        udp::{UdpTrackerClient, UdpTrackerConfig},
+        AnnounceEvent, AnnounceRequest, ScrapeStats,
+    },
+};
+use tokio::net::UdpSocket;
+
+#[derive(Debug, Default)]
+struct StandInState {
+    drop_count: usize,
+    error_message: Option<String>,
+    connect_count: u64,
+    requests: Vec<Vec<u8>>,
+}
+
+// Minimal BEP 15 tracker answering on loopback.
+async fn spawn_tracker(state: StandInState) -> (String, Arc<Mutex<StandInState>>) {
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("udp://{}/announce", socket.local_addr().unwrap());
+    let state = Arc::new(Mutex::new(state));
+
+    let task_state = state.clone();
+    tokio::spawn(async move {
+        let mut buf = [0; 1024];
+        loop {
+            let (len, from) = socket.recv_from(&mut buf).await.unwrap();
+            let packet = buf[..len].to_vec();
+
+            let response = {
+                let mut state = task_state.lock().unwrap();
+                if state.drop_count > 0 {
+                    state.drop_count -= 1;
+                    continue;
+                }
+                state.requests.push(packet.clone());
+
+                let action = &packet[8..12];
+                let transaction_id = &packet[12..16];
+                let mut response = Vec::new();
+
+                if let Some(msg) = &state.error_message {
+                    response.extend_from_slice(&3u32.to_be_bytes());
+                    response.extend_from_slice(transaction_id);
+                    response.extend_from_slice(msg.as_bytes());
+                } else {
+                    response.extend_from_slice(action);
+                    response.extend_from_slice(transaction_id);
+                    match action[3] {
+                        0 => {
+                            assert_eq!(packet[..8], 0x41727101980u64.to_be_bytes());
+                            state.connect_count += 1;
+                            response.extend_from_slice(&(1000 + state.connect_count).to_be_bytes());
+                        }
+                        1 => {
+                            response.extend_from_slice(&1800u32.to_be_bytes()); // interval
+                            response.extend_from_slice(&3u32.to_be_bytes()); // leechers
+                            response.extend_from_slice(&7u32.to_be_bytes()); // seeders
+                            response.extend_from_slice(&[127, 0, 0, 1, 0x1a, 0xe1]);
+                            response.extend_from_slice(&[10, 0, 0, 2, 0x1a, 0xe2]);
+                        }
+                        2 => {
+                            for idx in 0..(packet.len() - 16) / 20 {
+                                let idx = idx as u32;
+                                response.extend_from_slice(&(10 + idx).to_be_bytes());
+                                response.extend_from_slice(&(20 + idx).to_be_bytes());
+                                response.extend_from_slice(&(30 + idx).to_be_bytes());
+                            }
+                        }
+                        _ => unreachable!(),
+                    }
+                }
+                response
+            };
+            socket.send_to(&response, from).await.unwrap();
+        }
+    });
+
+    (url, state)
+}
+
+fn fast_config() -> UdpTrackerConfig {
+    UdpTrackerConfig {
+        base_timeout: Duration::from_millis(50),
+        max_retries: 3,
+        ..Default::default()
+    }
+}
+
+fn announce_request() -> AnnounceRequest {
+    AnnounceRequest {
+        info_hash: [1; 20],
+        peer_id: [2; 20],
+        port: 6881,
+        uploaded: 10,
+        downloaded: 20,
+        left: 30,
+        event: AnnounceEvent::Started,
+    }
+}
+
+#[tokio::test]
+async fn test_announce() {
+    let (url, state) = spawn_tracker(StandInState::default()).await;
+    let mut client = UdpTrackerClient::connect_with_config(&url, fast_config())
+        .await
+        .unwrap();
+
+    let response = client.announce(&announce_request()).await.unwrap();
+    assert_eq!(response.interval, 1800);
+    assert_eq!(response.incomplete, Some(3));
+    assert_eq!(response.complete, Some(7));
+    assert_eq!(
+        response.peer_addrs(),
+        vec![
+            SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 6881),
+            SocketAddr::new(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2)), 6882),
+        ]
+    );
+
+    // Check announce packet layout
+    let state = state.lock().unwrap();
+    assert_eq!(state.requests.len(), 2);
+    let packet = &state.requests[1];
+    assert_eq!(packet.len(), 98);
+    assert_eq!(packet[..8], 1001u64.to_be_bytes()); // connection ID
+    assert_eq!(packet[16..36], [1; 20]); // info hash
+    assert_eq!(packet[36..56], [2; 20]); // peer ID
+    assert_eq!(packet[56..64], 20u64.to_be_bytes()); // downloaded
+    assert_eq!(packet[64..72], 30u64.to_be_bytes()); // left
+    assert_eq!(packet[72..80], 10u64.to_be_bytes()); // uploaded
+    assert_eq!(packet[80..84], 2u32.to_be_bytes()); // event: started
+    assert_eq!(packet[96..98], 6881u16.to_be_bytes()); // port
+}
+
+#[tokio::test]
+async fn test_announce_by_scheme() {
+    let (url, _state) = spawn_tracker(StandInState::default()).await;
+
+    let response = trackers::announce(&url, &announce_request()).await.unwrap();
+    assert_eq!(response.peer_addrs().len(), 2);
+
+    let err = trackers::announce("wss://tracker.test", &announce_request())
+        .await
+        .unwrap_err();
+    assert!(matches!(err, TorrentError::Tracker(_)));
+}
+
+#[tokio::test]
+async fn test_connection_id_reused() {
+    let (url, state) = spawn_tracker(StandInState::default()).await;
+    let mut client = UdpTrackerClient::connect_with_config(&url, fast_config())
+        .await
+        .unwrap();
+
+    client.announce(&announce_request()).await.unwrap();
+    client.announce(&announce_request()).await.unwrap();
+    assert_eq!(state.lock().unwrap().connect_count, 1);
+}
+
+#[tokio::test]
+async fn test_connection_id_expired() {
+    let (url, state) = spawn_tracker(StandInState::default()).await;
+    let config = UdpTrackerConfig {
+        connection_ttl: Duration::ZERO,
+        ..fast_config()
+    };
+    let mut client = UdpTrackerClient::connect_with_config(&url, config)
+        .await
+        .unwrap();
+
+    client.announce(&announce_request()).await.unwrap();
+    client.announce(&announce_request()).await.unwrap();
+
+    let state = state.lock().unwrap();
+    assert_eq!(state.connect_count, 2);
+    assert_eq!(state.requests[3][..8], 1002u64.to_be_bytes());
+}
+
+#[tokio::test]
+async fn test_retry_lost_packets() {
+    let (url, state) = spawn_tracker(StandInState {
+        drop_count: 2,
+        ..Default::default()
+    })
+    .await;
+    let mut client = UdpTrackerClient::connect_with_config(&url, fast_config())
+        .await
+        .unwrap();
+
+    let response = client.announce(&announce_request()).await.unwrap();
+    assert_eq!(response.interval, 1800);
+    assert_eq!(state.lock().unwrap().connect_count, 1);
+}
+
+#[tokio::test]
+async fn test_no_response() {
+    let (url, _state) = spawn_tracker(StandInState {
+        drop_count: usize::MAX,
+        ..Default::default()
+    })
+    .await;
+    let config = UdpTrackerConfig {
+        base_timeout: Duration::from_millis(10),
+        max_retries: 2,
+        ..Default::default()
+    };
+    let mut client = UdpTrackerClient::connect_with_config(&url, config)
+        .await
+        .unwrap();
+
+    let err = client.announce(&announce_request()).await.unwrap_err();
+    assert_eq!(err.to_string(), "Tracker: UDP tracker did not respond");
+}
+
+#[tokio::test]
+async fn test_request_timeout() {
+    let (url, _state) = spawn_tracker(StandInState {
+        drop_count: usize::MAX,
+        ..Default::default()
+    })
+    .await;
+    let config = UdpTrackerConfig {
+        request_timeout: Some(Duration::from_millis(100)),
+        ..UdpTrackerConfig::bep15()
+    };
+    let mut client = UdpTrackerClient::connect_with_config(&url, config)
+        .await
+        .unwrap();
+
+    // Gives up long before the first 15s retransmit
+    let start = Instant::now();
+    let err = client.announce(&announce_request()).await.unwrap_err();
+    assert_eq!(err.to_string(), "Tracker: UDP tracker did not respond");
+    assert!(start.elapsed() < Duration::from_secs(5));
+}
+
+#[tokio::test]
+async fn test_error_action() {
+    let (url, _state) = spawn_tracker(StandInState {
+        error_message: Some("torrent not registered".to_string()),
+        ..Default::default()
+    })
+    .await;
+    let mut client = UdpTrackerClient::connect_with_config(&url, fast_config())
+        .await
+        .unwrap();
+
+    let err = client.announce(&announce_request()).await.unwrap_err();
+    assert!(matches!(err, TorrentError::TrackerFailure(_)));
+    assert_eq!(err.to_string(), "Tracker failure: torrent not registered");
+}
+
+#[tokio::test]
+async fn test_scrape() {
+    let (url, state) = spawn_tracker(StandInState::default()).await;
+    let mut client = UdpTrackerClient::connect_with_config(&url, fast_config())
+        .await
+        .unwrap();
+
+    let stats = client.scrape(&[[1; 20], [2; 20]]).await.unwrap();
+    assert_eq!(
+        stats,
+        vec![
+            ScrapeStats {
+                complete: 10,
+                downloaded: 20,
+                incomplete: 30
+            },
+            ScrapeStats {
+                complete: 11,
+                downloaded: 21,
+                incomplete: 31
+            },
+        ]
+    );
+
+    let state = state.lock().unwrap();
+    assert_eq!(state.requests[1].len(), 16 + 2 * 20);
+}
+
+#[tokio::test]
+async fn test_scrape_by_scheme() {
+    let (url, _state) = spawn_tracker(StandInState::default()).await;
+
+    let stats = trackers::scrape(&url, &[[1; 20]]).await.unwrap();
+    assert_eq!(stats[0].complete, 10);
+}
+
+#[tokio::test]
+async fn test_invalid_url() {
+    let err = UdpTrackerClient::connect("udp://no-port.test/announce")
+        .await
+        .unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "Tracker: Missing port in tracker URL: udp://no-port.test/announce"
+    );
+}
@@ -1,4 +1,4 @@
-use bittorrent_starter_rust::url_encode::url_encode;
+use bittorrent_starter_rust::url_encode::{url_decode, url_encode};
 
 #[test]
 fn test_url_encode() {
@@ -6,3 +6,17 @@ fn test_url_encode() {
     assert_eq!(url_encode(b"hello"), "%68%65%6c%6c%6f");
     assert_eq!(url_encode(&[0, 1, 2, 3]), "%00%01%02%03");
 }
+
+#[test]
+fn test_url_decode() {
+    assert_eq!(url_decode(""), Some(vec![]));
+    assert_eq!(url_decode("hello"), Some(b"hello".to_vec()));
+    assert_eq!(url_decode("%68%65%6C%6c%6f"), Some(b"hello".to_vec()));
+    assert_eq!(url_decode("a+b%20c"), Some(b"a b c".to_vec()));
+    assert_eq!(url_decode("%00%ff"), Some(vec![0, 255]));
+
+    assert_eq!(url_decode("%"), None);
+    assert_eq!(url_decode("%4"), None);
+    assert_eq!(url_decode("%zz"), None);
+    assert_eq!(url_decode("%+1"), None);
+}